@@ -0,0 +1,207 @@
+//! Checking replays and working out how each player did, without a window.
+//!
+//! A replay is simulated again from its seed and inputs. If it ends in the
+//! state it was recorded with, the match really could have been played that
+//! way, which is what makes the numbers worth putting on a leaderboard.
+
+use serde::Serialize;
+
+use std::fmt::Write;
+
+use crate::replay::{Replay, ReplayPlayer};
+use crate::simulation;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PlayerAnalysis {
+    pub player_id: u32,
+    pub pieces_placed: u32,
+    pub pieces_per_second: f64,
+    pub actions: u32,
+    pub actions_per_minute: f64,
+    pub finesse_faults: u32,
+    /// How many times one, two, three and four lines were cleared at once.
+    pub lines_cleared: [u32; 4],
+    pub topped_out: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ReplayAnalysis {
+    pub ticks: u32,
+    pub seconds: f64,
+    /// Hashes are written out in hex, since JSON numbers can't hold every
+    /// `u64`.
+    pub recorded_hash: Option<String>,
+    pub final_hash: String,
+    /// Whether the replay ended in the state it was recorded with.
+    pub verified: bool,
+    pub players: Vec<PlayerAnalysis>,
+}
+
+/// Simulates the whole replay and collects what happened to each grid.
+pub fn analyze(replay: Replay) -> ReplayAnalysis {
+    let settings = replay.settings().clone();
+    let recorded_hash = replay.final_hash();
+
+    let mut player = ReplayPlayer::new(replay);
+    while !player.finished() {
+        player.step();
+    }
+    let final_hash = simulation::state_hash(player.grids());
+
+    let seconds = f64::from(player.length()) / f64::from(settings.tick_rate);
+    let per_second = |count: u32| {
+        if seconds > 0.0 {
+            f64::from(count) / seconds
+        } else {
+            0.0
+        }
+    };
+
+    let players = player
+        .grids()
+        .iter()
+        .enumerate()
+        .map(|(player_id, grid)| {
+            let stats = grid.stats();
+            PlayerAnalysis {
+                player_id: player_id as u32,
+                pieces_placed: stats.pieces_placed,
+                pieces_per_second: per_second(stats.pieces_placed),
+                actions: stats.actions,
+                actions_per_minute: per_second(stats.actions) * 60.0,
+                finesse_faults: stats.finesse_faults,
+                lines_cleared: stats.lines_cleared,
+                topped_out: grid.gameover,
+            }
+        })
+        .collect();
+
+    ReplayAnalysis {
+        ticks: player.length(),
+        seconds,
+        recorded_hash: recorded_hash.map(|hash| format!("{:016x}", hash)),
+        final_hash: format!("{:016x}", final_hash),
+        verified: recorded_hash == Some(final_hash),
+        players,
+    }
+}
+
+impl ReplayAnalysis {
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let verdict = match self.recorded_hash {
+            Some(_) if self.verified => "verified",
+            Some(_) => "MISMATCH",
+            None => "no hash recorded",
+        };
+        // Writing to a String can't fail
+        let _ = writeln!(
+            text,
+            "{} ticks ({:.1}s), final state {} ({})",
+            self.ticks, self.seconds, self.final_hash, verdict
+        );
+
+        for player in self.players.iter() {
+            let _ = writeln!(
+                text,
+                "player {}: {} pieces ({:.2}/s), {} actions ({:.0} APM), {} finesse faults, \
+                 lines cleared 1x{} 2x{} 3x{} 4x{}{}",
+                player.player_id + 1,
+                player.pieces_placed,
+                player.pieces_per_second,
+                player.actions,
+                player.actions_per_minute,
+                player.finesse_faults,
+                player.lines_cleared[0],
+                player.lines_cleared[1],
+                player.lines_cleared[2],
+                player.lines_cleared[3],
+                if player.topped_out {
+                    ", topped out"
+                } else {
+                    ""
+                },
+            );
+        }
+        text
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn recorded_match(tampered: bool) -> Replay {
+    use crate::grid::GridInputEvent;
+    use crate::replay::ReplaySettings;
+    use crate::simulation::Rules;
+
+    let settings = ReplaySettings {
+        num_players: 2,
+        seed: 5,
+        grid_height: 40,
+        grid_width: 10,
+        tick_rate: 60,
+        rules: Rules::Versus,
+    };
+    let mut replay = Replay::new(settings);
+    let mut grids = simulation::new_grids(40, 10, 2, 5);
+
+    for tick in 0..600 {
+        let mut inputs = Vec::new();
+        // Three columns to either side and down, which wastes nothing
+        if tick % 30 == 0 {
+            let side = if tick % 60 == 0 {
+                GridInputEvent::MoveLeft
+            } else {
+                GridInputEvent::MoveRight
+            };
+            inputs.extend(vec![(0, side); 3]);
+            inputs.push((0, GridInputEvent::ForceToBottom));
+        }
+        // Turning all the way round and down wastes every press
+        if tick % 60 == 0 {
+            inputs.extend(vec![(1, GridInputEvent::Rotate); 4]);
+            inputs.push((1, GridInputEvent::ForceToBottom));
+        }
+        replay.push_tick(&inputs);
+        // A tampered replay claims an input the grids never saw
+        if tampered && tick == 300 {
+            inputs.push((0, GridInputEvent::MoveLeft));
+        }
+        simulation::step(&mut grids, &inputs, &Rules::Versus);
+    }
+    replay.finish(&grids);
+    replay
+}
+
+#[test]
+fn test_analysis_counts_each_players_play() {
+    let analysis = analyze(recorded_match(false));
+    assert!(analysis.verified);
+    assert_eq!(analysis.ticks, 600);
+    assert_eq!(analysis.seconds, 10.0);
+
+    let first = &analysis.players[0];
+    assert_eq!(first.pieces_placed, 20);
+    assert_eq!(first.pieces_per_second, 2.0);
+    assert_eq!(first.actions_per_minute, 480.0);
+    assert_eq!(first.finesse_faults, 0);
+    assert!(!first.topped_out);
+
+    let second = &analysis.players[1];
+    assert_eq!(second.pieces_placed, 10);
+    assert_eq!(second.actions, 50);
+    assert_eq!(second.finesse_faults, 40);
+}
+
+#[test]
+fn test_analysis_catches_replays_that_play_out_differently() {
+    let analysis = analyze(recorded_match(true));
+    assert!(!analysis.verified);
+    assert!(analysis.to_text().contains("MISMATCH"));
+
+    let json = serde_json::to_string(&analysis).unwrap();
+    assert!(json.contains("\"verified\":false"));
+}
@@ -0,0 +1,223 @@
+//! Checks on the commands a client sends, since a modified client can send
+//! as many as it likes.
+//!
+//! Each player's commands carry a sequence number so a command captured and
+//! sent again is dropped, and are limited in how many can be applied on one
+//! tick or within a second. Rates no person could keep up are flagged for
+//! the logs even when they stay under the limits.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Default for the most commands applied on a single tick. Several can
+/// arrive together after a hiccup in the network, so this allows a few.
+pub const DEFAULT_MAX_PER_TICK: u32 = 8;
+/// Default for the most commands applied within a second. Holding down two
+/// keys with key repeat comes to about this many.
+pub const DEFAULT_MAX_PER_SECOND: u32 = 60;
+/// Rates are measured over this long.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/// Commands a second above which we doubt a person is pressing the keys.
+/// Key repeat on one held key stays well below.
+const SUSPICIOUS_PER_SECOND: usize = 40;
+/// Flags for the same player are logged at most this often.
+const FLAG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InputLimits {
+    pub max_per_tick: u32,
+    pub max_per_second: u32,
+    /// Kick the player once this many commands have broken the limits.
+    /// `None` only drops them.
+    pub kick_after: Option<u32>,
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        Self {
+            max_per_tick: DEFAULT_MAX_PER_TICK,
+            max_per_second: DEFAULT_MAX_PER_SECOND,
+            kick_after: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Violation {
+    TooManyPerTick,
+    TooManyPerSecond,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::TooManyPerTick => write!(f, "too many inputs in one tick"),
+            Violation::TooManyPerSecond => write!(f, "too many inputs per second"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Accept,
+    /// Accepted, but faster than we think anyone could press the keys.
+    Suspicious {
+        per_second: usize,
+    },
+    /// Already seen, or older than one that was. Duplicates happen on
+    /// their own over UDP, so these count against nobody.
+    Replayed,
+    Reject(Violation),
+    /// Broke the limits once too often.
+    Kick(Violation),
+}
+
+/// Keeps track of the commands from one player.
+pub struct InputGuard {
+    limits: InputLimits,
+    last_sequence: Option<u32>,
+    tick: u64,
+    this_tick: u32,
+    // When each command accepted within the last window arrived
+    recent: VecDeque<Instant>,
+    violations: u32,
+    last_flagged: Option<Instant>,
+}
+
+impl InputGuard {
+    pub fn new(limits: InputLimits) -> Self {
+        Self {
+            limits,
+            last_sequence: None,
+            tick: 0,
+            this_tick: 0,
+            recent: VecDeque::new(),
+            violations: 0,
+            last_flagged: None,
+        }
+    }
+
+    /// Decides what to do with a command numbered `sequence` that arrived
+    /// at `now`, to be applied on `tick`.
+    pub fn check(&mut self, sequence: u32, tick: u64, now: Instant) -> Verdict {
+        if let Some(last_sequence) = self.last_sequence {
+            if sequence <= last_sequence {
+                return Verdict::Replayed;
+            }
+        }
+        self.last_sequence = Some(sequence);
+
+        if tick != self.tick {
+            self.tick = tick;
+            self.this_tick = 0;
+        }
+        while let Some(arrived) = self.recent.front() {
+            if now.duration_since(*arrived) < RATE_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+
+        let violation = if self.this_tick >= self.limits.max_per_tick {
+            Some(Violation::TooManyPerTick)
+        } else if self.recent.len() as u32 >= self.limits.max_per_second {
+            Some(Violation::TooManyPerSecond)
+        } else {
+            None
+        };
+        if let Some(violation) = violation {
+            self.violations += 1;
+            return match self.limits.kick_after {
+                Some(kick_after) if self.violations >= kick_after => Verdict::Kick(violation),
+                _ => Verdict::Reject(violation),
+            };
+        }
+
+        self.this_tick += 1;
+        self.recent.push_back(now);
+
+        let per_second = self.recent.len();
+        let flag_due = match self.last_flagged {
+            Some(last_flagged) => now.duration_since(last_flagged) >= FLAG_INTERVAL,
+            None => true,
+        };
+        if per_second > SUSPICIOUS_PER_SECOND && flag_due {
+            self.last_flagged = Some(now);
+            return Verdict::Suspicious { per_second };
+        }
+        Verdict::Accept
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_guard_drops_replayed_commands() {
+    let now = Instant::now();
+    let mut guard = InputGuard::new(InputLimits::default());
+
+    assert_eq!(guard.check(1, 0, now), Verdict::Accept);
+    assert_eq!(guard.check(1, 0, now), Verdict::Replayed);
+    assert_eq!(guard.check(3, 1, now), Verdict::Accept);
+    assert_eq!(guard.check(2, 1, now), Verdict::Replayed);
+}
+
+#[test]
+fn test_guard_limits_inputs_per_tick_and_second() {
+    let start = Instant::now();
+    let mut guard = InputGuard::new(InputLimits {
+        max_per_tick: 2,
+        max_per_second: 5,
+        kick_after: Some(3),
+    });
+
+    assert_eq!(guard.check(0, 0, start), Verdict::Accept);
+    assert_eq!(guard.check(1, 0, start), Verdict::Accept);
+    assert_eq!(
+        guard.check(2, 0, start),
+        Verdict::Reject(Violation::TooManyPerTick)
+    );
+
+    // A new tick, but not a new second
+    for sequence in 3..6 {
+        assert_eq!(
+            guard.check(sequence, sequence as u64, start),
+            Verdict::Accept
+        );
+    }
+    assert_eq!(
+        guard.check(6, 6, start),
+        Verdict::Reject(Violation::TooManyPerSecond)
+    );
+
+    let later = start + RATE_WINDOW;
+    assert_eq!(guard.check(7, 7, later), Verdict::Accept);
+    assert_eq!(guard.check(8, 7, later), Verdict::Accept);
+    assert_eq!(
+        guard.check(9, 7, later),
+        Verdict::Kick(Violation::TooManyPerTick)
+    );
+}
+
+#[test]
+fn test_guard_flags_inhuman_rates_now_and_then() {
+    let start = Instant::now();
+    let mut guard = InputGuard::new(InputLimits::default());
+
+    let mut flagged = 0;
+    for n in 0..(SUSPICIOUS_PER_SECOND as u32 + 10) {
+        let now = start + Duration::from_millis(15 * u64::from(n));
+        match guard.check(n, u64::from(n), now) {
+            Verdict::Accept => {}
+            Verdict::Suspicious { per_second } => {
+                assert!(per_second > SUSPICIOUS_PER_SECOND);
+                flagged += 1;
+            }
+            verdict => panic!("unexpected {:?}", verdict),
+        }
+    }
+    assert_eq!(flagged, 1);
+}
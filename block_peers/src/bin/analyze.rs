@@ -0,0 +1,85 @@
+extern crate getopts;
+
+// External
+use getopts::Options;
+
+// Std
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Internal
+use block_peers::analysis::{self, ReplayAnalysis};
+use block_peers::replay::Replay;
+
+// Nothing here opens a window or plays a sound, so it runs anywhere the
+// replays can be read, such as a leaderboard server.
+pub fn main() {
+    let options = get_options();
+    let mut all_verified = true;
+
+    for path in options.replays.iter() {
+        let replay = match Replay::load(path) {
+            Ok(replay) => replay,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                all_verified = false;
+                continue;
+            }
+        };
+
+        let analysis = analysis::analyze(replay);
+        all_verified &= analysis.verified;
+        print(path, &analysis, options.json);
+    }
+
+    // Lets scripts tell without reading the output whether every replay
+    // played out the way it was recorded
+    if !all_verified {
+        process::exit(1);
+    }
+}
+
+fn print(path: &Path, analysis: &ReplayAnalysis, json: bool) {
+    if json {
+        // One object a line, so several replays can be streamed
+        let line = serde_json::json!({ "file": path, "analysis": analysis });
+        println!("{}", line);
+    } else {
+        println!("{}", path.display());
+        print!("{}", analysis.to_text());
+    }
+}
+
+struct AnalyzeOptions {
+    json: bool,
+    replays: Vec<PathBuf>,
+}
+
+fn get_options() -> AnalyzeOptions {
+    let args: Vec<String> = env::args().collect();
+
+    let mut opts = Options::new();
+    opts.optflag(
+        "j",
+        "json",
+        "print one JSON object per replay instead of text",
+    );
+    opts.optflag("", "help", "print this help");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => panic!("{}", f),
+    };
+
+    if matches.opt_present("help") || matches.free.is_empty() {
+        let brief = format!("Usage: {} [options] REPLAY...", args[0]);
+        print!("{}", opts.usage(&brief));
+        process::exit(2);
+    }
+
+    AnalyzeOptions {
+        json: matches.opt_present("json"),
+        replays: matches.free.iter().map(PathBuf::from).collect(),
+    }
+}
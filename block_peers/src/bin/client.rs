@@ -13,16 +13,24 @@ use sdl2::keyboard::Keycode;
 // Std
 use std::env;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
 // Internal
+use block_peers::codec::CodecKind;
+use block_peers::grid::GameSoundEvent;
 use block_peers::logging;
-use block_peers::net::{ServerMessage, Socket};
+use block_peers::net::{ServerMessage, Socket, DEFAULT_CODEC, DEFAULT_TIMEOUT};
+use block_peers::profile::{self, Profile};
 use block_peers::render::{Renderer, VIEWPORT_HEIGHT, VIEWPORT_WIDTH};
-use block_peers::scene::{AppLifecycleEvent, GameSoundEvent, Scene};
-use block_peers::scenes::TitleScene;
+use block_peers::replay::{self, Replay};
+use block_peers::scene::{AppLifecycleEvent, Scene};
+use block_peers::scenes::peer_connect::PeerMode;
+use block_peers::scenes::{ReplayScene, TitleScene, TitleSettings};
 use block_peers::sound::{AudioManager, SoundEffect};
+use block_peers::transport::{
+    NetworkConditions, SimulatedTransport, Transport, UdpTransport, WebSocketTransport,
+};
 
 // Constants
 const WINDOW_WIDTH: u32 = VIEWPORT_WIDTH;
@@ -74,10 +82,64 @@ pub fn main() {
     let mut fps_timer = Instant::now();
 
     // Network
-    let mut socket = Socket::new().expect("could not open a new socket");
+    // When hosting a direct match other peers need to know where to find
+    // us, so listen on the given port instead of any available one.
+    let mut transport: Box<dyn Transport> = match options.peer_mode {
+        Some(PeerMode::Host) => Box::new(
+            UdpTransport::bind(SocketAddr::new(DEFAULT_BIND_HOST, options.port))
+                .expect("could not open a new socket"),
+        ),
+        _ if options.websocket => {
+            Box::new(WebSocketTransport::connect(server_addr).expect("could not open a new socket"))
+        }
+        _ => {
+            let transport = UdpTransport::bind(ANY_ADDR).expect("could not open a new socket");
+            // Lets the title screen look for games on the local network
+            if let Err(e) = transport.set_broadcast(true) {
+                warn!("unable to broadcast, LAN games won't be found: {}", e);
+            }
+            Box::new(transport)
+        }
+    };
+    if let Some(conditions) = options.simulate {
+        info!("simulating network conditions {:?}", conditions);
+        transport = Box::new(SimulatedTransport::new(transport, conditions));
+    }
+    let mut socket = Socket::with_transport(transport);
+    socket.set_codec(options.codec);
+
+    // Profile
+    let mut local_profile = match Profile::load_or_create(&options.profile) {
+        Ok(local_profile) => local_profile,
+        Err(e) => {
+            warn!(
+                "could not load profile {}: {}",
+                options.profile.display(),
+                e
+            );
+            Profile::default()
+        }
+    };
+    if let Some(name) = options.name {
+        local_profile.name = name;
+    }
+    profile::set_local_profile(local_profile);
 
     // Scene
-    let mut scene: Box<dyn Scene> = Box::new(TitleScene::new(server_addr));
+    replay::set_replay_dir(options.record);
+    let mut scene: Box<dyn Scene> = match options.replay {
+        Some(path) => {
+            let replay = Replay::load(&path)
+                .unwrap_or_else(|e| panic!("could not load replay {}: {}", path.display(), e));
+            Box::new(ReplayScene::new(replay))
+        }
+        None => Box::new(TitleScene::new(TitleSettings {
+            server_addr,
+            peer_mode: options.peer_mode,
+            timeout: options.timeout,
+            require_encryption: options.encrypt,
+        })),
+    };
 
     // Audio
     let mut audio_manager = AudioManager::new();
@@ -98,8 +160,8 @@ pub fn main() {
                 Ok(None) => {
                     break;
                 }
-                Err(_) => {
-                    error!("received unknown message");
+                Err(e) => {
+                    error!("received unknown message: {}", e);
                 }
             }
         }
@@ -107,6 +169,16 @@ pub fn main() {
         // Input
         for event in event_pump.poll_iter() {
             match event {
+                Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Q),
+                    ..
+                } if scene.typing() => {
+                    scene = scene.input(&mut socket, event);
+                }
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
@@ -181,12 +253,26 @@ pub fn main() {
 
 const DEFAULT_PORT: u16 = 4485;
 const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+const DEFAULT_BIND_HOST: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+const ANY_ADDR: SocketAddr = SocketAddr::new(DEFAULT_BIND_HOST, 0);
+const DEFAULT_SESSION: &str = "block-peers";
+const DEFAULT_PROFILE: &str = "profile.cfg";
 
 struct ClientOptions {
     port: u16,
     host: IpAddr,
     fullscreen: bool,
     no_sound: bool,
+    peer_mode: Option<PeerMode>,
+    timeout: Duration,
+    encrypt: bool,
+    codec: CodecKind,
+    websocket: bool,
+    simulate: Option<NetworkConditions>,
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    name: Option<String>,
+    profile: PathBuf,
 }
 
 fn get_options() -> ClientOptions {
@@ -207,6 +293,81 @@ fn get_options() -> ClientOptions {
     );
     opts.optflag("f", "fullscreen", "open the game in a fullscreen window");
     opts.optflag("s", "no-sound", "open the game without sound");
+    opts.optopt(
+        "t",
+        "timeout",
+        "seconds without hearing from the server before giving up (default 10)",
+        "SECONDS",
+    );
+    opts.optflag(
+        "e",
+        "encrypt",
+        "only connect to a server that encrypts the connection",
+    );
+    opts.optopt(
+        "c",
+        "codec",
+        "compress larger packets with none, deflate, lz4 or zstd (default deflate)",
+        "CODEC",
+    );
+    opts.optflag(
+        "w",
+        "websocket",
+        "connect to the server over WebSocket (TCP) instead of UDP",
+    );
+    opts.optopt(
+        "",
+        "simulate",
+        "add latency, jitter, loss, duplication and reordering, e.g. latency=100,loss=5",
+        "CONDITIONS",
+    );
+    opts.optopt(
+        "",
+        "record",
+        "save a replay of every lockstep match to the specified directory",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "replay",
+        "watch the replay saved in the specified file",
+        "FILE",
+    );
+    opts.optopt(
+        "n",
+        "name",
+        "name shown above your board, instead of the one in your profile",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "profile",
+        "read your name and player id from the specified file (default profile.cfg)",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "host-peer",
+        "host a game on the specified port for another peer to join, no server needed",
+    );
+    opts.optopt(
+        "",
+        "join-peer",
+        "join a game hosted by another peer at the specified address",
+        "HOST:PORT",
+    );
+    opts.optopt(
+        "",
+        "rendezvous",
+        "find another peer through the rendezvous server at the specified address",
+        "HOST:PORT",
+    );
+    opts.optopt(
+        "",
+        "session",
+        "session name to register with the rendezvous server (default block-peers)",
+        "NAME",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -228,10 +389,80 @@ fn get_options() -> ClientOptions {
     let fullscreen: bool = matches.opt_present("fullscreen");
     let no_sound: bool = matches.opt_present("no-sound");
 
+    let timeout: Duration = match matches.opt_get("timeout") {
+        Ok(Some(seconds)) => Duration::from_secs(seconds),
+        Ok(None) => DEFAULT_TIMEOUT,
+        Err(_) => panic!("specified timeout is not valid"),
+    };
+
+    let encrypt: bool = matches.opt_present("encrypt");
+
+    let codec: CodecKind = match matches.opt_get("codec") {
+        Ok(Some(codec)) => codec,
+        Ok(None) => DEFAULT_CODEC,
+        Err(_) => panic!("specified codec is not valid"),
+    };
+
+    let websocket: bool = matches.opt_present("websocket");
+
+    let simulate: Option<NetworkConditions> = match matches.opt_get("simulate") {
+        Ok(conditions) => conditions,
+        Err(e) => panic!("specified simulate is not valid: {}", e),
+    };
+
+    let record: Option<PathBuf> = matches.opt_str("record").map(PathBuf::from);
+    let replay: Option<PathBuf> = matches.opt_str("replay").map(PathBuf::from);
+
+    let name: Option<String> = match matches.opt_str("name") {
+        Some(name) => match profile::clean_name(&name) {
+            Some(name) => Some(name),
+            None => panic!("specified name is empty"),
+        },
+        None => None,
+    };
+    let profile: PathBuf = matches
+        .opt_str("profile")
+        .map_or_else(|| PathBuf::from(DEFAULT_PROFILE), PathBuf::from);
+
+    let join_peer: Option<SocketAddr> = match matches.opt_get("join-peer") {
+        Ok(addr) => addr,
+        Err(_) => panic!("specified join-peer was not a valid socket address"),
+    };
+
+    let rendezvous: Option<SocketAddr> = match matches.opt_get("rendezvous") {
+        Ok(addr) => addr,
+        Err(_) => panic!("specified rendezvous was not a valid socket address"),
+    };
+
+    let session: String = matches
+        .opt_str("session")
+        .unwrap_or_else(|| String::from(DEFAULT_SESSION));
+
+    let peer_mode = if matches.opt_present("host-peer") {
+        Some(PeerMode::Host)
+    } else if let Some(addr) = join_peer {
+        Some(PeerMode::Join(addr))
+    } else {
+        rendezvous.map(|introducer| PeerMode::Rendezvous {
+            introducer,
+            session,
+        })
+    };
+
     ClientOptions {
         host,
         port,
         fullscreen,
         no_sound,
+        peer_mode,
+        timeout,
+        encrypt,
+        codec,
+        websocket,
+        simulate,
+        record,
+        replay,
+        name,
+        profile,
     }
 }
@@ -3,83 +3,122 @@ extern crate log;
 extern crate bincode;
 extern crate getopts;
 
-use block_peers::grid::{Grid, GridAttackEvent, GridInputEvent};
+use block_peers::anticheat::{
+    InputGuard, InputLimits, Verdict, Violation, DEFAULT_MAX_PER_SECOND, DEFAULT_MAX_PER_TICK,
+};
+use block_peers::chat::ChatRoom;
+use block_peers::codec::CodecKind;
+use block_peers::grid::{GameSoundEvent, Grid, GridInputEvent};
+use block_peers::lockstep::{
+    LockstepMessage, LockstepSettings, DEFAULT_INPUT_DELAY, GRID_HEIGHT, GRID_WIDTH,
+    MAX_INPUT_DELAY,
+};
 use block_peers::logging;
-use block_peers::net::{ClientMessage, ServerEvent, ServerMessage, ServerSocket};
+use block_peers::net::{
+    ClientMessage, ServerEvent, ServerInfo, ServerMessage, ServerSocket, DEFAULT_CODEC,
+    DEFAULT_TIMEOUT, MAX_SERVER_NAME, PROTOCOL_VERSION,
+};
+use block_peers::profile::{PlayerInfo, Profile};
+use block_peers::rendezvous::Rendezvous;
+use block_peers::replay::{self, Replay, ReplaySettings};
+use block_peers::results::{EndReports, MatchResult, Placements, RematchVote};
+use block_peers::royale;
+use block_peers::simulation::{self, Rules};
+use block_peers::snapshot;
+use block_peers::stats::NetStats;
+use block_peers::throttle::SendThrottle;
+use block_peers::transport::{
+    NetworkConditions, SimulatedTransport, Transport, UdpTransport, WebSocketTransport,
+};
 
 use getopts::Options;
-use std::borrow::Cow;
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::env;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
-const GRID_HEIGHT: u32 = 20;
-const GRID_WIDTH: u32 = 10;
-const TICKS_PER_SECOND: u64 = 60;
-const MICROSECONDS_PER_SECOND: u64 = 1_000_000;
-const MICROSECONDS_PER_TICK: u64 = MICROSECONDS_PER_SECOND / TICKS_PER_SECOND;
+// Gravity and animations are counted in ticks, so the tick rate sets the
+// speed of the game
+const DEFAULT_TICK_RATE: u32 = 60;
+const DEFAULT_SEND_RATE: u32 = 20;
+// Bytes per second each client can be sent before we send less often
+const DEFAULT_BANDWIDTH: u32 = 16 * 1024;
 
 const DEFAULT_PORT: u16 = 4485;
 const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
 const DEFAULT_PLAYERS_PER_GAME: u32 = 1;
+const DEFAULT_NAME: &str = "Block Wars";
+// How long a player that timed out has to reconnect before forfeiting
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
 fn main() {
     logging::init();
     let options = get_options();
 
     let server_addr = SocketAddr::new(DEFAULT_HOST, options.port);
-    let mut socket = ServerSocket::bind(server_addr).expect("could not create socket");
 
-    let tick_duration = Duration::from_micros(MICROSECONDS_PER_TICK);
+    if options.rendezvous {
+        run_rendezvous(server_addr);
+        return;
+    }
+
+    let mut transport: Box<dyn Transport> = if options.websocket {
+        Box::new(WebSocketTransport::bind(server_addr).expect("could not create socket"))
+    } else {
+        Box::new(UdpTransport::bind(server_addr).expect("could not create socket"))
+    };
+    if let Some(conditions) = options.simulate {
+        info!("simulating network conditions {:?}", conditions);
+        transport = Box::new(SimulatedTransport::new(transport, conditions));
+    }
+    replay::set_replay_dir(options.record.clone());
+
+    let mut socket = ServerSocket::with_transport(transport);
+    socket.set_timeout(options.timeout);
+    socket.set_codec(options.codec);
+    if options.encrypt {
+        socket.enable_encryption();
+    }
+
+    let tick_duration = Duration::from_secs(1) / options.tick_rate;
     let mut previous_instant = Instant::now();
 
-    // The running game state: a list of player addrs and a list of
-    // grids. I tried to combine the addr and grid into a single
-    // struct, but I couldn't figure out how to extract just the grids
-    // for the ServerMessage::Sync message.
-    //
-    // The player_id is the index into each list (so it should be
-    // either 0 or 1 for a 2-player game).
-    let mut game: Option<(Vec<SocketAddr>, Vec<Grid>)> = None;
+    // The running game, if there is one.
+    let mut game: Option<Game> = None;
+
+    // Holds a list of clients connected along with their session
+    // tokens, profiles and team choices, and when there are at least 2
+    // clients connected it will start the game.
+    let mut connected_clients: HashMap<SocketAddr, LobbyClient> = HashMap::new();
 
-    // Holds a list of clients connected, and when there are at least
-    // 2 clients connected it will start the game.
-    let mut connected_clients: HashSet<SocketAddr> = HashSet::new();
+    // Everyone connected can talk to each other, playing or not.
+    let mut chat = ChatRoom::new();
 
-    'running: loop {
+    loop {
         let current_instant = Instant::now();
         while current_instant - previous_instant >= tick_duration {
-            match game {
-                // If there is an active game, update the grids each
-                // tick and sync the state to each client.
-                Some((ref client_addrs, ref mut grids)) => {
-                    let mut attacks: Vec<(u32, GridAttackEvent)> = Vec::new();
-                    for (i, grid) in grids.iter_mut().enumerate() {
-                        if let Some(attack) = grid.update() {
-                            attacks.push((i as u32, attack));
-                        }
-                    }
-
-                    for (from_player_id, attack) in attacks.iter() {
-                        for (i, grid) in grids.iter_mut().enumerate() {
-                            if i != *from_player_id as usize {
-                                grid.attack(attack.clone());
-                            }
-                        }
-                    }
-
-                    for (player_id, addr) in client_addrs.iter().enumerate() {
-                        let player_id = player_id as u32;
-                        let message = ServerMessage::Sync {
-                            player_id,
-                            grids: Cow::Borrowed(&grids),
-                        };
-                        socket.send(addr, &message).unwrap();
-                    }
+            if let Err(e) = socket.send_heartbeats() {
+                error!("error sending heartbeats: {}", e);
+            }
+            for (addr, sequence, line) in chat.outgoing(current_instant) {
+                if let Err(e) = socket.send(&addr, &ServerMessage::Chat { sequence, line }) {
+                    error!("error sending chat to {}: {}", addr, e);
+                }
+            }
 
-                    for grid in grids.iter_mut() {
-                        grid.sound_events.clear();
+            match game {
+                // If there is an active game, advance it by a tick.
+                Some(ref mut current_game) => {
+                    current_game.tick(&mut socket, current_instant);
+                    if current_game.all_players_left() {
+                        info!("all players left, ending the game");
+                        current_game.save_replay();
+                        game = None;
+                        chat.retain(|addr| connected_clients.contains_key(addr));
+                    } else if current_game.rematch_agreed() {
+                        info!("everyone wants a rematch, starting a new match");
+                        game = Some(current_game.rematch(&connected_clients, &options));
                     }
                 }
                 // If there isn't an active game, check if we have at
@@ -90,17 +129,17 @@ fn main() {
                     let players_per_game = options.players_per_game as usize;
 
                     if connected_clients.len() >= players_per_game {
-                        let clients = connected_clients
+                        let mut clients: Vec<(SocketAddr, LobbyClient)> = connected_clients
                             .iter()
-                            .cloned()
-                            .take(players_per_game)
+                            .map(|(addr, client)| (*addr, client.clone()))
                             .collect();
-                        let mut grids = Vec::with_capacity(players_per_game);
-                        for _ in 0..players_per_game {
-                            grids.push(Grid::new(GRID_HEIGHT, GRID_WIDTH));
-                        }
+                        let spectators = clients.split_off(players_per_game);
 
-                        game = Some((clients, grids));
+                        let mut new_game = Game::new(clients, &options);
+                        for (addr, client) in spectators {
+                            new_game.add_spectator(addr, client.token, &options);
+                        }
+                        game = Some(new_game);
                     }
                 }
             }
@@ -109,47 +148,164 @@ fn main() {
         }
 
         match socket.receive() {
-            Ok(Some(ServerEvent::ClientConnected(addr))) => {
-                connected_clients.insert(addr);
-            }
-            Ok(Some(ServerEvent::ClientDisconnected(_addr))) => {
-                break 'running;
-            }
-            Ok(Some(ServerEvent::GameEvent(addr, message))) => {
-                match message {
-                    ClientMessage::Command { player_id, event } => {
-                        trace!("server received command {:?}", event);
-
-                        // If there's an active game...
-                        if let Some((ref client_addrs, ref mut grids)) = game {
-                            let player_id = player_id as usize;
-
-                            // Check the specified player_id lines up with the
-                            // source addr before taking any action
-                            if player_id < client_addrs.len() && client_addrs[player_id] == addr {
-                                match event {
-                                    GridInputEvent::MoveLeft => {
-                                        grids[player_id].move_piece_left();
-                                    }
-                                    GridInputEvent::MoveRight => {
-                                        grids[player_id].move_piece_right();
-                                    }
-                                    GridInputEvent::MoveDown => {
-                                        grids[player_id].move_piece_down();
-                                    }
-                                    GridInputEvent::ForceToBottom => {
-                                        grids[player_id].move_piece_to_bottom();
-                                    }
-                                    GridInputEvent::Rotate => {
-                                        grids[player_id].rotate();
-                                    }
-                                }
-                            }
+            Ok(Some(ServerEvent::ClientConnected(addr, token, profile))) => {
+                match profile.id {
+                    Some(id) => info!("{} is {} ({:016x})", addr, profile.name, id),
+                    None => info!("{} is {}", addr, profile.name),
+                }
+                chat.join(addr, &profile.name);
+                connected_clients.insert(addr, LobbyClient::new(token, profile));
+
+                // Let them pick a team while they wait
+                if let Some(teams) = options.teams {
+                    let message = ServerMessage::TeamChoice { teams, team: None };
+                    if let Err(e) = socket.send(&addr, &message) {
+                        error!("error sending team choice to {}: {}", addr, e);
+                    }
+                }
+
+                // Anyone arriving after the match started watches it
+                if let Some(ref mut game) = game {
+                    game.add_spectator(addr, token, &options);
+                }
+            }
+            Ok(Some(ServerEvent::ClientDisconnected(addr))) => {
+                info!("client {} left", addr);
+                connected_clients.remove(&addr);
+                chat.leave(&addr);
+
+                if let Some(ref mut game) = game {
+                    game.forfeit(addr);
+                }
+            }
+            Ok(Some(ServerEvent::ClientTimedOut(addr))) => {
+                info!("client {} timed out", addr);
+                connected_clients.remove(&addr);
+
+                // They may come back to the match, and should find the
+                // chat where they left it
+                match game {
+                    Some(ref mut game) => game.hold_slot(addr),
+                    None => chat.leave(&addr),
+                }
+            }
+            Ok(Some(ServerEvent::ClientReconnecting(addr, token))) => {
+                let reconnected = match game {
+                    Some(ref mut game) => game.reconnect(addr, token),
+                    None => None,
+                };
+
+                match reconnected {
+                    Some(old_addr) => {
+                        info!("client {} reconnected from {}", old_addr, addr);
+                        let client = match connected_clients.remove(&old_addr) {
+                            Some(client) => client,
+                            None => LobbyClient::new(token, Profile::default()),
+                        };
+                        connected_clients.insert(addr, client);
+                        chat.moved(&old_addr, addr);
+                        if let Err(e) = socket.accept_reconnect(addr, token) {
+                            error!("error accepting reconnect from {}: {}", addr, e);
+                        }
+                    }
+                    None => {
+                        if let Err(e) = socket.reject(addr) {
+                            error!("error rejecting reconnect from {}: {}", addr, e);
                         }
                     }
-                    _ => {}
                 }
             }
+            Ok(Some(ServerEvent::Discovery(addr))) => {
+                let info = ServerInfo {
+                    name: options.name.clone(),
+                    version: PROTOCOL_VERSION,
+                    players: connected_clients.len() as u32,
+                    players_per_game: options.players_per_game,
+                    lockstep: options.lockstep,
+                    in_game: game.is_some(),
+                };
+                if let Err(e) = socket.send(&addr, &ServerMessage::ServerInfo(info)) {
+                    error!("error answering discovery from {}: {}", addr, e);
+                }
+            }
+            Ok(Some(ServerEvent::GameEvent(addr, message))) => match message {
+                ClientMessage::Command {
+                    player_id,
+                    sequence,
+                    event,
+                } => {
+                    trace!("server received command {:?}", event);
+
+                    let kick = match game {
+                        Some(ref mut game) => {
+                            game.handle_command(addr, player_id, sequence, event, Instant::now())
+                        }
+                        None => None,
+                    };
+
+                    if let Some(violation) = kick {
+                        connected_clients.remove(&addr);
+                        chat.leave(&addr);
+                        if let Some(ref mut game) = game {
+                            game.forfeit(addr);
+                        }
+                        if let Err(e) = socket.kick(addr, &violation.to_string()) {
+                            error!("error kicking {}: {}", addr, e);
+                        }
+                    }
+                }
+                ClientMessage::Lockstep(message) => {
+                    if let Some(ref mut game) = game {
+                        game.relay_lockstep(&mut socket, addr, message);
+                    }
+                }
+                ClientMessage::Chat { sequence, text } => {
+                    if let Some(received) = chat.receive(&addr, sequence, &text, Instant::now()) {
+                        let ack = ServerMessage::ChatAck { received };
+                        if let Err(e) = socket.send(&addr, &ack) {
+                            error!("error acknowledging chat from {}: {}", addr, e);
+                        }
+                    }
+                }
+                ClientMessage::ChatAck { received } => chat.acknowledge(&addr, received),
+                ClientMessage::Rematch => {
+                    if let Some(ref mut game) = game {
+                        game.vote_rematch(&mut socket, addr);
+                    }
+                }
+                ClientMessage::MatchOver { tick } => {
+                    if let Some(ref mut game) = game {
+                        game.report_match_over(addr, tick);
+                    }
+                }
+                ClientMessage::ChooseTeam { team } => {
+                    if let Some(client) = connected_clients.get_mut(&addr) {
+                        let teams = options.teams.unwrap_or(0);
+                        if team < teams {
+                            client.team = Some(team);
+                        }
+                        let message = ServerMessage::TeamChoice {
+                            teams,
+                            team: client.team,
+                        };
+                        if let Err(e) = socket.send(&addr, &message) {
+                            error!("error sending team choice to {}: {}", addr, e);
+                        }
+                    }
+                }
+                ClientMessage::ReturnToLobby => {
+                    let returned = match game {
+                        Some(ref mut game) => game.return_to_lobby(&mut socket, addr),
+                        None => true,
+                    };
+                    if returned {
+                        if let Err(e) = socket.send(&addr, &ServerMessage::ReturnedToLobby) {
+                            error!("error sending {} back to the lobby: {}", addr, e);
+                        }
+                    }
+                }
+                _ => {}
+            },
             Ok(None) => {}
             Err(e) => {
                 error!("error receiving message: {}", e);
@@ -158,9 +314,728 @@ fn main() {
     }
 }
 
+// Only introduce peers to each other so they can play without us.
+fn run_rendezvous(addr: SocketAddr) {
+    let mut rendezvous = Rendezvous::bind(addr).expect("could not create socket");
+    info!("rendezvous server listening on {}", addr);
+
+    loop {
+        if let Err(e) = rendezvous.poll() {
+            error!("error running rendezvous: {}", e);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+// Someone connected to the server, playing or not
+#[derive(Clone)]
+struct LobbyClient {
+    // Lets the client take back its slot in a match from a new address
+    token: u64,
+    profile: Profile,
+    // Team they asked to play on in a team match, if they asked
+    team: Option<u32>,
+}
+
+impl LobbyClient {
+    fn new(token: u64, profile: Profile) -> Self {
+        Self {
+            token,
+            profile,
+            team: None,
+        }
+    }
+}
+
+struct Game {
+    // The player_id is the index into this list (so it should be
+    // either 0 or 1 for a 2-player game).
+    players: Vec<Player>,
+    // Clients watching the match without a grid of their own
+    spectators: Vec<Spectator>,
+    grace_period: Duration,
+    mode: GameMode,
+    // Ticks simulated so far
+    tick: u64,
+    tick_rate: u32,
+    // What every client is told about each player, in player_id order
+    players_info: Vec<PlayerInfo>,
+    // How the match ended, once it has. Only the server knows in an
+    // authoritative match.
+    result: Option<MatchResult>,
+}
+
+struct Player {
+    addr: SocketAddr,
+    // Lets the client take back this slot from a new address
+    token: u64,
+    state: PlayerState,
+    feed: Feed,
+    guard: InputGuard,
+    // Wants to play the same match again once it's over
+    rematch: bool,
+}
+
+struct Spectator {
+    addr: SocketAddr,
+    token: u64,
+    feed: Feed,
+}
+
+// Snapshots of the grids going to one client, at whatever rate its
+// connection allows
+struct Feed {
+    throttle: SendThrottle,
+    // Sounds from each grid since the last snapshot this client was
+    // sent, so none are missed on the ticks in between. Only the newest
+    // few are kept for a client that's sent snapshots rarely.
+    pending_sounds: Vec<Vec<GameSoundEvent>>,
+}
+
+impl Feed {
+    fn new(options: &ServerOptions, num_grids: usize) -> Self {
+        Self {
+            throttle: SendThrottle::new(
+                options.tick_rate,
+                options.send_rate,
+                options.bandwidth,
+                Instant::now(),
+            ),
+            pending_sounds: vec![Vec::new(); num_grids],
+        }
+    }
+
+    fn collect_sounds(&mut self, grids: &[Grid]) {
+        for (pending, grid) in self.pending_sounds.iter_mut().zip(grids.iter()) {
+            pending.extend(grid.sound_events.iter().cloned());
+            if pending.len() > snapshot::MAX_SOUNDS {
+                let excess = pending.len() - snapshot::MAX_SOUNDS;
+                pending.drain(..excess);
+            }
+        }
+    }
+
+    // The grids to send on this tick, if it's time, along with every
+    // sound since the last ones
+    fn snapshot(
+        &mut self,
+        grids: &[Grid],
+        tick: u64,
+        stats: Option<NetStats>,
+        now: Instant,
+    ) -> Option<Vec<Grid>> {
+        if let Some(stats) = stats {
+            self.throttle.adjust(&stats, now);
+        }
+        if !self.throttle.ready(tick) {
+            return None;
+        }
+
+        let mut snapshot = grids.to_vec();
+        for (grid, pending) in snapshot.iter_mut().zip(self.pending_sounds.iter_mut()) {
+            grid.sound_events = std::mem::take(pending);
+        }
+        Some(snapshot)
+    }
+}
+
+#[derive(PartialEq)]
+enum PlayerState {
+    Connected,
+    // Timed out, but the slot is held in case they come back
+    Reconnecting { since: Instant },
+    // Left for good, their grid is lost
+    Forfeited,
+}
+
+enum GameMode {
+    // The server runs the simulation and syncs the grids to every
+    // client as often as their connection allows.
+    Authoritative {
+        grids: Vec<Grid>,
+        // Commands received since the last tick, applied at the start
+        // of the next one.
+        pending_inputs: Vec<(u32, GridInputEvent)>,
+        replay: Replay,
+        placements: Placements,
+        rules: Rules,
+    },
+    // Every client simulates the whole match and the server only
+    // relays inputs between them. Players keep getting told to start
+    // until we see their first message, and the match is only over once
+    // they all say so.
+    Lockstep {
+        settings: LockstepSettings,
+        started: Vec<bool>,
+        reports: EndReports,
+    },
+}
+
+impl Game {
+    fn new(clients: Vec<(SocketAddr, LobbyClient)>, options: &ServerOptions) -> Self {
+        let num_players = clients.len() as u32;
+        let seed = rand::random();
+        let choices: Vec<Option<u32>> = clients.iter().map(|(_, client)| client.team).collect();
+        let rules = options.rules(&choices);
+
+        let mode = if options.lockstep {
+            GameMode::Lockstep {
+                settings: LockstepSettings {
+                    num_players,
+                    seed,
+                    input_delay: options.input_delay,
+                    grid_height: GRID_HEIGHT,
+                    grid_width: GRID_WIDTH,
+                    rules: rules.clone(),
+                },
+                started: vec![false; clients.len()],
+                reports: EndReports::new(clients.len()),
+            }
+        } else {
+            GameMode::Authoritative {
+                grids: simulation::new_grids(GRID_HEIGHT, GRID_WIDTH, num_players, seed),
+                pending_inputs: Vec::new(),
+                replay: Replay::new(ReplaySettings {
+                    num_players,
+                    seed,
+                    grid_height: GRID_HEIGHT,
+                    grid_width: GRID_WIDTH,
+                    tick_rate: options.tick_rate,
+                    rules: rules.clone(),
+                }),
+                placements: Placements::new(num_players as usize, rules.clone()),
+                rules: rules.clone(),
+            }
+        };
+
+        let players_info = clients
+            .iter()
+            .enumerate()
+            .map(|(player_id, (_, client))| {
+                let mut info = PlayerInfo::from_profile(&client.profile, player_id as u32);
+                info.team = rules.team(player_id as u32);
+                info
+            })
+            .collect();
+
+        let players = clients
+            .into_iter()
+            .map(|(addr, client)| Player {
+                addr,
+                token: client.token,
+                state: PlayerState::Connected,
+                feed: Feed::new(options, num_players as usize),
+                guard: InputGuard::new(options.input_limits),
+                rematch: false,
+            })
+            .collect();
+
+        Self {
+            players,
+            spectators: Vec::new(),
+            grace_period: options.grace_period,
+            mode,
+            tick: 0,
+            tick_rate: options.tick_rate,
+            players_info,
+            result: None,
+        }
+    }
+
+    fn tick(&mut self, socket: &mut ServerSocket, now: Instant) {
+        self.tick += 1;
+
+        for player_id in 0..self.players.len() {
+            if let PlayerState::Reconnecting { since } = self.players[player_id].state {
+                if since.elapsed() >= self.grace_period {
+                    info!("player {} did not reconnect in time", player_id);
+                    let addr = self.players[player_id].addr;
+                    self.forfeit(addr);
+                }
+            }
+        }
+
+        if self.tick.is_multiple_of(u64::from(self.tick_rate)) {
+            self.resend_post_match(socket);
+        }
+
+        if self.result.is_some() {
+            // Nothing left to play, so inputs still arriving go nowhere
+            if let GameMode::Authoritative {
+                ref mut pending_inputs,
+                ..
+            } = self.mode
+            {
+                pending_inputs.clear();
+            }
+            return;
+        }
+
+        let mut finished = false;
+        match self.mode {
+            // Update the grids each tick and sync the state to each
+            // client whose throttle says it's time.
+            GameMode::Authoritative {
+                ref mut grids,
+                ref mut pending_inputs,
+                ref mut replay,
+                ref mut placements,
+                ref rules,
+            } => {
+                simulation::step(grids, pending_inputs, rules);
+                replay.push_tick(pending_inputs);
+                pending_inputs.clear();
+                finished = placements.update(grids);
+
+                for (player_id, player) in self.players.iter_mut().enumerate() {
+                    player.feed.collect_sounds(grids);
+                    if player.state != PlayerState::Connected {
+                        continue;
+                    }
+
+                    let stats = socket.stats(&player.addr);
+                    let snapshot = match player.feed.snapshot(grids, self.tick, stats, now) {
+                        Some(snapshot) => snapshot,
+                        None => continue,
+                    };
+                    let player_id = player_id as u32;
+                    for part in snapshot::split(self.tick, &snapshot, &self.players_info) {
+                        let message = ServerMessage::Sync { player_id, part };
+                        if let Err(e) = socket.send(&player.addr, &message) {
+                            error!("error sending to player {}: {}", player_id, e);
+                        }
+                    }
+                }
+
+                for spectator in self.spectators.iter_mut() {
+                    spectator.feed.collect_sounds(grids);
+
+                    let stats = socket.stats(&spectator.addr);
+                    if let Some(snapshot) = spectator.feed.snapshot(grids, self.tick, stats, now) {
+                        for part in snapshot::split(self.tick, &snapshot, &self.players_info) {
+                            let message = ServerMessage::Spectate(part);
+                            if let Err(e) = socket.send(&spectator.addr, &message) {
+                                error!("error sending to spectator {}: {}", spectator.addr, e);
+                            }
+                        }
+                    }
+                }
+
+                for grid in grids.iter_mut() {
+                    grid.sound_events.clear();
+                }
+            }
+            GameMode::Lockstep {
+                ref settings,
+                ref started,
+                ..
+            } => {
+                for (player_id, player) in self.players.iter().enumerate() {
+                    if !started[player_id] && player.state == PlayerState::Connected {
+                        let message = ServerMessage::LockstepStart {
+                            player_id: player_id as u32,
+                            settings: settings.clone(),
+                            players: self.players_info.clone(),
+                        };
+                        if let Err(e) = socket.send(&player.addr, &message) {
+                            error!("error sending to player {}: {}", player_id, e);
+                        }
+                    }
+                }
+            }
+        }
+
+        if finished {
+            self.finish(socket);
+        }
+    }
+
+    // Settles the placements once the last player standing is known.
+    fn finish(&mut self, socket: &mut ServerSocket) {
+        let result = match self.mode {
+            GameMode::Authoritative {
+                ref grids,
+                ref placements,
+                ..
+            } => placements.result(grids, self.tick_rate),
+            GameMode::Lockstep { .. } => return,
+        };
+
+        match result.winner() {
+            Some(winner) => info!("match won by player {}", winner),
+            None => info!("match ended without a winner"),
+        }
+        self.save_replay();
+        self.broadcast(socket, &ServerMessage::MatchResult(result.clone()));
+        self.result = Some(result);
+    }
+
+    // Sends the results and rematch votes again every so often until
+    // everyone leaves, so one lost packet doesn't strand anyone on a
+    // finished board.
+    fn resend_post_match(&self, socket: &mut ServerSocket) {
+        if let Some(ref result) = self.result {
+            self.broadcast(socket, &ServerMessage::MatchResult(result.clone()));
+        }
+
+        let votes = self.votes();
+        if self.is_finished() && votes.iter().any(|vote| *vote != RematchVote::Undecided) {
+            self.broadcast(socket, &ServerMessage::RematchVotes(votes));
+        }
+    }
+
+    fn broadcast(&self, socket: &mut ServerSocket, message: &ServerMessage) {
+        for (player_id, player) in self.players.iter().enumerate() {
+            if player.state != PlayerState::Connected {
+                continue;
+            }
+            if let Err(e) = socket.send(&player.addr, message) {
+                error!("error sending to player {}: {}", player_id, e);
+            }
+        }
+        for spectator in self.spectators.iter() {
+            if let Err(e) = socket.send(&spectator.addr, message) {
+                error!("error sending to spectator {}: {}", spectator.addr, e);
+            }
+        }
+    }
+
+    // Whether players may vote on a rematch or go back to the lobby. Only
+    // the clients know when a lockstep match ends, so it has to be every
+    // one of them still playing that says it has.
+    fn is_finished(&self) -> bool {
+        match self.mode {
+            GameMode::Authoritative { .. } => self.result.is_some(),
+            GameMode::Lockstep { ref reports, .. } => {
+                let playing: Vec<bool> = self
+                    .players
+                    .iter()
+                    .map(|player| player.state == PlayerState::Connected)
+                    .collect();
+                reports.agreed(&playing)
+            }
+        }
+    }
+
+    fn report_match_over(&mut self, addr: SocketAddr, tick: u64) {
+        let player_id = match self.player_id(addr) {
+            Some(player_id) => player_id as u32,
+            None => return,
+        };
+        if let GameMode::Lockstep {
+            ref mut reports, ..
+        } = self.mode
+        {
+            reports.report(player_id, tick);
+        }
+    }
+
+    fn votes(&self) -> Vec<RematchVote> {
+        self.players
+            .iter()
+            .map(|player| match player.state {
+                PlayerState::Forfeited => RematchVote::Left,
+                _ if player.rematch => RematchVote::Rematch,
+                _ => RematchVote::Undecided,
+            })
+            .collect()
+    }
+
+    fn vote_rematch(&mut self, socket: &mut ServerSocket, addr: SocketAddr) {
+        if !self.is_finished() {
+            return;
+        }
+
+        if let Some(player_id) = self.player_id(addr) {
+            let player = &mut self.players[player_id];
+            if player.state == PlayerState::Connected && !player.rematch {
+                info!("player {} wants a rematch", player_id);
+                player.rematch = true;
+            }
+            self.broadcast(socket, &ServerMessage::RematchVotes(self.votes()));
+        }
+    }
+
+    // The player gives up their place in the match but stays connected
+    // for the next one. Returns whether they're out of it now.
+    fn return_to_lobby(&mut self, socket: &mut ServerSocket, addr: SocketAddr) -> bool {
+        let is_player = self.player_id(addr).is_some();
+        if is_player && !self.is_finished() {
+            return false;
+        }
+
+        info!("client {} went back to the lobby", addr);
+        self.forfeit(addr);
+        if is_player {
+            self.broadcast(socket, &ServerMessage::RematchVotes(self.votes()));
+        }
+        true
+    }
+
+    // A rematch needs every player from the match, and all of them to
+    // want one
+    fn rematch_agreed(&self) -> bool {
+        self.is_finished()
+            && self
+                .players
+                .iter()
+                .all(|player| player.state == PlayerState::Connected && player.rematch)
+    }
+
+    // The same players and spectators in a new match on a new seed, with
+    // everyone on the same team as before
+    fn rematch(
+        &self,
+        connected_clients: &HashMap<SocketAddr, LobbyClient>,
+        options: &ServerOptions,
+    ) -> Game {
+        let clients = self
+            .players
+            .iter()
+            .zip(self.players_info.iter())
+            .map(|(player, info)| {
+                let profile = match connected_clients.get(&player.addr) {
+                    Some(client) => client.profile.clone(),
+                    None => Profile::default(),
+                };
+                let client = LobbyClient {
+                    token: player.token,
+                    profile,
+                    team: info.team,
+                };
+                (player.addr, client)
+            })
+            .collect();
+
+        let mut game = Game::new(clients, options);
+        for spectator in self.spectators.iter() {
+            game.add_spectator(spectator.addr, spectator.token, options);
+        }
+        game
+    }
+
+    // Queues the command for the next tick if it passes the player's
+    // input guard. Returns why the player should be kicked, if they
+    // should be.
+    fn handle_command(
+        &mut self,
+        addr: SocketAddr,
+        player_id: u32,
+        sequence: u32,
+        event: GridInputEvent,
+        now: Instant,
+    ) -> Option<Violation> {
+        if !self.is_player(addr, player_id) {
+            return None;
+        }
+
+        if let GameMode::Authoritative {
+            ref mut pending_inputs,
+            ..
+        } = self.mode
+        {
+            let guard = &mut self.players[player_id as usize].guard;
+            match guard.check(sequence, self.tick, now) {
+                Verdict::Accept => {}
+                Verdict::Suspicious { per_second } => {
+                    warn!(
+                        "player {} at {} sent {} inputs in the last second",
+                        player_id, addr, per_second
+                    );
+                }
+                Verdict::Replayed => {
+                    debug!("dropping replayed input {} from {}", sequence, addr);
+                    return None;
+                }
+                Verdict::Reject(violation) => {
+                    warn!("dropping input from player {}: {}", player_id, violation);
+                    return None;
+                }
+                Verdict::Kick(violation) => {
+                    warn!("kicking player {} at {}: {}", player_id, addr, violation);
+                    return Some(violation);
+                }
+            }
+            pending_inputs.push((player_id, event));
+        }
+        None
+    }
+
+    fn relay_lockstep(
+        &mut self,
+        socket: &mut ServerSocket,
+        addr: SocketAddr,
+        message: LockstepMessage,
+    ) {
+        let player_id = message.player_id();
+        if !self.is_player(addr, player_id) {
+            return;
+        }
+
+        if let GameMode::Lockstep {
+            ref mut started, ..
+        } = self.mode
+        {
+            started[player_id as usize] = true;
+
+            let message = ServerMessage::Lockstep(message);
+            for (other_player_id, other) in self.players.iter().enumerate() {
+                if other_player_id != player_id as usize && other.state == PlayerState::Connected {
+                    if let Err(e) = socket.send(&other.addr, &message) {
+                        error!("error relaying to player {}: {}", other_player_id, e);
+                    }
+                }
+            }
+        }
+    }
+
+    // Only the server has the grids to show a spectator in an
+    // authoritative match. Lockstep matches can't be watched, so anyone
+    // arriving during one waits for the next match instead.
+    fn add_spectator(&mut self, addr: SocketAddr, token: u64, options: &ServerOptions) {
+        if let GameMode::Authoritative { ref grids, .. } = self.mode {
+            info!("client {} is spectating", addr);
+            self.spectators.push(Spectator {
+                addr,
+                token,
+                feed: Feed::new(options, grids.len()),
+            });
+        }
+    }
+
+    // A player that left loses their grid. In lockstep games the other
+    // clients stop hearing from them and time out on their own, since
+    // their simulations can't be changed from here. Spectators are just
+    // forgotten.
+    fn forfeit(&mut self, addr: SocketAddr) {
+        self.spectators.retain(|spectator| spectator.addr != addr);
+
+        let player_id = match self.player_id(addr) {
+            Some(player_id) => player_id,
+            None => return,
+        };
+        self.players[player_id].state = PlayerState::Forfeited;
+
+        if let GameMode::Authoritative { ref mut grids, .. } = self.mode {
+            grids[player_id].gameover = true;
+        }
+    }
+
+    // Keep the player's grid around for the grace period in case they
+    // come back from a new address. Spectators have nothing to hold.
+    fn hold_slot(&mut self, addr: SocketAddr) {
+        self.spectators.retain(|spectator| spectator.addr != addr);
+
+        if let Some(player_id) = self.player_id(addr) {
+            let player = &mut self.players[player_id];
+            if player.state == PlayerState::Connected {
+                player.state = PlayerState::Reconnecting {
+                    since: Instant::now(),
+                };
+            }
+        }
+    }
+
+    // Hands the slot matching the token over to the new address,
+    // returning the address the player used before.
+    fn reconnect(&mut self, addr: SocketAddr, token: u64) -> Option<SocketAddr> {
+        if let Some(spectator) = self
+            .spectators
+            .iter_mut()
+            .find(|spectator| spectator.token == token)
+        {
+            let old_addr = spectator.addr;
+            spectator.addr = addr;
+            return Some(old_addr);
+        }
+
+        let player = self
+            .players
+            .iter_mut()
+            .find(|player| player.token == token && player.state != PlayerState::Forfeited)?;
+
+        let old_addr = player.addr;
+        player.addr = addr;
+        player.state = PlayerState::Connected;
+        Some(old_addr)
+    }
+
+    // Lockstep matches are recorded by the clients, since only they
+    // know which inputs were finally simulated on each tick
+    fn save_replay(&mut self) {
+        // Saved when the match ended
+        if self.result.is_some() {
+            return;
+        }
+
+        if let GameMode::Authoritative {
+            ref grids,
+            ref mut replay,
+            ..
+        } = self.mode
+        {
+            replay.finish(grids);
+            match replay::save_to_replay_dir(replay) {
+                Ok(Some(path)) => info!("saved replay to {}", path.display()),
+                Ok(None) => {}
+                Err(e) => error!("error saving replay: {}", e),
+            }
+        }
+    }
+
+    fn all_players_left(&self) -> bool {
+        self.players
+            .iter()
+            .all(|player| player.state == PlayerState::Forfeited)
+    }
+
+    fn player_id(&self, addr: SocketAddr) -> Option<usize> {
+        self.players.iter().position(|player| player.addr == addr)
+    }
+
+    // Check the specified player_id lines up with the source addr
+    // before taking any action
+    fn is_player(&self, addr: SocketAddr, player_id: u32) -> bool {
+        match self.players.get(player_id as usize) {
+            Some(player) => player.addr == addr && player.state == PlayerState::Connected,
+            None => false,
+        }
+    }
+}
+
 struct ServerOptions {
     port: u16,
+    name: String,
+    tick_rate: u32,
+    send_rate: u32,
+    bandwidth: u32,
     players_per_game: u32,
+    royale: bool,
+    // Number of teams in a team match
+    teams: Option<u32>,
+    lockstep: bool,
+    input_delay: u32,
+    rendezvous: bool,
+    timeout: Duration,
+    grace_period: Duration,
+    encrypt: bool,
+    codec: CodecKind,
+    websocket: bool,
+    simulate: Option<NetworkConditions>,
+    record: Option<PathBuf>,
+    input_limits: InputLimits,
+}
+
+impl ServerOptions {
+    // The rules of a match for players who chose these teams, in
+    // player_id order
+    fn rules(&self, choices: &[Option<u32>]) -> Rules {
+        match self.teams {
+            Some(teams) => Rules::teams(teams, choices),
+            None if self.royale => Rules::BattleRoyale,
+            None => Rules::Versus,
+        }
+    }
 }
 
 fn get_options() -> ServerOptions {
@@ -174,6 +1049,34 @@ fn get_options() -> ServerOptions {
         "PORT",
     );
 
+    opts.optopt(
+        "",
+        "name",
+        "name shown to clients looking for games on the local network",
+        "NAME",
+    );
+
+    opts.optopt(
+        "",
+        "tick-rate",
+        "ticks simulated per second, which sets the speed of the game (default 60)",
+        "HZ",
+    );
+
+    opts.optopt(
+        "",
+        "send-rate",
+        "most snapshots sent to each client per second (default 20)",
+        "HZ",
+    );
+
+    opts.optopt(
+        "",
+        "bandwidth",
+        "bytes per second each client can be sent before snapshots slow down (default 16384)",
+        "BYTES",
+    );
+
     opts.optopt(
         "n",
         "num-players",
@@ -181,6 +1084,106 @@ fn get_options() -> ServerOptions {
         "COUNT",
     );
 
+    opts.optflag(
+        "",
+        "royale",
+        "play a battle royale, where attacks go to a target and KOs earn badges",
+    );
+
+    opts.optopt(
+        "",
+        "teams",
+        "split the players of each game into this many teams, which they pick in the lobby",
+        "COUNT",
+    );
+
+    opts.optflag(
+        "l",
+        "lockstep",
+        "clients simulate the game and only exchange inputs",
+    );
+
+    opts.optopt(
+        "d",
+        "input-delay",
+        "ticks of input delay for lockstep games (default 3)",
+        "TICKS",
+    );
+
+    opts.optflag(
+        "r",
+        "rendezvous",
+        "only introduce peers to each other so they can play without a server",
+    );
+
+    opts.optopt(
+        "t",
+        "timeout",
+        "seconds without hearing from a client before dropping it (default 10)",
+        "SECONDS",
+    );
+
+    opts.optopt(
+        "g",
+        "grace-period",
+        "seconds a timed out player has to reconnect before forfeiting (default 30)",
+        "SECONDS",
+    );
+
+    opts.optflag(
+        "e",
+        "encrypt",
+        "encrypt and authenticate every connection, rejecting clients that won't",
+    );
+
+    opts.optopt(
+        "c",
+        "codec",
+        "compress larger packets with none, deflate, lz4 or zstd (default deflate)",
+        "CODEC",
+    );
+
+    opts.optflag(
+        "w",
+        "websocket",
+        "serve clients over WebSocket (TCP) instead of UDP",
+    );
+
+    opts.optopt(
+        "",
+        "simulate",
+        "add latency, jitter, loss, duplication and reordering, e.g. latency=100,loss=5",
+        "CONDITIONS",
+    );
+
+    opts.optopt(
+        "",
+        "record",
+        "save a replay of every match to the specified directory",
+        "DIR",
+    );
+
+    opts.optopt(
+        "",
+        "max-inputs-per-tick",
+        "most inputs applied for a player on one tick, the rest are dropped (default 8)",
+        "COUNT",
+    );
+
+    opts.optopt(
+        "",
+        "max-inputs-per-second",
+        "most inputs applied for a player within a second (default 60)",
+        "COUNT",
+    );
+
+    opts.optopt(
+        "",
+        "kick-after",
+        "kick players once this many of their inputs broke the limits",
+        "COUNT",
+    );
+
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => panic!(f.to_string()),
@@ -192,14 +1195,131 @@ fn get_options() -> ServerOptions {
         Err(_) => panic!("specified port not valid"),
     };
 
+    let name: String = match matches.opt_str("name") {
+        Some(name) if name.len() <= MAX_SERVER_NAME => name,
+        Some(_) => panic!("specified name is over {} bytes", MAX_SERVER_NAME),
+        None => String::from(DEFAULT_NAME),
+    };
+
+    let tick_rate: u32 = match matches.opt_get("tick-rate") {
+        Ok(Some(rate)) if rate > 0 => rate,
+        Ok(None) => DEFAULT_TICK_RATE,
+        _ => panic!("specified tick-rate is not valid"),
+    };
+
+    let send_rate: u32 = match matches.opt_get("send-rate") {
+        Ok(Some(rate)) if rate > 0 => rate,
+        Ok(None) => DEFAULT_SEND_RATE,
+        _ => panic!("specified send-rate is not valid"),
+    };
+
+    let bandwidth: u32 = match matches.opt_get("bandwidth") {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => DEFAULT_BANDWIDTH,
+        Err(_) => panic!("specified bandwidth is not valid"),
+    };
+
     let players_per_game: u32 = match matches.opt_get("num-players") {
-        Ok(Some(count)) => count,
+        Ok(Some(count)) if count <= royale::MAX_PLAYERS => count,
+        Ok(Some(_)) => panic!("specified num-players is over {}", royale::MAX_PLAYERS),
         Ok(None) => DEFAULT_PLAYERS_PER_GAME,
         Err(_) => panic!("specified num-players is not valid"),
     };
 
+    let teams: Option<u32> = match matches.opt_get("teams") {
+        Ok(Some(teams)) if teams >= 2 && teams <= players_per_game => Some(teams),
+        Ok(None) => None,
+        _ => panic!("specified teams is not valid"),
+    };
+
+    let royale: bool = matches.opt_present("royale");
+    if royale && teams.is_some() {
+        panic!("a battle royale can't be played in teams");
+    }
+
+    let lockstep: bool = matches.opt_present("lockstep");
+
+    let input_delay: u32 = match matches.opt_get("input-delay") {
+        Ok(Some(ticks)) if ticks <= MAX_INPUT_DELAY => ticks,
+        Ok(Some(_)) => panic!("specified input-delay is over {}", MAX_INPUT_DELAY),
+        Ok(None) => DEFAULT_INPUT_DELAY,
+        Err(_) => panic!("specified input-delay is not valid"),
+    };
+
+    let rendezvous: bool = matches.opt_present("rendezvous");
+
+    let timeout: Duration = match matches.opt_get("timeout") {
+        Ok(Some(seconds)) => Duration::from_secs(seconds),
+        Ok(None) => DEFAULT_TIMEOUT,
+        Err(_) => panic!("specified timeout is not valid"),
+    };
+
+    let grace_period: Duration = match matches.opt_get("grace-period") {
+        Ok(Some(seconds)) => Duration::from_secs(seconds),
+        Ok(None) => DEFAULT_GRACE_PERIOD,
+        Err(_) => panic!("specified grace-period is not valid"),
+    };
+
+    let encrypt: bool = matches.opt_present("encrypt");
+
+    let codec: CodecKind = match matches.opt_get("codec") {
+        Ok(Some(codec)) => codec,
+        Ok(None) => DEFAULT_CODEC,
+        Err(_) => panic!("specified codec is not valid"),
+    };
+
+    let websocket: bool = matches.opt_present("websocket");
+
+    let simulate: Option<NetworkConditions> = match matches.opt_get("simulate") {
+        Ok(conditions) => conditions,
+        Err(e) => panic!("specified simulate is not valid: {}", e),
+    };
+
+    let record: Option<PathBuf> = matches.opt_str("record").map(PathBuf::from);
+
+    let max_per_tick: u32 = match matches.opt_get("max-inputs-per-tick") {
+        Ok(Some(count)) => count,
+        Ok(None) => DEFAULT_MAX_PER_TICK,
+        Err(_) => panic!("specified max-inputs-per-tick is not valid"),
+    };
+
+    let max_per_second: u32 = match matches.opt_get("max-inputs-per-second") {
+        Ok(Some(count)) => count,
+        Ok(None) => DEFAULT_MAX_PER_SECOND,
+        Err(_) => panic!("specified max-inputs-per-second is not valid"),
+    };
+
+    let kick_after: Option<u32> = match matches.opt_get("kick-after") {
+        Ok(Some(count)) if count > 0 => Some(count),
+        Ok(None) => None,
+        _ => panic!("specified kick-after is not valid"),
+    };
+
+    let input_limits = InputLimits {
+        max_per_tick,
+        max_per_second,
+        kick_after,
+    };
+
     ServerOptions {
         port,
+        name,
+        tick_rate,
+        send_rate,
+        bandwidth,
         players_per_game,
+        royale,
+        teams,
+        lockstep,
+        input_delay,
+        rendezvous,
+        timeout,
+        grace_period,
+        encrypt,
+        codec,
+        websocket,
+        simulate,
+        record,
+        input_limits,
     }
 }
@@ -1,10 +1,9 @@
+#[cfg(feature = "client")]
 use sdl2::rect::Rect;
 use serde::{Deserialize, Serialize};
 use std::convert::From;
 use std::ops::Add;
 
-use crate::image::Image;
-
 // --------
 // GridCell
 // --------
@@ -17,6 +16,7 @@ pub struct GridCell {
     pub row: i32,
 }
 
+#[cfg(feature = "client")]
 impl Into<Rect> for GridCell {
     fn into(self) -> Rect {
         Rect::new(
@@ -81,6 +81,9 @@ impl Add<GridCell> for GridCell {
 // Brick
 // -----
 
+/// Frames in the smoke animation of a breaking brick.
+pub const MAX_SMOKE_FRAME: u16 = 12;
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum BrickType {
     Red,
@@ -109,7 +112,7 @@ impl Brick {
         match self {
             Brick::Breaking(frame) => {
                 let next = frame + 1;
-                if next < Image::max_smoke_frame() {
+                if next < MAX_SMOKE_FRAME {
                     Some(Brick::Breaking(next))
                 } else {
                     Some(Brick::Broken)
@@ -139,6 +142,51 @@ impl Brick {
             _ => false,
         }
     }
+
+    /// Four bit code for the brick in the compact wire format, along with
+    /// the animation frame for the codes that need one.
+    pub fn to_wire(self) -> (u8, Option<u16>) {
+        use BrickType::*;
+        match self {
+            Brick::Empty => (0, None),
+            Brick::Occupied(Red) => (1, None),
+            Brick::Occupied(Green) => (2, None),
+            Brick::Occupied(Blue) => (3, None),
+            Brick::Occupied(Yellow) => (4, None),
+            Brick::Occupied(Orange) => (5, None),
+            Brick::Occupied(Purple) => (6, None),
+            Brick::Occupied(Teal) => (7, None),
+            Brick::Occupied(Attacked) => (8, None),
+            Brick::Broken => (9, None),
+            Brick::Breaking(frame) => (10, Some(frame)),
+            Brick::Occupied(Smoke(frame)) => (11, Some(frame)),
+        }
+    }
+
+    /// Whether a brick with the given wire code is followed by its frame.
+    pub fn wire_has_frame(code: u8) -> bool {
+        code == 10 || code == 11
+    }
+
+    pub fn from_wire(code: u8, frame: u16) -> Option<Brick> {
+        use BrickType::*;
+        let brick = match code {
+            0 => Brick::Empty,
+            1 => Brick::Occupied(Red),
+            2 => Brick::Occupied(Green),
+            3 => Brick::Occupied(Blue),
+            4 => Brick::Occupied(Yellow),
+            5 => Brick::Occupied(Orange),
+            6 => Brick::Occupied(Purple),
+            7 => Brick::Occupied(Teal),
+            8 => Brick::Occupied(Attacked),
+            9 => Brick::Broken,
+            10 => Brick::Breaking(frame),
+            11 => Brick::Occupied(Smoke(frame)),
+            _ => return None,
+        };
+        Some(brick)
+    }
 }
 
 pub struct BrickIterator {
@@ -267,3 +315,34 @@ where
         None
     }
 }
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_brick_wire_codes_roundtrip() {
+    use BrickType::*;
+    let bricks = [
+        Brick::Empty,
+        Brick::Occupied(Red),
+        Brick::Occupied(Green),
+        Brick::Occupied(Blue),
+        Brick::Occupied(Yellow),
+        Brick::Occupied(Orange),
+        Brick::Occupied(Purple),
+        Brick::Occupied(Teal),
+        Brick::Occupied(Attacked),
+        Brick::Occupied(Smoke(3)),
+        Brick::Breaking(7),
+        Brick::Broken,
+    ];
+
+    for brick in bricks.iter() {
+        let (code, frame) = brick.to_wire();
+        assert!(code < 16);
+        assert_eq!(Brick::wire_has_frame(code), frame.is_some());
+        assert_eq!(Brick::from_wire(code, frame.unwrap_or(0)), Some(*brick));
+    }
+    assert_eq!(Brick::from_wire(15, 0), None);
+}
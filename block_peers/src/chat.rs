@@ -0,0 +1,584 @@
+//! Text chat between everyone connected to a server, both while waiting for
+//! a match and during one.
+//!
+//! Chat goes over the reliable channel, so nothing said is lost with a
+//! dropped packet. The server stamps each line with the name of whoever
+//! sent it and filters what it passes on, since a client could send
+//! anything.
+
+#[cfg(feature = "client")]
+use sdl2::event::Event;
+#[cfg(feature = "client")]
+use sdl2::keyboard::Keycode;
+#[cfg(feature = "client")]
+use sdl2::pixels::Color;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "client")]
+use crate::net::{ClientMessage, Socket};
+use crate::profile;
+use crate::reliable::{ReliableReceiver, ReliableSender};
+#[cfg(feature = "client")]
+use crate::render::{Renderer, VIEWPORT_HEIGHT};
+#[cfg(feature = "client")]
+use crate::text::Text;
+#[cfg(feature = "client")]
+use crate::transport::Transport;
+
+/// Longest message, in characters.
+pub const MAX_MESSAGE_LEN: usize = 120;
+/// Lines kept in the log for scrolling back through.
+#[cfg(feature = "client")]
+const HISTORY: usize = 50;
+/// Lines drawn at once.
+#[cfg(feature = "client")]
+const VISIBLE_LINES: usize = 6;
+#[cfg(feature = "client")]
+const LINE_HEIGHT: u32 = 18;
+/// A player can send this many messages within `SPAM_WINDOW` before the
+/// rest are dropped.
+const SPAM_MAX_MESSAGES: usize = 5;
+const SPAM_WINDOW: Duration = Duration::from_secs(10);
+/// Saying the same thing again within this long is dropped too.
+const REPEAT_WINDOW: Duration = Duration::from_secs(30);
+/// Masked wherever they make up a whole word, or a word with one of the
+/// endings below. Matching whole words leaves place names alone.
+const PROFANITY: [&str; 8] = [
+    "arse", "asshole", "bastard", "bitch", "cunt", "fuck", "shit", "wanker",
+];
+const ENDINGS: [&str; 6] = ["s", "es", "ed", "er", "ing", "y"];
+
+/// One line of the chat log.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChatLine {
+    /// Who said it, or `None` for a notice from the server.
+    pub from: Option<String>,
+    pub text: String,
+}
+
+/// Drops control characters and surrounding space and cuts the message down
+/// to `MAX_MESSAGE_LEN`, or `None` if nothing is left.
+pub fn clean_message(text: &str) -> Option<String> {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .chars()
+        .take(MAX_MESSAGE_LEN)
+        .collect();
+    let text = text.trim_end();
+
+    if text.is_empty() {
+        None
+    } else {
+        Some(String::from(text))
+    }
+}
+
+/// Replaces every letter of a profane word with `*`.
+pub fn mask_profanity(text: &str) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut word = String::new();
+
+    for c in text.chars().chain(std::iter::once(' ')) {
+        if c.is_alphanumeric() {
+            word.push(c);
+            continue;
+        }
+
+        if is_profane(&word) {
+            masked.extend(word.chars().map(|_| '*'));
+        } else {
+            masked.push_str(&word);
+        }
+        word.clear();
+        masked.push(c);
+    }
+
+    // Drop the space added to end the last word
+    masked.pop();
+    masked
+}
+
+fn is_profane(word: &str) -> bool {
+    let word = word.to_lowercase();
+    PROFANITY
+        .iter()
+        .any(|profane| match word.strip_prefix(profane) {
+            Some(ending) => ending.is_empty() || ENDINGS.contains(&ending),
+            None => false,
+        })
+}
+
+// ------
+// Server
+// ------
+
+/// Keeps one player from flooding the chat.
+struct SpamFilter {
+    recent: VecDeque<Instant>,
+    last: Option<(String, Instant)>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Spam {
+    TooFast,
+    Repeated,
+}
+
+impl SpamFilter {
+    fn new() -> Self {
+        Self {
+            recent: VecDeque::new(),
+            last: None,
+        }
+    }
+
+    fn check(&mut self, text: &str, now: Instant) -> Option<Spam> {
+        while let Some(sent) = self.recent.front() {
+            if now.duration_since(*sent) < SPAM_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+
+        if self.recent.len() >= SPAM_MAX_MESSAGES {
+            return Some(Spam::TooFast);
+        }
+        if let Some((ref last, sent)) = self.last {
+            if last.eq_ignore_ascii_case(text) && now.duration_since(sent) < REPEAT_WINDOW {
+                return Some(Spam::Repeated);
+            }
+        }
+
+        self.recent.push_back(now);
+        self.last = Some((String::from(text), now));
+        None
+    }
+}
+
+struct Member {
+    name: String,
+    inbox: ReliableReceiver,
+    outbox: ReliableSender<ChatLine>,
+    filter: SpamFilter,
+}
+
+/// Everyone connected to the server, who all hear each other.
+#[derive(Default)]
+pub struct ChatRoom {
+    members: HashMap<SocketAddr, Member>,
+}
+
+impl ChatRoom {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a client to the room under the name from its profile.
+    pub fn join(&mut self, addr: SocketAddr, name: &str) {
+        let name = profile::clean_name(name).unwrap_or_else(|| String::from(profile::DEFAULT_NAME));
+        self.members.insert(
+            addr,
+            Member {
+                name,
+                inbox: ReliableReceiver::new(),
+                outbox: ReliableSender::new(),
+                filter: SpamFilter::new(),
+            },
+        );
+    }
+
+    pub fn leave(&mut self, addr: &SocketAddr) {
+        self.members.remove(addr);
+    }
+
+    /// Keeps only the members `keep` says are still around.
+    pub fn retain<F: Fn(&SocketAddr) -> bool>(&mut self, keep: F) {
+        self.members.retain(|addr, _| keep(addr));
+    }
+
+    /// A member reconnected from a new address, and carries on where it
+    /// left off.
+    pub fn moved(&mut self, old_addr: &SocketAddr, new_addr: SocketAddr) {
+        if let Some(member) = self.members.remove(old_addr) {
+            self.members.insert(new_addr, member);
+        }
+    }
+
+    /// Takes message number `sequence` from a member and passes it on to
+    /// everyone if it gets past the filters. Returns what to acknowledge,
+    /// or `None` if the sender isn't in the room.
+    pub fn receive(
+        &mut self,
+        addr: &SocketAddr,
+        sequence: u32,
+        text: &str,
+        now: Instant,
+    ) -> Option<u32> {
+        let member = self.members.get_mut(addr)?;
+        if !member.inbox.receive(sequence) {
+            return Some(member.inbox.received());
+        }
+        let received = member.inbox.received();
+
+        let text = match clean_message(text) {
+            Some(text) => text,
+            None => return Some(received),
+        };
+        let notice = match member.filter.check(&text, now) {
+            Some(Spam::TooFast) => "You're sending messages too quickly",
+            Some(Spam::Repeated) => "You just said that",
+            None => {
+                let line = ChatLine {
+                    from: Some(member.name.clone()),
+                    text: mask_profanity(&text),
+                };
+                debug!("chat from {}: {}", addr, line.text);
+                for member in self.members.values_mut() {
+                    member.outbox.push(line.clone());
+                }
+                return Some(received);
+            }
+        };
+
+        // Only the sender hears why their message went nowhere
+        member.outbox.push(ChatLine {
+            from: None,
+            text: String::from(notice),
+        });
+        Some(received)
+    }
+
+    /// A member has taken the first `received` lines sent to it.
+    pub fn acknowledge(&mut self, addr: &SocketAddr, received: u32) {
+        if let Some(member) = self.members.get_mut(addr) {
+            member.outbox.acknowledge(received);
+        }
+    }
+
+    /// The lines to send to each member now, with their numbers.
+    pub fn outgoing(&mut self, now: Instant) -> Vec<(SocketAddr, u32, ChatLine)> {
+        let mut outgoing = Vec::new();
+        for (addr, member) in self.members.iter_mut() {
+            for (sequence, line) in member.outbox.due(now) {
+                outgoing.push((*addr, sequence, line));
+            }
+        }
+        outgoing
+    }
+}
+
+// ------
+// Client
+// ------
+
+/// The chat log and the line being typed, shared by the scenes connected
+/// to a server. Enter starts a message and sends it, Escape abandons it and
+/// Page Up and Page Down scroll back through the log.
+#[cfg(feature = "client")]
+pub struct ChatBox {
+    log: VecDeque<ChatLine>,
+    // How many lines we've scrolled back from the newest
+    scroll: usize,
+    // What's been typed so far, while typing
+    draft: Option<String>,
+    outbox: ReliableSender<String>,
+    inbox: ReliableReceiver,
+}
+
+#[cfg(feature = "client")]
+impl Default for ChatBox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "client")]
+impl ChatBox {
+    pub fn new() -> Self {
+        Self {
+            log: VecDeque::new(),
+            scroll: 0,
+            draft: None,
+            outbox: ReliableSender::new(),
+            inbox: ReliableReceiver::new(),
+        }
+    }
+
+    /// Whether a message is being typed, when every key belongs to the chat.
+    pub fn typing(&self) -> bool {
+        self.draft.is_some()
+    }
+
+    /// Handles keys and text meant for the chat. Returns whether the event
+    /// was used, in which case nothing else should act on it.
+    pub fn input(&mut self, event: &Event) -> bool {
+        let draft = match self.draft {
+            Some(ref mut draft) => draft,
+            None => {
+                return match event {
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Return),
+                        ..
+                    } => {
+                        self.draft = Some(String::new());
+                        true
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::PageUp),
+                        ..
+                    } => {
+                        let oldest = self.log.len().saturating_sub(VISIBLE_LINES);
+                        self.scroll = (self.scroll + 1).min(oldest);
+                        true
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::PageDown),
+                        ..
+                    } => {
+                        self.scroll = self.scroll.saturating_sub(1);
+                        true
+                    }
+                    _ => false,
+                };
+            }
+        };
+
+        match event {
+            Event::TextInput { text, .. } => {
+                let room = MAX_MESSAGE_LEN.saturating_sub(draft.chars().count());
+                draft.extend(text.chars().take(room));
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Backspace),
+                ..
+            } => {
+                draft.pop();
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Return),
+                ..
+            } => {
+                if let Some(text) = clean_message(draft) {
+                    if !self.outbox.push(text) {
+                        warn!("too many chat messages waiting to be sent, dropping one");
+                    }
+                }
+                self.draft = None;
+                self.scroll = 0;
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            } => {
+                self.draft = None;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Sends the messages due to the server. Should be called every update.
+    pub fn update<T: Transport + ?Sized>(
+        &mut self,
+        socket: &mut Socket<T>,
+        server_addr: SocketAddr,
+    ) {
+        for (sequence, text) in self.outbox.due(Instant::now()) {
+            let message = ClientMessage::Chat { sequence, text };
+            if let Err(e) = socket.send(server_addr, &message) {
+                warn!("error sending chat to the server: {}", e);
+            }
+        }
+    }
+
+    /// Adds line number `sequence` from the server to the log if it's the
+    /// next one, and acknowledges it either way.
+    pub fn receive<T: Transport + ?Sized>(
+        &mut self,
+        socket: &mut Socket<T>,
+        server_addr: SocketAddr,
+        sequence: u32,
+        line: ChatLine,
+    ) {
+        if self.inbox.receive(sequence) {
+            self.log.push_back(line);
+            if self.log.len() > HISTORY {
+                self.log.pop_front();
+            }
+            // Keep the lines being read where they are
+            if self.scroll > 0 {
+                self.scroll += 1;
+            }
+        }
+
+        let received = self.inbox.received();
+        if let Err(e) = socket.send(server_addr, &ClientMessage::ChatAck { received }) {
+            warn!("error acknowledging chat from the server: {}", e);
+        }
+    }
+
+    /// The server has taken the first `received` messages we sent.
+    pub fn acknowledge(&mut self, received: u32) {
+        self.outbox.acknowledge(received);
+    }
+
+    /// Renders the newest lines in the bottom left corner, with the line
+    /// being typed under them.
+    pub fn render(&self, renderer: &mut Renderer) {
+        let mut bottom = VIEWPORT_HEIGHT as i32 - 8;
+
+        if let Some(ref draft) = self.draft {
+            bottom -= LINE_HEIGHT as i32;
+            renderer.render_text(
+                Text::from(format!("> {}_", draft))
+                    .left_top_xy(8, bottom)
+                    .height(LINE_HEIGHT)
+                    .color(Color::RGB(255, 220, 120))
+                    .build(),
+            );
+        }
+
+        let end = self.log.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(VISIBLE_LINES);
+        for line in self.log.range(start..end).rev() {
+            bottom -= LINE_HEIGHT as i32;
+            let mut text = match line.from {
+                Some(ref from) => Text::from(format!("{}: {}", from, line.text)),
+                None => {
+                    let mut text = Text::from(line.text.clone());
+                    text.color_gray();
+                    text
+                }
+            };
+            renderer.render_text(text.left_top_xy(8, bottom).height(LINE_HEIGHT).build());
+        }
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_messages_are_cleaned_and_masked() {
+    assert_eq!(
+        clean_message("  hi\u{7} there \n"),
+        Some(String::from("hi there"))
+    );
+    assert_eq!(clean_message(" \t"), None);
+    assert_eq!(
+        clean_message(&"a".repeat(500)).map(|text| text.len()),
+        Some(MAX_MESSAGE_LEN)
+    );
+
+    assert_eq!(
+        mask_profanity("oh Shit, fucking hell"),
+        "oh ****, ******* hell"
+    );
+    // Only whole words are masked
+    assert_eq!(mask_profanity("Scunthorpe passes"), "Scunthorpe passes");
+}
+
+#[test]
+fn test_room_passes_lines_on_and_drops_spam() {
+    let ada = "127.0.0.1:1".parse().unwrap();
+    let bob = "127.0.0.1:2".parse().unwrap();
+    let now = Instant::now();
+
+    let mut room = ChatRoom::new();
+    room.join(ada, "Ada");
+    room.join(bob, "\u{1b}");
+
+    assert_eq!(room.receive(&ada, 0, "hello", now), Some(1));
+    // Resent because the acknowledgement was lost
+    assert_eq!(room.receive(&ada, 0, "hello", now), Some(1));
+    assert_eq!(room.receive(&bob, 0, "hi", now), Some(1));
+
+    let mut outgoing = room.outgoing(now);
+    outgoing.sort_by_key(|(addr, sequence, _)| (*addr, *sequence));
+    let hello = ChatLine {
+        from: Some(String::from("Ada")),
+        text: String::from("hello"),
+    };
+    let hi = ChatLine {
+        from: Some(String::from(profile::DEFAULT_NAME)),
+        text: String::from("hi"),
+    };
+    assert_eq!(
+        outgoing,
+        vec![
+            (ada, 0, hello.clone()),
+            (ada, 1, hi.clone()),
+            (bob, 0, hello),
+            (bob, 1, hi),
+        ]
+    );
+
+    room.acknowledge(&ada, 2);
+    room.acknowledge(&bob, 2);
+    assert_eq!(room.receive(&ada, 1, "HELLO", now), Some(2));
+    for sequence in 2..2 + SPAM_MAX_MESSAGES as u32 {
+        room.receive(&ada, sequence, &sequence.to_string(), now);
+    }
+
+    let outgoing = room.outgoing(now);
+    let notices: Vec<&str> = outgoing
+        .iter()
+        .filter(|(addr, _, line)| *addr == ada && line.from.is_none())
+        .map(|(_, _, line)| line.text.as_str())
+        .collect();
+    assert_eq!(
+        notices,
+        vec!["You just said that", "You're sending messages too quickly"]
+    );
+    // Bob only hears what got through
+    let to_bob = outgoing.iter().filter(|(addr, _, _)| *addr == bob).count();
+    assert_eq!(to_bob, SPAM_MAX_MESSAGES - 1);
+}
+
+#[test]
+#[cfg(feature = "client")]
+fn test_chat_box_types_and_sends_a_message() {
+    use sdl2::keyboard::Mod;
+
+    let key = |keycode| Event::KeyDown {
+        timestamp: 0,
+        window_id: 0,
+        keycode: Some(keycode),
+        scancode: None,
+        keymod: Mod::empty(),
+        repeat: false,
+    };
+    let text = |text: &str| Event::TextInput {
+        timestamp: 0,
+        window_id: 0,
+        text: String::from(text),
+    };
+
+    let mut chat = ChatBox::new();
+    // Keys that aren't for the chat are left for the game
+    assert!(!chat.input(&key(Keycode::A)));
+    assert!(!chat.input(&text("a")));
+
+    assert!(chat.input(&key(Keycode::Return)));
+    assert!(chat.typing());
+    assert!(chat.input(&text("gg")));
+    assert!(chat.input(&text("x")));
+    assert!(chat.input(&key(Keycode::Backspace)));
+    // Game keys are swallowed while typing
+    assert!(chat.input(&key(Keycode::A)));
+    assert!(chat.input(&key(Keycode::Return)));
+    assert!(!chat.typing());
+
+    assert_eq!(
+        chat.outbox.due(Instant::now()),
+        vec![(0, String::from("gg"))]
+    );
+}
@@ -1,27 +1,262 @@
-use flate2::write::{GzDecoder, GzEncoder};
+use flate2::read::{DeflateDecoder, GzDecoder};
+use flate2::write::{DeflateEncoder, GzEncoder};
 use flate2::Compression;
+use ruzstd::decoding::StreamingDecoder;
+use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+use std::fmt;
 use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
 
-// TODO: These should really return Result's and do proper error handling
+/// Packets smaller than this are sent uncompressed, since the headers
+/// added by compression would likely make them bigger rather than smaller.
+pub const COMPRESSION_THRESHOLD: usize = 128;
 
-pub fn gzip_encode(buffer: &[u8]) -> Vec<u8> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+/// A way of compressing packets.
+pub trait Codec {
+    fn encode(&self, buffer: &[u8]) -> Result<Vec<u8>>;
 
-    encoder
-        .write_all(&buffer)
-        .expect("error writing bytes to encoder");
+    /// Decompresses the buffer, failing if the result would be longer than
+    /// `limit` bytes.
+    fn decode(&self, buffer: &[u8], limit: usize) -> Result<Vec<u8>>;
+}
+
+/// Identifies the codec used for a packet, so the other end knows how to
+/// decompress it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CodecKind {
+    Gzip,
+    None,
+    Deflate,
+    Lz4,
+    Zstd,
+}
+
+impl CodecKind {
+    /// Fits in four bits. Gzip is zero because every packet sent before
+    /// there was a choice of codec was gzipped and has zero here.
+    pub fn id(self) -> u8 {
+        match self {
+            CodecKind::Gzip => 0,
+            CodecKind::None => 1,
+            CodecKind::Deflate => 2,
+            CodecKind::Lz4 => 3,
+            CodecKind::Zstd => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CodecKind::Gzip),
+            1 => Some(CodecKind::None),
+            2 => Some(CodecKind::Deflate),
+            3 => Some(CodecKind::Lz4),
+            4 => Some(CodecKind::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            CodecKind::Gzip => &Gzip,
+            CodecKind::None => &NoCompression,
+            CodecKind::Deflate => &Deflate,
+            CodecKind::Lz4 => &Lz4,
+            CodecKind::Zstd => &Zstd,
+        }
+    }
+
+    /// Picks the codec for a packet of `size` bytes when `self` is the one
+    /// we'd like to use.
+    pub fn for_size(self, size: usize) -> Self {
+        if size < COMPRESSION_THRESHOLD {
+            CodecKind::None
+        } else {
+            self
+        }
+    }
+}
+
+impl fmt::Display for CodecKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CodecKind::Gzip => "gzip",
+            CodecKind::None => "none",
+            CodecKind::Deflate => "deflate",
+            CodecKind::Lz4 => "lz4",
+            CodecKind::Zstd => "zstd",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(CodecKind::Gzip),
+            "none" => Ok(CodecKind::None),
+            "deflate" => Ok(CodecKind::Deflate),
+            "lz4" => Ok(CodecKind::Lz4),
+            "zstd" => Ok(CodecKind::Zstd),
+            _ => Err(format!("unknown codec {}", s)),
+        }
+    }
+}
+
+pub struct NoCompression;
+
+impl Codec for NoCompression {
+    fn encode(&self, buffer: &[u8]) -> Result<Vec<u8>> {
+        Ok(buffer.to_vec())
+    }
+
+    fn decode(&self, buffer: &[u8], limit: usize) -> Result<Vec<u8>> {
+        if buffer.len() > limit {
+            return Err(too_large());
+        }
+        Ok(buffer.to_vec())
+    }
+}
+
+pub struct Gzip;
+
+impl Codec for Gzip {
+    fn encode(&self, buffer: &[u8]) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(buffer)?;
+        encoder.finish()
+    }
+
+    fn decode(&self, buffer: &[u8], limit: usize) -> Result<Vec<u8>> {
+        read_limited(GzDecoder::new(buffer), limit)
+    }
+}
+
+/// The same compression as gzip without its 18 bytes of header and footer.
+pub struct Deflate;
+
+impl Codec for Deflate {
+    fn encode(&self, buffer: &[u8]) -> Result<Vec<u8>> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(buffer)?;
+        encoder.finish()
+    }
+
+    fn decode(&self, buffer: &[u8], limit: usize) -> Result<Vec<u8>> {
+        read_limited(DeflateDecoder::new(buffer), limit)
+    }
+}
+
+/// Compresses less than the others but is much quicker.
+pub struct Lz4;
+
+impl Codec for Lz4 {
+    fn encode(&self, buffer: &[u8]) -> Result<Vec<u8>> {
+        Ok(lz4_flex::compress_prepend_size(buffer))
+    }
+
+    fn decode(&self, buffer: &[u8], limit: usize) -> Result<Vec<u8>> {
+        let (size, compressed) = lz4_flex::block::uncompressed_size(buffer)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if size > limit {
+            return Err(too_large());
+        }
+
+        let mut decoded = vec![0; size];
+        let written = lz4_flex::decompress_into(compressed, &mut decoded)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        decoded.truncate(written);
+        Ok(decoded)
+    }
+}
+
+pub struct Zstd;
+
+impl Codec for Zstd {
+    fn encode(&self, buffer: &[u8]) -> Result<Vec<u8>> {
+        Ok(compress_to_vec(buffer, CompressionLevel::Fastest))
+    }
+
+    fn decode(&self, buffer: &[u8], limit: usize) -> Result<Vec<u8>> {
+        let decoder =
+            StreamingDecoder::new(buffer).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        read_limited(decoder, limit)
+    }
+}
+
+fn read_limited<R: Read>(reader: R, limit: usize) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut decoded)?;
+
+    if decoded.len() > limit {
+        return Err(too_large());
+    }
+    Ok(decoded)
+}
+
+fn too_large() -> Error {
+    Error::new(ErrorKind::InvalidData, "decompressed data too large")
+}
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+const ALL_CODECS: [CodecKind; 5] = [
+    CodecKind::Gzip,
+    CodecKind::None,
+    CodecKind::Deflate,
+    CodecKind::Lz4,
+    CodecKind::Zstd,
+];
+
+#[test]
+fn test_codecs_roundtrip() {
+    let data: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
 
-    encoder
-        .finish()
-        .expect("error in compressing bytes for transmit")
+    for kind in ALL_CODECS.iter() {
+        let encoded = kind.codec().encode(&data).unwrap();
+        assert_eq!(kind.codec().decode(&encoded, data.len()).unwrap(), data);
+    }
 }
 
-pub fn gzip_decode(buffer: &[u8]) -> Vec<u8> {
-    let mut decoder = GzDecoder::new(Vec::new());
+#[test]
+fn test_codecs_refuse_garbage() {
+    let garbage = [0xde, 0xad, 0xbe, 0xef, 0xff, 0xff, 0xff, 0xff];
 
-    decoder
-        .write_all(&buffer[..])
-        .expect("error writing incoming packet to decoder");
+    for kind in ALL_CODECS.iter().filter(|kind| **kind != CodecKind::None) {
+        assert!(kind.codec().decode(&garbage, 1000).is_err());
+    }
+}
+
+#[test]
+fn test_codecs_refuse_output_over_limit() {
+    let data = vec![0; 1000];
+
+    for kind in ALL_CODECS.iter() {
+        let encoded = kind.codec().encode(&data).unwrap();
+        assert!(kind.codec().decode(&encoded, data.len() - 1).is_err());
+    }
+}
+
+#[test]
+fn test_codec_ids_roundtrip() {
+    for kind in ALL_CODECS.iter() {
+        assert!(kind.id() < 16);
+        assert_eq!(CodecKind::from_id(kind.id()), Some(*kind));
+        assert_eq!(kind.to_string().parse::<CodecKind>(), Ok(*kind));
+    }
+    assert_eq!(CodecKind::from_id(15), None);
+}
 
-    decoder.finish().expect("error decoding incoming packet")
+#[test]
+fn test_small_packets_are_not_compressed() {
+    assert_eq!(CodecKind::Zstd.for_size(20), CodecKind::None);
+    assert_eq!(
+        CodecKind::Zstd.for_size(COMPRESSION_THRESHOLD),
+        CodecKind::Zstd
+    );
 }
@@ -1,24 +1,46 @@
+#[cfg(feature = "client")]
 use sdl2::pixels::Color;
+#[cfg(feature = "client")]
 use sdl2::rect::Rect;
-use serde::{Deserialize, Serialize};
+use serde::de::{self, Deserializer, Unexpected, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter;
 
 // Internal
 use crate::brick::{
     Brick, BrickIterator, BrickType, GridCell, LineIterator, MatchingLine, CELL_SIZE,
 };
+#[cfg(feature = "client")]
 use crate::image::Image;
 use crate::piece::{random_next_piece, Piece};
+#[cfg(feature = "client")]
 use crate::render::{Opacity, Renderer};
-use crate::scene::GameSoundEvent;
+use crate::rng::Rng;
+use crate::royale::{BattleStatus, Targeting};
+#[cfg(feature = "client")]
 use crate::text::Text;
-
-#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
+use crate::wire::{Reader, Writer};
+
+/// Largest grid accepted in the wire format, so a bogus size can't make us
+/// allocate a huge number of cells.
+pub const MAX_WIRE_CELLS: u64 = 4096;
+/// Starts a run of identical rows in the wire format. The low four bits
+/// hold the brick filling them.
+const WIRE_ROW_RUN: u8 = 0x80;
+/// Starts a single row with a code for each cell.
+const WIRE_ROW_MIXED: u8 = 0x00;
+
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub enum GridInputEvent {
     MoveLeft,
     MoveRight,
     MoveDown,
     ForceToBottom,
     Rotate,
+    /// Changes who attacks go to in a battle royale.
+    Target(Targeting),
 }
 
 #[derive(Copy, Clone, Serialize, Deserialize, Debug)]
@@ -26,10 +48,52 @@ pub enum GridAttackEvent {
     LinesCleared(u8),
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+impl GridAttackEvent {
+    /// Rows of garbage the attack sends. Clearing a single line sends
+    /// nothing.
+    pub fn rows(self) -> u32 {
+        match self {
+            GridAttackEvent::LinesCleared(lines) => u32::from(lines).saturating_sub(1),
+        }
+    }
+}
+
+/// Sounds for the client to play. The server passes on the ones from each
+/// grid it simulates.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum GameSoundEvent {
+    LinesCleared(u8),
+    TurnSoundsOff,
+    TurnSoundsOn,
+    MovePieceDown,
+}
+
+/// Counts of how a grid has been played, for analysing replays. Like the
+/// sound events they have no effect on the simulation, so they are left out
+/// of the state hash and the wire format.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GridStats {
+    pub pieces_placed: u32,
+    /// Every input handled, whether it moved anything or not.
+    pub actions: u32,
+    /// Left, right and rotate presses beyond the fewest that would have put
+    /// each piece where it was placed.
+    pub finesse_faults: u32,
+    /// How many times one, two, three and four lines were cleared at once.
+    pub lines_cleared: [u32; 4],
+    /// Rows of garbage sent to each opponent, and received from them.
+    pub attack_sent: u32,
+    pub attack_received: u32,
+    // Left, right and rotate presses since the current piece spawned
+    presses: u32,
+}
+
+#[derive(Clone, Debug)]
 pub struct Grid {
     pub gameover: bool,
     pub sound_events: Vec<GameSoundEvent>,
+    /// Only changes in a battle royale.
+    pub battle: BattleStatus,
     height: u32,
     width: u32,
     cells: Vec<Brick>,
@@ -39,6 +103,8 @@ pub struct Grid {
     score: u32,
     hard_drop_count: u32,
     pending_attack: Option<GridAttackEvent>,
+    rng: Rng,
+    stats: GridStats,
 }
 
 // ------------
@@ -46,14 +112,26 @@ pub struct Grid {
 // ------------
 impl Grid {
     pub fn new(height: u32, width: u32) -> Self {
+        Self::with_rng(height, width, Rng::from_entropy())
+    }
+
+    /// Creates a grid whose sequence of pieces is entirely determined by
+    /// `seed`. Two grids created with the same seed and given the same inputs
+    /// on the same ticks will always end up in the same state.
+    pub fn with_seed(height: u32, width: u32, seed: u64) -> Self {
+        Self::with_rng(height, width, Rng::new(seed))
+    }
+
+    fn with_rng(height: u32, width: u32, mut rng: Rng) -> Self {
         let cell_count = height * width;
         let cells = vec![Brick::Empty; cell_count as usize];
-        let current_piece = random_next_piece().center(width);
-        let staged_piece = random_next_piece().center(width);
+        let current_piece = random_next_piece(&mut rng).center(width);
+        let staged_piece = random_next_piece(&mut rng).center(width);
 
         Self {
             gameover: false,
             sound_events: Vec::new(),
+            battle: BattleStatus::new(),
             height,
             width,
             cells,
@@ -63,6 +141,8 @@ impl Grid {
             score: 0,
             hard_drop_count: 0,
             pending_attack: None,
+            rng,
+            stats: GridStats::default(),
         }
     }
 
@@ -70,6 +150,66 @@ impl Grid {
         (self.width * CELL_SIZE, self.height * CELL_SIZE)
     }
 
+    pub fn stats(&self) -> &GridStats {
+        &self.stats
+    }
+
+    pub fn score(&self) -> u32 {
+        self.score
+    }
+
+    /// Rows from the bottom up to the highest brick.
+    pub fn stack_height(&self) -> u32 {
+        match self.cells.iter().position(|brick| !brick.is_empty()) {
+            Some(index) => self.height - index as u32 / self.width,
+            None => 0,
+        }
+    }
+
+    pub fn handle_input(&mut self, event: GridInputEvent) {
+        self.stats.actions += 1;
+        if matches!(
+            event,
+            GridInputEvent::MoveLeft | GridInputEvent::MoveRight | GridInputEvent::Rotate
+        ) {
+            self.stats.presses += 1;
+        }
+
+        match event {
+            GridInputEvent::MoveLeft => self.move_piece_left(),
+            GridInputEvent::MoveRight => self.move_piece_right(),
+            GridInputEvent::MoveDown => {
+                self.move_piece_down();
+            }
+            GridInputEvent::ForceToBottom => self.move_piece_to_bottom(),
+            GridInputEvent::Rotate => self.rotate(),
+            GridInputEvent::Target(targeting) => self.battle.targeting = targeting,
+        }
+    }
+
+    /// Hash of everything that affects how the grid will be simulated going
+    /// forward. Peers simulating the same match compare these to detect when
+    /// their copies of a grid have diverged. Sound events are left out since
+    /// they are only a side effect for the client.
+    pub fn state_hash(&self) -> u64 {
+        let state = (
+            self.gameover,
+            &self.battle,
+            self.height,
+            self.width,
+            &self.cells,
+            &self.staged_piece,
+            &self.current_piece,
+            self.drop_counter,
+            self.score,
+            self.hard_drop_count,
+            &self.pending_attack,
+            &self.rng,
+        );
+        let bytes = bincode::serialize(&state).expect("error serializing grid state for hashing");
+        fnv1a_hash(&bytes)
+    }
+
     pub fn move_piece_left(&mut self) {
         let next = self.current_piece.move_left();
         if self.does_piece_fit(&next) {
@@ -116,32 +256,29 @@ impl Grid {
     }
 
     pub fn attack(&mut self, event: GridAttackEvent) {
-        match event {
-            GridAttackEvent::LinesCleared(num) => {
-                let matching_row: Option<i32> = self
-                    .lines_matching(|_, brick| brick.is_attacked())
-                    .flat_map(|line| line.cells)
-                    .map(|cell| cell.row)
-                    .min();
-                let mut rows_to_attack = Vec::new();
-                let row = match matching_row {
-                    Some(i) => i - 1,
-                    None => (self.height - 1) as i32,
-                };
-                if num >= 2 {
-                    rows_to_attack.push(row);
-                }
-                if num >= 3 {
-                    rows_to_attack.push(row - 1);
-                }
-                if num >= 4 {
-                    rows_to_attack.push(row - 2);
-                }
+        self.receive_garbage(event.rows());
+    }
 
-                for val in rows_to_attack {
-                    self.mark_row_as_attacked(val as i32);
-                }
+    /// Fills `rows` rows with garbage on top of any already there. Garbage
+    /// that would reach past the top buries the grid.
+    pub fn receive_garbage(&mut self, rows: u32) {
+        let matching_row: Option<i32> = self
+            .lines_matching(|_, brick| brick.is_attacked())
+            .flat_map(|line| line.cells)
+            .map(|cell| cell.row)
+            .min();
+        let row = match matching_row {
+            Some(i) => i - 1,
+            None => (self.height - 1) as i32,
+        };
+
+        self.stats.attack_received += rows;
+        for val in (0..rows as i32).map(|offset| row - offset) {
+            if val < 0 {
+                self.gameover = true;
+                break;
             }
+            self.mark_row_as_attacked(val);
         }
     }
 
@@ -183,38 +320,6 @@ impl Grid {
 
         None
     }
-
-    pub fn render(&self, renderer: &mut Renderer) {
-        let section_margin = 8;
-        self.render_staged_piece(renderer);
-
-        renderer.with_relative_offset(0, section_margin, |renderer| {
-            self.render_outline(renderer);
-
-            // Render occupied cells on the board
-            for cell in self.grid_iterator() {
-                let idx = self.cell_index(cell);
-                match self.cells[idx] {
-                    Brick::Occupied(brick_type) => {
-                        let image = Image::from_brick_type(brick_type);
-                        renderer.render_image(image, cell, Opacity::Opaque);
-                    }
-                    Brick::Breaking(frame) => {
-                        let image = Image::from_brick_type(BrickType::Smoke(frame));
-                        renderer.render_image(image, cell, Opacity::Opaque);
-                    }
-                    _ => {}
-                }
-            }
-
-            self.render_piece(renderer, &self.current_piece, Opacity::Opaque);
-            self.render_piece(renderer, &self.ghost_piece(), Opacity::Translucent(128));
-
-            renderer.with_relative_offset(0, section_margin, |renderer| {
-                self.render_score(renderer);
-            });
-        });
-    }
 }
 
 // ------------
@@ -224,29 +329,6 @@ impl Grid {
 // These methods are only for convenience in creating the menu title screen animation.
 // Avoid using them in the 'real' game.
 impl Grid {
-    pub fn render_for_title(&self, renderer: &mut Renderer) {
-        self.render_outline(renderer);
-
-        // Render occupied cells on the board
-        for cell in self.grid_iterator() {
-            let idx = self.cell_index(cell);
-            match self.cells[idx] {
-                Brick::Occupied(brick_type) => {
-                    let image = Image::from_brick_type(brick_type);
-                    renderer.render_image(image, cell, Opacity::Opaque);
-                }
-                Brick::Breaking(frame) => {
-                    let image = Image::from_brick_type(BrickType::Smoke(frame));
-                    renderer.render_image(image, cell, Opacity::Opaque);
-                }
-                _ => {}
-            }
-        }
-
-        self.render_piece(renderer, &self.current_piece, Opacity::Opaque);
-        self.render_piece(renderer, &self.ghost_piece(), Opacity::Translucent(128));
-    }
-
     pub fn place_piece_at_bottom(&mut self, piece: Piece) {
         let mut piece = piece;
         let mut next = piece.move_down();
@@ -270,7 +352,7 @@ impl Grid {
     fn spawn_next_piece(&mut self) {
         if self.does_piece_fit(&self.staged_piece) {
             self.current_piece = self.staged_piece;
-            self.staged_piece = random_next_piece().center(self.width);
+            self.staged_piece = random_next_piece(&mut self.rng).center(self.width);
         } else {
             self.gameover = true;
         }
@@ -313,6 +395,12 @@ impl Grid {
             let idx = self.cell_index(cell);
             self.cells[idx] = Brick::Occupied(self.current_piece.brick_type());
         }
+
+        let fewest_presses = self.current_piece.moves_from_spawn(self.width);
+        self.stats.pieces_placed += 1;
+        self.stats.finesse_faults += self.stats.presses.saturating_sub(fewest_presses);
+        self.stats.presses = 0;
+
         self.animate_full_lines();
     }
 
@@ -330,6 +418,9 @@ impl Grid {
         self.add_score(number_lines_cleared);
 
         if number_lines_cleared > 0 {
+            self.stats.lines_cleared[number_lines_cleared as usize - 1] += 1;
+            // Clearing one line sends nothing, see `attack`
+            self.stats.attack_sent += number_lines_cleared - 1;
             self.sound_events
                 .push(GameSoundEvent::LinesCleared(number_lines_cleared as u8));
             if number_lines_cleared >= 1 {
@@ -385,6 +476,95 @@ impl Grid {
             }
         }
     }
+}
+
+// ---------
+// Rendering
+// ---------
+#[cfg(feature = "client")]
+impl Grid {
+    pub fn render(&self, renderer: &mut Renderer) {
+        let section_margin = 8;
+        self.render_staged_piece(renderer);
+
+        renderer.with_relative_offset(0, section_margin, |renderer| {
+            self.render_outline(renderer);
+
+            // Render occupied cells on the board
+            for cell in self.grid_iterator() {
+                let idx = self.cell_index(cell);
+                match self.cells[idx] {
+                    Brick::Occupied(brick_type) => {
+                        let image = Image::from_brick_type(brick_type);
+                        renderer.render_image(image, cell, Opacity::Opaque);
+                    }
+                    Brick::Breaking(frame) => {
+                        let image = Image::from_brick_type(BrickType::Smoke(frame));
+                        renderer.render_image(image, cell, Opacity::Opaque);
+                    }
+                    _ => {}
+                }
+            }
+
+            self.render_piece(renderer, &self.current_piece, Opacity::Opaque);
+            self.render_piece(renderer, &self.ghost_piece(), Opacity::Translucent(128));
+
+            renderer.with_relative_offset(0, section_margin, |renderer| {
+                self.render_score(renderer);
+            });
+        });
+    }
+
+    /// Renders just the bricks with each cell `cell_size` pixels wide, for
+    /// keeping an eye on a lot of opponents at once.
+    pub fn render_mini(&self, renderer: &mut Renderer, cell_size: u32) {
+        renderer.fill_rect(
+            Rect::new(0, 0, self.width * cell_size, self.height * cell_size),
+            Color::RGB(22, 22, 22),
+        );
+
+        let scaled = |cell: GridCell| {
+            Rect::new(
+                cell.col * cell_size as i32,
+                cell.row * cell_size as i32,
+                cell_size,
+                cell_size,
+            )
+        };
+        for cell in self.grid_iterator() {
+            let idx = self.cell_index(cell);
+            if let Brick::Occupied(brick_type) = self.cells[idx] {
+                let image = Image::from_brick_type(brick_type);
+                renderer.render_image(image, scaled(cell), Opacity::Opaque);
+            }
+        }
+        for cell in self.current_piece.global_iter() {
+            renderer.render_image(self.current_piece.image(), scaled(cell), Opacity::Opaque);
+        }
+    }
+
+    pub fn render_for_title(&self, renderer: &mut Renderer) {
+        self.render_outline(renderer);
+
+        // Render occupied cells on the board
+        for cell in self.grid_iterator() {
+            let idx = self.cell_index(cell);
+            match self.cells[idx] {
+                Brick::Occupied(brick_type) => {
+                    let image = Image::from_brick_type(brick_type);
+                    renderer.render_image(image, cell, Opacity::Opaque);
+                }
+                Brick::Breaking(frame) => {
+                    let image = Image::from_brick_type(BrickType::Smoke(frame));
+                    renderer.render_image(image, cell, Opacity::Opaque);
+                }
+                _ => {}
+            }
+        }
+
+        self.render_piece(renderer, &self.current_piece, Opacity::Opaque);
+        self.render_piece(renderer, &self.ghost_piece(), Opacity::Translucent(128));
+    }
 
     fn ghost_piece(&self) -> Piece {
         let mut ghost_piece = self.current_piece;
@@ -503,6 +683,213 @@ impl Grid {
     }
 }
 
+// -----------
+// Wire Format
+// -----------
+//
+// Grids are sent to every client on every tick, so they have a compact
+// encoding of their own instead of bincode's. Cells take four bits each,
+// and a run of identical rows (like the empty ones at the top of the board)
+// takes two bytes however long it is.
+impl Grid {
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.varint(self.height.into());
+        writer.varint(self.width.into());
+        writer.u8(self.gameover as u8);
+        self.current_piece.write(&mut writer);
+        self.staged_piece.write(&mut writer);
+        writer.varint(self.drop_counter.into());
+        writer.varint(self.score.into());
+        writer.varint(self.hard_drop_count.into());
+        match self.pending_attack {
+            None => writer.varint(0),
+            Some(GridAttackEvent::LinesCleared(lines)) => writer.varint(u64::from(lines) + 1),
+        }
+        writer.u64(self.rng.state());
+        self.battle.write(&mut writer);
+
+        writer.varint(self.sound_events.len() as u64);
+        for event in self.sound_events.iter() {
+            match event {
+                GameSoundEvent::LinesCleared(lines) => {
+                    writer.u8(0);
+                    writer.u8(*lines);
+                }
+                GameSoundEvent::TurnSoundsOff => writer.u8(1),
+                GameSoundEvent::TurnSoundsOn => writer.u8(2),
+                GameSoundEvent::MovePieceDown => writer.u8(3),
+            }
+        }
+
+        let rows: Vec<&[Brick]> = self.cells.chunks(self.width as usize).collect();
+        let mut row = 0;
+        while row < rows.len() {
+            let first = rows[row][0];
+            if rows[row].iter().all(|brick| *brick == first) {
+                let mut run = 1;
+                while row + run < rows.len() && run < 255 && rows[row + run] == rows[row] {
+                    run += 1;
+                }
+
+                let (code, frame) = first.to_wire();
+                writer.u8(WIRE_ROW_RUN | code);
+                writer.u8(run as u8);
+                if let Some(frame) = frame {
+                    writer.varint(frame.into());
+                }
+                row += run;
+            } else {
+                let codes: Vec<(u8, Option<u16>)> =
+                    rows[row].iter().map(|brick| brick.to_wire()).collect();
+                writer.u8(WIRE_ROW_MIXED);
+                writer.nibbles(codes.iter().map(|(code, _)| *code));
+                for frame in codes.iter().filter_map(|(_, frame)| *frame) {
+                    writer.varint(frame.into());
+                }
+                row += 1;
+            }
+        }
+
+        writer.into_bytes()
+    }
+
+    /// Reads a grid written by `to_wire`, or `None` if the bytes don't hold
+    /// a valid one.
+    pub fn from_wire(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        let height = reader.varint_u32()?;
+        let width = reader.varint_u32()?;
+        // Pieces spawn in the middle four columns
+        if width < 4 || height == 0 || u64::from(height) * u64::from(width) > MAX_WIRE_CELLS {
+            return None;
+        }
+
+        let gameover = match reader.u8()? {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        let current_piece = Piece::read(&mut reader)?;
+        let staged_piece = Piece::read(&mut reader)?;
+        let drop_counter = reader.varint_u32()?;
+        let score = reader.varint_u32()?;
+        let hard_drop_count = reader.varint_u32()?;
+        let pending_attack = match reader.varint()? {
+            0 => None,
+            lines => Some(GridAttackEvent::LinesCleared(u8::try_from(lines - 1).ok()?)),
+        };
+        let rng = Rng::from_state(reader.u64()?)?;
+        let battle = BattleStatus::read(&mut reader)?;
+
+        let mut sound_events = Vec::new();
+        for _ in 0..reader.varint()? {
+            let event = match reader.u8()? {
+                0 => GameSoundEvent::LinesCleared(reader.u8()?),
+                1 => GameSoundEvent::TurnSoundsOff,
+                2 => GameSoundEvent::TurnSoundsOn,
+                3 => GameSoundEvent::MovePieceDown,
+                _ => return None,
+            };
+            sound_events.push(event);
+        }
+
+        let width = width as usize;
+        let cell_count = height as usize * width;
+        let mut cells = Vec::with_capacity(cell_count);
+        while cells.len() < cell_count {
+            match reader.u8()? {
+                WIRE_ROW_MIXED => {
+                    for code in reader.nibbles(width)? {
+                        let frame = read_wire_frame(&mut reader, code)?;
+                        cells.push(Brick::from_wire(code, frame)?);
+                    }
+                }
+                header if header & 0xf0 == WIRE_ROW_RUN => {
+                    let code = header & 0x0f;
+                    let run = reader.u8()? as usize;
+                    if run == 0 || cells.len() + run * width > cell_count {
+                        return None;
+                    }
+
+                    let frame = read_wire_frame(&mut reader, code)?;
+                    let brick = Brick::from_wire(code, frame)?;
+                    cells.extend(iter::repeat_n(brick, run * width));
+                }
+                _ => return None,
+            }
+        }
+
+        if !reader.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            gameover,
+            sound_events,
+            battle,
+            height,
+            width: width as u32,
+            cells,
+            staged_piece,
+            current_piece,
+            drop_counter,
+            score,
+            hard_drop_count,
+            pending_attack,
+            rng,
+            // Only the grid doing the simulating keeps count
+            stats: GridStats::default(),
+        })
+    }
+}
+
+fn read_wire_frame(reader: &mut Reader, code: u8) -> Option<u16> {
+    if Brick::wire_has_frame(code) {
+        u16::try_from(reader.varint()?).ok()
+    } else {
+        Some(0)
+    }
+}
+
+impl Serialize for Grid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_wire())
+    }
+}
+
+impl<'de> Deserialize<'de> for Grid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(GridVisitor)
+    }
+}
+
+struct GridVisitor;
+
+impl<'de> Visitor<'de> for GridVisitor {
+    type Value = Grid;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a grid in its wire format")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Grid, E> {
+        Grid::from_wire(bytes).ok_or_else(|| E::invalid_value(Unexpected::Bytes(bytes), &self))
+    }
+}
+
+/// 64-bit FNV-1a. Used instead of `DefaultHasher` because the result needs to
+/// be identical across machines and compiler versions.
+pub fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(feature = "client")]
 fn lerp(start: (f64, f64, f64), end: (f64, f64, f64), time: f64) -> (f64, f64, f64) {
     (
         start.0 * (1f64 - time) + end.0 * time,
@@ -510,3 +897,231 @@ fn lerp(start: (f64, f64, f64), end: (f64, f64, f64), time: f64) -> (f64, f64, f
         start.2 * (1f64 - time) + end.2 * time,
     )
 }
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn random_brick(rng: &mut Rng) -> Brick {
+    let code = rng.gen_range(0, 12) as u8;
+    let frame = rng.gen_range(0, 300) as u16;
+    Brick::from_wire(code, frame).unwrap()
+}
+
+#[cfg(test)]
+fn random_piece(rng: &mut Rng) -> Piece {
+    let mut piece = random_next_piece(rng);
+    for _ in 0..rng.gen_range(0, 40) {
+        piece = match rng.gen_range(0, 4) {
+            0 => piece.move_left(),
+            1 => piece.move_right(),
+            2 => piece.move_down(),
+            _ => piece.rotate(),
+        };
+    }
+    piece
+}
+
+#[cfg(test)]
+fn random_grid(rng: &mut Rng) -> Grid {
+    let height = rng.gen_range(1, 30) as u32;
+    let width = rng.gen_range(4, 16) as u32;
+    let mut grid = Grid::with_seed(height, width, rng.next_u64());
+
+    // A mix of empty rows, rows of a single brick and rows of anything
+    let mut cells = grid.cells.clone();
+    for row in cells.chunks_mut(width as usize) {
+        match rng.gen_range(0, 3) {
+            0 => {}
+            1 => {
+                let brick = random_brick(rng);
+                row.iter_mut().for_each(|cell| *cell = brick);
+            }
+            _ => row.iter_mut().for_each(|cell| *cell = random_brick(rng)),
+        }
+    }
+    grid.cells = cells;
+
+    grid.gameover = rng.gen_range(0, 2) == 1;
+    grid.current_piece = random_piece(rng);
+    grid.staged_piece = random_piece(rng);
+    grid.drop_counter = rng.next_u64() as u32;
+    grid.score = rng.next_u64() as u32;
+    grid.hard_drop_count = rng.gen_range(0, 30) as u32;
+    if rng.gen_range(0, 2) == 1 {
+        grid.pending_attack = Some(GridAttackEvent::LinesCleared(rng.next_u64() as u8));
+    }
+    grid.battle = BattleStatus {
+        targeting: Targeting::from_wire(rng.gen_range(0, 4) as u8).unwrap(),
+        target: Some(rng.gen_range(0, 32) as u32).filter(|_| rng.gen_range(0, 2) == 1),
+        kos: rng.gen_range(0, 32) as u32,
+        badge_points: rng.next_u64() as u32,
+        last_attacker: None,
+        picks: rng.gen_range(0, 1000) as u32,
+    };
+    for _ in 0..rng.gen_range(0, 5) {
+        grid.sound_events.push(match rng.gen_range(0, 4) {
+            0 => GameSoundEvent::LinesCleared(rng.next_u64() as u8),
+            1 => GameSoundEvent::TurnSoundsOff,
+            2 => GameSoundEvent::TurnSoundsOn,
+            _ => GameSoundEvent::MovePieceDown,
+        });
+    }
+    grid
+}
+
+/// A grid that takes as many bytes in the wire format as one can, for
+/// checking what's sent fits.
+#[cfg(test)]
+pub(crate) fn worst_case_grid(height: u32, width: u32, seed: u64) -> Grid {
+    use crate::snapshot::MAX_SOUNDS;
+
+    let mut rng = Rng::new(seed);
+    let mut grid = Grid::with_seed(height, width, seed);
+    // No two cells in a row alike, and every frame as long as it gets
+    for (idx, cell) in grid.cells.iter_mut().enumerate() {
+        *cell = if idx % 2 == 0 {
+            Brick::Breaking(u16::MAX)
+        } else {
+            Brick::Occupied(BrickType::Smoke(u16::MAX))
+        };
+    }
+    grid.current_piece = random_piece(&mut rng);
+    grid.staged_piece = random_piece(&mut rng);
+    grid.drop_counter = u32::MAX;
+    grid.score = u32::MAX;
+    grid.hard_drop_count = u32::MAX;
+    grid.pending_attack = Some(GridAttackEvent::LinesCleared(u8::MAX));
+    grid.battle = BattleStatus {
+        targeting: Targeting::Attackers,
+        target: Some(u32::MAX - 1),
+        kos: u32::MAX,
+        badge_points: u32::MAX,
+        last_attacker: Some(u32::MAX - 1),
+        picks: u32::MAX,
+    };
+    grid.sound_events = vec![GameSoundEvent::LinesCleared(u8::MAX); MAX_SOUNDS];
+    grid
+}
+
+#[cfg(test)]
+fn assert_wire_roundtrip(grid: &Grid) {
+    let decoded = Grid::from_wire(&grid.to_wire()).expect("grid did not decode");
+
+    // The state hash covers everything but the sound events
+    assert_eq!(decoded.state_hash(), grid.state_hash());
+    assert_eq!(decoded.sound_events, grid.sound_events);
+    assert_eq!(decoded.cells, grid.cells);
+}
+
+#[test]
+fn test_wire_format_roundtrips_random_grids() {
+    let mut rng = Rng::new(35);
+
+    for _ in 0..1000 {
+        assert_wire_roundtrip(&random_grid(&mut rng));
+    }
+}
+
+#[test]
+fn test_wire_format_roundtrips_played_games() {
+    let mut rng = Rng::new(36);
+    let mut grid = Grid::with_seed(20, 10, 36);
+
+    for tick in 0..5000 {
+        let event = match rng.gen_range(0, 10) {
+            0 => Some(GridInputEvent::MoveLeft),
+            1 => Some(GridInputEvent::MoveRight),
+            2 => Some(GridInputEvent::Rotate),
+            3 => Some(GridInputEvent::ForceToBottom),
+            _ => None,
+        };
+        if let Some(event) = event {
+            grid.handle_input(event);
+        }
+        if tick % 300 == 0 {
+            grid.attack(GridAttackEvent::LinesCleared(2));
+        }
+        grid.update();
+
+        assert_wire_roundtrip(&grid);
+        grid.sound_events.clear();
+        if grid.gameover {
+            grid = Grid::with_seed(20, 10, tick);
+        }
+    }
+}
+
+#[test]
+fn test_wire_format_fits_a_full_board_in_a_small_datagram() {
+    let mut grid = Grid::with_seed(20, 10, 1);
+    assert!(grid.to_wire().len() < 40);
+
+    // No two neighbouring cells or rows are the same, the worst case
+    use BrickType::*;
+    let colors = [Red, Green, Blue, Yellow, Orange, Purple, Teal];
+    for (index, cell) in grid.cells.iter_mut().enumerate() {
+        *cell = Brick::Occupied(colors[index % colors.len()]);
+    }
+    let full = grid.to_wire();
+    assert!(full.len() < 160, "full board took {} bytes", full.len());
+
+    let grids = vec![grid.clone(), grid];
+    let serialized = bincode::serialize(&grids).unwrap();
+    assert!(serialized.len() < 400);
+}
+
+#[test]
+fn test_wire_format_refuses_bad_input() {
+    let mut rng = Rng::new(37);
+
+    for _ in 0..100 {
+        let bytes = random_grid(&mut rng).to_wire();
+        for length in 0..bytes.len() {
+            assert!(Grid::from_wire(&bytes[..length]).is_none());
+        }
+
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(Grid::from_wire(&extended).is_none());
+    }
+
+    // Random bytes are almost always refused, but mustn't panic either way
+    for _ in 0..10000 {
+        let length = rng.gen_range(0, 200);
+        let bytes: Vec<u8> = (0..length).map(|_| rng.next_u64() as u8).collect();
+        let _ = Grid::from_wire(&bytes);
+    }
+
+    // Sizes too large to allocate are refused before reading any cells
+    let mut writer = Writer::new();
+    writer.varint(1 << 20);
+    writer.varint(1 << 20);
+    assert!(Grid::from_wire(&writer.into_bytes()).is_none());
+}
+
+#[test]
+fn test_stats_count_pieces_and_finesse_faults() {
+    let mut grid = Grid::with_seed(20, 10, 12);
+
+    // Moving back and forth wastes both presses
+    grid.handle_input(GridInputEvent::MoveLeft);
+    grid.handle_input(GridInputEvent::MoveRight);
+    grid.handle_input(GridInputEvent::ForceToBottom);
+    assert_eq!(grid.stats().pieces_placed, 1);
+    assert_eq!(grid.stats().actions, 3);
+    assert_eq!(grid.stats().finesse_faults, 2);
+
+    // Going straight to the wall wastes nothing
+    for _ in 0..3 {
+        grid.handle_input(GridInputEvent::MoveLeft);
+    }
+    grid.handle_input(GridInputEvent::ForceToBottom);
+    assert_eq!(grid.stats().pieces_placed, 2);
+    assert_eq!(grid.stats().finesse_faults, 2);
+
+    // Counts aren't sent to clients
+    let decoded = Grid::from_wire(&grid.to_wire()).unwrap();
+    assert_eq!(decoded.stats(), &GridStats::default());
+}
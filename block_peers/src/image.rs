@@ -1,4 +1,4 @@
-use crate::brick::BrickType;
+use crate::brick::{BrickType, MAX_SMOKE_FRAME};
 use crate::render::ImageFrame;
 use sdl2::rect::Rect;
 use serde::{Deserialize, Serialize};
@@ -32,7 +32,7 @@ impl ImageFrame for Image {
             Self::PurpleBrick => Rect::new(160, 0, 32, 32),
             Self::TealBrick => Rect::new(192, 0, 32, 32),
             Self::SmokeBrick(frame) => {
-                if frame > 12 {
+                if frame > MAX_SMOKE_FRAME {
                     panic!("unavailable smoke brick, greatest index is 12")
                 }
                 Rect::new((frame * 32) as i32, 32, 32, 32)
@@ -42,10 +42,6 @@ impl ImageFrame for Image {
 }
 
 impl Image {
-    pub fn max_smoke_frame() -> u16 {
-        12
-    }
-
     pub fn from_brick_type(brick_type: BrickType) -> Self {
         use BrickType::*;
         match brick_type {
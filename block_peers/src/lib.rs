@@ -4,16 +4,41 @@ extern crate log;
 extern crate lazy_static;
 extern crate flate2;
 
+#[cfg(feature = "client")]
 pub mod ai;
+pub mod analysis;
+pub mod anticheat;
 pub mod brick;
+pub mod chat;
 pub mod codec;
+pub mod crypto;
 pub mod grid;
+#[cfg(feature = "client")]
 pub mod image;
+pub mod lockstep;
 pub mod logging;
 pub mod net;
 pub mod piece;
+pub mod profile;
+pub mod reliable;
+#[cfg(feature = "client")]
 pub mod render;
+pub mod rendezvous;
+pub mod replay;
+pub mod results;
+pub mod rng;
+pub mod royale;
+#[cfg(feature = "client")]
 pub mod scene;
+#[cfg(feature = "client")]
 pub mod scenes;
+pub mod simulation;
+pub mod snapshot;
+#[cfg(feature = "client")]
 pub mod sound;
+pub mod stats;
+#[cfg(feature = "client")]
 pub mod text;
+pub mod throttle;
+pub mod transport;
+pub mod wire;
@@ -0,0 +1,699 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+
+use crate::grid::{GameSoundEvent, Grid, GridInputEvent};
+use crate::replay::{Replay, ReplaySettings};
+use crate::results::{MatchResult, Placements};
+use crate::royale;
+use crate::simulation::{self, Rules};
+
+/// Number of ticks local input is held back before it is simulated. This gives
+/// the input time to reach the other peers so they rarely have to roll back.
+pub const DEFAULT_INPUT_DELAY: u32 = 3;
+/// Most input delay a match can be played with, a few seconds' worth.
+pub const MAX_INPUT_DELAY: u32 = 3 * TICK_RATE;
+/// Size of the grids every lockstep match is played on.
+pub const GRID_HEIGHT: u32 = 20;
+pub const GRID_WIDTH: u32 = 10;
+/// How far past the last confirmed tick we are willing to predict before
+/// stalling to wait for input from the other peers.
+const MAX_PREDICTION_TICKS: u32 = 12;
+/// How far past our current tick (beyond both peers' input delay) input
+/// from another peer is kept. An honest peer can't get further ahead than
+/// this, so anything later is bogus and would only eat memory.
+const INPUT_WINDOW: u32 = 2 * MAX_PREDICTION_TICKS;
+/// Peers simulate a tick every time the client updates.
+pub const TICK_RATE: u32 = 60;
+/// How often (in ticks) peers exchange hashes of their confirmed state.
+const HASH_INTERVAL: u32 = 60;
+/// Number of old state hashes to hold on to while waiting for the matching
+/// hash from the other peers.
+const MAX_STORED_HASHES: usize = 16;
+
+/// Messages exchanged between the peers of a lockstep match. Either sent
+/// directly or relayed by the server.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum LockstepMessage {
+    /// Inputs of `player_id` for consecutive ticks starting at `start_tick`.
+    /// Every message repeats all inputs the other peers haven't confirmed yet
+    /// (`ack_tick` is the sender's own confirmed tick) so a dropped packet
+    /// doesn't need to be resent explicitly.
+    Input {
+        player_id: u32,
+        start_tick: u32,
+        ack_tick: u32,
+        inputs: Vec<Vec<GridInputEvent>>,
+    },
+    /// Hash of the confirmed state of every grid once `tick` ticks have been
+    /// simulated.
+    StateHash {
+        player_id: u32,
+        tick: u32,
+        hash: u64,
+    },
+}
+
+impl LockstepMessage {
+    pub fn player_id(&self) -> u32 {
+        match self {
+            LockstepMessage::Input { player_id, .. } => *player_id,
+            LockstepMessage::StateHash { player_id, .. } => *player_id,
+        }
+    }
+}
+
+/// Everything the peers need to agree on before a lockstep match starts.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct LockstepSettings {
+    pub num_players: u32,
+    pub seed: u64,
+    pub input_delay: u32,
+    pub grid_height: u32,
+    pub grid_width: u32,
+    pub rules: Rules,
+}
+
+impl LockstepSettings {
+    /// Whether a match can be played as `player_id` with these settings.
+    /// They come from another peer or the server, and anything we didn't
+    /// check could have us allocate or loop without end.
+    pub fn is_playable_by(&self, player_id: u32) -> bool {
+        let teams_fit = match self.rules {
+            Rules::Teams(ref teams) => teams.len() == self.num_players as usize,
+            _ => true,
+        };
+        (1..=royale::MAX_PLAYERS).contains(&self.num_players)
+            && player_id < self.num_players
+            && self.input_delay <= MAX_INPUT_DELAY
+            && self.grid_height == GRID_HEIGHT
+            && self.grid_width == GRID_WIDTH
+            && teams_fit
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SessionEvent {
+    /// Our confirmed state no longer matches the confirmed state of another
+    /// peer. There's no recovering from this, the simulations have diverged.
+    Desync {
+        player_id: u32,
+        tick: u32,
+        local_hash: u64,
+        remote_hash: u64,
+    },
+}
+
+/// Runs a match where every peer simulates all of the grids locally and only
+/// the inputs are exchanged.
+///
+/// Input from remote players that hasn't arrived yet is predicted to be empty
+/// (the common case in a falling blocks game). When a late input shows the
+/// prediction was wrong, the session rolls back to the last state where the
+/// input of every player was known and re-simulates up to the current tick.
+pub struct LockstepSession {
+    local_player_id: u32,
+    input_delay: u32,
+    /// Next tick to simulate on the predicted grids.
+    current_tick: u32,
+    /// Grids including any predicted ticks. This is what gets rendered.
+    grids: Vec<Grid>,
+    /// Every tick before this one has been simulated with real input.
+    confirmed_tick: u32,
+    confirmed_grids: Vec<Grid>,
+    /// Known input of each player, keyed by tick.
+    inputs: Vec<BTreeMap<u32, Vec<GridInputEvent>>>,
+    /// Confirmed tick each remote peer last told us about.
+    remote_acks: Vec<u32>,
+    pending_local_input: Vec<GridInputEvent>,
+    rollback_needed: bool,
+    local_hashes: BTreeMap<u32, u64>,
+    remote_hashes: BTreeMap<u32, Vec<(u32, u64)>>,
+    outgoing: Vec<LockstepMessage>,
+    events: Vec<SessionEvent>,
+    /// Every confirmed tick, which is all the peers can agree happened.
+    replay: Replay,
+    /// Who topped out when on the confirmed grids.
+    placements: Placements,
+    rules: Rules,
+}
+
+impl LockstepSession {
+    pub fn new(local_player_id: u32, settings: LockstepSettings) -> Self {
+        let num_players = settings.num_players as usize;
+        let grids = simulation::new_grids(
+            settings.grid_height,
+            settings.grid_width,
+            settings.num_players,
+            settings.seed,
+        );
+
+        // Nobody can have input for the first few ticks because of the input
+        // delay, so those are known to be empty for everyone.
+        let mut inputs = vec![BTreeMap::new(); num_players];
+        for player_inputs in inputs.iter_mut() {
+            for tick in 0..settings.input_delay {
+                player_inputs.insert(tick, Vec::new());
+            }
+        }
+
+        Self {
+            local_player_id,
+            input_delay: settings.input_delay,
+            current_tick: 0,
+            confirmed_grids: grids.clone(),
+            grids,
+            confirmed_tick: 0,
+            inputs,
+            remote_acks: vec![0; num_players],
+            pending_local_input: Vec::new(),
+            rollback_needed: false,
+            local_hashes: BTreeMap::new(),
+            remote_hashes: BTreeMap::new(),
+            outgoing: Vec::new(),
+            events: Vec::new(),
+            replay: Replay::new(ReplaySettings {
+                num_players: settings.num_players,
+                seed: settings.seed,
+                grid_height: settings.grid_height,
+                grid_width: settings.grid_width,
+                tick_rate: TICK_RATE,
+                rules: settings.rules.clone(),
+            }),
+            placements: Placements::new(num_players, settings.rules.clone()),
+            rules: settings.rules,
+        }
+    }
+
+    pub fn grids(&self) -> &[Grid] {
+        &self.grids
+    }
+
+    pub fn local_player_id(&self) -> u32 {
+        self.local_player_id
+    }
+
+    pub fn current_tick(&self) -> u32 {
+        self.current_tick
+    }
+
+    pub fn confirmed_tick(&self) -> u32 {
+        self.confirmed_tick
+    }
+
+    /// What's been confirmed of the match so far, finished with the state
+    /// the confirmed grids are in.
+    pub fn replay(&self) -> Replay {
+        let mut replay = self.replay.clone();
+        replay.finish(&self.confirmed_grids);
+        replay
+    }
+
+    /// How the match ended, once it has on the confirmed grids. Predicted
+    /// grids topping out don't count, since a rollback could save them.
+    pub fn result(&self) -> Option<MatchResult> {
+        if self.placements.is_over() {
+            Some(self.placements.result(&self.confirmed_grids, TICK_RATE))
+        } else {
+            None
+        }
+    }
+
+    /// The tick the match ended on, once it has on the confirmed grids.
+    /// Every peer agrees on it, so it's what they tell the server.
+    pub fn ended_on(&self) -> Option<u64> {
+        if self.placements.is_over() {
+            Some(self.placements.ticks())
+        } else {
+            None
+        }
+    }
+
+    /// Queues input from the local player. It will be simulated `input_delay`
+    /// ticks after the next call to `advance`.
+    pub fn add_local_input(&mut self, event: GridInputEvent) {
+        self.pending_local_input.push(event);
+    }
+
+    /// Simulates the next tick. Returns false if the session had to stall
+    /// because it is too far ahead of the input received from the other peers.
+    pub fn advance(&mut self) -> bool {
+        self.confirm();
+
+        if self.current_tick - self.confirmed_tick >= MAX_PREDICTION_TICKS {
+            self.queue_local_input_message();
+            return false;
+        }
+
+        let local_tick = self.current_tick + self.input_delay;
+        let local_input = std::mem::take(&mut self.pending_local_input);
+        self.inputs[self.local_player_id as usize].insert(local_tick, local_input);
+        self.queue_local_input_message();
+
+        if self.rollback_needed {
+            self.rollback();
+        }
+
+        let inputs = self.inputs_for_tick(self.current_tick);
+        simulation::step(&mut self.grids, &inputs, &self.rules);
+        self.current_tick += 1;
+
+        true
+    }
+
+    pub fn receive(&mut self, message: LockstepMessage) {
+        let player_id = message.player_id();
+        if player_id == self.local_player_id || player_id as usize >= self.inputs.len() {
+            return;
+        }
+
+        match message {
+            LockstepMessage::Input {
+                start_tick,
+                ack_tick,
+                inputs,
+                ..
+            } => {
+                let remote_ack = &mut self.remote_acks[player_id as usize];
+                *remote_ack = (*remote_ack).max(ack_tick);
+
+                let window_end = self
+                    .current_tick
+                    .saturating_add(self.input_delay.saturating_mul(2))
+                    .saturating_add(INPUT_WINDOW);
+                for (offset, events) in inputs.into_iter().enumerate() {
+                    let tick = match start_tick.checked_add(offset as u32) {
+                        Some(tick) if tick < window_end => tick,
+                        _ => break,
+                    };
+                    if tick < self.confirmed_tick {
+                        continue;
+                    }
+
+                    if let Entry::Vacant(entry) = self.inputs[player_id as usize].entry(tick) {
+                        // We predicted no input for ticks already simulated,
+                        // so anything else means the prediction was wrong.
+                        if tick < self.current_tick && !events.is_empty() {
+                            self.rollback_needed = true;
+                        }
+                        entry.insert(events);
+                    }
+                }
+            }
+            LockstepMessage::StateHash { tick, hash, .. } => match self.local_hashes.get(&tick) {
+                Some(local_hash) => self.compare_hashes(player_id, tick, *local_hash, hash),
+                None => {
+                    self.remote_hashes
+                        .entry(tick)
+                        .or_default()
+                        .push((player_id, hash));
+                    prune(&mut self.remote_hashes);
+                }
+            },
+        }
+    }
+
+    /// Messages that need to be delivered to every other peer.
+    pub fn drain_outgoing(&mut self) -> Vec<LockstepMessage> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn drain_events(&mut self) -> Vec<SessionEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn drain_sound_events(&mut self, sounds: &mut Vec<GameSoundEvent>) {
+        for grid in self.grids.iter_mut() {
+            sounds.append(&mut grid.sound_events);
+        }
+    }
+}
+
+impl LockstepSession {
+    /// Simulates the confirmed grids forward as far as the received input
+    /// allows.
+    fn confirm(&mut self) {
+        while self.confirmed_tick < self.current_tick
+            && self
+                .inputs
+                .iter()
+                .all(|player_inputs| player_inputs.contains_key(&self.confirmed_tick))
+        {
+            let inputs = self.inputs_for_tick(self.confirmed_tick);
+            simulation::step(&mut self.confirmed_grids, &inputs, &self.rules);
+            self.replay.push_tick(&inputs);
+            self.placements.update(&self.confirmed_grids);
+            for grid in self.confirmed_grids.iter_mut() {
+                grid.sound_events.clear();
+            }
+            self.confirmed_tick += 1;
+
+            if self.confirmed_tick.is_multiple_of(HASH_INTERVAL) {
+                self.record_hash();
+            }
+        }
+
+        // Remote input before the confirmed tick is never needed again. Our
+        // own input has to stick around until every peer has confirmed it.
+        let confirmed_tick = self.confirmed_tick;
+        let oldest_remote_ack = self.oldest_remote_ack().min(confirmed_tick);
+        for (player_id, player_inputs) in self.inputs.iter_mut().enumerate() {
+            let keep_from = if player_id as u32 == self.local_player_id {
+                oldest_remote_ack
+            } else {
+                confirmed_tick
+            };
+            *player_inputs = player_inputs.split_off(&keep_from);
+        }
+    }
+
+    fn rollback(&mut self) {
+        debug!(
+            "rolling back from tick {} to {}",
+            self.current_tick, self.confirmed_tick
+        );
+
+        self.grids = self.confirmed_grids.clone();
+        for tick in self.confirmed_tick..self.current_tick {
+            let inputs = self.inputs_for_tick(tick);
+            simulation::step(&mut self.grids, &inputs, &self.rules);
+        }
+
+        // The sounds for these ticks were already played the first time round.
+        for grid in self.grids.iter_mut() {
+            grid.sound_events.clear();
+        }
+
+        self.rollback_needed = false;
+    }
+
+    fn record_hash(&mut self) {
+        let tick = self.confirmed_tick;
+        let hash = simulation::state_hash(&self.confirmed_grids);
+
+        self.local_hashes.insert(tick, hash);
+        prune(&mut self.local_hashes);
+
+        self.outgoing.push(LockstepMessage::StateHash {
+            player_id: self.local_player_id,
+            tick,
+            hash,
+        });
+
+        if let Some(remote) = self.remote_hashes.remove(&tick) {
+            for (player_id, remote_hash) in remote {
+                self.compare_hashes(player_id, tick, hash, remote_hash);
+            }
+        }
+    }
+
+    fn compare_hashes(&mut self, player_id: u32, tick: u32, local_hash: u64, remote_hash: u64) {
+        if local_hash != remote_hash {
+            error!(
+                "desync with player {} at tick {}: {:x} != {:x}",
+                player_id, tick, local_hash, remote_hash
+            );
+            self.events.push(SessionEvent::Desync {
+                player_id,
+                tick,
+                local_hash,
+                remote_hash,
+            });
+        }
+    }
+
+    fn queue_local_input_message(&mut self) {
+        let local_inputs = &self.inputs[self.local_player_id as usize];
+        let start_tick = match local_inputs.keys().next() {
+            Some(tick) => *tick,
+            None => return,
+        };
+
+        self.outgoing.push(LockstepMessage::Input {
+            player_id: self.local_player_id,
+            start_tick,
+            ack_tick: self.confirmed_tick,
+            inputs: local_inputs.values().cloned().collect(),
+        });
+    }
+
+    fn oldest_remote_ack(&self) -> u32 {
+        self.remote_acks
+            .iter()
+            .enumerate()
+            .filter(|(player_id, _)| *player_id as u32 != self.local_player_id)
+            .map(|(_, ack)| *ack)
+            .min()
+            .unwrap_or(self.confirmed_tick)
+    }
+
+    /// Input of every player for the tick, predicting no input for players we
+    /// haven't heard from yet.
+    fn inputs_for_tick(&self, tick: u32) -> Vec<(u32, GridInputEvent)> {
+        let mut inputs = Vec::new();
+        for (player_id, player_inputs) in self.inputs.iter().enumerate() {
+            if let Some(events) = player_inputs.get(&tick) {
+                for event in events {
+                    inputs.push((player_id as u32, *event));
+                }
+            }
+        }
+        inputs
+    }
+}
+
+fn prune<V>(map: &mut BTreeMap<u32, V>) {
+    while map.len() > MAX_STORED_HASHES {
+        let oldest = *map.keys().next().unwrap();
+        map.remove(&oldest);
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn test_settings(seed: u64) -> LockstepSettings {
+    LockstepSettings {
+        num_players: 2,
+        seed,
+        input_delay: DEFAULT_INPUT_DELAY,
+        grid_height: GRID_HEIGHT,
+        grid_width: GRID_WIDTH,
+        rules: Rules::Versus,
+    }
+}
+
+#[cfg(test)]
+fn deliver(from: &mut LockstepSession, to: &mut LockstepSession) {
+    for message in from.drain_outgoing() {
+        to.receive(message);
+    }
+}
+
+#[cfg(test)]
+fn scripted_input(player_id: u32, tick: u32) -> Option<GridInputEvent> {
+    match (tick + player_id * 7) % 23 {
+        0 => Some(GridInputEvent::MoveLeft),
+        5 => Some(GridInputEvent::Rotate),
+        11 => Some(GridInputEvent::MoveRight),
+        17 => Some(GridInputEvent::ForceToBottom),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_peers_stay_in_sync() {
+    let mut a = LockstepSession::new(0, test_settings(7));
+    let mut b = LockstepSession::new(1, test_settings(7));
+
+    for tick in 0..600 {
+        if let Some(event) = scripted_input(0, tick) {
+            a.add_local_input(event);
+        }
+        if let Some(event) = scripted_input(1, tick) {
+            b.add_local_input(event);
+        }
+
+        assert!(a.advance());
+        assert!(b.advance());
+        deliver(&mut a, &mut b);
+        deliver(&mut b, &mut a);
+    }
+
+    assert!(a.drain_events().is_empty());
+    assert!(b.drain_events().is_empty());
+    assert_eq!(
+        simulation::state_hash(a.grids()),
+        simulation::state_hash(b.grids())
+    );
+}
+
+#[test]
+fn test_late_input_rolls_back_to_same_state() {
+    let mut on_time_a = LockstepSession::new(0, test_settings(3));
+    let mut on_time_b = LockstepSession::new(1, test_settings(3));
+    let mut late_a = LockstepSession::new(0, test_settings(3));
+    let mut late_b = LockstepSession::new(1, test_settings(3));
+
+    for tick in 0..300 {
+        for (a, b) in [(&mut on_time_a, &mut on_time_b), (&mut late_a, &mut late_b)].iter_mut() {
+            if let Some(event) = scripted_input(0, tick) {
+                a.add_local_input(event);
+            }
+            if let Some(event) = scripted_input(1, tick) {
+                b.add_local_input(event);
+            }
+            a.advance();
+            b.advance();
+        }
+
+        deliver(&mut on_time_a, &mut on_time_b);
+        deliver(&mut on_time_b, &mut on_time_a);
+
+        // Only let the late pair talk every 8 ticks so input arrives well
+        // after the delay and has to be rolled back into the simulation.
+        if tick % 8 == 0 {
+            deliver(&mut late_a, &mut late_b);
+            deliver(&mut late_b, &mut late_a);
+        }
+    }
+
+    // Let everything settle before comparing.
+    for _ in 0..MAX_PREDICTION_TICKS {
+        for session in [&mut on_time_a, &mut on_time_b, &mut late_a, &mut late_b].iter_mut() {
+            session.advance();
+        }
+        deliver(&mut on_time_a, &mut on_time_b);
+        deliver(&mut on_time_b, &mut on_time_a);
+        deliver(&mut late_a, &mut late_b);
+        deliver(&mut late_b, &mut late_a);
+    }
+
+    assert_eq!(late_a.current_tick(), on_time_a.current_tick());
+    assert_eq!(
+        simulation::state_hash(late_a.grids()),
+        simulation::state_hash(on_time_a.grids())
+    );
+    assert_eq!(
+        simulation::state_hash(late_b.grids()),
+        simulation::state_hash(on_time_b.grids())
+    );
+}
+
+#[test]
+fn test_replay_matches_confirmed_state() {
+    let mut a = LockstepSession::new(0, test_settings(5));
+    let mut b = LockstepSession::new(1, test_settings(5));
+
+    for tick in 0..400 {
+        if let Some(event) = scripted_input(0, tick) {
+            a.add_local_input(event);
+        }
+        if let Some(event) = scripted_input(1, tick) {
+            b.add_local_input(event);
+        }
+        a.advance();
+        b.advance();
+        deliver(&mut a, &mut b);
+        deliver(&mut b, &mut a);
+    }
+
+    assert_eq!(a.replay(), b.replay());
+    assert_eq!(a.replay().length(), a.confirmed_tick());
+
+    let mut player = crate::replay::ReplayPlayer::new(a.replay());
+    player.seek(a.replay().length());
+    assert_eq!(
+        simulation::state_hash(player.grids()),
+        simulation::state_hash(&a.confirmed_grids)
+    );
+}
+
+#[test]
+fn test_stalls_without_remote_input() {
+    let mut session = LockstepSession::new(0, test_settings(1));
+
+    let mut advanced = 0;
+    for _ in 0..100 {
+        if session.advance() {
+            advanced += 1;
+        }
+    }
+
+    assert_eq!(advanced, MAX_PREDICTION_TICKS + DEFAULT_INPUT_DELAY);
+}
+
+#[test]
+fn test_drops_input_outside_the_window() {
+    let mut session = LockstepSession::new(0, test_settings(1));
+    let known = session.inputs[1].len();
+
+    for start_tick in [u32::MAX, 1000].iter() {
+        session.receive(LockstepMessage::Input {
+            player_id: 1,
+            start_tick: *start_tick,
+            ack_tick: 0,
+            inputs: vec![vec![GridInputEvent::MoveLeft]; 3],
+        });
+    }
+    assert_eq!(session.inputs[1].len(), known);
+
+    // Input just ahead of us is still taken
+    session.receive(LockstepMessage::Input {
+        player_id: 1,
+        start_tick: DEFAULT_INPUT_DELAY,
+        ack_tick: 0,
+        inputs: vec![Vec::new(); 2],
+    });
+    assert_eq!(session.inputs[1].len(), known + 2);
+}
+
+#[test]
+fn test_detects_desync() {
+    // Different seeds mean different pieces, so the hashes can't match.
+    let mut a = LockstepSession::new(0, test_settings(1));
+    let mut b = LockstepSession::new(1, test_settings(2));
+
+    for _ in 0..(HASH_INTERVAL + DEFAULT_INPUT_DELAY + 2) {
+        a.advance();
+        b.advance();
+        deliver(&mut a, &mut b);
+        deliver(&mut b, &mut a);
+    }
+
+    let events = a.drain_events();
+    assert_eq!(events.len(), 1);
+    match events[0] {
+        SessionEvent::Desync {
+            player_id, tick, ..
+        } => {
+            assert_eq!(player_id, 1);
+            assert_eq!(tick, HASH_INTERVAL);
+        }
+    }
+}
+
+#[test]
+fn test_refuses_settings_it_cant_play() {
+    assert!(test_settings(1).is_playable_by(1));
+    assert!(!test_settings(1).is_playable_by(2));
+
+    let mut settings = test_settings(1);
+    settings.input_delay = u32::MAX;
+    assert!(!settings.is_playable_by(0));
+
+    let mut settings = test_settings(1);
+    settings.num_players = u32::MAX;
+    assert!(!settings.is_playable_by(0));
+
+    let mut settings = test_settings(1);
+    settings.grid_height = u32::MAX;
+    assert!(!settings.is_playable_by(0));
+
+    let mut settings = test_settings(1);
+    settings.rules = Rules::Teams(vec![0, 1, 0]);
+    assert!(!settings.is_playable_by(0));
+}
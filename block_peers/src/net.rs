@@ -3,14 +3,24 @@ use rand::Rng;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
-use std::borrow::Cow;
-use std::collections::hash_map::Entry;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::io::{Error, ErrorKind, Result};
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
 
-use crate::codec::{gzip_decode, gzip_encode};
-use crate::grid::{Grid, GridInputEvent};
+use crate::chat::ChatLine;
+use crate::codec::CodecKind;
+use crate::crypto::{KeyPair, PublicKey, Role, Session};
+use crate::grid::GridInputEvent;
+use crate::lockstep::{LockstepMessage, LockstepSettings};
+use crate::profile::{PlayerInfo, Profile};
+use crate::results::{MatchResult, RematchVote};
+use crate::snapshot::SnapshotPart;
+use crate::stats::{ConnectionStats, NetStats};
+use crate::transport::{Transport, UdpTransport};
 
 lazy_static! {
     static ref PROTOCOL_ID: u32 = {
@@ -18,141 +28,733 @@ lazy_static! {
         for b in "Block Wars".bytes() {
             total += b as u32;
         }
-        total += PROTOCOL_VERSION;
         total
     };
 }
 
-/// Buffer size used for incoming packets. We don't currently have fragmentation so if a packet
-/// exceeds this size undefined behavior will ensue.
+/// Buffer size used for incoming packets. We don't currently have fragmentation so packets
+/// this size or larger are refused.
 const BUFFER_SIZE: usize = 4096;
+/// Largest a packet is allowed to be once decompressed, so a small packet can't expand into
+/// something that exhausts our memory.
+const MAX_PACKET_SIZE: usize = 64 * 1024;
 /// Block Wars game protocol version used in determining if incoming packets are allowed or
-/// should be ignored.
-const PROTOCOL_VERSION: u32 = 1;
+/// should be ignored. Bump this whenever the messages change.
+pub const PROTOCOL_VERSION: u32 = 17;
+/// How long to go without hearing from the other end of a connection before giving up on it.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// A heartbeat is sent whenever nothing else has been sent for this long, so the other end
+/// doesn't mistake a quiet connection for a dead one.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// After missing this many heartbeats' worth of traffic the client assumes its address may have
+/// changed and starts asking the server to move its connection over.
+const RECONNECT_AFTER: Duration = Duration::from_secs(2);
+/// How long a challenge cookie handed out to a connecting client stays valid.
+const COOKIE_LIFETIME: Duration = Duration::from_secs(10);
+/// Default limit on the number of clients connected at once.
+const DEFAULT_MAX_CONNECTIONS: usize = 64;
+/// Packets per second allowed from an address that hasn't finished the handshake.
+const HANDSHAKE_RATE: f32 = 20.0;
+/// Packets an unknown address can send in a burst before being rate limited.
+const HANDSHAKE_BURST: f32 = 40.0;
+/// Upper bound on the number of addresses tracked by the rate limiter.
+const MAX_RATE_LIMITED_IPS: usize = 4096;
+/// The first byte of a datagram says how to read the rest of it. The low
+/// four bits hold the kind of frame, the high four the codec the packet was
+/// compressed with.
+const FRAME_KIND_MASK: u8 = 0x0f;
+/// Frame holding a packet anyone could have written.
+const FRAME_PLAIN: u8 = 0;
+/// Frame holding a packet encrypted with the session key.
+const FRAME_SEALED: u8 = 1;
+/// Asks the other end to send the id that follows straight back.
+const FRAME_PING: u8 = 2;
+/// The answer to a ping, carrying the same id.
+const FRAME_PONG: u8 = 3;
+/// Codec used for packets big enough to be worth compressing.
+pub const DEFAULT_CODEC: CodecKind = CodecKind::Deflate;
+/// Longest server name sent in answer to a discovery query. Anyone can ask,
+/// so the answer is kept small.
+pub const MAX_SERVER_NAME: usize = 32;
 
 // ---------------------------
 // Server <--> Client Messages
 // ---------------------------
 
-#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ClientMessage {
     Connect,
+    // Numbered from zero in the order they were sent, so the server can
+    // drop one it has already seen
     Command {
         player_id: u32,
+        sequence: u32,
         event: GridInputEvent,
     },
     Disconnect,
+    // Echo the salt from the challenge. If the server offered a key,
+    // our half of the key exchange goes along with it, and either way
+    // who we are.
     ChallengeResponse {
         salt: u64,
+        public_key: Option<PublicKey>,
+        profile: Profile,
+    },
+    // Input or state hash from a lockstep peer for the server to relay
+    // to the other players in the match
+    Lockstep(LockstepMessage),
+    // Ask a rendezvous server to introduce us to the other peer
+    // registered with the same session name
+    Rendezvous {
+        session: String,
+    },
+    // Nothing else to send but we're still here
+    Heartbeat,
+    // Resume the connection identified by the token given to us when
+    // it was first accepted, possibly from a different address
+    Reconnect {
+        token: u64,
+    },
+    // Broadcast to find servers on the local network, which answer with
+    // what they're running
+    Discover,
+    // Something we typed in the chat, numbered for the reliable channel
+    Chat {
+        sequence: u32,
+        text: String,
+    },
+    // We've taken the first `received` chat lines the server sent
+    ChatAck {
+        received: u32,
+    },
+    // Once the match is over, we'd like to play it again with the same
+    // players. Resent until the server's votes show it.
+    Rematch,
+    // Once the match is over, we're done with it but want to stay for the
+    // next one. Resent until the server says we're back in the lobby.
+    ReturnToLobby,
+    // The lockstep match ended on this tick for us. The server only lets
+    // the players vote once they all say so, and we keep saying it until
+    // we leave the results.
+    MatchOver {
+        tick: u64,
+    },
+    // While waiting in the lobby, the team we'd like to play on in the
+    // next team match. The server answers with `TeamChoice`.
+    ChooseTeam {
+        team: u32,
     },
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ServerMessage<'a> {
-    // Use copy-on-write (Cow) here for the grid because we want to
-    // borrow the grid from server when it is writing the message, but
-    // own the grid when the client receives and deserializes the
-    // message. Cow lets us treat borrowed and owned data similarly
+    // We can't understand the client because it is running a different
+    // version of the game. This has to stay the first variant with the
+    // same fields in every version, so clients can decode it no matter
+    // which version they are.
+    VersionMismatch {
+        server_version: u32,
+    },
+    // Part of a snapshot of the grids, which borrows them from the
+    // server when it is writing the message but owns them once the
+    // client has deserialized it. Snapshots aren't sent every tick, and
+    // big ones are split across several of these so each fits in a
+    // packet. See `snapshot`.
     Sync {
         player_id: u32,
-        grids: Cow<'a, Vec<Grid>>,
+        part: SnapshotPart<'a>,
     },
     // Server can't accept anymore incoming connections
     ConnectionRejected,
-    // Client challenge was successful and connection has been accepted
-    ConnectionAccepted,
+    // Client challenge was successful and connection has been accepted.
+    // The token is kept secret between us and the client, which can use
+    // it to reconnect if its address changes.
+    ConnectionAccepted {
+        token: u64,
+    },
     // Server needs to confirm that the client is who they say they are
     // by sending a unique salt and waiting for the client to respond
-    // with the salt.
+    // with the salt. When the server encrypts its connections it also
+    // sends its half of the key exchange.
     Challenge {
         salt: u64,
+        public_key: Option<PublicKey>,
+    },
+    // The match is being played in lockstep: the client simulates every
+    // grid itself and only inputs are exchanged from here on
+    LockstepStart {
+        player_id: u32,
+        settings: LockstepSettings,
+        players: Vec<PlayerInfo>,
+    },
+    // Input or state hash from another peer in a lockstep match
+    Lockstep(LockstepMessage),
+    // Sent by the rendezvous server once a second peer registers for
+    // the same session. `peer_addr` is where the other peer can be
+    // reached and `player_id` decides which of the two hosts the match.
+    Introduction {
+        peer_addr: SocketAddr,
+        player_id: u32,
+    },
+    // When playing without a server the peers talk to each other with
+    // server messages, since each one is the "server" of the other.
+    // This is sent by a peer that wants to join a hosted match. It also
+    // punches a hole through any NAT between the two peers, and says
+    // who is playing.
+    PeerHello(PlayerInfo),
+    // Nothing else to send but we're still here
+    Heartbeat,
+    // The answer to a discovery query
+    ServerInfo(ServerInfo),
+    // Sent instead of `Sync` to clients that joined after the match
+    // started, who watch without a grid of their own
+    Spectate(SnapshotPart<'a>),
+    // The server won't have us anymore, and says why
+    Kicked {
+        reason: String,
     },
+    // A line for the chat log, numbered for the reliable channel
+    Chat {
+        sequence: u32,
+        line: ChatLine,
+    },
+    // The server has taken the first `received` chat messages we sent
+    ChatAck {
+        received: u32,
+    },
+    // The match is over. Sent to everyone in it every so often until
+    // they leave, in case it's lost.
+    MatchResult(MatchResult),
+    // How each player in a finished match voted on a rematch, in
+    // player_id order. Sent whenever a vote changes and along with the
+    // results.
+    RematchVotes(Vec<RematchVote>),
+    // We left the match and are waiting for the next one
+    ReturnedToLobby,
+    // How many teams the next match is played in, none if it isn't, and
+    // which one we chose. The answer to `ChooseTeam`, and sent when we
+    // first connect.
+    TeamChoice {
+        teams: u32,
+        team: Option<u32>,
+    },
+}
+
+/// What a server tells clients looking for a game on the local network.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: u32,
+    // Clients connected, whether playing or waiting for a game
+    pub players: u32,
+    pub players_per_game: u32,
+    pub lockstep: bool,
+    pub in_game: bool,
+}
+
+impl Message for ClientMessage {
+    fn is_handshake(&self) -> bool {
+        matches!(
+            self,
+            ClientMessage::Connect
+                | ClientMessage::ChallengeResponse { .. }
+                | ClientMessage::Reconnect { .. }
+                | ClientMessage::Rendezvous { .. }
+                | ClientMessage::Discover
+        )
+    }
+}
+
+impl<'a> Message for ServerMessage<'a> {
+    fn is_handshake(&self) -> bool {
+        matches!(
+            self,
+            ServerMessage::Challenge { .. }
+                | ServerMessage::ConnectionRejected
+                | ServerMessage::VersionMismatch { .. }
+                | ServerMessage::ServerInfo(_)
+        )
+    }
+
+    fn is_version_mismatch(&self) -> bool {
+        matches!(self, ServerMessage::VersionMismatch { .. })
+    }
 }
 
 pub enum ServerEvent {
-    ClientConnected(SocketAddr),
+    // A client finished the handshake, and was given the token. The
+    // profile is as the client sent it, so it can't be trusted.
+    ClientConnected(SocketAddr, u64, Profile),
     ClientDisconnected(SocketAddr),
+    // We haven't heard from a connected client within the timeout, so
+    // assume they crashed or lost their network
+    ClientTimedOut(SocketAddr),
+    // A client wants to resume its session from the given address. The
+    // server decides whether the token is still good and calls
+    // `accept_reconnect` if so.
+    ClientReconnecting(SocketAddr, u64),
+    // Someone on the local network is looking for a game. The server
+    // answers with `ServerMessage::ServerInfo`, since only it knows what
+    // it's running.
+    Discovery(SocketAddr),
     GameEvent(SocketAddr, ClientMessage),
 }
 
 pub struct ServerSocket {
     socket: Socket,
     connections: HashMap<SocketAddr, Connection>,
+    timeout: Duration,
+    max_connections: usize,
+    // Secret key for the challenge cookies, so they can't be forged
+    cookie_key: RandomState,
+    started_at: Instant,
+    rate_limiter: RateLimiter,
+    // Our half of the key exchange, if connections are encrypted
+    keys: Option<KeyPair>,
 }
 
 impl ServerSocket {
     pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let socket = Socket::bind(addr)?;
-        let connections = HashMap::new();
-        Ok(Self {
+        Ok(Self::with_socket(Socket::bind(addr)?))
+    }
+
+    /// Serves clients over something other than UDP.
+    pub fn with_transport(transport: Box<dyn Transport>) -> Self {
+        Self::with_socket(Socket::with_transport(transport))
+    }
+
+    fn with_socket(socket: Socket) -> Self {
+        Self {
             socket,
-            connections,
-        })
+            connections: HashMap::new(),
+            timeout: DEFAULT_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            cookie_key: RandomState::new(),
+            started_at: Instant::now(),
+            rate_limiter: RateLimiter::new(HANDSHAKE_RATE, HANDSHAKE_BURST),
+            keys: None,
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// How long a client can go without sending anything before it is dropped.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// The most clients that can be connected at once. Anyone past that is
+    /// rejected during the handshake.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    /// Requires every client to agree on a key during the handshake, after
+    /// which everything sent on the connection is encrypted and
+    /// authenticated. Clients that don't are rejected.
+    pub fn enable_encryption(&mut self) {
+        self.keys = Some(KeyPair::generate());
+    }
+
+    pub fn set_codec(&mut self, codec: CodecKind) {
+        self.socket.set_codec(codec);
     }
 
     pub fn receive(&mut self) -> Result<Option<ServerEvent>> {
-        match self.socket.receive::<ClientMessage>() {
-            Ok(Some((source_addr, ClientMessage::Connect))) => {
-                match self.connections.entry(source_addr) {
-                    Entry::Vacant(entry) => {
-                        let client = Connection::new(source_addr);
-                        let salt = client.salt;
-                        entry.insert(client);
-                        self.socket
-                            .send(source_addr, &ServerMessage::Challenge { salt })
-                            .unwrap();
-                    }
-                    Entry::Occupied(entry) => {
-                        self.socket
-                            .send(
-                                source_addr,
-                                &ServerMessage::Challenge {
-                                    salt: entry.get().salt,
-                                },
-                            )
-                            .unwrap();
-                    }
+        if let Some(addr) = self.remove_timed_out_connection() {
+            return Ok(Some(ServerEvent::ClientTimedOut(addr)));
+        }
+
+        let (source_addr, message) = match self.socket.receive::<ClientMessage>() {
+            Ok(Some(received)) => received,
+            Ok(None) => return Ok(None),
+            Err(NetError::VersionMismatch {
+                source_addr,
+                version,
+            }) => {
+                self.reply_version_mismatch(source_addr, version)?;
+                return Err(NetError::VersionMismatch {
+                    source_addr,
+                    version,
+                });
+            }
+            Err(e) => return Err(e),
+        };
+
+        match self.connections.get_mut(&source_addr) {
+            Some(connection) => connection.keepalive.received(),
+            // Anyone we don't know yet is limited in how often they can
+            // make us do work, so we can't be used to flood someone else
+            // with challenges.
+            None => {
+                if !self.rate_limiter.allow(source_addr.ip()) {
+                    trace!("rate limiting packet from {}", source_addr);
+                    return Ok(None);
                 }
+            }
+        }
+
+        match message {
+            ClientMessage::Connect => {
+                // Nothing is stored until the client proves it can receive
+                // at its address by echoing the cookie back to us.
+                let salt = self.cookie(source_addr, self.cookie_period());
+                let public_key = self.keys.as_ref().map(KeyPair::public_key);
+                self.socket
+                    .send(source_addr, &ServerMessage::Challenge { salt, public_key })?;
                 Ok(None)
             }
-            Ok(Some((source_addr, ClientMessage::ChallengeResponse { salt }))) => {
-                match self.connections.entry(source_addr) {
-                    Entry::Vacant(_) => Ok(None),
-                    Entry::Occupied(mut entry) => {
-                        if entry.get().salt == salt {
-                            entry.get_mut().challenge_confirmed = true;
-                            self.socket
-                                .send(source_addr, &ServerMessage::ConnectionAccepted)
-                                .unwrap();
+            ClientMessage::ChallengeResponse {
+                salt,
+                public_key,
+                profile,
+            } => {
+                if !self.valid_cookie(source_addr, salt) {
+                    debug!("ignoring bad challenge response from {}", source_addr);
+                    return Ok(None);
+                }
+
+                // The response may be resent until the client hears back
+                if let Some(connection) = self.connections.get(&source_addr) {
+                    let token = connection.token;
+                    self.socket
+                        .send(source_addr, &ServerMessage::ConnectionAccepted { token })?;
+                    return Ok(None);
+                }
+
+                if self.connections.len() >= self.max_connections {
+                    debug!("rejecting {}, too many connections", source_addr);
+                    self.reject(source_addr)?;
+                    return Ok(None);
+                }
+
+                if let Some(ref keys) = self.keys {
+                    match public_key {
+                        Some(public_key) => {
+                            let session = keys.session(&public_key, Role::Server);
+                            self.socket.set_session(source_addr, session);
+                        }
+                        None => {
+                            debug!("rejecting {}, it won't encrypt", source_addr);
+                            self.reject(source_addr)?;
+                            return Ok(None);
                         }
-                        Ok(Some(ServerEvent::ClientConnected(source_addr)))
                     }
                 }
+
+                let connection = Connection::new(source_addr, self.timeout);
+                let token = connection.token;
+                self.connections.insert(source_addr, connection);
+                self.send(&source_addr, &ServerMessage::ConnectionAccepted { token })?;
+                Ok(Some(ServerEvent::ClientConnected(
+                    source_addr,
+                    token,
+                    profile,
+                )))
             }
-            Ok(Some((source_addr, ClientMessage::Disconnect))) => {
-                // TODO: check that they exist in the connections
-                Ok(Some(ServerEvent::ClientDisconnected(source_addr)))
+            ClientMessage::Disconnect => match self.connections.remove(&source_addr) {
+                Some(_) => {
+                    self.socket.remove_session(&source_addr);
+                    self.socket.forget_stats(&source_addr);
+                    Ok(Some(ServerEvent::ClientDisconnected(source_addr)))
+                }
+                None => Ok(None),
+            },
+            ClientMessage::Heartbeat => Ok(None),
+            ClientMessage::Reconnect { token } => {
+                Ok(Some(ServerEvent::ClientReconnecting(source_addr, token)))
             }
-            Ok(Some((source_addr, client_message))) => {
-                Ok(Some(ServerEvent::GameEvent(source_addr, client_message)))
+            ClientMessage::Discover => Ok(Some(ServerEvent::Discovery(source_addr))),
+            client_message => {
+                // Only clients that finished the handshake get to play
+                if self.connections.contains_key(&source_addr) {
+                    Ok(Some(ServerEvent::GameEvent(source_addr, client_message)))
+                } else {
+                    Ok(None)
+                }
             }
-            Ok(None) => Ok(None),
-            Err(e) => Err(e),
         }
     }
 
-    pub fn send<A: ToSocketAddrs>(&mut self, addr: A, message: &ServerMessage) -> Result<()> {
+    /// How the connection to a client is doing.
+    pub fn stats(&mut self, addr: &SocketAddr) -> Option<NetStats> {
+        self.socket.stats(addr)
+    }
+
+    pub fn send(&mut self, addr: &SocketAddr, message: &ServerMessage) -> Result<()> {
+        if let Some(connection) = self.connections.get_mut(addr) {
+            connection.keepalive.sent();
+        }
         self.socket.send(addr, message)
     }
+
+    /// Moves the connection holding `token` over to `addr`, which the client
+    /// is now reaching us from.
+    pub fn accept_reconnect(&mut self, addr: SocketAddr, token: u64) -> Result<()> {
+        let old_addr = self
+            .connections
+            .iter()
+            .find(|(_, connection)| connection.token == token)
+            .map(|(old_addr, _)| *old_addr);
+
+        // The session key goes with the token, the client has no way of
+        // knowing it needs a new one
+        if let Some(old_addr) = old_addr {
+            self.connections.remove(&old_addr);
+            self.socket.forget_stats(&old_addr);
+            if let Some(session) = self.socket.remove_session(&old_addr) {
+                self.socket.set_session(addr, session);
+            }
+        }
+
+        let mut connection = Connection::new(addr, self.timeout);
+        connection.token = token;
+        self.connections.insert(addr, connection);
+
+        self.send(&addr, &ServerMessage::ConnectionAccepted { token })
+    }
+
+    // Lets a client running a different version know why it isn't
+    // getting anywhere. It isn't connected, so it's rate limited like
+    // any other handshake.
+    fn reply_version_mismatch(&mut self, addr: SocketAddr, version: u32) -> Result<()> {
+        debug!(
+            "{} is running version {}, we're on {}",
+            addr, version, PROTOCOL_VERSION
+        );
+
+        if !self.rate_limiter.allow(addr.ip()) {
+            return Ok(());
+        }
+
+        let message = ServerMessage::VersionMismatch {
+            server_version: PROTOCOL_VERSION,
+        };
+        self.socket.send(addr, &message)
+    }
+
+    /// Tells a connected client why it's being dropped and forgets it, so
+    /// anything else it sends is ignored.
+    pub fn kick(&mut self, addr: SocketAddr, reason: &str) -> Result<()> {
+        let message = ServerMessage::Kicked {
+            reason: String::from(reason),
+        };
+        let sent = self.send(&addr, &message);
+
+        self.connections.remove(&addr);
+        self.socket.remove_session(&addr);
+        self.socket.forget_stats(&addr);
+        sent
+    }
+
+    pub fn reject(&mut self, addr: SocketAddr) -> Result<()> {
+        self.socket.send(addr, &ServerMessage::ConnectionRejected)
+    }
+
+    /// Sends a heartbeat to every connected client we haven't sent anything to
+    /// in a while. Should be called regularly, e.g. every tick.
+    pub fn send_heartbeats(&mut self) -> Result<()> {
+        for (addr, connection) in self.connections.iter_mut() {
+            if connection.keepalive.needs_heartbeat() {
+                connection.keepalive.sent();
+                self.socket.send(addr, &ServerMessage::Heartbeat)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_timed_out_connection(&mut self) -> Option<SocketAddr> {
+        let timed_out = self
+            .connections
+            .iter()
+            .find(|(_, connection)| connection.keepalive.timed_out())
+            .map(|(addr, _)| *addr);
+
+        if let Some(addr) = timed_out {
+            debug!("client {} timed out", addr);
+            self.connections.remove(&addr);
+            self.socket.remove_session(&addr);
+            self.socket.forget_stats(&addr);
+        }
+        timed_out
+    }
+
+    // The challenge salt is a keyed hash of the client's address and the
+    // current cookie period rather than a random number we have to
+    // remember, so a flood of connects can't fill up our memory.
+    fn cookie(&self, addr: SocketAddr, period: u64) -> u64 {
+        let mut hasher = self.cookie_key.build_hasher();
+        addr.hash(&mut hasher);
+        period.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn cookie_period(&self) -> u64 {
+        self.started_at.elapsed().as_secs() / COOKIE_LIFETIME.as_secs()
+    }
+
+    // Cookies from the previous period are still good, otherwise a client
+    // connecting right at the end of a period would be turned away.
+    fn valid_cookie(&self, addr: SocketAddr, salt: u64) -> bool {
+        let period = self.cookie_period();
+        salt == self.cookie(addr, period) || (period > 0 && salt == self.cookie(addr, period - 1))
+    }
+}
+
+// -------------
+// Rate Limiting
+// -------------
+
+/// Token bucket per IP address, allowing short bursts but limiting the
+/// average rate of packets. Only a bounded number of addresses are tracked.
+struct RateLimiter {
+    rate: f32,
+    burst: f32,
+    buckets: HashMap<IpAddr, (f32, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: f32, burst: f32) -> Self {
+        Self {
+            rate,
+            burst,
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+
+        if !self.buckets.contains_key(&ip) && self.buckets.len() >= MAX_RATE_LIMITED_IPS {
+            self.forget_full_buckets(now);
+            if self.buckets.len() >= MAX_RATE_LIMITED_IPS {
+                return false;
+            }
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        let (tokens, last_refill) = self.buckets.entry(ip).or_insert((burst, now));
+        let elapsed = now.duration_since(*last_refill).as_secs_f32();
+        *tokens = (*tokens + elapsed * rate).min(burst);
+        *last_refill = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Addresses that have been quiet long enough to refill their bucket
+    // are no different from ones we've never seen.
+    fn forget_full_buckets(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, (tokens, last_refill)| {
+            let elapsed = now.duration_since(*last_refill).as_secs_f32();
+            *tokens + elapsed * rate < burst
+        });
+    }
+}
+
+// ---------
+// Keepalive
+// ---------
+
+/// Tracks the traffic on one end of a connection to decide when a heartbeat
+/// needs to be sent and when the other end should be considered gone.
+#[derive(Copy, Clone, Debug)]
+pub struct Keepalive {
+    timeout: Duration,
+    last_received: Instant,
+    last_sent: Instant,
+    last_reconnect: Instant,
+}
+
+impl Keepalive {
+    pub fn new(timeout: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            timeout,
+            last_received: now,
+            last_sent: now,
+            last_reconnect: now,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn received(&mut self) {
+        self.last_received = Instant::now();
+    }
+
+    pub fn sent(&mut self) {
+        self.last_sent = Instant::now();
+    }
+
+    pub fn needs_heartbeat(&self) -> bool {
+        self.last_sent.elapsed() >= HEARTBEAT_INTERVAL
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.last_received.elapsed() >= self.timeout
+    }
+
+    /// Whether the other end has gone quiet long enough that our address
+    /// may have changed, and it's time for another reconnect attempt.
+    /// Attempts are spaced out by the heartbeat interval.
+    pub fn reconnect_due(&mut self) -> bool {
+        if self.last_received.elapsed() >= RECONNECT_AFTER
+            && self.last_reconnect.elapsed() >= HEARTBEAT_INTERVAL
+        {
+            self.last_reconnect = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
 }
 
 // -------
 // Socket
 // -------
 
-pub struct Socket {
-    socket: UdpSocket,
+/// Messages that can be sent and received in the clear even once a
+/// connection is encrypted, because they're used to set it up.
+pub trait Message {
+    fn is_handshake(&self) -> bool;
+
+    /// Whether this is the message telling us the other end runs a
+    /// different version, which is the only one we try to read from them.
+    fn is_version_mismatch(&self) -> bool {
+        false
+    }
+}
+
+impl<M: Message> Message for &M {
+    fn is_handshake(&self) -> bool {
+        (*self).is_handshake()
+    }
+
+    fn is_version_mismatch(&self) -> bool {
+        (*self).is_version_mismatch()
+    }
+}
+
+/// Frames, encrypts and compresses messages over a transport.
+pub struct Socket<T: Transport + ?Sized = dyn Transport> {
+    transport: Box<T>,
     buffer: [u8; BUFFER_SIZE],
+    // Encrypted sessions with other sockets. Anything other than a
+    // handshake message to or from these addresses must be sealed.
+    sessions: HashMap<SocketAddr, Session>,
+    codec: CodecKind,
+    // Everyone we've sent anything other than a handshake to
+    stats: HashMap<SocketAddr, ConnectionStats>,
 }
 
 impl Socket {
@@ -163,59 +765,336 @@ impl Socket {
 
     /// Binds a new UDP socket to the specific socket address.
     pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let socket = UdpSocket::bind(addr)?;
-        socket.set_nonblocking(true)?;
+        Ok(Self::with_transport(Box::new(UdpTransport::bind(addr)?)))
+    }
+}
 
-        let buffer = [0; BUFFER_SIZE];
-        Ok(Socket { socket, buffer })
+impl<T: Transport + ?Sized> Socket<T> {
+    /// Exchanges messages over something other than UDP.
+    pub fn with_transport(transport: Box<T>) -> Self {
+        Socket {
+            transport,
+            buffer: [0; BUFFER_SIZE],
+            sessions: HashMap::new(),
+            codec: DEFAULT_CODEC,
+            stats: HashMap::new(),
+        }
     }
 
-    pub fn receive<D: DeserializeOwned>(&mut self) -> Result<Option<(SocketAddr, D)>> {
-        let (bytes_received, source_addr) = match self.socket.recv_from(&mut self.buffer) {
-            Ok(result) => result,
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
-            Err(e) => return Err(e),
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.transport.local_addr()?)
+    }
+
+    /// Encrypts everything exchanged with `addr` from now on, replacing any
+    /// session already set up with it.
+    pub fn set_session(&mut self, addr: SocketAddr, session: Session) {
+        self.sessions.insert(addr, session);
+    }
+
+    pub fn remove_session(&mut self, addr: &SocketAddr) -> Option<Session> {
+        self.sessions.remove(addr)
+    }
+
+    /// Sets the codec used to compress packets. Small packets are always
+    /// sent uncompressed whatever the codec.
+    pub fn set_codec(&mut self, codec: CodecKind) {
+        self.codec = codec;
+    }
+
+    /// How the connection to `addr` is doing, if we've been talking to it.
+    pub fn stats(&mut self, addr: &SocketAddr) -> Option<NetStats> {
+        let stats = self.stats.get_mut(addr)?;
+        Some(stats.snapshot(Instant::now()))
+    }
+
+    /// Stops keeping stats for `addr`, e.g. once it has disconnected.
+    pub fn forget_stats(&mut self, addr: &SocketAddr) {
+        self.stats.remove(addr);
+    }
+
+    pub fn receive<D: DeserializeOwned + Message>(&mut self) -> Result<Option<(SocketAddr, D)>> {
+        loop {
+            let (bytes_received, source_addr) = match self.transport.recv_from(&mut self.buffer)? {
+                Some(received) => received,
+                None => return Ok(None),
+            };
+
+            // A datagram that filled the whole buffer may have been cut short
+            if bytes_received >= BUFFER_SIZE {
+                return Err(NetError::Oversize {
+                    size: bytes_received,
+                });
+            }
+
+            let now = Instant::now();
+            if let Some(stats) = self.stats.get_mut(&source_addr) {
+                stats.received(bytes_received, now);
+            }
+
+            let data = self.buffer[..bytes_received].to_vec();
+            let kind = data.first().map(|frame| frame & FRAME_KIND_MASK);
+            if kind == Some(FRAME_PING) || kind == Some(FRAME_PONG) {
+                self.handle_ping(source_addr, &data, now)?;
+                continue;
+            }
+
+            let message = self.unframe(source_addr, &data)?;
+            return Ok(Some((source_addr, message)));
+        }
+    }
+
+    // Pings are answered here rather than passed on, so every kind of
+    // connection gets a round trip time without the scenes knowing. Only
+    // addresses we're already talking to get an answer, so we can't be
+    // used to bounce traffic at someone else.
+    fn handle_ping(&mut self, source_addr: SocketAddr, data: &[u8], now: Instant) -> Result<()> {
+        if data.len() != 5 {
+            return Err(NetError::Protocol("malformed ping"));
+        }
+        let mut id = [0; 4];
+        id.copy_from_slice(&data[1..]);
+        let id = u32::from_le_bytes(id);
+
+        match self.stats.get_mut(&source_addr) {
+            Some(stats) if data[0] == FRAME_PONG => {
+                stats.pong(id, now);
+                Ok(())
+            }
+            Some(_) => self.send_control(source_addr, FRAME_PONG, id, now),
+            None => Ok(()),
+        }
+    }
+
+    fn send_control(&mut self, addr: SocketAddr, frame: u8, id: u32, now: Instant) -> Result<()> {
+        let mut bytes = vec![frame];
+        bytes.extend_from_slice(&id.to_le_bytes());
+        self.transport.send_to(&bytes, addr)?;
+        if let Some(stats) = self.stats.get_mut(&addr) {
+            stats.sent(bytes.len(), now);
+        }
+        Ok(())
+    }
+
+    // Turns a datagram back into the message it holds. Anyone can send us
+    // anything, so this has to cope with arbitrary bytes.
+    fn unframe<D: DeserializeOwned + Message>(
+        &mut self,
+        source_addr: SocketAddr,
+        data: &[u8],
+    ) -> Result<D> {
+        let (frame, body) = match data.split_first() {
+            Some((&frame, body)) => (frame, body),
+            None => return Err(NetError::Protocol("empty datagram")),
+        };
+        let codec = match CodecKind::from_id(frame >> 4) {
+            Some(codec) => codec,
+            None => return Err(NetError::Protocol("unknown codec")),
         };
 
-        let data = &self.buffer[..bytes_received];
-        let decoded = gzip_decode(&data);
+        let (sealed, compressed) =
+            match (frame & FRAME_KIND_MASK, self.sessions.get_mut(&source_addr)) {
+                (FRAME_PLAIN, _) => (false, body.to_vec()),
+                (FRAME_SEALED, Some(session)) => match session.open(&[frame], body) {
+                    Some(opened) => (true, opened),
+                    None => return Err(NetError::Protocol("unable to open sealed packet")),
+                },
+                _ => return Err(NetError::Protocol("unexpected frame")),
+            };
+        let decoded = codec
+            .codec()
+            .decode(&compressed, MAX_PACKET_SIZE)
+            .map_err(|_| NetError::Decompress)?;
 
-        match bincode::deserialize::<Packet<D>>(&decoded) {
-            Ok(packet) => {
-                if packet.header.protocol_id != *PROTOCOL_ID {
-                    return Err(Error::new(ErrorKind::Other, "incorrect protocol id"));
+        // The header is read on its own first, since the rest of the packet
+        // may not make sense to us if it comes from a different version.
+        let header: PacketHeader = deserialize(&decoded)?;
+        if header.protocol_id != *PROTOCOL_ID {
+            return Err(NetError::Protocol("incorrect protocol id"));
+        }
+        if header.version != PROTOCOL_VERSION {
+            if let Ok(packet) = deserialize::<Packet<D>>(&decoded) {
+                if packet.message.is_version_mismatch() {
+                    return Ok(packet.message);
                 }
-
-                Ok(Some((source_addr, packet.message)))
             }
-            Err(_) => Err(Error::new(ErrorKind::Other, "unable to deserialize packet")),
+
+            return Err(NetError::VersionMismatch {
+                source_addr,
+                version: header.version,
+            });
         }
+
+        let packet: Packet<D> = deserialize(&decoded)?;
+
+        // Anyone could have sent a plain packet, so once there's a
+        // session they can only be used to set up a new one.
+        if !sealed && self.sessions.contains_key(&source_addr) && !packet.message.is_handshake() {
+            return Err(NetError::Protocol("expected sealed packet"));
+        }
+
+        Ok(packet.message)
     }
 
-    pub fn send<A: ToSocketAddrs, S: Serialize>(&mut self, addr: A, message: S) -> Result<()> {
-        let packet = Packet::new(message);
-        let bytes = gzip_encode(&packet.as_bytes());
-        self.socket.send_to(&bytes, addr)?;
+    pub fn send<A: ToSocketAddrs, S: Serialize + Message>(
+        &mut self,
+        addr: A,
+        message: S,
+    ) -> Result<()> {
+        let addr = match addr.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(NetError::Protocol("no address to send to")),
+        };
+
+        let handshake = message.is_handshake();
+        let bytes = self.frame(addr, message)?;
+        self.transport.send_to(&bytes, addr)?;
+        if handshake {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let stats = self
+            .stats
+            .entry(addr)
+            .or_insert_with(|| ConnectionStats::new(now));
+        stats.sent(bytes.len(), now);
+        if let Some(id) = stats.ping_due(now) {
+            self.send_control(addr, FRAME_PING, id, now)?;
+        }
         Ok(())
     }
+
+    // Builds the datagram for a message, sealing it if we have a session
+    // with the address.
+    fn frame<S: Serialize + Message>(&mut self, addr: SocketAddr, message: S) -> Result<Vec<u8>> {
+        let handshake = message.is_handshake();
+        // Versions before there was a choice of codec can only read gzip, so
+        // that's all they get when telling them they're out of date.
+        let codec = if message.is_version_mismatch() {
+            CodecKind::Gzip
+        } else {
+            self.codec
+        };
+        let packet = Packet::new(message).as_bytes();
+        let (codec, compressed) = compress(codec, &packet)?;
+
+        let mut bytes = Vec::with_capacity(compressed.len() + 1);
+        match self.sessions.get_mut(&addr) {
+            Some(session) if !handshake => {
+                let frame = FRAME_SEALED | codec.id() << 4;
+                bytes.push(frame);
+                bytes.extend(session.seal(&[frame], &compressed));
+            }
+            _ => {
+                bytes.push(FRAME_PLAIN | codec.id() << 4);
+                bytes.extend(compressed);
+            }
+        }
+
+        // The other end can't tell a packet this large from one that was
+        // cut short, so don't bother sending it.
+        if bytes.len() >= BUFFER_SIZE {
+            return Err(NetError::Oversize { size: bytes.len() });
+        }
+        Ok(bytes)
+    }
+}
+
+// Compresses the packet, sending it as it is instead if it's too small to
+// bother or compressing doesn't make it any smaller. Gzip is only used when
+// the other end can't read anything else, so it's always kept.
+fn compress(codec: CodecKind, packet: &[u8]) -> Result<(CodecKind, Vec<u8>)> {
+    if codec == CodecKind::Gzip {
+        return Ok((codec, codec.codec().encode(packet)?));
+    }
+
+    let codec = codec.for_size(packet.len());
+    let compressed = codec.codec().encode(packet)?;
+    if compressed.len() < packet.len() {
+        Ok((codec, compressed))
+    } else {
+        Ok((CodecKind::None, packet.to_vec()))
+    }
 }
 
+// Decodes with a limit on how much can be allocated, so a bogus length in
+// a packet can't make us reserve huge amounts of memory.
+fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T> {
+    bincode::config()
+        .limit(MAX_PACKET_SIZE as u64)
+        .deserialize(bytes)
+        .map_err(|_| NetError::Decode)
+}
+
+// -------
+// Errors
+// -------
+
+#[derive(Debug)]
+pub enum NetError {
+    /// The socket itself failed.
+    Io(io::Error),
+    /// The packet is too large to fit in a single datagram.
+    Oversize { size: usize },
+    /// The packet couldn't be decompressed, or decompressed to something
+    /// larger than any packet we'd send.
+    Decompress,
+    /// The packet decompressed fine but doesn't hold a message we know.
+    Decode,
+    /// The packet came from a different version of the game.
+    VersionMismatch {
+        source_addr: SocketAddr,
+        version: u32,
+    },
+    /// The packet broke the rules of the protocol, e.g. it isn't one of
+    /// ours or should have been sealed.
+    Protocol(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, NetError>;
+
+impl From<io::Error> for NetError {
+    fn from(error: io::Error) -> Self {
+        NetError::Io(error)
+    }
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::Io(error) => write!(f, "socket error: {}", error),
+            NetError::Oversize { size } => write!(f, "packet of {} bytes is too large", size),
+            NetError::Decompress => write!(f, "unable to decompress packet"),
+            NetError::Decode => write!(f, "unable to deserialize packet"),
+            NetError::VersionMismatch {
+                source_addr,
+                version,
+            } => write!(
+                f,
+                "{} is running protocol version {}, expected {}",
+                source_addr, version, PROTOCOL_VERSION
+            ),
+            NetError::Protocol(reason) => write!(f, "protocol error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
+
 struct Connection {
     // TODO: reassess whether we need when introducing multiplayer
     _address: SocketAddr,
-    challenge_confirmed: bool,
-    salt: u64,
+    // Identifies the session across changes of address
+    token: u64,
+    keepalive: Keepalive,
 }
 
 impl Connection {
-    fn new(address: SocketAddr) -> Self {
-        let mut rng = rand::thread_rng();
-        let salt = rng.gen_range(0, std::u64::MAX);
-
+    fn new(address: SocketAddr, timeout: Duration) -> Self {
         Self {
             _address: address,
-            salt,
-            challenge_confirmed: false,
+            token: rand::thread_rng().gen(),
+            keepalive: Keepalive::new(timeout),
         }
     }
 }
@@ -224,9 +1103,13 @@ impl Connection {
 // Packet
 // -------
 
+/// Starts every packet. Its layout (and the framing around it, along with
+/// gzip being codec zero) must not change between versions, so that a
+/// packet from any version can be recognized as coming from a different one.
 #[derive(Copy, Clone, Serialize, Deserialize, Debug)]
 struct PacketHeader {
     protocol_id: u32,
+    version: u32,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -240,6 +1123,7 @@ impl<S: Serialize> Packet<S> {
         Self {
             header: PacketHeader {
                 protocol_id: *PROTOCOL_ID,
+                version: PROTOCOL_VERSION,
             },
             message,
         }
@@ -249,3 +1133,945 @@ impl<S: Serialize> Packet<S> {
         bincode::serialize(&self).expect("error serializing packet into bytes for transmission")
     }
 }
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn connect_client(server: &mut ServerSocket, client: &mut Socket) -> SocketAddr {
+    let server_addr = server.local_addr().unwrap();
+    let client_addr = client.local_addr().unwrap();
+
+    client.send(server_addr, ClientMessage::Connect).unwrap();
+    let salt = loop {
+        server.receive().unwrap();
+        if let Ok(Some((_, ServerMessage::Challenge { salt, .. }))) = client.receive() {
+            break salt;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    };
+
+    let response = ClientMessage::ChallengeResponse {
+        salt,
+        public_key: None,
+        profile: Profile::default(),
+    };
+    client.send(server_addr, response).unwrap();
+    loop {
+        if let Some(ServerEvent::ClientConnected(addr, _, _)) = server.receive().unwrap() {
+            assert_eq!(addr, client_addr);
+            return addr;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_server_socket_times_out_silent_client() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    server.set_timeout(Duration::from_millis(50));
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let client_addr = connect_client(&mut server, &mut client);
+
+    std::thread::sleep(Duration::from_millis(60));
+    match server.receive() {
+        Ok(Some(ServerEvent::ClientTimedOut(addr))) => assert_eq!(addr, client_addr),
+        _ => panic!("expected the client to time out"),
+    }
+
+    // The connection is forgotten, so it only times out once
+    assert!(server.receive().unwrap().is_none());
+}
+
+#[test]
+fn test_server_socket_keeps_client_sending_heartbeats() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    server.set_timeout(Duration::from_millis(100));
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    connect_client(&mut server, &mut client);
+
+    for _ in 0..10 {
+        client.send(server_addr, ClientMessage::Heartbeat).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        while let Some(event) = server.receive().unwrap() {
+            if let ServerEvent::ClientTimedOut(_) = event {
+                panic!("client sending heartbeats should not time out");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sockets_measure_round_trip_time() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let client_addr = connect_client(&mut server, &mut client);
+
+    // Sending anything past the handshake starts the pings going
+    client.send(server_addr, ClientMessage::Heartbeat).unwrap();
+    let started = Instant::now();
+    while client.stats(&server_addr).unwrap().rtt.is_none() {
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let _ = server.receive();
+        let _ = client.receive::<ServerMessage>();
+    }
+
+    assert_eq!(client.stats(&server_addr).unwrap().loss, 0.0);
+    // The server's first ping went out with the acceptance, before the
+    // client knew to answer it
+    assert!(server.stats(&client_addr).is_some());
+}
+
+#[test]
+fn test_sockets_only_answer_pings_from_known_addresses() {
+    let mut first = Socket::bind("127.0.0.1:0").unwrap();
+    let first_addr = first.local_addr().unwrap();
+    let mut second = Socket::bind("127.0.0.1:0").unwrap();
+    let second_addr = second.local_addr().unwrap();
+
+    // The heartbeat is followed by a ping, which second doesn't answer
+    // since it has never sent anything to first
+    first.send(second_addr, ServerMessage::Heartbeat).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(matches!(
+        second.receive::<ServerMessage>(),
+        Ok(Some((_, ServerMessage::Heartbeat)))
+    ));
+    assert!(second.receive::<ServerMessage>().unwrap().is_none());
+    assert!(second.stats(&first_addr).is_none());
+
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(first.receive::<ServerMessage>().unwrap().is_none());
+    assert_eq!(first.stats(&second_addr).unwrap().rtt, None);
+}
+
+#[test]
+fn test_keepalive_needs_heartbeat_after_interval() {
+    let mut keepalive = Keepalive::new(DEFAULT_TIMEOUT);
+    assert!(!keepalive.needs_heartbeat());
+    assert!(!keepalive.timed_out());
+
+    keepalive.last_sent -= HEARTBEAT_INTERVAL;
+    assert!(keepalive.needs_heartbeat());
+    keepalive.sent();
+    assert!(!keepalive.needs_heartbeat());
+
+    keepalive.last_received -= DEFAULT_TIMEOUT;
+    assert!(keepalive.timed_out());
+}
+
+#[test]
+fn test_server_socket_moves_reconnecting_client() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let old_addr = connect_client(&mut server, &mut client);
+    let token = server.connections[&old_addr].token;
+
+    // The client comes back from a different port
+    let mut moved_client = Socket::bind("127.0.0.1:0").unwrap();
+    let new_addr = moved_client.local_addr().unwrap();
+    moved_client
+        .send(server_addr, ClientMessage::Reconnect { token })
+        .unwrap();
+
+    let event = loop {
+        if let Some(event) = server.receive().unwrap() {
+            break event;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    };
+    match event {
+        ServerEvent::ClientReconnecting(addr, reconnect_token) => {
+            assert_eq!(addr, new_addr);
+            assert_eq!(reconnect_token, token);
+        }
+        _ => panic!("expected a reconnect request"),
+    }
+
+    server.accept_reconnect(new_addr, token).unwrap();
+    assert!(!server.connections.contains_key(&old_addr));
+    assert_eq!(server.connections[&new_addr].token, token);
+}
+
+// Packets that don't produce an event look the same as no packet at all, so
+// keep receiving for a little while to be sure everything was handled.
+#[cfg(test)]
+fn drain_events(server: &mut ServerSocket) -> Vec<ServerEvent> {
+    let mut events = Vec::new();
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(20) {
+        if let Some(event) = server.receive().unwrap() {
+            events.push(event);
+        }
+    }
+    events
+}
+
+#[test]
+fn test_server_socket_connect_does_not_allocate_connection() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+
+    for _ in 0..10 {
+        client.send(server_addr, ClientMessage::Connect).unwrap();
+    }
+    assert!(drain_events(&mut server).is_empty());
+    assert!(server.connections.is_empty());
+
+    // The same cookie is handed out every time within a period
+    let mut salts = Vec::new();
+    while let Ok(Some((_, ServerMessage::Challenge { salt, .. }))) = client.receive() {
+        salts.push(salt);
+    }
+    assert_eq!(salts.len(), 10);
+    assert!(salts.iter().all(|salt| *salt == salts[0]));
+}
+
+#[test]
+fn test_server_socket_ignores_mismatched_salt() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let client_addr = client.local_addr().unwrap();
+
+    let salt = server.cookie(client_addr, server.cookie_period());
+    let response = ClientMessage::ChallengeResponse {
+        salt: salt ^ 1,
+        public_key: None,
+        profile: Profile::default(),
+    };
+    client.send(server_addr, response).unwrap();
+
+    assert!(drain_events(&mut server).is_empty());
+    assert!(server.connections.is_empty());
+    assert!(client.receive::<ServerMessage>().unwrap().is_none());
+}
+
+#[test]
+fn test_server_socket_cookie_is_bound_to_address() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let mut spoofer = Socket::bind("127.0.0.1:0").unwrap();
+
+    // A cookie issued to one address is no good from another
+    let salt = server.cookie(client.local_addr().unwrap(), server.cookie_period());
+    let response = ClientMessage::ChallengeResponse {
+        salt,
+        public_key: None,
+        profile: Profile::default(),
+    };
+    spoofer.send(server_addr, response.clone()).unwrap();
+    assert!(drain_events(&mut server).is_empty());
+
+    client.send(server_addr, response).unwrap();
+    match drain_events(&mut server).as_slice() {
+        [ServerEvent::ClientConnected(addr, _, _)] => {
+            assert_eq!(*addr, client.local_addr().unwrap())
+        }
+        _ => panic!("expected the client to connect"),
+    }
+}
+
+#[test]
+fn test_server_socket_cookies_expire() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+    server.started_at -= COOKIE_LIFETIME * 3;
+    let period = server.cookie_period();
+    assert!(server.valid_cookie(addr, server.cookie(addr, period)));
+    assert!(server.valid_cookie(addr, server.cookie(addr, period - 1)));
+    assert!(!server.valid_cookie(addr, server.cookie(addr, period - 2)));
+}
+
+#[test]
+fn test_server_socket_reports_discovery_queries() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let client_addr = client.local_addr().unwrap();
+
+    client.send(server_addr, ClientMessage::Discover).unwrap();
+    let events = drain_events(&mut server);
+    assert_eq!(events.len(), 1);
+    match events[0] {
+        ServerEvent::Discovery(addr) => assert_eq!(addr, client_addr),
+        _ => panic!("expected a discovery query"),
+    }
+    assert!(server.connections.is_empty());
+
+    let info = ServerInfo {
+        name: String::from("test"),
+        version: PROTOCOL_VERSION,
+        players: 1,
+        players_per_game: 2,
+        lockstep: false,
+        in_game: false,
+    };
+    server
+        .send(&client_addr, &ServerMessage::ServerInfo(info.clone()))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    match client.receive() {
+        Ok(Some((_, ServerMessage::ServerInfo(received)))) => assert_eq!(received, info),
+        other => panic!("expected server info, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_server_socket_drops_game_events_from_unknown_clients() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+
+    let command = ClientMessage::Command {
+        player_id: 0,
+        sequence: 0,
+        event: GridInputEvent::MoveLeft,
+    };
+    client.send(server_addr, command.clone()).unwrap();
+    assert!(drain_events(&mut server).is_empty());
+
+    connect_client(&mut server, &mut client);
+    client.send(server_addr, command).unwrap();
+    match drain_events(&mut server).as_slice() {
+        [ServerEvent::GameEvent(_, ClientMessage::Command { .. })] => {}
+        _ => panic!("expected the command from the connected client"),
+    }
+}
+
+#[test]
+fn test_server_socket_forgets_kicked_clients() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let client_addr = connect_client(&mut server, &mut client);
+
+    server.kick(client_addr, "too many inputs").unwrap();
+    let reason = loop {
+        if let Ok(Some((_, ServerMessage::Kicked { reason }))) = client.receive() {
+            break reason;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    };
+    assert_eq!(reason, "too many inputs");
+
+    let command = ClientMessage::Command {
+        player_id: 0,
+        sequence: 0,
+        event: GridInputEvent::MoveLeft,
+    };
+    client.send(server_addr, command).unwrap();
+    assert!(drain_events(&mut server).is_empty());
+}
+
+#[test]
+fn test_server_socket_rejects_clients_past_max_connections() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    server.set_max_connections(1);
+    let server_addr = server.local_addr().unwrap();
+
+    let mut first = Socket::bind("127.0.0.1:0").unwrap();
+    connect_client(&mut server, &mut first);
+
+    let mut second = Socket::bind("127.0.0.1:0").unwrap();
+    let salt = server.cookie(second.local_addr().unwrap(), server.cookie_period());
+    let response = ClientMessage::ChallengeResponse {
+        salt,
+        public_key: None,
+        profile: Profile::default(),
+    };
+    second.send(server_addr, response).unwrap();
+    assert!(drain_events(&mut server).is_empty());
+    assert_eq!(server.connections.len(), 1);
+
+    match second.receive::<ServerMessage>() {
+        Ok(Some((_, ServerMessage::ConnectionRejected))) => {}
+        other => panic!("expected a rejection, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_server_socket_rate_limits_handshakes() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+
+    let attempts = HANDSHAKE_BURST as usize * 2;
+    for _ in 0..attempts {
+        client.send(server_addr, ClientMessage::Connect).unwrap();
+    }
+    drain_events(&mut server);
+
+    let mut challenges = 0;
+    while let Ok(Some(_)) = client.receive::<ServerMessage>() {
+        challenges += 1;
+    }
+    assert!(challenges >= HANDSHAKE_BURST as usize);
+    assert!(challenges < attempts);
+}
+
+#[test]
+fn test_rate_limiter_refills_over_time() {
+    let mut limiter = RateLimiter::new(10.0, 2.0);
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let other_ip: IpAddr = "10.0.0.2".parse().unwrap();
+
+    assert!(limiter.allow(ip));
+    assert!(limiter.allow(ip));
+    assert!(!limiter.allow(ip));
+
+    // Each address has its own bucket
+    assert!(limiter.allow(other_ip));
+
+    limiter.buckets.get_mut(&ip).unwrap().1 -= Duration::from_millis(100);
+    assert!(limiter.allow(ip));
+    assert!(!limiter.allow(ip));
+}
+
+#[test]
+fn test_rate_limiter_tracks_bounded_number_of_addresses() {
+    let mut limiter = RateLimiter::new(1.0, 2.0);
+    for i in 0..MAX_RATE_LIMITED_IPS as u32 {
+        assert!(limiter.allow(IpAddr::from(i.to_be_bytes())));
+    }
+
+    // Every bucket is still draining, so nobody new can be tracked
+    let newcomer: IpAddr = "255.255.255.255".parse().unwrap();
+    assert!(!limiter.allow(newcomer));
+    assert_eq!(limiter.buckets.len(), MAX_RATE_LIMITED_IPS);
+
+    // Once the old buckets have refilled they're forgotten
+    for (_, last_refill) in limiter.buckets.values_mut() {
+        *last_refill -= Duration::from_secs(5);
+    }
+    assert!(limiter.allow(newcomer));
+    assert_eq!(limiter.buckets.len(), 1);
+}
+
+#[cfg(test)]
+fn connect_encrypted_client<T: Transport + ?Sized>(
+    server: &mut ServerSocket,
+    client: &mut Socket<T>,
+) -> SocketAddr {
+    let server_addr = server.local_addr().unwrap();
+    let keys = KeyPair::generate();
+
+    client.send(server_addr, ClientMessage::Connect).unwrap();
+    let (salt, server_key) = loop {
+        server.receive().unwrap();
+        if let Ok(Some((_, ServerMessage::Challenge { salt, public_key }))) = client.receive() {
+            break (salt, public_key.expect("server should offer a key"));
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    };
+
+    client.set_session(server_addr, keys.session(&server_key, Role::Client));
+    let response = ClientMessage::ChallengeResponse {
+        salt,
+        public_key: Some(keys.public_key()),
+        profile: Profile::default(),
+    };
+    client.send(server_addr, response).unwrap();
+
+    let addr = match drain_events(server).as_slice() {
+        [ServerEvent::ClientConnected(addr, _, _)] => *addr,
+        _ => panic!("expected the client to connect"),
+    };
+
+    // The acceptance is sealed, proving the server has the same key
+    match client.receive::<ServerMessage>() {
+        Ok(Some((_, ServerMessage::ConnectionAccepted { .. }))) => {}
+        other => panic!("expected a sealed acceptance, got {:?}", other),
+    }
+    addr
+}
+
+#[test]
+fn test_server_socket_encrypts_connections() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    server.enable_encryption();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let client_addr = connect_encrypted_client(&mut server, &mut client);
+
+    let command = ClientMessage::Command {
+        player_id: 0,
+        sequence: 0,
+        event: GridInputEvent::Rotate,
+    };
+    let sealed = client.frame(server_addr, command.clone()).unwrap();
+    assert_eq!(sealed[0] & FRAME_KIND_MASK, FRAME_SEALED);
+    client.transport.send_to(&sealed, server_addr).unwrap();
+    match drain_events(&mut server).as_slice() {
+        [ServerEvent::GameEvent(addr, ClientMessage::Command { .. })] => {
+            assert_eq!(*addr, client_addr)
+        }
+        _ => panic!("expected the sealed command"),
+    }
+
+    // Replaying the same datagram does nothing
+    client.transport.send_to(&sealed, server_addr).unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(server.receive().is_err());
+
+    // Neither does a plain command forged from the client's address
+    client.remove_session(&server_addr);
+    client.send(server_addr, command).unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(server.receive().is_err());
+}
+
+#[test]
+fn test_server_socket_rejects_clients_that_wont_encrypt() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    server.enable_encryption();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let salt = server.cookie(client_addr, server.cookie_period());
+    let response = ClientMessage::ChallengeResponse {
+        salt,
+        public_key: None,
+        profile: Profile::default(),
+    };
+    client.send(server_addr, response).unwrap();
+    assert!(drain_events(&mut server).is_empty());
+    assert!(server.connections.is_empty());
+
+    match client.receive::<ServerMessage>() {
+        Ok(Some((_, ServerMessage::ConnectionRejected))) => {}
+        other => panic!("expected a rejection, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_server_socket_moves_session_on_reconnect() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    server.enable_encryption();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let old_addr = connect_encrypted_client(&mut server, &mut client);
+    let token = server.connections[&old_addr].token;
+
+    // The client's session moves to a new socket, as if its port changed
+    let session = client.remove_session(&server_addr).unwrap();
+    let mut moved_client = Socket::bind("127.0.0.1:0").unwrap();
+    let new_addr = moved_client.local_addr().unwrap();
+    moved_client.set_session(server_addr, session);
+    moved_client
+        .send(server_addr, ClientMessage::Reconnect { token })
+        .unwrap();
+
+    match drain_events(&mut server).as_slice() {
+        [ServerEvent::ClientReconnecting(addr, _)] => assert_eq!(*addr, new_addr),
+        _ => panic!("expected a reconnect request"),
+    }
+    server.accept_reconnect(new_addr, token).unwrap();
+
+    std::thread::sleep(Duration::from_millis(10));
+    match moved_client.receive::<ServerMessage>() {
+        Ok(Some((_, ServerMessage::ConnectionAccepted { .. }))) => {}
+        other => panic!("expected a sealed acceptance, got {:?}", other),
+    }
+}
+
+#[cfg(test)]
+fn send_with_version<S: Serialize>(
+    socket: &mut Socket,
+    addr: SocketAddr,
+    version: u32,
+    message: S,
+) {
+    let mut packet = Packet::new(message);
+    packet.header.version = version;
+    // Older versions only know how to gzip
+    let mut bytes = vec![FRAME_PLAIN];
+    bytes.extend(CodecKind::Gzip.codec().encode(&packet.as_bytes()).unwrap());
+    socket.transport.send_to(&bytes, addr).unwrap();
+}
+
+#[test]
+fn test_server_socket_replies_to_other_versions() {
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let client_addr = client.local_addr().unwrap();
+
+    send_with_version(
+        &mut client,
+        server_addr,
+        PROTOCOL_VERSION + 1,
+        ClientMessage::Connect,
+    );
+    std::thread::sleep(Duration::from_millis(10));
+
+    match server.receive() {
+        Err(NetError::VersionMismatch {
+            source_addr,
+            version,
+        }) => {
+            assert_eq!(source_addr, client_addr);
+            assert_eq!(version, PROTOCOL_VERSION + 1);
+        }
+        _ => panic!("expected a version error"),
+    }
+    assert!(server.connections.is_empty());
+
+    std::thread::sleep(Duration::from_millis(10));
+    match client.receive::<ServerMessage>() {
+        Ok(Some((_, ServerMessage::VersionMismatch { server_version }))) => {
+            assert_eq!(server_version, PROTOCOL_VERSION)
+        }
+        other => panic!("expected a version mismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_socket_reads_version_mismatch_from_other_versions() {
+    let mut server = Socket::bind("127.0.0.1:0").unwrap();
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    let client_addr = client.local_addr().unwrap();
+
+    // Every version can tell the client it's out of date
+    let other_version = PROTOCOL_VERSION + 1;
+    let message = ServerMessage::VersionMismatch {
+        server_version: other_version,
+    };
+    send_with_version(&mut server, client_addr, other_version, message);
+    // But nothing else from them is trusted
+    send_with_version(
+        &mut server,
+        client_addr,
+        other_version,
+        ServerMessage::Heartbeat,
+    );
+    std::thread::sleep(Duration::from_millis(10));
+
+    match client.receive::<ServerMessage>() {
+        Ok(Some((_, ServerMessage::VersionMismatch { server_version }))) => {
+            assert_eq!(server_version, other_version)
+        }
+        other => panic!("expected a version mismatch, got {:?}", other),
+    }
+    match client.receive::<ServerMessage>() {
+        Err(NetError::VersionMismatch { version, .. }) => assert_eq!(version, other_version),
+        other => panic!("expected a version error, got {:?}", other),
+    }
+}
+
+#[cfg(test)]
+const FUZZ_CODECS: [CodecKind; 5] = [
+    CodecKind::Gzip,
+    CodecKind::None,
+    CodecKind::Deflate,
+    CodecKind::Lz4,
+    CodecKind::Zstd,
+];
+
+#[cfg(test)]
+fn random_bytes(rng: &mut crate::rng::Rng, max_len: usize) -> Vec<u8> {
+    let len = rng.gen_range(0, max_len);
+    (0..len).map(|_| rng.next_u64() as u8).collect()
+}
+
+#[cfg(test)]
+fn fuzz_sockets() -> (Socket, SocketAddr, Socket, SocketAddr) {
+    let plain = Socket::bind("127.0.0.1:0").unwrap();
+    let mut sealed = Socket::bind("127.0.0.1:0").unwrap();
+    let plain_addr = plain.local_addr().unwrap();
+    let sealed_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+    let keys = KeyPair::generate();
+    let other_keys = KeyPair::generate();
+    sealed.set_session(
+        sealed_addr,
+        keys.session(&other_keys.public_key(), Role::Server),
+    );
+    (plain, plain_addr, sealed, sealed_addr)
+}
+
+#[test]
+fn test_fuzz_random_datagrams_never_panic() {
+    let mut rng = crate::rng::Rng::new(33);
+    let (mut plain, plain_addr, mut sealed, sealed_addr) = fuzz_sockets();
+
+    for _ in 0..5000 {
+        let mut bytes = random_bytes(&mut rng, 256);
+        // Make sure plenty of them get past the frame type
+        if !bytes.is_empty() && rng.gen_range(0, 2) == 0 {
+            bytes[0] = rng.gen_range(0, 2) as u8;
+        }
+
+        let _ = plain.unframe::<ClientMessage>(plain_addr, &bytes);
+        let _ = plain.unframe::<ServerMessage>(plain_addr, &bytes);
+        let _ = sealed.unframe::<ClientMessage>(sealed_addr, &bytes);
+        let _ = sealed.unframe::<ServerMessage>(sealed_addr, &bytes);
+    }
+}
+
+#[test]
+fn test_fuzz_mangled_packets_never_panic() {
+    use crate::grid::Grid;
+    use crate::snapshot;
+
+    let mut rng = crate::rng::Rng::new(34);
+    let (mut plain, plain_addr, _, _) = fuzz_sockets();
+
+    let grids = vec![Grid::with_seed(20, 10, 1), Grid::with_seed(20, 10, 2)];
+    let messages = vec![
+        plain.frame(plain_addr, ClientMessage::Connect).unwrap(),
+        plain
+            .frame(
+                plain_addr,
+                ClientMessage::Command {
+                    player_id: 1,
+                    sequence: 0,
+                    event: GridInputEvent::Rotate,
+                },
+            )
+            .unwrap(),
+        plain
+            .frame(
+                plain_addr,
+                ServerMessage::Sync {
+                    player_id: 0,
+                    part: snapshot::split(0, &grids, &[]).remove(0),
+                },
+            )
+            .unwrap(),
+    ];
+
+    // Packets are decompressed before being decoded, so mangle both the
+    // compressed bytes and the packet inside them, using every codec
+    let mut packets = messages.clone();
+    for message in messages.iter() {
+        let codec = CodecKind::from_id(message[0] >> 4).unwrap();
+        let mut inner = codec
+            .codec()
+            .decode(&message[1..], MAX_PACKET_SIZE)
+            .unwrap();
+        for _ in 0..10 {
+            let index = rng.gen_range(0, inner.len());
+            inner[index] = rng.next_u64() as u8;
+            for codec in FUZZ_CODECS.iter() {
+                let mut packet = vec![FRAME_PLAIN | codec.id() << 4];
+                packet.extend(codec.codec().encode(&inner).unwrap());
+                packets.push(packet);
+            }
+        }
+    }
+
+    for packet in packets.iter() {
+        for _ in 0..200 {
+            let mut mangled = packet.clone();
+            match rng.gen_range(0, 3) {
+                0 => {
+                    let index = rng.gen_range(0, mangled.len());
+                    mangled[index] ^= 1 << rng.gen_range(0, 8);
+                }
+                1 => mangled.truncate(rng.gen_range(0, mangled.len())),
+                _ => mangled.extend(random_bytes(&mut rng, 16)),
+            }
+
+            let _ = plain.unframe::<ClientMessage>(plain_addr, &mangled);
+            let _ = plain.unframe::<ServerMessage>(plain_addr, &mangled);
+        }
+    }
+}
+
+#[test]
+fn test_decompression_bombs_are_refused() {
+    let (mut plain, plain_addr, _, _) = fuzz_sockets();
+
+    for codec in FUZZ_CODECS
+        .iter()
+        .filter(|codec| **codec != CodecKind::None)
+    {
+        let mut packet = vec![FRAME_PLAIN | codec.id() << 4];
+        packet.extend(codec.codec().encode(&vec![0; MAX_PACKET_SIZE * 4]).unwrap());
+        assert!(packet.len() < BUFFER_SIZE);
+
+        match plain.unframe::<ClientMessage>(plain_addr, &packet) {
+            Err(NetError::Decompress) => {}
+            other => panic!("expected a decompression error, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_server_socket_survives_garbage_over_loopback() {
+    let mut rng = crate::rng::Rng::new(35);
+    let mut server = ServerSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let attacker = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    for _ in 0..200 {
+        attacker
+            .send_to(&random_bytes(&mut rng, BUFFER_SIZE + 100), server_addr)
+            .unwrap();
+    }
+
+    // Every one of them should be refused with an error rather than a panic
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(50) {
+        let _ = server.receive();
+    }
+
+    // It still lets real clients in afterwards
+    let mut client = Socket::bind("127.0.0.1:0").unwrap();
+    connect_client(&mut server, &mut client);
+}
+
+#[test]
+fn test_socket_refuses_to_send_oversize_packets() {
+    let mut socket = Socket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    // Random bytes don't compress
+    let mut rng = crate::rng::Rng::new(36);
+    let session: String = (0..BUFFER_SIZE * 2)
+        .map(|_| (b'a' + rng.gen_range(0, 26) as u8) as char)
+        .collect();
+
+    match socket.send(addr, ClientMessage::Rendezvous { session }) {
+        Err(NetError::Oversize { .. }) => {}
+        other => panic!("expected an oversize error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_socket_only_compresses_large_packets() {
+    use crate::grid::Grid;
+    use crate::snapshot;
+
+    let mut socket = Socket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    // Enough players for the sync to be worth compressing
+    let grids: Vec<Grid> = (0..8).map(|seed| Grid::with_seed(20, 10, seed)).collect();
+
+    for codec in FUZZ_CODECS
+        .iter()
+        .filter(|codec| **codec != CodecKind::Gzip)
+    {
+        socket.set_codec(*codec);
+
+        let command = socket
+            .frame(
+                addr,
+                ClientMessage::Command {
+                    player_id: 1,
+                    sequence: 0,
+                    event: GridInputEvent::Rotate,
+                },
+            )
+            .unwrap();
+        assert_eq!(command[0] >> 4, CodecKind::None.id());
+
+        let sync = ServerMessage::Sync {
+            player_id: 0,
+            part: snapshot::split(0, &grids, &[]).remove(0),
+        };
+        let bytes = socket.frame(addr, &sync).unwrap();
+        assert_eq!(bytes[0] >> 4, codec.id());
+        match socket.unframe::<ServerMessage>(addr, &bytes) {
+            Ok(ServerMessage::Sync { part, .. }) => assert_eq!(part.grids.len(), 8),
+            other => panic!("expected a sync, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_biggest_snapshots_fit_in_a_packet() {
+    use crate::grid::{worst_case_grid, Grid};
+    use crate::profile::MAX_NAME_LEN;
+    use crate::royale::MAX_PLAYERS;
+    use crate::snapshot;
+
+    let (mut plain, plain_addr, mut sealed, sealed_addr) = fuzz_sockets();
+    let grids: Vec<Grid> = (0..MAX_PLAYERS)
+        .map(|seed| worst_case_grid(20, 10, u64::from(seed)))
+        .collect();
+    let players: Vec<PlayerInfo> = (0..MAX_PLAYERS)
+        .map(|player_id| PlayerInfo {
+            name: "\u{1F600}".repeat(MAX_NAME_LEN),
+            team: Some(player_id),
+        })
+        .collect();
+    let parts = snapshot::split(u64::MAX, &grids, &players);
+
+    for codec in FUZZ_CODECS.iter() {
+        plain.set_codec(*codec);
+        sealed.set_codec(*codec);
+        for part in parts.iter() {
+            let sync = ServerMessage::Sync {
+                player_id: u32::MAX,
+                part: part.clone(),
+            };
+            let spectate = ServerMessage::Spectate(part.clone());
+            for message in [&sync, &spectate].iter() {
+                for (socket, addr) in
+                    [(&mut plain, plain_addr), (&mut sealed, sealed_addr)].iter_mut()
+                {
+                    let bytes = socket.frame(*addr, message).unwrap();
+                    assert!(bytes.len() < BUFFER_SIZE, "{:?}: {}", codec, bytes.len());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_version_mismatch_is_always_gzipped() {
+    let mut socket = Socket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    socket.set_codec(CodecKind::Lz4);
+
+    let bytes = socket
+        .frame(
+            addr,
+            ServerMessage::VersionMismatch {
+                server_version: PROTOCOL_VERSION,
+            },
+        )
+        .unwrap();
+    assert_eq!(bytes[0], FRAME_PLAIN);
+}
+
+#[test]
+fn test_server_socket_accepts_clients_over_websockets() {
+    use crate::transport::WebSocketTransport;
+
+    let transport = WebSocketTransport::bind("127.0.0.1:0").unwrap();
+    let server_addr = transport.local_addr().unwrap();
+    let mut server = ServerSocket::with_transport(Box::new(transport));
+    server.enable_encryption();
+
+    let transport = WebSocketTransport::connect(server_addr).unwrap();
+    let mut client = Socket::with_transport(Box::new(transport));
+    let client_addr = connect_encrypted_client(&mut server, &mut client);
+
+    client
+        .send(
+            server_addr,
+            ClientMessage::Command {
+                player_id: 0,
+                sequence: 0,
+                event: GridInputEvent::Rotate,
+            },
+        )
+        .unwrap();
+    match drain_events(&mut server).as_slice() {
+        [ServerEvent::GameEvent(addr, ClientMessage::Command { .. })] => {
+            assert_eq!(*addr, client_addr)
+        }
+        _ => panic!("expected a command"),
+    }
+}
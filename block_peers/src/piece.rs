@@ -1,8 +1,11 @@
-use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
 use crate::brick::{BrickType, GridCell};
+#[cfg(feature = "client")]
 use crate::image::Image;
+use crate::rng::Rng;
+use crate::wire::{Reader, Writer};
 
 // --------
 // Piece
@@ -56,8 +59,7 @@ const SHAPES: [PieceShape; 7] = [
     ],
 ];
 
-pub fn random_next_piece() -> Piece {
-    let mut rng = rand::thread_rng();
+pub fn random_next_piece(rng: &mut Rng) -> Piece {
     let idx = rng.gen_range(0, SHAPES.len());
     Piece::new(idx)
 }
@@ -70,6 +72,7 @@ pub struct Piece {
 }
 
 impl Piece {
+    #[cfg(feature = "client")]
     pub fn image(&self) -> Image {
         Image::from_brick_type(self.brick_type())
     }
@@ -140,6 +143,24 @@ impl Piece {
         }
     }
 
+    /// Fewest left, right and rotate presses that take a newly spawned piece
+    /// of the same shape to where this one is, ignoring anything in the way.
+    /// Rotations that leave the shape looking the same are free.
+    pub fn moves_from_spawn(&self, width: u32) -> u32 {
+        let (col, footprint) = self.footprint();
+        let mut spawned = Piece::new(self.shape_idx).center(width);
+        let mut fewest = None;
+        for rotations in 0..4 {
+            let (spawned_col, spawned_footprint) = spawned.footprint();
+            if spawned_footprint == footprint {
+                let moves = rotations + (col - spawned_col).unsigned_abs();
+                fewest = Some(fewest.map_or(moves, |fewest: u32| fewest.min(moves)));
+            }
+            spawned = spawned.rotate();
+        }
+        fewest.expect("a piece always matches one of its own rotations")
+    }
+
     /// `global_iter` provides the (col, row) of occupied bricks inside the "global"
     /// grid context of a PieceShape taking the current rotation and the pieces
     /// origin into consideration.
@@ -164,6 +185,56 @@ impl Piece {
     fn cells(&self) -> Vec<bool> {
         SHAPES[self.shape_idx].to_vec()
     }
+
+    // Leftmost occupied column, and the occupied cells relative to the
+    // top left of the piece
+    fn footprint(&self) -> (i32, Vec<(i32, i32)>) {
+        let cells: Vec<GridCell> = self.global_iter().collect();
+        let left = cells.iter().map(|cell| cell.col).min().unwrap_or(0);
+        let top = cells.iter().map(|cell| cell.row).min().unwrap_or(0);
+        let mut footprint: Vec<(i32, i32)> = cells
+            .iter()
+            .map(|cell| (cell.col - left, cell.row - top))
+            .collect();
+        footprint.sort();
+        (left, footprint)
+    }
+}
+
+// -----------
+// Wire Format
+// -----------
+//
+// One byte for the shape and rotation followed by the column and row, which
+// usually take a byte each.
+impl Piece {
+    pub fn write(&self, writer: &mut Writer) {
+        writer.u8(self.shape_idx as u8 | self.rotation.index() << 3);
+        writer.signed_varint(i64::from(self.position.col));
+        writer.signed_varint(i64::from(self.position.row));
+    }
+
+    pub fn read(reader: &mut Reader) -> Option<Self> {
+        let pose = reader.u8()?;
+        let shape_idx = (pose & 0x07) as usize;
+        if shape_idx >= SHAPES.len() {
+            return None;
+        }
+        let rotation = Rotation::from_index(pose >> 3)?;
+
+        let col = reader.signed_varint()?;
+        let row = reader.signed_varint()?;
+        let position = GridCell {
+            col: i32::try_from(col).ok()?,
+            row: i32::try_from(row).ok()?,
+        };
+
+        Some(Self {
+            shape_idx,
+            rotation,
+            position,
+        })
+    }
 }
 
 pub struct PieceIterator {
@@ -229,6 +300,27 @@ enum Rotation {
 }
 
 impl Rotation {
+    fn index(self) -> u8 {
+        use Rotation::*;
+        match self {
+            Zero => 0,
+            Ninety => 1,
+            OneEighty => 2,
+            TwoSeventy => 3,
+        }
+    }
+
+    fn from_index(index: u8) -> Option<Rotation> {
+        use Rotation::*;
+        match index {
+            0 => Some(Zero),
+            1 => Some(Ninety),
+            2 => Some(OneEighty),
+            3 => Some(TwoSeventy),
+            _ => None,
+        }
+    }
+
     fn next(self) -> Rotation {
         use Rotation::*;
         match self {
@@ -314,3 +406,41 @@ fn test_piece_iterator() {
         assert_eq!(tt.expected, result);
     }
 }
+
+#[test]
+fn test_piece_wire_format_roundtrip() {
+    let piece = Piece::new(5)
+        .rotate()
+        .rotate()
+        .move_left()
+        .move_left()
+        .move_down();
+
+    let mut writer = Writer::new();
+    piece.write(&mut writer);
+    let bytes = writer.into_bytes();
+    assert_eq!(bytes.len(), 3);
+
+    let read = Piece::read(&mut Reader::new(&bytes)).unwrap();
+    assert_eq!(read.shape_idx, piece.shape_idx);
+    assert_eq!(read.rotation, piece.rotation);
+    assert_eq!(read.position, piece.position);
+
+    // Unknown shapes and rotations are refused
+    assert!(Piece::read(&mut Reader::new(&[7, 0, 0])).is_none());
+    assert!(Piece::read(&mut Reader::new(&[0x20, 0, 0])).is_none());
+}
+
+#[test]
+fn test_piece_moves_from_spawn() {
+    let spawned = Piece::new(0).center(10);
+    assert_eq!(spawned.moves_from_spawn(10), 0);
+    assert_eq!(spawned.move_left().move_left().moves_from_spawn(10), 2);
+    assert_eq!(spawned.rotate().move_right().moves_from_spawn(10), 2);
+    // Three rotations one way can't be done in fewer here
+    assert_eq!(spawned.rotate().rotate().rotate().moves_from_spawn(10), 3);
+
+    // The square looks the same however it's turned
+    let square = Piece::new(2).center(10);
+    assert_eq!(square.rotate().rotate().move_left().moves_from_spawn(10), 1);
+}
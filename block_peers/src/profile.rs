@@ -0,0 +1,182 @@
+//! Who's playing: the name shown above their board and an id that stays
+//! the same from one session to the next.
+//!
+//! The local player's profile lives in a small config file of `key = value`
+//! lines, created with a new id the first time the game runs.
+
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Longest name, in characters, that fits above a board.
+pub const MAX_NAME_LEN: usize = 16;
+pub const DEFAULT_NAME: &str = "Player";
+
+lazy_static! {
+    /// Set once at startup like the sound setting, so every scene that
+    /// connects somewhere can say who we are.
+    static ref LOCAL_PROFILE: Mutex<Profile> = Mutex::new(Profile::default());
+}
+
+pub fn set_local_profile(profile: Profile) {
+    *LOCAL_PROFILE.lock().unwrap() = profile;
+}
+
+pub fn local_profile() -> Profile {
+    LOCAL_PROFILE.lock().unwrap().clone()
+}
+
+/// What a client tells the server about its player when connecting.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    /// Kept in the config file so the same player can be recognised
+    /// across sessions. Never shown to the other players.
+    pub id: Option<u64>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            name: String::from(DEFAULT_NAME),
+            id: None,
+        }
+    }
+}
+
+impl Profile {
+    /// Reads the profile at `path`, or creates one there with a new id if
+    /// there isn't one yet.
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(config) => Ok(Self::parse(&config)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                let profile = Self {
+                    id: Some(rand::random()),
+                    ..Self::default()
+                };
+                fs::write(path, profile.to_config())?;
+                Ok(profile)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads `name` and `id` lines, ignoring anything it doesn't know.
+    pub fn parse(config: &str) -> Self {
+        let mut profile = Self::default();
+        for line in config.lines() {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => continue,
+            };
+
+            match key {
+                "name" => {
+                    if let Some(name) = clean_name(value) {
+                        profile.name = name;
+                    }
+                }
+                "id" => profile.id = u64::from_str_radix(value, 16).ok(),
+                _ => {}
+            }
+        }
+        profile
+    }
+
+    pub fn to_config(&self) -> String {
+        let mut config = format!("name = {}\n", self.name);
+        if let Some(id) = self.id {
+            config.push_str(&format!("id = {:016x}\n", id));
+        }
+        config
+    }
+}
+
+/// What every client in a match is told about each player.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PlayerInfo {
+    pub name: String,
+    /// Which team the player is on in a team match.
+    pub team: Option<u32>,
+}
+
+impl PlayerInfo {
+    /// Trusts nothing in the profile a client sent, falling back to a name
+    /// made from the player's place in the match.
+    pub fn from_profile(profile: &Profile, player_id: u32) -> Self {
+        Self {
+            name: clean_name(&profile.name).unwrap_or_else(|| fallback_name(player_id)),
+            team: None,
+        }
+    }
+}
+
+/// What to call a player we know nothing about.
+pub fn fallback_name(player_id: u32) -> String {
+    format!("{} {}", DEFAULT_NAME, player_id + 1)
+}
+
+/// Drops control characters and surrounding space and cuts the name down
+/// to `MAX_NAME_LEN`, or `None` if nothing is left.
+pub fn clean_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .chars()
+        .take(MAX_NAME_LEN)
+        .collect();
+    let name = name.trim_end();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(String::from(name))
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_profile_roundtrips_through_config() {
+    let profile = Profile {
+        name: String::from("Ada"),
+        id: Some(0xdead_beef),
+    };
+    assert_eq!(Profile::parse(&profile.to_config()), profile);
+
+    // Unknown keys and broken lines are skipped
+    let config = "# mine\nname =  Grace \ncolour = blue\nid = nope\n";
+    assert_eq!(
+        Profile::parse(config),
+        Profile {
+            name: String::from("Grace"),
+            id: None,
+        }
+    );
+}
+
+#[test]
+fn test_names_are_cleaned_up() {
+    assert_eq!(clean_name("  Ada\n"), Some(String::from("Ada")));
+    assert_eq!(clean_name("\u{7}\u{1b}"), None);
+    assert_eq!(
+        clean_name("a name far too long for a board"),
+        Some(String::from("a name far too l"))
+    );
+
+    let blank = Profile {
+        name: String::from("   "),
+        id: None,
+    };
+    assert_eq!(PlayerInfo::from_profile(&blank, 1).name, "Player 2");
+}
@@ -0,0 +1,171 @@
+//! Delivery that survives lost packets, for the few messages that can't
+//! simply be overtaken by a newer one the way grid snapshots are.
+//!
+//! Each message is numbered and resent until the other end acknowledges
+//! it. The other end only takes messages in order and acknowledges how many
+//! it has taken so far, so one acknowledgement covers everything before it.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How long to wait for an acknowledgement before sending a message again.
+pub const RESEND_INTERVAL: Duration = Duration::from_millis(250);
+/// Most messages waiting to be acknowledged at once. Past that new ones are
+/// refused, so an end that's gone quiet can't make us hold on to everything.
+pub const MAX_UNACKED: usize = 64;
+
+struct Pending<T> {
+    sequence: u32,
+    message: T,
+    last_sent: Option<Instant>,
+}
+
+/// The sending half, which keeps every message until it's acknowledged.
+pub struct ReliableSender<T> {
+    next_sequence: u32,
+    unacked: VecDeque<Pending<T>>,
+}
+
+impl<T: Clone> ReliableSender<T> {
+    pub fn new() -> Self {
+        Self {
+            next_sequence: 0,
+            unacked: VecDeque::new(),
+        }
+    }
+
+    /// Queues a message to be sent, or returns false if too many are
+    /// waiting to be acknowledged already.
+    pub fn push(&mut self, message: T) -> bool {
+        if self.unacked.len() >= MAX_UNACKED {
+            return false;
+        }
+
+        self.unacked.push_back(Pending {
+            sequence: self.next_sequence,
+            message,
+            last_sent: None,
+        });
+        self.next_sequence += 1;
+        true
+    }
+
+    /// The messages to send now along with their numbers: the ones never
+    /// sent and the ones that have gone unacknowledged for too long.
+    pub fn due(&mut self, now: Instant) -> Vec<(u32, T)> {
+        let mut due = Vec::new();
+        for pending in self.unacked.iter_mut() {
+            let resend = match pending.last_sent {
+                Some(last_sent) => now.duration_since(last_sent) >= RESEND_INTERVAL,
+                None => true,
+            };
+            if resend {
+                pending.last_sent = Some(now);
+                due.push((pending.sequence, pending.message.clone()));
+            }
+        }
+        due
+    }
+
+    /// The other end has taken the first `received` messages.
+    pub fn acknowledge(&mut self, received: u32) {
+        while let Some(pending) = self.unacked.front() {
+            if pending.sequence >= received {
+                break;
+            }
+            self.unacked.pop_front();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unacked.is_empty()
+    }
+}
+
+impl<T: Clone> Default for ReliableSender<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The receiving half, which takes messages in the order they were sent.
+#[derive(Default)]
+pub struct ReliableReceiver {
+    received: u32,
+}
+
+impl ReliableReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the message numbered `sequence` is the next one and should
+    /// be used. Ones seen already and ones that skipped ahead of a lost
+    /// message are dropped, the sender will get to them again.
+    pub fn receive(&mut self, sequence: u32) -> bool {
+        if sequence == self.received {
+            self.received += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How many messages have been taken, to acknowledge to the sender
+    /// after each one arrives.
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_messages_are_resent_until_acknowledged() {
+    let start = Instant::now();
+    let mut sender = ReliableSender::new();
+    sender.push("a");
+    sender.push("b");
+
+    assert_eq!(sender.due(start), vec![(0, "a"), (1, "b")]);
+    assert_eq!(sender.due(start), vec![]);
+    sender.push("c");
+    assert_eq!(sender.due(start), vec![(2, "c")]);
+
+    // Only the first arrived
+    sender.acknowledge(1);
+    let later = start + RESEND_INTERVAL;
+    assert_eq!(sender.due(later), vec![(1, "b"), (2, "c")]);
+
+    sender.acknowledge(3);
+    assert!(sender.is_empty());
+    assert_eq!(sender.due(later + RESEND_INTERVAL), vec![]);
+}
+
+#[test]
+fn test_receiver_takes_messages_in_order_once() {
+    let mut receiver = ReliableReceiver::new();
+
+    assert!(receiver.receive(0));
+    assert!(!receiver.receive(0));
+    // 1 was lost, so 2 waits for it to be resent
+    assert!(!receiver.receive(2));
+    assert_eq!(receiver.received(), 1);
+    assert!(receiver.receive(1));
+    assert!(receiver.receive(2));
+    assert_eq!(receiver.received(), 3);
+}
+
+#[test]
+fn test_sender_refuses_messages_past_the_limit() {
+    let mut sender = ReliableSender::new();
+    for n in 0..MAX_UNACKED {
+        assert!(sender.push(n));
+    }
+    assert!(!sender.push(MAX_UNACKED));
+
+    sender.acknowledge(1);
+    assert!(sender.push(MAX_UNACKED));
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::net::{ClientMessage, Result, ServerMessage, Socket};
+
+/// How long a peer stays registered without hearing from them again.
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Introduces two peers to each other so they can play without a server.
+///
+/// Both peers register with the same session name. Once the second one shows
+/// up, each is sent the public address the other one registered from. Because
+/// the peers have already sent a packet out through their NAT to get here,
+/// they can usually reach each other directly at those addresses (UDP hole
+/// punching). The introducer takes no further part in the match.
+pub struct Rendezvous {
+    socket: Socket,
+    // Peers waiting for someone to join their session
+    waiting: HashMap<String, (SocketAddr, Instant)>,
+    // Peers that have already been introduced, in case the introduction
+    // is lost and they register again
+    introduced: HashMap<SocketAddr, (SocketAddr, u32, Instant)>,
+}
+
+impl Rendezvous {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(Self {
+            socket: Socket::bind(addr)?,
+            waiting: HashMap::new(),
+            introduced: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Handles every registration waiting on the socket.
+    pub fn poll(&mut self) -> Result<()> {
+        self.expire_registrations();
+
+        loop {
+            match self.socket.receive::<ClientMessage>() {
+                Ok(Some((source_addr, ClientMessage::Rendezvous { session }))) => {
+                    self.register(source_addr, session)?;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(()),
+                Err(e) => error!("rendezvous received bad packet: {}", e),
+            }
+        }
+    }
+
+    fn register(&mut self, addr: SocketAddr, session: String) -> Result<()> {
+        if let Some((peer_addr, player_id, _)) = self.introduced.get(&addr) {
+            let message = ServerMessage::Introduction {
+                peer_addr: *peer_addr,
+                player_id: *player_id,
+            };
+            return self.socket.send(addr, message);
+        }
+
+        match self.waiting.remove(&session) {
+            Some((host_addr, _)) if host_addr != addr => {
+                debug!("introducing {} and {} for {}", host_addr, addr, session);
+
+                let now = Instant::now();
+                self.introduced.insert(host_addr, (addr, 0, now));
+                self.introduced.insert(addr, (host_addr, 1, now));
+
+                self.socket.send(
+                    host_addr,
+                    ServerMessage::Introduction {
+                        peer_addr: addr,
+                        player_id: 0,
+                    },
+                )?;
+                self.socket.send(
+                    addr,
+                    ServerMessage::Introduction {
+                        peer_addr: host_addr,
+                        player_id: 1,
+                    },
+                )
+            }
+            _ => {
+                self.waiting.insert(session, (addr, Instant::now()));
+                Ok(())
+            }
+        }
+    }
+
+    fn expire_registrations(&mut self) {
+        self.waiting
+            .retain(|_, (_, registered_at)| registered_at.elapsed() < REGISTRATION_TIMEOUT);
+        self.introduced
+            .retain(|_, (_, _, introduced_at)| introduced_at.elapsed() < REGISTRATION_TIMEOUT);
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn receive_within(
+    socket: &mut Socket,
+    timeout: Duration,
+) -> Option<(SocketAddr, ServerMessage<'static>)> {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if let Ok(Some(received)) = socket.receive::<ServerMessage>() {
+            return Some(received);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    None
+}
+
+#[test]
+fn test_rendezvous_introduces_peers_over_loopback() {
+    let mut rendezvous = Rendezvous::bind("127.0.0.1:0").unwrap();
+    let rendezvous_addr = rendezvous.local_addr().unwrap();
+
+    let mut host = Socket::bind("127.0.0.1:0").unwrap();
+    let mut guest = Socket::bind("127.0.0.1:0").unwrap();
+    let host_addr = host.local_addr().unwrap();
+    let guest_addr = guest.local_addr().unwrap();
+
+    let register = ClientMessage::Rendezvous {
+        session: String::from("test"),
+    };
+    host.send(rendezvous_addr, register.clone()).unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    rendezvous.poll().unwrap();
+    guest.send(rendezvous_addr, register).unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    rendezvous.poll().unwrap();
+
+    match receive_within(&mut host, Duration::from_secs(1)) {
+        Some((
+            _,
+            ServerMessage::Introduction {
+                peer_addr,
+                player_id,
+            },
+        )) => {
+            assert_eq!(peer_addr, guest_addr);
+            assert_eq!(player_id, 0);
+        }
+        other => panic!("host expected an introduction, got {:?}", other),
+    }
+
+    match receive_within(&mut guest, Duration::from_secs(1)) {
+        Some((
+            _,
+            ServerMessage::Introduction {
+                peer_addr,
+                player_id,
+            },
+        )) => {
+            assert_eq!(peer_addr, host_addr);
+            assert_eq!(player_id, 1);
+        }
+        other => panic!("guest expected an introduction, got {:?}", other),
+    }
+
+    // The peers can now talk to each other without the introducer
+    let hello = ServerMessage::PeerHello(crate::profile::PlayerInfo {
+        name: String::from("guest"),
+        team: None,
+    });
+    guest.send(host_addr, hello).unwrap();
+    match receive_within(&mut host, Duration::from_secs(1)) {
+        Some((source_addr, ServerMessage::PeerHello(_))) => assert_eq!(source_addr, guest_addr),
+        other => panic!("host expected hello from guest, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_rendezvous_keeps_sessions_separate() {
+    let mut rendezvous = Rendezvous::bind("127.0.0.1:0").unwrap();
+    let rendezvous_addr = rendezvous.local_addr().unwrap();
+
+    let mut first = Socket::bind("127.0.0.1:0").unwrap();
+    let mut second = Socket::bind("127.0.0.1:0").unwrap();
+
+    for (socket, session) in [(&mut first, "one"), (&mut second, "two")].iter_mut() {
+        let register = ClientMessage::Rendezvous {
+            session: String::from(*session),
+        };
+        socket.send(rendezvous_addr, register).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(10));
+    rendezvous.poll().unwrap();
+
+    assert!(receive_within(&mut first, Duration::from_millis(50)).is_none());
+    assert!(receive_within(&mut second, Duration::from_millis(50)).is_none());
+}
@@ -0,0 +1,450 @@
+//! Recording matches so they can be watched again.
+//!
+//! Grids are deterministic given their seed and inputs, so a replay only has
+//! to hold the settings the match started with and every input, tagged with
+//! the tick it was applied on. Playing it back runs the same simulation
+//! again.
+
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::grid::{Grid, GridInputEvent, MAX_WIRE_CELLS};
+use crate::royale::{self, Targeting};
+use crate::simulation::{self, Rules};
+use crate::wire::{Reader, Writer};
+
+const MAGIC: &[u8; 4] = b"BWRP";
+/// Bumped whenever the file layout or the simulation changes, since an old
+/// replay would play out differently.
+const FORMAT_VERSION: u8 = 3;
+/// How often the player keeps a copy of the grids, so seeking backwards
+/// doesn't have to start from the beginning.
+const KEYFRAME_INTERVAL: u32 = 600;
+
+lazy_static! {
+    /// Where finished matches are saved, if anywhere. Set once at startup
+    /// like the sound setting, so the scenes don't all have to pass it on.
+    static ref REPLAY_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+pub fn set_replay_dir(dir: Option<PathBuf>) {
+    *REPLAY_DIR.lock().unwrap() = dir;
+}
+
+/// Saves the replay into the replay directory under a name made from the
+/// current time, if a directory was set. Returns where it was saved.
+pub fn save_to_replay_dir(replay: &Replay) -> io::Result<Option<PathBuf>> {
+    let dir = match *REPLAY_DIR.lock().unwrap() {
+        Some(ref dir) => dir.clone(),
+        None => return Ok(None),
+    };
+
+    fs::create_dir_all(&dir)?;
+    let since_the_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let path = dir.join(format!("match-{}.replay", since_the_epoch.as_millis()));
+    replay.save(&path)?;
+    Ok(Some(path))
+}
+
+/// What a match started with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplaySettings {
+    pub num_players: u32,
+    pub seed: u64,
+    pub grid_height: u32,
+    pub grid_width: u32,
+    /// Ticks simulated each second, to turn ticks back into time.
+    pub tick_rate: u32,
+    pub rules: Rules,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replay {
+    settings: ReplaySettings,
+    // Ticks recorded so far
+    length: u32,
+    // Tick, player id and input, in the order they were applied
+    inputs: Vec<(u32, u32, GridInputEvent)>,
+    // Hash of every grid after the last tick, for checking a replay plays
+    // out the way it was recorded
+    final_hash: Option<u64>,
+}
+
+impl Replay {
+    pub fn new(settings: ReplaySettings) -> Self {
+        Self {
+            settings,
+            length: 0,
+            inputs: Vec::new(),
+            final_hash: None,
+        }
+    }
+
+    pub fn settings(&self) -> &ReplaySettings {
+        &self.settings
+    }
+
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    pub fn final_hash(&self) -> Option<u64> {
+        self.final_hash
+    }
+
+    /// Records the state the grids ended up in, as of the last tick pushed.
+    pub fn finish(&mut self, grids: &[Grid]) {
+        self.final_hash = Some(simulation::state_hash(grids));
+    }
+
+    /// Records the inputs applied on the next tick, which may be none.
+    pub fn push_tick(&mut self, inputs: &[(u32, GridInputEvent)]) {
+        for (player_id, event) in inputs.iter() {
+            self.inputs.push((self.length, *player_id, *event));
+        }
+        self.length += 1;
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Self::from_bytes(&bytes)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "not a replay we can play"))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    /// Inputs are stored as the number of ticks since the previous one, so
+    /// a match where nobody presses anything for a while costs nothing.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        for byte in MAGIC.iter() {
+            writer.u8(*byte);
+        }
+        writer.u8(FORMAT_VERSION);
+        writer.varint(u64::from(self.settings.num_players));
+        writer.u64(self.settings.seed);
+        writer.varint(u64::from(self.settings.grid_height));
+        writer.varint(u64::from(self.settings.grid_width));
+        writer.varint(u64::from(self.settings.tick_rate));
+        self.settings.rules.write(&mut writer);
+        writer.varint(u64::from(self.length));
+        writer.varint(self.inputs.len() as u64);
+
+        let mut previous_tick = 0;
+        for (tick, player_id, event) in self.inputs.iter() {
+            writer.varint(u64::from(tick - previous_tick));
+            writer.varint(u64::from(*player_id));
+            writer.u8(event_to_wire(*event));
+            previous_tick = *tick;
+        }
+
+        match self.final_hash {
+            Some(hash) => {
+                writer.u8(1);
+                writer.u64(hash);
+            }
+            None => writer.u8(0),
+        }
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        for byte in MAGIC.iter() {
+            if reader.u8()? != *byte {
+                return None;
+            }
+        }
+        if reader.u8()? != FORMAT_VERSION {
+            return None;
+        }
+
+        let settings = ReplaySettings {
+            num_players: reader.varint_u32()?,
+            seed: reader.u64()?,
+            grid_height: reader.varint_u32()?,
+            grid_width: reader.varint_u32()?,
+            tick_rate: reader.varint_u32()?,
+            rules: Rules::read(&mut reader)?,
+        };
+        // Refuse replays claiming more players than could ever fit on screen
+        if settings.num_players == 0 || settings.num_players > royale::MAX_PLAYERS {
+            return None;
+        }
+        // Every player has to be on a team in a team match
+        if let Rules::Teams(ref teams) = settings.rules {
+            if teams.len() != settings.num_players as usize {
+                return None;
+            }
+        }
+        // Grids refuse to be built smaller than this, and a bogus size
+        // mustn't make us allocate a huge number of cells
+        let cells = u64::from(settings.grid_height) * u64::from(settings.grid_width);
+        if settings.grid_width < 4
+            || settings.grid_height < 4
+            || cells > MAX_WIRE_CELLS
+            || settings.tick_rate == 0
+        {
+            return None;
+        }
+        let length = reader.varint_u32()?;
+        let count = reader.varint()?;
+
+        let mut inputs = Vec::new();
+        let mut tick = 0u32;
+        for _ in 0..count {
+            tick = tick.checked_add(reader.varint_u32()?)?;
+            let player_id = reader.varint_u32()?;
+            let event = event_from_wire(reader.u8()?)?;
+            if tick >= length || player_id >= settings.num_players {
+                return None;
+            }
+            inputs.push((tick, player_id, event));
+        }
+
+        let final_hash = match reader.u8()? {
+            0 => None,
+            1 => Some(reader.u64()?),
+            _ => return None,
+        };
+
+        if !reader.is_empty() {
+            return None;
+        }
+        Some(Self {
+            settings,
+            length,
+            inputs,
+            final_hash,
+        })
+    }
+}
+
+fn event_to_wire(event: GridInputEvent) -> u8 {
+    match event {
+        GridInputEvent::MoveLeft => 0,
+        GridInputEvent::MoveRight => 1,
+        GridInputEvent::MoveDown => 2,
+        GridInputEvent::ForceToBottom => 3,
+        GridInputEvent::Rotate => 4,
+        GridInputEvent::Target(targeting) => 5 + targeting.to_wire(),
+    }
+}
+
+fn event_from_wire(code: u8) -> Option<GridInputEvent> {
+    match code {
+        0 => Some(GridInputEvent::MoveLeft),
+        1 => Some(GridInputEvent::MoveRight),
+        2 => Some(GridInputEvent::MoveDown),
+        3 => Some(GridInputEvent::ForceToBottom),
+        4 => Some(GridInputEvent::Rotate),
+        5..=8 => Some(GridInputEvent::Target(Targeting::from_wire(code - 5)?)),
+        _ => None,
+    }
+}
+
+/// Plays a replay back by simulating it again, one tick at a time.
+pub struct ReplayPlayer {
+    replay: Replay,
+    grids: Vec<Grid>,
+    // Ticks simulated so far
+    tick: u32,
+    // Index of the next input to apply
+    next_input: usize,
+    // Grids and next input as they were every `KEYFRAME_INTERVAL` ticks
+    keyframes: Vec<(Vec<Grid>, usize)>,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        let grids = new_grids(&replay.settings);
+        Self {
+            keyframes: vec![(grids.clone(), 0)],
+            replay,
+            grids,
+            tick: 0,
+            next_input: 0,
+        }
+    }
+
+    pub fn grids(&self) -> &[Grid] {
+        &self.grids
+    }
+
+    pub fn grids_mut(&mut self) -> &mut [Grid] {
+        &mut self.grids
+    }
+
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    pub fn length(&self) -> u32 {
+        self.replay.length
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    pub fn finished(&self) -> bool {
+        self.tick >= self.replay.length
+    }
+
+    /// Simulates the next tick, unless the replay is over.
+    pub fn step(&mut self) {
+        if self.finished() {
+            return;
+        }
+
+        let mut inputs = Vec::new();
+        while let Some((tick, player_id, event)) = self.replay.inputs.get(self.next_input) {
+            if *tick != self.tick {
+                break;
+            }
+            inputs.push((*player_id, *event));
+            self.next_input += 1;
+        }
+        simulation::step(&mut self.grids, &inputs, &self.replay.settings.rules);
+        self.tick += 1;
+
+        let keyframe = (self.tick / KEYFRAME_INTERVAL) as usize;
+        if self.tick.is_multiple_of(KEYFRAME_INTERVAL) && keyframe == self.keyframes.len() {
+            self.keyframes.push((self.grids.clone(), self.next_input));
+        }
+    }
+
+    /// Jumps to just after `tick` ticks have been simulated, starting from
+    /// the closest keyframe before it when going backwards.
+    pub fn seek(&mut self, tick: u32) {
+        let tick = tick.min(self.replay.length);
+        if tick < self.tick {
+            let keyframe = ((tick / KEYFRAME_INTERVAL) as usize).min(self.keyframes.len() - 1);
+            let (grids, next_input) = &self.keyframes[keyframe];
+            self.grids = grids.clone();
+            self.next_input = *next_input;
+            self.tick = keyframe as u32 * KEYFRAME_INTERVAL;
+        }
+
+        while self.tick < tick {
+            self.step();
+        }
+        // Sounds from the ticks skipped over shouldn't all play at once
+        for grid in self.grids.iter_mut() {
+            grid.sound_events.clear();
+        }
+    }
+}
+
+fn new_grids(settings: &ReplaySettings) -> Vec<Grid> {
+    simulation::new_grids(
+        settings.grid_height,
+        settings.grid_width,
+        settings.num_players,
+        settings.seed,
+    )
+}
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn recorded_match(ticks: u32) -> (Replay, Vec<Grid>) {
+    let settings = ReplaySettings {
+        num_players: 2,
+        seed: 99,
+        grid_height: 20,
+        grid_width: 10,
+        tick_rate: 60,
+        rules: Rules::Versus,
+    };
+    let mut grids = new_grids(&settings);
+    let mut replay = Replay::new(settings.clone());
+
+    for tick in 0..ticks {
+        let mut inputs = Vec::new();
+        if tick % 7 == 0 {
+            inputs.push((0, GridInputEvent::Rotate));
+        }
+        if tick == 3 {
+            inputs.push((1, GridInputEvent::Target(Targeting::KOs)));
+        }
+        if tick % 11 == 0 {
+            inputs.push((1, GridInputEvent::MoveLeft));
+            inputs.push((1, GridInputEvent::ForceToBottom));
+        }
+        replay.push_tick(&inputs);
+        simulation::step(&mut grids, &inputs, &settings.rules);
+    }
+    replay.finish(&grids);
+    (replay, grids)
+}
+
+#[test]
+fn test_replay_roundtrips_through_bytes() {
+    let (replay, _) = recorded_match(500);
+    let bytes = replay.to_bytes();
+    assert_eq!(Replay::from_bytes(&bytes), Some(replay.clone()));
+
+    // Anything cut short or with extra bytes is refused
+    assert_eq!(Replay::from_bytes(&bytes[..bytes.len() - 1]), None);
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert_eq!(Replay::from_bytes(&longer), None);
+    assert_eq!(Replay::from_bytes(b"BWRP"), None);
+
+    let mut huge = replay;
+    huge.settings.grid_height = u32::MAX;
+    huge.settings.grid_width = u32::MAX;
+    assert_eq!(Replay::from_bytes(&huge.to_bytes()), None);
+
+    let (mut teams, _) = recorded_match(500);
+    teams.settings.rules = Rules::Teams(vec![1, 0]);
+    assert_eq!(Replay::from_bytes(&teams.to_bytes()), Some(teams.clone()));
+    teams.settings.rules = Rules::Teams(vec![1, 0, 0]);
+    assert_eq!(Replay::from_bytes(&teams.to_bytes()), None);
+}
+
+#[test]
+fn test_replay_plays_back_the_same_match() {
+    let (replay, grids) = recorded_match(1500);
+    let mut player = ReplayPlayer::new(replay);
+    while !player.finished() {
+        player.step();
+    }
+    assert_eq!(player.tick(), 1500);
+    assert_eq!(
+        simulation::state_hash(player.grids()),
+        simulation::state_hash(&grids)
+    );
+    assert_eq!(
+        player.replay().final_hash(),
+        Some(simulation::state_hash(&grids))
+    );
+}
+
+#[test]
+fn test_replay_seeks_both_ways() {
+    let (replay, _) = recorded_match(1500);
+    let (_, grids_at_700) = recorded_match(700);
+    let mut player = ReplayPlayer::new(replay);
+
+    player.seek(1400);
+    player.seek(700);
+    assert_eq!(player.tick(), 700);
+    assert_eq!(
+        simulation::state_hash(player.grids()),
+        simulation::state_hash(&grids_at_700)
+    );
+
+    player.seek(5000);
+    assert!(player.finished());
+}
@@ -0,0 +1,359 @@
+//! Working out who won a match and how everyone played.
+//!
+//! Players are placed by how long they lasted: whoever is left standing
+//! wins, and the rest are placed in the reverse of the order they topped
+//! out in. Players who top out on the same tick share a place. In a team
+//! match the same goes for whole teams, with a team lasting until the last
+//! of its players tops out.
+
+use serde::{Deserialize, Serialize};
+
+use std::time::Duration;
+
+use crate::grid::Grid;
+use crate::simulation::Rules;
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PlayerResult {
+    pub player_id: u32,
+    pub team: Option<u32>,
+    /// 1 for the winner.
+    pub placement: u32,
+    pub score: u32,
+    pub lines: u32,
+    pub pieces: u32,
+    /// Rows of garbage sent to each opponent.
+    pub attack_sent: u32,
+    pub attack_received: u32,
+    /// Players knocked out in a battle royale.
+    pub kos: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MatchResult {
+    pub duration: Duration,
+    /// Best placed first.
+    pub players: Vec<PlayerResult>,
+}
+
+impl MatchResult {
+    /// The only player in first place, if there's one. Nobody wins a draw.
+    pub fn winner(&self) -> Option<u32> {
+        match self.players.as_slice() {
+            [first, second, ..] if first.placement == second.placement => None,
+            [first, ..] if first.placement == 1 => Some(first.player_id),
+            _ => None,
+        }
+    }
+
+    /// The team in first place, if the match was played in teams and one
+    /// of them outlasted the others.
+    pub fn winning_team(&self) -> Option<u32> {
+        let mut first = self.players.iter().filter(|player| player.placement == 1);
+        let team = first.next()?.team?;
+        if first.all(|player| player.team == Some(team)) {
+            Some(team)
+        } else {
+            None
+        }
+    }
+
+    pub fn player(&self, player_id: u32) -> Option<&PlayerResult> {
+        self.players
+            .iter()
+            .find(|player| player.player_id == player_id)
+    }
+}
+
+/// Where each player stands on playing the same match again.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum RematchVote {
+    Undecided,
+    Rematch,
+    /// Went back to the lobby or left the server, so there's no rematch.
+    Left,
+}
+
+/// What the players of a lockstep match say about when it ended. Only
+/// the clients simulate a lockstep match, so the server takes it as over
+/// once everyone still playing agrees on the tick it ended on.
+#[derive(Clone, Debug)]
+pub struct EndReports {
+    ended_on: Vec<Option<u64>>,
+}
+
+impl EndReports {
+    pub fn new(num_players: usize) -> Self {
+        Self {
+            ended_on: vec![None; num_players],
+        }
+    }
+
+    /// Notes the tick a player says the match ended on.
+    pub fn report(&mut self, player_id: u32, tick: u64) {
+        if let Some(ended_on) = self.ended_on.get_mut(player_id as usize) {
+            *ended_on = Some(tick);
+        }
+    }
+
+    /// Whether every player still `playing` has said the match ended, and
+    /// on the same tick.
+    pub fn agreed(&self, playing: &[bool]) -> bool {
+        let mut reports = self
+            .ended_on
+            .iter()
+            .zip(playing)
+            .filter(|(_, playing)| **playing)
+            .map(|(ended_on, _)| *ended_on);
+        match reports.next() {
+            Some(Some(tick)) => reports.all(|ended_on| ended_on == Some(tick)),
+            _ => false,
+        }
+    }
+}
+
+/// Follows a match tick by tick to see who tops out when.
+#[derive(Clone, Debug)]
+pub struct Placements {
+    num_players: usize,
+    rules: Rules,
+    ticks: u64,
+    // Tick each player topped out on, in the order they did
+    topped_out: Vec<(u64, u32)>,
+}
+
+impl Placements {
+    pub fn new(num_players: usize, rules: Rules) -> Self {
+        Self {
+            num_players,
+            rules,
+            ticks: 0,
+            topped_out: Vec::new(),
+        }
+    }
+
+    /// Notes who has topped out since the last tick. Should be called after
+    /// every tick simulated, and returns whether the match is over.
+    pub fn update(&mut self, grids: &[Grid]) -> bool {
+        if self.is_over() {
+            return true;
+        }
+
+        self.ticks += 1;
+        for (player_id, grid) in grids.iter().enumerate() {
+            let player_id = player_id as u32;
+            if grid.gameover && !self.has_topped_out(player_id) {
+                self.topped_out.push((self.ticks, player_id));
+            }
+        }
+        self.is_over()
+    }
+
+    /// A match is over once one player or team is left, or once everyone
+    /// has topped out when there was nobody to play against.
+    pub fn is_over(&self) -> bool {
+        let sides = self.sides().len();
+        let standing = self.sides_standing().len();
+        if sides > 1 {
+            standing <= 1
+        } else {
+            standing == 0
+        }
+    }
+
+    /// Ticks the match went on for, or has so far.
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Everyone's placement and stats, with the match having gone on for
+    /// as many ticks as `update` was called at `tick_rate`.
+    pub fn result(&self, grids: &[Grid], tick_rate: u32) -> MatchResult {
+        let mut players: Vec<PlayerResult> = grids
+            .iter()
+            .enumerate()
+            .map(|(player_id, grid)| {
+                let player_id = player_id as u32;
+                let stats = grid.stats();
+                PlayerResult {
+                    player_id,
+                    team: self.rules.team(player_id),
+                    placement: self.placement(player_id),
+                    score: grid.score(),
+                    lines: stats
+                        .lines_cleared
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, count)| (idx as u32 + 1) * count)
+                        .sum(),
+                    pieces: stats.pieces_placed,
+                    attack_sent: stats.attack_sent,
+                    attack_received: stats.attack_received,
+                    kos: grid.battle.kos,
+                }
+            })
+            .collect();
+        players.sort_by_key(|player| (player.placement, player.player_id));
+
+        MatchResult {
+            duration: Duration::from_secs(self.ticks) / tick_rate,
+            players,
+        }
+    }
+
+    fn has_topped_out(&self, player_id: u32) -> bool {
+        self.topped_out.iter().any(|(_, id)| *id == player_id)
+    }
+
+    // The team a player is on, or just the player when there are no teams
+    fn side(&self, player_id: u32) -> u32 {
+        self.rules.team(player_id).unwrap_or(player_id)
+    }
+
+    fn sides(&self) -> Vec<u32> {
+        let mut sides: Vec<u32> = (0..self.num_players as u32)
+            .map(|player_id| self.side(player_id))
+            .collect();
+        sides.sort_unstable();
+        sides.dedup();
+        sides
+    }
+
+    fn sides_standing(&self) -> Vec<u32> {
+        self.sides()
+            .into_iter()
+            .filter(|side| self.side_out_on(*side).is_none())
+            .collect()
+    }
+
+    // Tick the last player on a side topped out on, if they all have
+    fn side_out_on(&self, side: u32) -> Option<u64> {
+        let mut out_on = 0;
+        for player_id in 0..self.num_players as u32 {
+            if self.side(player_id) != side {
+                continue;
+            }
+            let tick = self
+                .topped_out
+                .iter()
+                .find(|(_, id)| *id == player_id)
+                .map(|(tick, _)| *tick)?;
+            out_on = out_on.max(tick);
+        }
+        Some(out_on)
+    }
+
+    // One more than the sides that outlasted this player's
+    fn placement(&self, player_id: u32) -> u32 {
+        let outlasted_by = match self.side_out_on(self.side(player_id)) {
+            Some(tick) => self
+                .sides()
+                .into_iter()
+                .filter(|side| match self.side_out_on(*side) {
+                    Some(other_tick) => other_tick > tick,
+                    None => true,
+                })
+                .count(),
+            None => 0,
+        };
+        outlasted_by as u32 + 1
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_players_are_placed_by_how_long_they_lasted() {
+    use crate::simulation;
+
+    let mut grids = simulation::new_grids(20, 10, 4, 1);
+    let mut placements = Placements::new(grids.len(), Rules::Versus);
+
+    assert!(!placements.update(&grids));
+    grids[2].gameover = true;
+    assert!(!placements.update(&grids));
+    grids[0].gameover = true;
+    grids[3].gameover = true;
+    assert!(placements.update(&grids));
+
+    let result = placements.result(&grids, 2);
+    assert_eq!(result.duration, Duration::from_millis(1500));
+    let order: Vec<(u32, u32)> = result
+        .players
+        .iter()
+        .map(|player| (player.player_id, player.placement))
+        .collect();
+    assert_eq!(order, vec![(1, 1), (0, 2), (3, 2), (2, 4)]);
+    assert_eq!(result.winner(), Some(1));
+}
+
+#[test]
+fn test_nobody_wins_when_the_last_players_top_out_together() {
+    use crate::simulation;
+
+    let mut grids = simulation::new_grids(20, 10, 2, 1);
+    let mut placements = Placements::new(grids.len(), Rules::Versus);
+    for grid in grids.iter_mut() {
+        grid.gameover = true;
+    }
+    assert!(placements.update(&grids));
+
+    let result = placements.result(&grids, 60);
+    assert!(result.players.iter().all(|player| player.placement == 1));
+    assert_eq!(result.winner(), None);
+}
+
+#[test]
+fn test_lockstep_votes_wait_for_everyone_to_report_the_end() {
+    let mut reports = EndReports::new(3);
+    let playing = [true, true, false];
+
+    // One player claiming the match is over mid-match isn't enough
+    assert!(!reports.agreed(&playing));
+    reports.report(0, 900);
+    assert!(!reports.agreed(&playing));
+    reports.report(1, 901);
+    assert!(!reports.agreed(&playing));
+
+    // Players who left don't hold anyone up
+    reports.report(1, 900);
+    assert!(reports.agreed(&playing));
+    assert!(!reports.agreed(&[false, false, false]));
+}
+
+#[test]
+fn test_teams_last_until_their_last_player_tops_out() {
+    use crate::simulation;
+
+    // Chosen in the lobby, rather than dealt out in turn
+    let rules = Rules::teams(2, &[Some(0), Some(1), Some(1), Some(0)]);
+    let mut grids = simulation::new_grids(20, 10, 4, 1);
+    let mut placements = Placements::new(grids.len(), rules);
+
+    // Players 0 and 3 are on one team, 1 and 2 on the other
+    grids[0].gameover = true;
+    grids[1].gameover = true;
+    assert!(!placements.update(&grids));
+    grids[2].gameover = true;
+    assert!(placements.update(&grids));
+
+    let result = placements.result(&grids, 60);
+    let order: Vec<(u32, Option<u32>, u32)> = result
+        .players
+        .iter()
+        .map(|player| (player.player_id, player.team, player.placement))
+        .collect();
+    assert_eq!(
+        order,
+        vec![
+            (0, Some(0), 1),
+            (3, Some(0), 1),
+            (1, Some(1), 2),
+            (2, Some(1), 2)
+        ]
+    );
+    assert_eq!(result.winner(), None);
+    assert_eq!(result.winning_team(), Some(0));
+}
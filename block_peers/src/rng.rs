@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+/// Small deterministic pseudo random number generator (xorshift64*).
+///
+/// Anything that has to be reproduced exactly on another machine, such as the
+/// sequence of pieces generated for a grid, should draw from this instead of
+/// `rand::thread_rng`. Two generators created with the same seed will always
+/// produce the same sequence, and the state is serializable so it travels with
+/// the grid.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero, so mix the seed and make sure at least
+        // one bit is set.
+        let state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        Self { state }
+    }
+
+    /// Creates a generator seeded from the operating system's randomness.
+    pub fn from_entropy() -> Self {
+        Self::new(rand::random())
+    }
+
+    /// The generator's internal state, for encodings that want to store it
+    /// more compactly than serde would.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    /// Restores a generator from `state()`. Zero is never a valid state.
+    pub fn from_state(state: u64) -> Option<Self> {
+        if state == 0 {
+            return None;
+        }
+        Some(Self { state })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns a number in the half-open range `[low, high)`.
+    pub fn gen_range(&mut self, low: usize, high: usize) -> usize {
+        assert!(low < high, "empty range given to gen_range");
+        low + (self.next_u64() % (high - low) as u64) as usize
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_same_seed_same_sequence() {
+    let mut a = Rng::new(42);
+    let mut b = Rng::new(42);
+
+    for _ in 0..100 {
+        assert_eq!(a.next_u64(), b.next_u64());
+    }
+}
+
+#[test]
+fn test_different_seed_different_sequence() {
+    let mut a = Rng::new(1);
+    let mut b = Rng::new(2);
+
+    let a_values: Vec<u64> = (0..10).map(|_| a.next_u64()).collect();
+    let b_values: Vec<u64> = (0..10).map(|_| b.next_u64()).collect();
+    assert_ne!(a_values, b_values);
+}
+
+#[test]
+fn test_gen_range_in_bounds() {
+    let mut rng = Rng::new(0);
+
+    for _ in 0..1000 {
+        let value = rng.gen_range(3, 7);
+        assert!((3..7).contains(&value));
+    }
+}
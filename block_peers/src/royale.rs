@@ -0,0 +1,336 @@
+//! Battle royale, where a big lobby plays until one player is left.
+//!
+//! Instead of hitting every opponent, each attack goes to whoever the player
+//! is targeting. Topping out a player earns whoever attacked them last a KO
+//! along with the badge points they had, and badges make attacks hit harder.
+//! Everything here runs inside the simulation, so it has to be deterministic.
+
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Reverse;
+
+use crate::grid::{fnv1a_hash, Grid, GridAttackEvent};
+use crate::wire::{Reader, Writer};
+
+/// Most players a battle royale can be played with.
+pub const MAX_PLAYERS: u32 = 32;
+/// Badge points needed for each badge. A KO is worth one point plus the
+/// points of the player knocked out.
+const BADGE_THRESHOLDS: [u32; 4] = [2, 6, 14, 30];
+/// Extra garbage each badge adds to an attack, in percent.
+const BADGE_BONUS: u32 = 25;
+
+/// How a player picks who their attacks go to.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Targeting {
+    /// Anyone still standing, until they're knocked out.
+    Random,
+    /// Whoever is closest to topping out.
+    KOs,
+    /// Whoever has the most badge points.
+    Badges,
+    /// Everyone targeting us, or someone at random if nobody is.
+    Attackers,
+}
+
+impl Targeting {
+    pub fn name(self) -> &'static str {
+        match self {
+            Targeting::Random => "Random",
+            Targeting::KOs => "KOs",
+            Targeting::Badges => "Badges",
+            Targeting::Attackers => "Attackers",
+        }
+    }
+
+    pub fn to_wire(self) -> u8 {
+        match self {
+            Targeting::Random => 0,
+            Targeting::KOs => 1,
+            Targeting::Badges => 2,
+            Targeting::Attackers => 3,
+        }
+    }
+
+    pub fn from_wire(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Targeting::Random),
+            1 => Some(Targeting::KOs),
+            2 => Some(Targeting::Badges),
+            3 => Some(Targeting::Attackers),
+            _ => None,
+        }
+    }
+}
+
+/// Where a player stands in a battle royale. Kept with their grid so it's
+/// synced and hashed along with it.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct BattleStatus {
+    pub targeting: Targeting,
+    /// Who the next attack goes to, if anyone is left to attack.
+    pub target: Option<u32>,
+    pub kos: u32,
+    pub badge_points: u32,
+    /// Gets the KO if we top out.
+    pub last_attacker: Option<u32>,
+    /// Targets picked at random so far, so each pick is a new one.
+    pub picks: u32,
+}
+
+impl BattleStatus {
+    pub fn new() -> Self {
+        Self {
+            targeting: Targeting::Random,
+            target: None,
+            kos: 0,
+            badge_points: 0,
+            last_attacker: None,
+            picks: 0,
+        }
+    }
+
+    pub fn badges(&self) -> u32 {
+        BADGE_THRESHOLDS
+            .iter()
+            .filter(|threshold| self.badge_points >= **threshold)
+            .count() as u32
+    }
+
+    /// Extra garbage our badges add to each attack, in percent.
+    pub fn bonus(&self) -> u32 {
+        BADGE_BONUS * self.badges()
+    }
+
+    /// How many rows of garbage an attack of `rows` becomes with our badges.
+    pub fn boost(&self, rows: u32) -> u32 {
+        rows * (100 + self.bonus()) / 100
+    }
+}
+
+// -----------
+// Wire Format
+// -----------
+//
+// The targeting takes a byte, and everything else usually takes a byte
+// each. Players are written one higher so zero can stand for nobody.
+impl BattleStatus {
+    pub fn write(&self, writer: &mut Writer) {
+        writer.u8(self.targeting.to_wire());
+        write_player(writer, self.target);
+        writer.varint(self.kos.into());
+        writer.varint(self.badge_points.into());
+        write_player(writer, self.last_attacker);
+        writer.varint(self.picks.into());
+    }
+
+    pub fn read(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            targeting: Targeting::from_wire(reader.u8()?)?,
+            target: read_player(reader)?,
+            kos: reader.varint_u32()?,
+            badge_points: reader.varint_u32()?,
+            last_attacker: read_player(reader)?,
+            picks: reader.varint_u32()?,
+        })
+    }
+}
+
+fn write_player(writer: &mut Writer, player_id: Option<u32>) {
+    match player_id {
+        Some(player_id) => writer.varint(u64::from(player_id) + 1),
+        None => writer.varint(0),
+    }
+}
+
+fn read_player(reader: &mut Reader) -> Option<Option<u32>> {
+    match reader.varint_u32()? {
+        0 => Some(None),
+        player_id => Some(Some(player_id - 1)),
+    }
+}
+
+impl Default for BattleStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sends every attack made this tick to the attacker's targets and hands out
+/// KOs for the players who topped out. `standing` says who hadn't topped out
+/// before the tick started.
+pub fn resolve(grids: &mut [Grid], attacks: &[(u32, GridAttackEvent)], standing: &[bool]) {
+    retarget(grids);
+
+    for (from_player_id, attack) in attacks.iter() {
+        let battle = grids[*from_player_id as usize].battle;
+        let rows = battle.boost(attack.rows());
+        if rows == 0 {
+            continue;
+        }
+
+        let mut targets = match battle.targeting {
+            Targeting::Attackers => attackers(grids, *from_player_id),
+            _ => Vec::new(),
+        };
+        if targets.is_empty() {
+            targets.extend(battle.target);
+        }
+        for target in targets {
+            let grid = &mut grids[target as usize];
+            grid.receive_garbage(rows);
+            grid.battle.last_attacker = Some(*from_player_id);
+        }
+    }
+
+    for victim in 0..grids.len() {
+        if !standing[victim] || !grids[victim].gameover {
+            continue;
+        }
+        if let Some(attacker) = grids[victim].battle.last_attacker {
+            let points = 1 + grids[victim].battle.badge_points;
+            let battle = &mut grids[attacker as usize].battle;
+            battle.kos += 1;
+            battle.badge_points += points;
+        }
+    }
+
+    // So everyone can see who they're aiming at next
+    retarget(grids);
+}
+
+/// Players still standing, not counting the grid left out.
+pub fn opponents(grids: &[Grid], player_id: u32) -> Vec<u32> {
+    (0..grids.len() as u32)
+        .filter(|id| *id != player_id && !grids[*id as usize].gameover)
+        .collect()
+}
+
+/// Players still standing who are targeting the given player.
+pub fn attackers(grids: &[Grid], player_id: u32) -> Vec<u32> {
+    opponents(grids, player_id)
+        .into_iter()
+        .filter(|id| grids[*id as usize].battle.target == Some(player_id))
+        .collect()
+}
+
+// Picks who every player still standing goes after next
+fn retarget(grids: &mut [Grid]) {
+    for player_id in 0..grids.len() {
+        let target = if grids[player_id].gameover {
+            None
+        } else {
+            pick_target(grids, player_id as u32)
+        };
+        grids[player_id].battle.target = target;
+    }
+}
+
+fn pick_target(grids: &mut [Grid], player_id: u32) -> Option<u32> {
+    let opponents = opponents(grids, player_id);
+    let battle = grids[player_id as usize].battle;
+    match battle.targeting {
+        Targeting::KOs => opponents
+            .into_iter()
+            .max_by_key(|id| (grids[*id as usize].stack_height(), Reverse(*id))),
+        Targeting::Badges => opponents
+            .into_iter()
+            .max_by_key(|id| (grids[*id as usize].battle.badge_points, Reverse(*id))),
+        Targeting::Attackers => match attackers(grids, player_id).first() {
+            Some(attacker) => Some(*attacker),
+            None => pick_random(grids, player_id, &opponents),
+        },
+        Targeting::Random => pick_random(grids, player_id, &opponents),
+    }
+}
+
+// Sticks with the current target while they're standing
+fn pick_random(grids: &mut [Grid], player_id: u32, opponents: &[u32]) -> Option<u32> {
+    let battle = &mut grids[player_id as usize].battle;
+    if let Some(target) = battle.target {
+        if opponents.contains(&target) {
+            return Some(target);
+        }
+    }
+    if opponents.is_empty() {
+        return None;
+    }
+
+    let mut bytes = player_id.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&battle.picks.to_le_bytes());
+    battle.picks += 1;
+    let index = fnv1a_hash(&bytes) % opponents.len() as u64;
+    Some(opponents[index as usize])
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_attacks_only_hit_the_target() {
+    use crate::simulation;
+
+    let mut grids = simulation::new_grids(20, 10, 4, 1);
+    for grid in grids.iter_mut() {
+        grid.battle.targeting = Targeting::Badges;
+    }
+    grids[2].battle.badge_points = 3;
+    let standing = vec![true; 4];
+
+    resolve(
+        &mut grids,
+        &[(0, GridAttackEvent::LinesCleared(3))],
+        &standing,
+    );
+    let received: Vec<u32> = grids
+        .iter()
+        .map(|grid| grid.stats().attack_received)
+        .collect();
+    assert_eq!(received, vec![0, 0, 2, 0]);
+    assert_eq!(grids[2].battle.last_attacker, Some(0));
+    // Player 2 has the most badge points, but can't target themselves
+    assert_eq!(grids[2].battle.target, Some(0));
+}
+
+#[test]
+fn test_knock_outs_collect_badges() {
+    use crate::simulation;
+
+    let mut grids = simulation::new_grids(20, 10, 3, 1);
+    grids[1].battle.badge_points = 5;
+    grids[1].battle.last_attacker = Some(0);
+    grids[1].gameover = true;
+
+    resolve(&mut grids, &[], &[true, true, true]);
+    let battle = grids[0].battle;
+    assert_eq!((battle.kos, battle.badge_points), (1, 6));
+    assert_eq!(battle.badges(), 2);
+    assert_eq!(battle.boost(4), 6);
+    // Nobody aims at a player who's out
+    assert!(grids.iter().all(|grid| grid.battle.target != Some(1)));
+    assert_eq!(grids[1].battle.target, None);
+
+    // Players who were out already aren't counted again
+    resolve(&mut grids, &[], &[true, false, true]);
+    assert_eq!(grids[0].battle.kos, 1);
+}
+
+#[test]
+fn test_random_targets_are_kept_until_knocked_out() {
+    use crate::simulation;
+
+    let mut grids = simulation::new_grids(20, 10, 8, 1);
+    let standing = vec![true; 8];
+    resolve(&mut grids, &[], &standing);
+    let target = grids[0].battle.target.unwrap();
+    assert_ne!(target, 0);
+
+    resolve(&mut grids, &[], &standing);
+    assert_eq!(grids[0].battle.target, Some(target));
+
+    grids[target as usize].gameover = true;
+    resolve(&mut grids, &[], &standing);
+    let next = grids[0].battle.target.unwrap();
+    assert!(next != target && next != 0);
+}
@@ -1,9 +1,10 @@
 use sdl2::event::Event;
-use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
+use crate::grid::GameSoundEvent;
 use crate::net::{ServerMessage, Socket};
 use crate::render::Renderer;
+use crate::transport::Transport;
 
 pub enum AppLifecycleEvent {
     /// Either the user has requested to quit or some OS event has happened which wants the app to
@@ -11,30 +12,30 @@ pub enum AppLifecycleEvent {
     Shutdown,
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
-pub enum GameSoundEvent {
-    LinesCleared(u8),
-    TurnSoundsOff,
-    TurnSoundsOn,
-    MovePieceDown,
-}
-
-pub trait Scene {
-    fn lifecycle(&mut self, _socket: &mut Socket, _event: AppLifecycleEvent) {}
-    fn input(self: Box<Self>, socket: &mut Socket, event: Event) -> Box<dyn Scene>;
+/// A screen of the game, reached over any kind of `Transport`. Scenes only
+/// talk through the `Socket` wrapping it, so a test can drive one over a
+/// `MemoryTransport` just as the client does over UDP or WebSockets.
+pub trait Scene<T: Transport + ?Sized = dyn Transport> {
+    fn lifecycle(&mut self, _socket: &mut Socket<T>, _event: AppLifecycleEvent) {}
+    fn input(self: Box<Self>, socket: &mut Socket<T>, event: Event) -> Box<dyn Scene<T>>;
     fn render(&self, renderer: &mut Renderer);
     fn handle_message(
         self: Box<Self>,
-        socket: &mut Socket,
+        socket: &mut Socket<T>,
         source_addr: SocketAddr,
         message: ServerMessage,
-    ) -> Box<dyn Scene>;
+    ) -> Box<dyn Scene<T>>;
     fn update(
         self: Box<Self>,
-        socket: &mut Socket,
+        socket: &mut Socket<T>,
         sounds: &mut Vec<GameSoundEvent>,
-    ) -> Box<dyn Scene>;
+    ) -> Box<dyn Scene<T>>;
     fn should_quit(&self) -> bool {
         false
     }
+    /// Whether text is being typed, so keys that otherwise quit the game
+    /// are passed on to the scene instead.
+    fn typing(&self) -> bool {
+        false
+    }
 }
@@ -1,11 +1,21 @@
 use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
 use std::net::SocketAddr;
 
-use crate::net::{ClientMessage, ServerMessage, Socket};
+use crate::chat::ChatBox;
+use crate::crypto::{KeyPair, Role};
+use crate::grid::GameSoundEvent;
+use crate::net::{ClientMessage, Keepalive, ServerMessage, Socket, PROTOCOL_VERSION};
+use crate::profile;
 use crate::render::Renderer;
-use crate::scene::{GameSoundEvent, Scene};
-use crate::scenes::GameScene;
+use crate::scene::Scene;
+use crate::scenes::game::{team_color, team_name};
+use crate::scenes::lockstep::LockstepPeer;
+use crate::scenes::{GameScene, LockstepScene, TitleSettings};
+use crate::snapshot::Snapshot;
 use crate::text::Text;
+use crate::transport::Transport;
 
 // ~10 seconds at 60 fps
 const MAX_CONNECTION_ATTEMPTS: u64 = 600;
@@ -15,10 +25,15 @@ enum ConnectionState {
     SendingConnectionRequest,
     // We've reached the server but need to finish the secret handshake
     SendingChallengeResponse { salt: u64 },
-    // Challenge was successful, time to play game
-    Connected,
+    // Challenge was successful, time to play game. The token lets us
+    // reconnect to the match if our address changes.
+    Connected { token: u64 },
     // We've been rejected because a game is already going
     Rejected,
+    // We wanted an encrypted connection but the server doesn't offer one
+    Unencrypted,
+    // The server is running a different version of the game
+    VersionMismatch { server_version: u32 },
     // We've tried connecting to the server for X seconds but have been unable to reach.
     TimedOut,
 }
@@ -27,17 +42,88 @@ pub struct ConnectScene {
     server_addr: SocketAddr,
     state: ConnectionState,
     connection_attempt_counter: u64,
+    // What to go back to the title screen with
+    title: TitleSettings,
+    keepalive: Keepalive,
+    // Our half of the key exchange, used if the server offers encryption
+    keys: KeyPair,
+    encrypted: bool,
+    // Lets players talk while they wait, and goes with us into the match
+    chat: ChatBox,
+    // The first snapshot of the match, which can take a few packets
+    snapshot: Snapshot,
+    // Teams the next match is played in, zero if it isn't, and the one
+    // the server has us down for
+    teams: u32,
+    team: Option<u32>,
+    // The team we asked for, asked again every so often until the server
+    // answers
+    chosen_team: Option<u32>,
 }
 
 impl ConnectScene {
-    pub fn new(server_addr: SocketAddr) -> Self {
+    pub fn new(server_addr: SocketAddr, title: TitleSettings) -> Self {
         Self {
             server_addr,
             state: ConnectionState::SendingConnectionRequest,
             connection_attempt_counter: 0,
+            keepalive: Keepalive::new(title.timeout),
+            title,
+            keys: KeyPair::generate(),
+            encrypted: false,
+            chat: ChatBox::new(),
+            snapshot: Snapshot::new(),
+            teams: 0,
+            team: None,
+            chosen_team: None,
         }
     }
 
+    /// Waits for the next match on a server we're connected to already,
+    /// after leaving the last one.
+    pub fn lobby(server_addr: SocketAddr, token: u64, title: TitleSettings) -> Self {
+        Self {
+            state: ConnectionState::Connected { token },
+            ..Self::new(server_addr, title)
+        }
+    }
+
+    /// Carries on with the chat from the match.
+    pub fn with_chat(mut self, chat: ChatBox) -> Self {
+        self.chat = chat;
+        self
+    }
+
+    fn choose_team<T: Transport + ?Sized>(&self, socket: &mut Socket<T>) {
+        if let Some(team) = self.chosen_team {
+            let message = ClientMessage::ChooseTeam { team };
+            if let Err(e) = socket.send(self.server_addr, message) {
+                warn!("error sending team choice to the server: {}", e);
+            }
+        }
+    }
+
+    fn render_team(&self, renderer: &mut Renderer) {
+        let (message, color) = match self.team {
+            Some(team) => (
+                format!(
+                    "Playing for {} (1-{} to change)",
+                    team_name(team),
+                    self.teams
+                ),
+                team_color(team),
+            ),
+            None => (
+                format!("Press 1-{} to pick a team", self.teams),
+                Color::RGB(255, 255, 255),
+            ),
+        };
+        let mut text = Text::from(message);
+        text.center_xy(400, 350).height(24);
+        text.color(color);
+        renderer.render_text(text.build());
+    }
+
     fn dots(&self) -> String {
         let num_dots = lerp(
             1f32,
@@ -54,8 +140,27 @@ impl ConnectScene {
     }
 }
 
-impl Scene for ConnectScene {
-    fn input(self: Box<Self>, _socket: &mut Socket, _event: Event) -> Box<dyn Scene> {
+impl<T: Transport + ?Sized> Scene<T> for ConnectScene {
+    fn typing(&self) -> bool {
+        self.chat.typing()
+    }
+
+    fn input(mut self: Box<Self>, socket: &mut Socket<T>, event: Event) -> Box<dyn Scene<T>> {
+        if let ConnectionState::Connected { .. } = self.state {
+            if !self.chat.typing() {
+                if let Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } = event
+                {
+                    if let Some(team) = team_key(keycode) {
+                        self.chosen_team = Some(team);
+                        self.choose_team(socket);
+                    }
+                }
+            }
+            self.chat.input(&event);
+        }
         self
     }
 
@@ -73,8 +178,12 @@ impl Scene for ConnectScene {
                         .build(),
                 );
             }
-            ConnectionState::Connected => {
+            ConnectionState::Connected { .. } => {
                 message = "Waiting to start game...";
+                if self.teams > 0 {
+                    self.render_team(renderer);
+                }
+                self.chat.render(renderer);
             }
             ConnectionState::TimedOut => {
                 message = "Timed Out";
@@ -82,6 +191,26 @@ impl Scene for ConnectScene {
             ConnectionState::Rejected => {
                 message = "REJECTED";
             }
+            ConnectionState::Unencrypted => {
+                message = "Server does not support encryption";
+            }
+            ConnectionState::VersionMismatch { server_version } => {
+                let server_message = format!("Server is running version {}", server_version);
+                let client_message = format!("You have version {}", PROTOCOL_VERSION);
+                renderer.render_text(
+                    Text::from(server_message)
+                        .center_xy(400, 300)
+                        .height(40)
+                        .build(),
+                );
+                renderer.render_text(
+                    Text::from(client_message)
+                        .center_xy(400, 350)
+                        .height(30)
+                        .build(),
+                );
+                return;
+            }
         }
 
         renderer.render_text(Text::new(message).center_xy(400, 300).height(40).build());
@@ -89,9 +218,9 @@ impl Scene for ConnectScene {
 
     fn update(
         mut self: Box<Self>,
-        socket: &mut Socket,
+        socket: &mut Socket<T>,
         _sounds: &mut Vec<GameSoundEvent>,
-    ) -> Box<dyn Scene> {
+    ) -> Box<dyn Scene<T>> {
         if self.connection_attempt_counter >= MAX_CONNECTION_ATTEMPTS {
             self.state = ConnectionState::TimedOut;
             return self;
@@ -100,16 +229,45 @@ impl Scene for ConnectScene {
         match self.state {
             ConnectionState::SendingConnectionRequest => {
                 debug!("sending connection request");
-                socket
-                    .send(self.server_addr, &ClientMessage::Connect)
-                    .unwrap();
+                if let Err(e) = socket.send(self.server_addr, &ClientMessage::Connect) {
+                    warn!("error sending connection request: {}", e);
+                }
                 self.connection_attempt_counter += 1;
             }
             ConnectionState::SendingChallengeResponse { salt } => {
                 debug!("sending challenge response");
-                socket
-                    .send(self.server_addr, &ClientMessage::ChallengeResponse { salt })
-                    .unwrap();
+                let public_key = if self.encrypted {
+                    Some(self.keys.public_key())
+                } else {
+                    None
+                };
+                let response = ClientMessage::ChallengeResponse {
+                    salt,
+                    public_key,
+                    profile: profile::local_profile(),
+                };
+                if let Err(e) = socket.send(self.server_addr, response) {
+                    warn!("error sending challenge response: {}", e);
+                }
+            }
+            // Keep the connection alive while waiting for the game to start
+            ConnectionState::Connected { .. } => {
+                if self.keepalive.timed_out() {
+                    error!("lost connection to server while waiting for game");
+                    self.state = ConnectionState::TimedOut;
+                } else if self.keepalive.needs_heartbeat() {
+                    match self.chosen_team {
+                        Some(_) => self.choose_team(socket),
+                        None => {
+                            if let Err(e) = socket.send(self.server_addr, ClientMessage::Heartbeat)
+                            {
+                                warn!("error sending heartbeat to the server: {}", e);
+                            }
+                        }
+                    }
+                    self.keepalive.sent();
+                }
+                self.chat.update(socket, self.server_addr);
             }
             _ => {}
         }
@@ -119,26 +277,116 @@ impl Scene for ConnectScene {
 
     fn handle_message(
         mut self: Box<Self>,
-        _socket: &mut Socket,
+        socket: &mut Socket<T>,
         source_addr: SocketAddr,
         message: ServerMessage,
-    ) -> Box<dyn Scene> {
+    ) -> Box<dyn Scene<T>> {
+        // Anyone could send us a packet, but only the server gets a say in
+        // how connecting goes
+        if source_addr != self.server_addr {
+            return self;
+        }
+        self.keepalive.received();
+
         match message {
-            ServerMessage::Sync { player_id, grids } => {
-                debug!("connected to server at {:?}", source_addr);
+            // The match starts once every grid has arrived
+            ServerMessage::Sync { player_id, part } => {
+                self.snapshot.receive(part);
+                // A grid that isn't in the match can't be ours
+                let ours = (player_id as usize) < self.snapshot.grids().len();
+                match self.state {
+                    ConnectionState::Connected { token } if self.snapshot.is_complete() && ours => {
+                        debug!("connected to server at {:?}", source_addr);
+                        let snapshot = std::mem::take(&mut self.snapshot);
+                        Box::new(
+                            GameScene::new(
+                                Some(player_id),
+                                snapshot,
+                                self.server_addr,
+                                token,
+                                self.title,
+                            )
+                            .with_chat(self.chat),
+                        )
+                    }
+                    _ => self,
+                }
+            }
+            ServerMessage::Spectate(part) => {
+                self.snapshot.receive(part);
+                match self.state {
+                    ConnectionState::Connected { token } if self.snapshot.is_complete() => {
+                        debug!("spectating match on server at {:?}", source_addr);
+                        let snapshot = std::mem::take(&mut self.snapshot);
+                        Box::new(
+                            GameScene::new(None, snapshot, self.server_addr, token, self.title)
+                                .with_chat(self.chat),
+                        )
+                    }
+                    _ => self,
+                }
+            }
+            ServerMessage::LockstepStart {
+                player_id,
+                settings,
+                players,
+            } => {
+                debug!("starting lockstep match with server at {:?}", source_addr);
 
                 match self.state {
-                    ConnectionState::Connected => Box::new(GameScene::new(
-                        player_id,
-                        grids.into_owned(),
-                        self.server_addr,
-                    )),
+                    ConnectionState::Connected { token } if settings.is_playable_by(player_id) => {
+                        Box::new(
+                            LockstepScene::new(
+                                player_id,
+                                settings,
+                                players,
+                                LockstepPeer::Server {
+                                    addr: self.server_addr,
+                                    token,
+                                },
+                                self.title,
+                            )
+                            .with_chat(self.chat),
+                        )
+                    }
                     _ => self,
                 }
             }
-            ServerMessage::ConnectionAccepted => {
+            ServerMessage::Lockstep(_)
+            | ServerMessage::Introduction { .. }
+            | ServerMessage::PeerHello(_)
+            | ServerMessage::Heartbeat
+            | ServerMessage::ServerInfo(_)
+            | ServerMessage::Kicked { .. }
+            | ServerMessage::MatchResult(_)
+            | ServerMessage::RematchVotes(_)
+            | ServerMessage::ReturnedToLobby => self,
+            ServerMessage::TeamChoice { teams, team } => {
+                // Anything but our choice means the server wouldn't have it
+                self.teams = teams;
+                self.team = team;
+                self.chosen_team = None;
+                self
+            }
+            ServerMessage::Chat { sequence, line } => {
+                self.chat.receive(socket, self.server_addr, sequence, line);
+                self
+            }
+            ServerMessage::ChatAck { received } => {
+                self.chat.acknowledge(received);
+                self
+            }
+            ServerMessage::ConnectionAccepted { token } => {
                 debug!("connection accepted for client {}", source_addr);
-                self.state = ConnectionState::Connected;
+                self.state = ConnectionState::Connected { token };
+                self
+            }
+            ServerMessage::VersionMismatch { server_version } => {
+                error!(
+                    "server is running version {}, we're on {}",
+                    server_version, PROTOCOL_VERSION
+                );
+                self.state = ConnectionState::VersionMismatch { server_version };
                 self
             }
             ServerMessage::ConnectionRejected => {
@@ -146,8 +394,32 @@ impl Scene for ConnectScene {
                 self.state = ConnectionState::Rejected;
                 self
             }
-            ServerMessage::Challenge { salt } => {
+            // The challenge is resent for every connection request that
+            // reaches the server, but only the first one is taken so the key
+            // we agreed on can't be swapped out from under us
+            ServerMessage::Challenge { .. }
+                if !matches!(self.state, ConnectionState::SendingConnectionRequest) =>
+            {
+                self
+            }
+            ServerMessage::Challenge { salt, public_key } => {
                 debug!("received challenge from server: {}", salt);
+
+                match public_key {
+                    Some(public_key) if !self.encrypted => {
+                        debug!("encrypting connection to server");
+                        let session = self.keys.session(&public_key, Role::Client);
+                        socket.set_session(self.server_addr, session);
+                        self.encrypted = true;
+                    }
+                    None if self.title.require_encryption => {
+                        error!("server at {} does not support encryption", source_addr);
+                        self.state = ConnectionState::Unencrypted;
+                        return self;
+                    }
+                    _ => {}
+                }
+
                 self.state = ConnectionState::SendingChallengeResponse { salt };
                 self
             }
@@ -155,6 +427,109 @@ impl Scene for ConnectScene {
     }
 }
 
+// Teams are picked with the number keys, 1 for the first
+fn team_key(keycode: Keycode) -> Option<u32> {
+    match keycode {
+        Keycode::Num1 => Some(0),
+        Keycode::Num2 => Some(1),
+        Keycode::Num3 => Some(2),
+        Keycode::Num4 => Some(3),
+        Keycode::Num5 => Some(4),
+        Keycode::Num6 => Some(5),
+        Keycode::Num7 => Some(6),
+        Keycode::Num8 => Some(7),
+        Keycode::Num9 => Some(8),
+        _ => None,
+    }
+}
+
 fn lerp(start: f32, end: f32, time: f32) -> f32 {
     start * (1f32 - time) + end * time
 }
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_connect_scene_joins_server() {
+    use crate::net::{ServerEvent, ServerSocket, DEFAULT_TIMEOUT};
+    use crate::transport::MemoryTransport;
+
+    let client_addr = "127.0.0.1:1".parse().unwrap();
+    let server_addr = "127.0.0.1:2".parse().unwrap();
+    let (client_transport, server_transport) = MemoryTransport::pair(client_addr, server_addr);
+    let mut socket = Socket::with_transport(Box::new(client_transport));
+    let mut server = ServerSocket::with_transport(Box::new(server_transport));
+
+    let title = TitleSettings {
+        server_addr,
+        peer_mode: None,
+        timeout: DEFAULT_TIMEOUT,
+        require_encryption: false,
+    };
+    let mut scene: Box<dyn Scene<MemoryTransport>> =
+        Box::new(ConnectScene::new(server_addr, title));
+    let mut sounds = Vec::new();
+    for _ in 0..10 {
+        scene = scene.update(&mut socket, &mut sounds);
+
+        while let Some(event) = server.receive().unwrap() {
+            if let ServerEvent::ClientConnected(addr, _, _) = event {
+                assert_eq!(addr, client_addr);
+                return;
+            }
+        }
+
+        while let Some((addr, message)) = socket.receive::<ServerMessage>().unwrap() {
+            scene = scene.handle_message(&mut socket, addr, message);
+        }
+    }
+    panic!("client never connected");
+}
+
+#[test]
+fn test_connect_scene_ignores_other_hosts() {
+    use crate::net::{ServerEvent, ServerSocket, DEFAULT_TIMEOUT};
+    use crate::transport::MemoryTransport;
+
+    let client_addr = "127.0.0.1:1".parse().unwrap();
+    let server_addr = "127.0.0.1:2".parse().unwrap();
+    let spoofed_addr = "127.0.0.1:3".parse().unwrap();
+    let (client_transport, server_transport) = MemoryTransport::pair(client_addr, server_addr);
+    let mut socket = Socket::with_transport(Box::new(client_transport));
+    let mut server = ServerSocket::with_transport(Box::new(server_transport));
+
+    let title = TitleSettings {
+        server_addr,
+        peer_mode: None,
+        timeout: DEFAULT_TIMEOUT,
+        require_encryption: false,
+    };
+    let mut scene: Box<dyn Scene<MemoryTransport>> =
+        Box::new(ConnectScene::new(server_addr, title));
+    let mut sounds = Vec::new();
+    for _ in 0..10 {
+        scene = scene.update(&mut socket, &mut sounds);
+
+        while let Some(event) = server.receive().unwrap() {
+            if let ServerEvent::ClientConnected(addr, _, _) = event {
+                assert_eq!(addr, client_addr);
+                return;
+            }
+        }
+
+        // Neither of these should stop us from getting in
+        scene = scene.handle_message(&mut socket, spoofed_addr, ServerMessage::ConnectionRejected);
+        let challenge = ServerMessage::Challenge {
+            salt: 1,
+            public_key: Some(KeyPair::generate().public_key()),
+        };
+        scene = scene.handle_message(&mut socket, spoofed_addr, challenge);
+
+        while let Some((addr, message)) = socket.receive::<ServerMessage>().unwrap() {
+            scene = scene.handle_message(&mut socket, addr, message);
+        }
+    }
+    panic!("client never connected");
+}
@@ -0,0 +1,56 @@
+use sdl2::event::Event;
+
+use std::net::SocketAddr;
+
+use crate::grid::GameSoundEvent;
+use crate::net::{ServerMessage, Socket};
+use crate::render::Renderer;
+use crate::scene::Scene;
+use crate::text::Text;
+use crate::transport::Transport;
+
+/// Shown when we stop hearing from the server or the other peer in the
+/// middle of a match.
+pub struct DisconnectedScene {
+    message: String,
+}
+
+impl DisconnectedScene {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl<T: Transport + ?Sized> Scene<T> for DisconnectedScene {
+    fn input(self: Box<Self>, _socket: &mut Socket<T>, _event: Event) -> Box<dyn Scene<T>> {
+        self
+    }
+
+    fn render(&self, renderer: &mut Renderer) {
+        renderer.render_text(
+            Text::from(self.message.clone())
+                .center_xy(400, 300)
+                .height(40)
+                .build(),
+        );
+    }
+
+    fn handle_message(
+        self: Box<Self>,
+        _socket: &mut Socket<T>,
+        _source_addr: SocketAddr,
+        _message: ServerMessage,
+    ) -> Box<dyn Scene<T>> {
+        self
+    }
+
+    fn update(
+        self: Box<Self>,
+        _socket: &mut Socket<T>,
+        _sounds: &mut Vec<GameSoundEvent>,
+    ) -> Box<dyn Scene<T>> {
+        self
+    }
+}
@@ -1,166 +1,246 @@
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use crate::brick::CELL_SIZE;
-use crate::grid::{Grid, GridInputEvent};
+use crate::chat::ChatBox;
+use crate::grid::{GameSoundEvent, Grid, GridInputEvent};
 use crate::image::Image;
-use crate::net::{ClientMessage, ServerMessage, Socket};
+use crate::net::{ClientMessage, Keepalive, ServerMessage, Socket};
+use crate::profile::{self, PlayerInfo};
 use crate::render::{Opacity, Renderer, VIEWPORT_HEIGHT, VIEWPORT_WIDTH};
-use crate::scene::{AppLifecycleEvent, GameSoundEvent, Scene};
-use crate::scenes::GameOverScene;
+use crate::royale::{self, Targeting};
+use crate::scene::{AppLifecycleEvent, Scene};
+use crate::scenes::{DisconnectedScene, GameOverScene, TitleSettings};
+use crate::snapshot::Snapshot;
+use crate::stats::NetStats;
+use crate::text::Text;
+use crate::transport::Transport;
+
+// The overlay only changes this often, so it can be read and doesn't make
+// a new text texture every frame
+const STATS_REFRESH: Duration = Duration::from_secs(1);
+
+// Most grids shown full size next to each other
+const MAX_SIDE_BY_SIDE: usize = 4;
+// Where our own grid goes when everyone else's is shown small
+const LOCAL_GRID_X: i32 = 40;
+const MINI_CELL_SIZE: u32 = 4;
+const MINI_SLOT_WIDTH: i32 = 60;
+const MINI_SLOT_HEIGHT: i32 = 120;
+const MINI_TOP: i32 = 40;
+const MINI_COLUMNS: i32 = 8;
+const MINI_LEFT: i32 = 290;
+// Spectators have no grid of their own, so the mini grids fill the screen
+const SPECTATOR_MINI_COLUMNS: i32 = 12;
+const SPECTATOR_MINI_LEFT: i32 = 50;
+const MINI_NAME_LENGTH: usize = 8;
+const HUD_TOP: i32 = 530;
 
 pub struct GameScene {
-    player_id: u32,
-    grids: Vec<Grid>,
+    // None when spectating
+    player_id: Option<u32>,
+    // Grids as of the newest parts of snapshots the server sent
+    snapshot: Snapshot,
     address: SocketAddr,
+    // Given to us by the server so we can take our grid back if our
+    // address changes
+    token: u64,
+    // Number of the next command we send
+    sequence: u32,
+    keepalive: Keepalive,
+    // What to go back to the title screen with once the match is over
+    title: TitleSettings,
+    show_stats: bool,
+    stats: Option<NetStats>,
+    stats_refreshed: Instant,
+    chat: ChatBox,
 }
 
 impl GameScene {
-    pub fn new(player_id: u32, grids: Vec<Grid>, address: SocketAddr) -> Self {
+    pub fn new(
+        player_id: Option<u32>,
+        snapshot: Snapshot,
+        address: SocketAddr,
+        token: u64,
+        title: TitleSettings,
+    ) -> Self {
         Self {
             player_id,
-            grids,
+            snapshot,
             address,
+            token,
+            sequence: 0,
+            keepalive: Keepalive::new(title.timeout),
+            title,
+            show_stats: false,
+            stats: None,
+            stats_refreshed: Instant::now(),
+            chat: ChatBox::new(),
         }
     }
+
+    /// Carries on with the chat from the lobby.
+    pub fn with_chat(mut self, chat: ChatBox) -> Self {
+        self.chat = chat;
+        self
+    }
 }
 
-impl Scene for GameScene {
-    fn lifecycle(&mut self, socket: &mut Socket, event: AppLifecycleEvent) {
+impl<T: Transport + ?Sized> Scene<T> for GameScene {
+    fn typing(&self) -> bool {
+        self.chat.typing()
+    }
+
+    fn lifecycle(&mut self, socket: &mut Socket<T>, event: AppLifecycleEvent) {
         match event {
             AppLifecycleEvent::Shutdown => {
                 trace!("sending disconnect to the server");
-                socket
-                    .send(self.address, &ClientMessage::Disconnect)
-                    .unwrap();
+                if let Err(e) = socket.send(self.address, &ClientMessage::Disconnect) {
+                    warn!("error sending disconnect to the server: {}", e);
+                }
             }
         }
     }
 
-    fn input(self: Box<Self>, socket: &mut Socket, event: Event) -> Box<dyn Scene> {
-        let player_id = self.player_id;
+    fn input(mut self: Box<Self>, socket: &mut Socket<T>, event: Event) -> Box<dyn Scene<T>> {
+        if self.chat.input(&event) {
+            return self;
+        }
 
-        match event {
-            Event::KeyDown {
-                keycode: Some(Keycode::A),
-                ..
-            } => {
-                socket
-                    .send(
-                        self.address,
-                        &ClientMessage::Command {
-                            player_id,
-                            event: GridInputEvent::MoveLeft,
-                        },
-                    )
-                    .unwrap();
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::D),
-                ..
-            } => {
-                socket
-                    .send(
-                        self.address,
-                        &ClientMessage::Command {
-                            player_id,
-                            event: GridInputEvent::MoveRight,
-                        },
-                    )
-                    .unwrap();
+        if let Event::KeyDown {
+            keycode: Some(keycode),
+            ..
+        } = event
+        {
+            if keycode == Keycode::F3 {
+                self.show_stats = !self.show_stats;
             }
-            Event::KeyDown {
-                keycode: Some(Keycode::S),
-                ..
-            } => {
-                socket
-                    .send(
-                        self.address,
-                        &ClientMessage::Command {
-                            player_id,
-                            event: GridInputEvent::MoveDown,
-                        },
-                    )
-                    .unwrap();
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::W),
-                ..
-            } => {
-                socket
-                    .send(
-                        self.address,
-                        &ClientMessage::Command {
-                            player_id,
-                            event: GridInputEvent::ForceToBottom,
-                        },
-                    )
-                    .unwrap();
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::E),
-                ..
-            } => {
-                socket
-                    .send(
-                        self.address,
-                        &ClientMessage::Command {
-                            player_id,
-                            event: GridInputEvent::Rotate,
-                        },
-                    )
-                    .unwrap();
+
+            if let (Some(player_id), Some(event)) = (self.player_id, grid_input_event(keycode)) {
+                let command = ClientMessage::Command {
+                    player_id,
+                    sequence: self.sequence,
+                    event,
+                };
+                if let Err(e) = socket.send(self.address, &command) {
+                    warn!("error sending command to the server: {}", e);
+                }
+                self.sequence = self.sequence.wrapping_add(1);
+                self.keepalive.sent();
             }
-            _ => {}
         }
         self
     }
 
     fn render(&self, renderer: &mut Renderer) {
-        renderer.render_image(
-            Image::PlayingField,
-            Rect::new(0, 0, 800, 600),
-            Opacity::Opaque,
+        render_grids(
+            renderer,
+            self.snapshot.grids(),
+            self.snapshot.players(),
+            self.player_id,
         );
+        if self.player_id.is_none() {
+            render_spectator_banner(renderer);
+        }
 
-        for (idx, grid) in self.grids.iter().enumerate() {
-            let (x_offset, y_offset) =
-                grid_offset(grid.size(), idx as u32, self.grids.len() as u32);
-            renderer.with_relative_offset(x_offset, y_offset, |renderer| grid.render(renderer));
+        if self.show_stats {
+            render_stats(renderer, self.stats);
         }
+        self.chat.render(renderer);
     }
 
     fn update(
         mut self: Box<Self>,
-        _socket: &mut Socket,
+        socket: &mut Socket<T>,
         sounds: &mut Vec<GameSoundEvent>,
-    ) -> Box<dyn Scene> {
-        if self.grids.iter().any(|grid| grid.gameover) {
-            Box::new(GameOverScene::new(self.address))
-        } else {
-            for grid in self.grids.iter_mut() {
-                for event in grid.sound_events.iter() {
-                    sounds.push(event.clone());
-                }
+    ) -> Box<dyn Scene<T>> {
+        if self.keepalive.timed_out() {
+            error!("lost connection to server during game");
+            return Box::new(DisconnectedScene::new("Lost connection to server"));
+        }
+
+        if self.keepalive.reconnect_due() {
+            debug!("haven't heard from the server, trying to reconnect");
+            let message = ClientMessage::Reconnect { token: self.token };
+            if let Err(e) = socket.send(self.address, message) {
+                warn!("error sending reconnect to the server: {}", e);
+            }
+            self.keepalive.sent();
+        } else if self.keepalive.needs_heartbeat() {
+            if let Err(e) = socket.send(self.address, ClientMessage::Heartbeat) {
+                warn!("error sending heartbeat to the server: {}", e);
+            }
+            self.keepalive.sent();
+        }
+
+        self.chat.update(socket, self.address);
+
+        if self.stats_refreshed.elapsed() >= STATS_REFRESH {
+            self.stats = socket.stats(&self.address);
+            self.stats_refreshed = Instant::now();
+        }
 
-                grid.sound_events.clear();
+        for grid in self.snapshot.grids_mut() {
+            for event in grid.sound_events.iter() {
+                sounds.push(event.clone());
             }
-            self
+
+            grid.sound_events.clear();
         }
+        self
     }
 
     fn handle_message(
         mut self: Box<Self>,
-        _socket: &mut Socket,
-        _source_addr: SocketAddr,
+        socket: &mut Socket<T>,
+        source_addr: SocketAddr,
         message: ServerMessage,
-    ) -> Box<dyn Scene> {
+    ) -> Box<dyn Scene<T>> {
+        // Only the server can change the match
+        if source_addr != self.address {
+            return self;
+        }
+        self.keepalive.received();
+
         match message {
-            ServerMessage::Sync { grids, .. } => {
-                self.grids = grids.into_owned();
+            // The server sends snapshots less often than we render, so the
+            // grids are drawn as they are until the next one arrives. Parts
+            // overtaken by a newer one are out of date already.
+            ServerMessage::Sync { part, .. } | ServerMessage::Spectate(part) => {
+                self.snapshot.receive(part);
+                self
+            }
+            ServerMessage::ConnectionRejected => {
+                error!("server would not let us back into the match");
+                Box::new(DisconnectedScene::new("Could not rejoin the match"))
+            }
+            ServerMessage::Kicked { reason } => {
+                error!("kicked by the server: {}", reason);
+                Box::new(DisconnectedScene::new(format!("Kicked: {}", reason)))
+            }
+            // Players who topped out keep watching until the server says
+            // the match is over
+            ServerMessage::MatchResult(result) => Box::new(
+                GameOverScene::new(
+                    self.player_id,
+                    result,
+                    self.snapshot.players().to_vec(),
+                    self.title,
+                )
+                .on_server(self.address, self.token)
+                .with_chat(self.chat),
+            ),
+            ServerMessage::Chat { sequence, line } => {
+                self.chat.receive(socket, self.address, sequence, line);
+                self
+            }
+            ServerMessage::ChatAck { received } => {
+                self.chat.acknowledge(received);
                 self
             }
             _ => self,
@@ -168,6 +248,282 @@ impl Scene for GameScene {
     }
 }
 
+/// Maps the keys used to control a grid to the input they trigger.
+pub(crate) fn grid_input_event(keycode: Keycode) -> Option<GridInputEvent> {
+    match keycode {
+        Keycode::A => Some(GridInputEvent::MoveLeft),
+        Keycode::D => Some(GridInputEvent::MoveRight),
+        Keycode::S => Some(GridInputEvent::MoveDown),
+        Keycode::W => Some(GridInputEvent::ForceToBottom),
+        Keycode::E => Some(GridInputEvent::Rotate),
+        Keycode::Num1 => Some(GridInputEvent::Target(Targeting::Random)),
+        Keycode::Num2 => Some(GridInputEvent::Target(Targeting::KOs)),
+        Keycode::Num3 => Some(GridInputEvent::Target(Targeting::Badges)),
+        Keycode::Num4 => Some(GridInputEvent::Target(Targeting::Attackers)),
+        _ => None,
+    }
+}
+
+/// Renders the playing field with every grid side by side, each labelled
+/// with the name of whoever plays it. Grids without a player to go with
+/// them are labelled by number. Past a few grids there's no room for that,
+/// so our own grid is shown full size and everyone else's is shrunk down.
+/// In a team match teammates sit together, framed in their team's color.
+pub(crate) fn render_grids(
+    renderer: &mut Renderer,
+    grids: &[Grid],
+    players: &[PlayerInfo],
+    local_player: Option<u32>,
+) {
+    renderer.render_image(
+        Image::PlayingField,
+        Rect::new(0, 0, 800, 600),
+        Opacity::Opaque,
+    );
+
+    if grids.len() > MAX_SIDE_BY_SIDE {
+        render_mini_grids(renderer, grids, players, local_player);
+        return;
+    }
+
+    let order = display_order(grids.len() as u32, players);
+    for (slot, idx) in order.into_iter().enumerate() {
+        let grid = &grids[idx as usize];
+        let (x_offset, y_offset) = grid_offset(grid.size(), slot as u32, grids.len() as u32);
+        let team = player_team(players, idx);
+        if let Some(team) = team {
+            render_team_frame(renderer, grid, x_offset, y_offset, team);
+        }
+        renderer.with_relative_offset(x_offset, y_offset, |renderer| grid.render(renderer));
+
+        let (grid_width, _) = grid.size();
+        let mut label = Text::from(player_name(players, idx));
+        label
+            .center_xy(x_offset + grid_width as i32 / 2, y_offset - 16)
+            .height(24);
+        if let Some(team) = team {
+            label.color(team_color(team));
+        }
+        renderer.render_text(label.build());
+    }
+}
+
+// Our grid on the left with the others in rows of mini grids beside it,
+// outlined when we're targeting them or they're targeting us
+fn render_mini_grids(
+    renderer: &mut Renderer,
+    grids: &[Grid],
+    players: &[PlayerInfo],
+    local_player: Option<u32>,
+) {
+    let mut target = None;
+    let mut attackers = Vec::new();
+    let (columns, left) = match local_player {
+        Some(player_id) => {
+            let grid = &grids[player_id as usize];
+            let (_, y_offset) = grid_offset(grid.size(), 0, 1);
+            if let Some(team) = player_team(players, player_id) {
+                render_team_frame(renderer, grid, LOCAL_GRID_X, y_offset, team);
+            }
+            renderer.with_relative_offset(LOCAL_GRID_X, y_offset, |renderer| grid.render(renderer));
+            if grids.iter().any(|grid| grid.battle.target.is_some()) {
+                render_battle_hud(renderer, grids, players, player_id);
+            }
+
+            target = grid.battle.target;
+            attackers = royale::attackers(grids, player_id);
+            (MINI_COLUMNS, MINI_LEFT)
+        }
+        None => (SPECTATOR_MINI_COLUMNS, SPECTATOR_MINI_LEFT),
+    };
+
+    let opponents = display_order(grids.len() as u32, players)
+        .into_iter()
+        .filter(|idx| Some(*idx) != local_player);
+    for (slot, idx) in opponents.enumerate() {
+        let slot = slot as i32;
+        let x = left + (slot % columns) * MINI_SLOT_WIDTH;
+        let y = MINI_TOP + (slot / columns) * MINI_SLOT_HEIGHT;
+        let grid = &grids[idx as usize];
+        let (grid_width, grid_height) = grid.size();
+        let width = grid_width / CELL_SIZE * MINI_CELL_SIZE;
+        let height = grid_height / CELL_SIZE * MINI_CELL_SIZE;
+
+        let team = player_team(players, idx);
+        let outline = if target == Some(idx) {
+            Some(Color::RGB(241, 196, 15))
+        } else if attackers.contains(&idx) {
+            Some(Color::RGB(234, 77, 72))
+        } else {
+            team.map(team_color)
+        };
+        if let Some(color) = outline {
+            renderer.fill_rect(Rect::new(x - 2, y - 2, width + 4, height + 4), color);
+        }
+
+        renderer.with_relative_offset(x, y, |renderer| grid.render_mini(renderer, MINI_CELL_SIZE));
+        if grid.gameover {
+            renderer.fill_rect(Rect::new(x, y, width, height), Color::RGB(44, 44, 44));
+            renderer.render_text(
+                Text::new("KO")
+                    .center_xy(x + width as i32 / 2, y + height as i32 / 2)
+                    .height(24)
+                    .color(Color::RGB(234, 77, 72))
+                    .build(),
+            );
+        }
+
+        let name: String = player_name(players, idx)
+            .chars()
+            .take(MINI_NAME_LENGTH)
+            .collect();
+        let mut label = Text::from(name);
+        label
+            .center_xy(x + width as i32 / 2, y + height as i32 + 10)
+            .height(14);
+        if let Some(team) = team {
+            label.color(team_color(team));
+        }
+        renderer.render_text(label.build());
+    }
+}
+
+// A border in the team's color around the whole of a full size grid, from
+// the next piece down to the score
+fn render_team_frame(renderer: &mut Renderer, grid: &Grid, x: i32, y: i32, team: u32) {
+    let (grid_width, grid_height) = grid.size();
+    renderer.fill_rect(
+        Rect::new(
+            x - 4,
+            y - 5 * CELL_SIZE as i32 - 4,
+            grid_width + 8,
+            grid_height + 7 * CELL_SIZE + 24,
+        ),
+        team_color(team),
+    );
+}
+
+// Our KOs and badges, who we're after and how many are left, under the
+// mini grids
+fn render_battle_hud(
+    renderer: &mut Renderer,
+    grids: &[Grid],
+    players: &[PlayerInfo],
+    player_id: u32,
+) {
+    let battle = grids[player_id as usize].battle;
+    let standing = grids.iter().filter(|grid| !grid.gameover).count();
+    let target = match battle.target {
+        Some(target) => player_name(players, target),
+        None => String::from("-"),
+    };
+
+    let lines = [
+        format!(
+            "KOs {}   Badges {} (+{}%)   Players left {}",
+            battle.kos,
+            battle.badges(),
+            battle.bonus(),
+            standing
+        ),
+        format!(
+            "Targeting {} [1-4]   Target {}",
+            battle.targeting.name(),
+            target
+        ),
+    ];
+    for (idx, line) in lines.iter().enumerate() {
+        renderer.render_text(
+            Text::from(line.clone())
+                .left_top_xy(MINI_LEFT, HUD_TOP + 24 * idx as i32)
+                .height(20)
+                .build(),
+        );
+    }
+}
+
+// Teammates next to each other, otherwise in player order
+fn display_order(num_grids: u32, players: &[PlayerInfo]) -> Vec<u32> {
+    let mut order: Vec<u32> = (0..num_grids).collect();
+    order.sort_by_key(|player_id| player_team(players, *player_id));
+    order
+}
+
+fn player_team(players: &[PlayerInfo], player_id: u32) -> Option<u32> {
+    players
+        .get(player_id as usize)
+        .and_then(|player| player.team)
+}
+
+/// What each team is called, after its color.
+pub(crate) fn team_name(team: u32) -> String {
+    match team {
+        0 => String::from("Blue"),
+        1 => String::from("Red"),
+        2 => String::from("Green"),
+        3 => String::from("Yellow"),
+        _ => format!("Team {}", team + 1),
+    }
+}
+
+pub(crate) fn team_color(team: u32) -> Color {
+    match team % 4 {
+        0 => Color::RGB(95, 124, 202),
+        1 => Color::RGB(234, 77, 72),
+        2 => Color::RGB(46, 204, 113),
+        _ => Color::RGB(241, 196, 15),
+    }
+}
+
+fn player_name(players: &[PlayerInfo], player_id: u32) -> String {
+    match players.get(player_id as usize) {
+        Some(player) => player.name.clone(),
+        None => profile::fallback_name(player_id),
+    }
+}
+
+/// Says we're only watching.
+fn render_spectator_banner(renderer: &mut Renderer) {
+    renderer.render_text(
+        Text::new("Spectating")
+            .center_xy((VIEWPORT_WIDTH / 2) as i32, 20)
+            .height(30)
+            .color(Color::RGB(234, 77, 72))
+            .build(),
+    );
+}
+
+/// Renders the network stats in the top left corner, over the grids.
+pub(crate) fn render_stats(renderer: &mut Renderer, stats: Option<NetStats>) {
+    let lines = match stats {
+        Some(stats) => vec![
+            match stats.rtt {
+                Some(rtt) => format!("RTT {}ms", rtt.as_millis()),
+                None => String::from("RTT -"),
+            },
+            format!("Loss {:.1}%", stats.loss * 100.0),
+            format!(
+                "Up {} B/s {} pkt/s",
+                stats.bytes_sent_per_second, stats.packets_sent_per_second
+            ),
+            format!(
+                "Down {} B/s {} pkt/s",
+                stats.bytes_received_per_second, stats.packets_received_per_second
+            ),
+        ],
+        None => vec![String::from("No network stats yet")],
+    };
+
+    for (idx, line) in lines.into_iter().enumerate() {
+        renderer.render_text(
+            Text::from(line)
+                .left_top_xy(8, 4 + 20 * idx as i32)
+                .height(20)
+                .build(),
+        );
+    }
+}
+
 fn grid_offset(grid_size: (u32, u32), index: u32, num_grids: u32) -> (i32, i32) {
     let (grid_width, grid_height) = grid_size;
 
@@ -1,61 +1,577 @@
 use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
 
 use std::net::SocketAddr;
 
-use crate::net::{ClientMessage, ServerMessage, Socket};
-use crate::render::Renderer;
-use crate::scene::{AppLifecycleEvent, GameSoundEvent, Scene};
+use crate::chat::ChatBox;
+use crate::grid::GameSoundEvent;
+use crate::net::{ClientMessage, Keepalive, ServerMessage, Socket};
+use crate::profile::{self, PlayerInfo};
+use crate::render::{Renderer, VIEWPORT_WIDTH};
+use crate::results::{MatchResult, RematchVote};
+use crate::scene::{AppLifecycleEvent, Scene};
+use crate::scenes::game::team_name;
+use crate::scenes::lockstep::LockstepPeer;
+use crate::scenes::{
+    ConnectScene, DisconnectedScene, GameScene, LockstepScene, TitleScene, TitleSettings,
+};
+use crate::snapshot::Snapshot;
 use crate::text::Text;
+use crate::transport::Transport;
 
+// Rows that fit between the heading and the menu
+const MAX_ROWS: usize = 8;
+const ROW_HEIGHT: i32 = 30;
+// Left edge of each column of the results table
+const COLUMNS: [(&str, i32); 8] = [
+    ("#", 40),
+    ("Player", 80),
+    ("Score", 300),
+    ("Lines", 390),
+    ("Pieces", 470),
+    ("Sent", 560),
+    ("Recv", 640),
+    ("KOs", 720),
+];
+// The menu sits to the right, clear of the chat in the bottom left corner
+const MENU_X: i32 = 600;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum MenuItem {
+    Rematch,
+    BackToLobby,
+    BackToTitle,
+}
+
+impl MenuItem {
+    fn text(self) -> &'static str {
+        match self {
+            MenuItem::Rematch => "Rematch",
+            MenuItem::BackToLobby => "Back to Lobby",
+            MenuItem::BackToTitle => "Back to Title",
+        }
+    }
+}
+
+/// Shows who won and how everyone played once a match is over, and lets
+/// the players agree on a rematch.
 pub struct GameOverScene {
-    address: SocketAddr,
+    // Server to notify when we leave. None when the game was played
+    // directly against another peer.
+    address: Option<SocketAddr>,
+    // Lets us back into the next match on the same server
+    token: u64,
+    // What the title screen was opened with, for going back to it or
+    // finding the other peer again for a rematch
+    title: TitleSettings,
+    // None when spectating
+    player_id: Option<u32>,
+    result: MatchResult,
+    players: Vec<PlayerInfo>,
+    menu: Vec<MenuItem>,
+    selected: usize,
+    // What we've asked the server for, either a rematch or to go back to
+    // the lobby. It's asked again every so often until the server does it.
+    chosen: Option<MenuItem>,
+    // How each player voted, as the server last told us
+    votes: Vec<RematchVote>,
+    // Tick a lockstep match ended on. The server won't take votes until
+    // every player has told it, so it's told again every so often.
+    ended_on: Option<u64>,
+    // The first snapshot of the next match, which can take a few packets
+    snapshot: Snapshot,
+    keepalive: Keepalive,
+    chat: ChatBox,
 }
 
 impl GameOverScene {
-    pub fn new(address: SocketAddr) -> Self {
-        Self { address }
+    pub fn new(
+        player_id: Option<u32>,
+        result: MatchResult,
+        players: Vec<PlayerInfo>,
+        title: TitleSettings,
+    ) -> Self {
+        Self {
+            address: None,
+            token: 0,
+            keepalive: Keepalive::new(title.timeout),
+            title,
+            player_id,
+            result,
+            players,
+            menu: vec![MenuItem::Rematch, MenuItem::BackToTitle],
+            selected: 0,
+            chosen: None,
+            votes: Vec::new(),
+            ended_on: None,
+            snapshot: Snapshot::new(),
+            chat: ChatBox::new(),
+        }
+    }
+
+    /// The match was played on a server, where the players vote on a
+    /// rematch and can wait in the lobby for the next match instead.
+    pub fn on_server(mut self, address: SocketAddr, token: u64) -> Self {
+        self.address = Some(address);
+        self.token = token;
+        self.menu = match self.player_id {
+            Some(_) => vec![
+                MenuItem::Rematch,
+                MenuItem::BackToLobby,
+                MenuItem::BackToTitle,
+            ],
+            // Spectators have no say, and watch the rematch if there is one
+            None => vec![MenuItem::BackToLobby, MenuItem::BackToTitle],
+        };
+        self
+    }
+
+    /// The match was played in lockstep on a server, and ended on `tick`.
+    pub fn ended_on(mut self, tick: u64) -> Self {
+        self.ended_on = Some(tick);
+        self
+    }
+
+    /// Carries on with the chat from the match.
+    pub fn with_chat(mut self, chat: ChatBox) -> Self {
+        self.chat = chat;
+        self
+    }
+
+    fn name(&self, player_id: u32) -> String {
+        match self.players.get(player_id as usize) {
+            Some(player) => player.name.clone(),
+            None => profile::fallback_name(player_id),
+        }
+    }
+
+    fn heading(&self) -> String {
+        if let Some(team) = self.result.winning_team() {
+            let our_team = self
+                .player_id
+                .and_then(|player_id| self.result.player(player_id))
+                .and_then(|player| player.team);
+            return match our_team {
+                Some(our_team) if our_team == team => String::from("Your Team Wins!"),
+                Some(_) => String::from("Your Team Loses"),
+                None => format!("{} Team Wins", team_name(team)),
+            };
+        }
+
+        match (self.result.winner(), self.player_id) {
+            (Some(winner), Some(player_id)) if winner == player_id => String::from("You Win!"),
+            (Some(_), Some(_)) => String::from("You Lose"),
+            (Some(winner), None) => format!("{} Wins", self.name(winner)),
+            (None, _) if self.result.players.len() > 1 => String::from("Draw"),
+            (None, _) => String::from("Game Over"),
+        }
+    }
+
+    // Once anyone has left a match on a server, it can't be played again
+    // with the same players
+    fn can_rematch(&self) -> bool {
+        self.chosen != Some(MenuItem::BackToLobby) && !self.votes.contains(&RematchVote::Left)
+    }
+
+    // Who has voted what, if anyone has yet
+    fn vote_status(&self) -> Option<String> {
+        if let Some(player_id) = self
+            .votes
+            .iter()
+            .position(|vote| *vote == RematchVote::Left)
+        {
+            return Some(format!("{} left", self.name(player_id as u32)));
+        }
+
+        let ready = self
+            .votes
+            .iter()
+            .filter(|vote| **vote == RematchVote::Rematch)
+            .count();
+        if ready > 0 {
+            Some(format!("{}/{} want a rematch", ready, self.votes.len()))
+        } else {
+            None
+        }
+    }
+
+    // The request to make again if the server hasn't acted on it yet
+    fn pending_request(&self) -> Option<ClientMessage> {
+        match self.chosen {
+            Some(MenuItem::Rematch) => {
+                let counted = self
+                    .player_id
+                    .and_then(|player_id| self.votes.get(player_id as usize))
+                    == Some(&RematchVote::Rematch);
+                if counted {
+                    None
+                } else {
+                    Some(ClientMessage::Rematch)
+                }
+            }
+            Some(MenuItem::BackToLobby) => Some(ClientMessage::ReturnToLobby),
+            _ => None,
+        }
+    }
+
+    // The server only lets anyone vote once every player of a lockstep
+    // match has said it's over
+    fn report_end<T: Transport + ?Sized>(&self, socket: &mut Socket<T>, address: SocketAddr) {
+        if let Some(tick) = self.ended_on {
+            if let Err(e) = socket.send(address, ClientMessage::MatchOver { tick }) {
+                warn!("error telling the server the match is over: {}", e);
+            }
+        }
+    }
+
+    fn leave<T: Transport + ?Sized>(&self, socket: &mut Socket<T>) {
+        if let Some(address) = self.address {
+            trace!("sending disconnect to the server");
+            if let Err(e) = socket.send(address, &ClientMessage::Disconnect) {
+                warn!("error sending disconnect to the server: {}", e);
+            }
+        }
+    }
+
+    fn choose<T: Transport + ?Sized>(
+        mut self: Box<Self>,
+        socket: &mut Socket<T>,
+        item: MenuItem,
+    ) -> Box<dyn Scene<T>> {
+        match (item, self.address) {
+            (MenuItem::BackToTitle, _) => self.back_to_title(socket),
+            // A direct match is played again by finding the peer again
+            (MenuItem::Rematch, None) => self.title.start_game(),
+            (MenuItem::Rematch, Some(_)) if !self.can_rematch() => self,
+            (_, Some(address)) => {
+                if self.chosen != Some(item) {
+                    self.chosen = Some(item);
+                    self.report_end(socket, address);
+                    if let Some(request) = self.pending_request() {
+                        if let Err(e) = socket.send(address, request) {
+                            warn!("error sending request to the server: {}", e);
+                        }
+                        self.keepalive.sent();
+                    }
+                }
+                self
+            }
+            (MenuItem::BackToLobby, None) => self,
+        }
+    }
+
+    fn back_to_title<T: Transport + ?Sized>(
+        self: Box<Self>,
+        socket: &mut Socket<T>,
+    ) -> Box<dyn Scene<T>> {
+        self.leave(socket);
+        Box::new(TitleScene::new(self.title))
     }
 }
 
-impl Scene for GameOverScene {
-    fn lifecycle(&mut self, socket: &mut Socket, event: AppLifecycleEvent) {
+impl<T: Transport + ?Sized> Scene<T> for GameOverScene {
+    fn typing(&self) -> bool {
+        self.chat.typing()
+    }
+
+    fn lifecycle(&mut self, socket: &mut Socket<T>, event: AppLifecycleEvent) {
         match event {
-            AppLifecycleEvent::Shutdown => {
-                trace!("sending disconnect to the server");
-                socket
-                    .send(self.address, &ClientMessage::Disconnect)
-                    .unwrap();
-            }
+            AppLifecycleEvent::Shutdown => self.leave(socket),
         }
     }
 
-    fn input(self: Box<Self>, _socket: &mut Socket, _event: Event) -> Box<dyn Scene> {
-        self
+    fn input(mut self: Box<Self>, socket: &mut Socket<T>, event: Event) -> Box<dyn Scene<T>> {
+        if self.address.is_some() && self.chat.input(&event) {
+            return self;
+        }
+
+        match event {
+            Event::KeyDown {
+                keycode: Some(Keycode::Up),
+                ..
+            } => {
+                self.selected = (self.selected + self.menu.len() - 1) % self.menu.len();
+                self
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Down),
+                ..
+            } => {
+                self.selected = (self.selected + 1) % self.menu.len();
+                self
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Return),
+                ..
+            } => {
+                let item = self.menu[self.selected];
+                self.choose(socket, item)
+            }
+            _ => self,
+        }
     }
 
     fn render(&self, renderer: &mut Renderer) {
         renderer.render_text(
-            Text::new("Game Over")
-                .center_xy(400, 300)
-                .height(40)
+            Text::from(self.heading())
+                .center_xy((VIEWPORT_WIDTH / 2) as i32, 50)
+                .height(50)
                 .build(),
         );
+
+        let seconds = self.result.duration.as_secs();
+        renderer.render_text(
+            Text::from(format!("Match lasted {}:{:02}", seconds / 60, seconds % 60))
+                .center_xy((VIEWPORT_WIDTH / 2) as i32, 95)
+                .height(24)
+                .color_gray()
+                .build(),
+        );
+
+        for (title, x) in COLUMNS.iter() {
+            renderer.render_text(
+                Text::new(title)
+                    .left_top_xy(*x, 130)
+                    .height(24)
+                    .color_gray()
+                    .build(),
+            );
+        }
+
+        for (row, player) in self.result.players.iter().take(MAX_ROWS).enumerate() {
+            let y = 165 + row as i32 * ROW_HEIGHT;
+            let color = if Some(player.player_id) == self.player_id {
+                Color::RGB(234, 77, 72)
+            } else {
+                Color::RGB(255, 255, 255)
+            };
+
+            let cells = [
+                player.placement.to_string(),
+                self.name(player.player_id),
+                player.score.to_string(),
+                player.lines.to_string(),
+                player.pieces.to_string(),
+                player.attack_sent.to_string(),
+                player.attack_received.to_string(),
+                player.kos.to_string(),
+            ];
+            for (cell, (_, x)) in cells.iter().zip(COLUMNS.iter()) {
+                renderer.render_text(
+                    Text::from(cell.clone())
+                        .left_top_xy(*x, y)
+                        .height(24)
+                        .color(color)
+                        .build(),
+                );
+            }
+        }
+
+        if let Some(status) = self.vote_status() {
+            renderer.render_text(
+                Text::from(status)
+                    .center_xy(MENU_X, 420)
+                    .height(24)
+                    .color_gray()
+                    .build(),
+            );
+        }
+
+        for (idx, item) in self.menu.iter().enumerate() {
+            let mut text = match (*item, self.chosen) {
+                (MenuItem::Rematch, Some(MenuItem::Rematch)) => Text::new("Rematch (waiting)"),
+                (MenuItem::BackToLobby, Some(MenuItem::BackToLobby)) => Text::new("Leaving..."),
+                _ => Text::new(item.text()),
+            };
+            if idx == self.selected {
+                text.color(Color::RGB(234, 77, 72));
+            } else if *item == MenuItem::Rematch && !self.can_rematch() {
+                text.color_gray();
+            }
+            renderer.render_text(
+                text.center_xy(MENU_X, 460 + idx as i32 * 45)
+                    .height(36)
+                    .build(),
+            );
+        }
+
+        self.chat.render(renderer);
     }
 
     fn handle_message(
-        self: Box<Self>,
-        _socket: &mut Socket,
-        _source_addr: SocketAddr,
-        _message: ServerMessage,
-    ) -> Box<dyn Scene> {
-        self
+        mut self: Box<Self>,
+        socket: &mut Socket<T>,
+        source_addr: SocketAddr,
+        message: ServerMessage,
+    ) -> Box<dyn Scene<T>> {
+        let address = match self.address {
+            Some(address) if address == source_addr => address,
+            _ => return self,
+        };
+        self.keepalive.received();
+
+        match message {
+            ServerMessage::RematchVotes(votes) => {
+                self.votes = votes;
+                self
+            }
+            // Everyone agreed, and the rematch has started once every grid
+            // has arrived
+            ServerMessage::Sync { player_id, part } if self.chosen == Some(MenuItem::Rematch) => {
+                self.snapshot.receive(part);
+                // A grid that isn't in the match can't be ours
+                let ours = (player_id as usize) < self.snapshot.grids().len();
+                if !self.snapshot.is_complete() || !ours {
+                    return self;
+                }
+                let snapshot = std::mem::take(&mut self.snapshot);
+                Box::new(
+                    GameScene::new(Some(player_id), snapshot, address, self.token, self.title)
+                        .with_chat(self.chat),
+                )
+            }
+            ServerMessage::LockstepStart {
+                player_id,
+                settings,
+                players,
+            } if self.chosen == Some(MenuItem::Rematch) && settings.is_playable_by(player_id) => {
+                Box::new(
+                    LockstepScene::new(
+                        player_id,
+                        settings,
+                        players,
+                        LockstepPeer::Server {
+                            addr: address,
+                            token: self.token,
+                        },
+                        self.title,
+                    )
+                    .with_chat(self.chat),
+                )
+            }
+            ServerMessage::Spectate(part) if self.player_id.is_none() && self.chosen.is_none() => {
+                self.snapshot.receive(part);
+                if !self.snapshot.is_complete() {
+                    return self;
+                }
+                let snapshot = std::mem::take(&mut self.snapshot);
+                Box::new(
+                    GameScene::new(None, snapshot, address, self.token, self.title)
+                        .with_chat(self.chat),
+                )
+            }
+            ServerMessage::ReturnedToLobby if self.chosen == Some(MenuItem::BackToLobby) => {
+                Box::new(ConnectScene::lobby(address, self.token, self.title).with_chat(self.chat))
+            }
+            ServerMessage::Kicked { reason } => {
+                error!("kicked by the server: {}", reason);
+                Box::new(DisconnectedScene::new(format!("Kicked: {}", reason)))
+            }
+            ServerMessage::Chat { sequence, line } => {
+                self.chat.receive(socket, address, sequence, line);
+                self
+            }
+            ServerMessage::ChatAck { received } => {
+                self.chat.acknowledge(received);
+                self
+            }
+            _ => self,
+        }
     }
 
     fn update(
-        self: Box<Self>,
-        _socket: &mut Socket,
+        mut self: Box<Self>,
+        socket: &mut Socket<T>,
         _sounds: &mut Vec<GameSoundEvent>,
-    ) -> Box<dyn Scene> {
+    ) -> Box<dyn Scene<T>> {
+        let address = match self.address {
+            Some(address) => address,
+            None => return self,
+        };
+
+        if self.keepalive.timed_out() {
+            error!("lost connection to server after the match");
+            return Box::new(DisconnectedScene::new("Lost connection to server"));
+        }
+
+        // Stay in the match until we choose to leave it, asking again for
+        // whatever we chose in case it was lost
+        if self.keepalive.needs_heartbeat() {
+            self.report_end(socket, address);
+            let message = self.pending_request().unwrap_or(ClientMessage::Heartbeat);
+            if let Err(e) = socket.send(address, message) {
+                warn!("error sending to the server: {}", e);
+            }
+            self.keepalive.sent();
+        }
+        self.chat.update(socket, address);
         self
     }
 }
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn finished_match() -> GameOverScene {
+    use crate::net::DEFAULT_TIMEOUT;
+    use crate::results::PlayerResult;
+    use std::time::Duration;
+
+    let players = (0..2)
+        .map(|player_id| PlayerResult {
+            player_id,
+            team: None,
+            placement: player_id + 1,
+            score: 0,
+            lines: 0,
+            pieces: 0,
+            attack_sent: 0,
+            attack_received: 0,
+            kos: 0,
+        })
+        .collect();
+    let result = MatchResult {
+        duration: Duration::from_secs(60),
+        players,
+    };
+    let address = "127.0.0.1:2".parse().unwrap();
+    let title = TitleSettings {
+        server_addr: address,
+        peer_mode: None,
+        timeout: DEFAULT_TIMEOUT,
+        require_encryption: false,
+    };
+    GameOverScene::new(Some(0), result, Vec::new(), title).on_server(address, 7)
+}
+
+#[test]
+fn test_rematch_vote_is_asked_until_counted() {
+    let mut scene = finished_match();
+    assert!(scene.pending_request().is_none());
+
+    scene.chosen = Some(MenuItem::Rematch);
+    assert!(matches!(
+        scene.pending_request(),
+        Some(ClientMessage::Rematch)
+    ));
+
+    scene.votes = vec![RematchVote::Rematch, RematchVote::Undecided];
+    assert!(scene.pending_request().is_none());
+    assert_eq!(
+        scene.vote_status(),
+        Some(String::from("1/2 want a rematch"))
+    );
+}
+
+#[test]
+fn test_no_rematch_once_someone_left() {
+    let mut scene = finished_match();
+    assert!(scene.can_rematch());
+
+    scene.votes = vec![RematchVote::Undecided, RematchVote::Left];
+    assert!(!scene.can_rematch());
+    assert_eq!(scene.vote_status(), Some(String::from("Player 2 left")));
+}
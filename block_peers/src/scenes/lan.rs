@@ -0,0 +1,260 @@
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::grid::GameSoundEvent;
+use crate::net::{ClientMessage, ServerInfo, ServerMessage, Socket, PROTOCOL_VERSION};
+use crate::render::Renderer;
+use crate::scene::Scene;
+use crate::scenes::{TitleScene, TitleSettings};
+use crate::text::Text;
+use crate::transport::Transport;
+
+// How often the query is broadcast again, to find servers started since
+const QUERY_INTERVAL: Duration = Duration::from_secs(1);
+// Servers that stop answering for this long are taken off the list
+const FORGET_AFTER: Duration = Duration::from_secs(3);
+// Only this many servers fit on the screen
+const MAX_LISTED: usize = 8;
+
+enum Listing {
+    Compatible(ServerInfo),
+    // Answered with a version mismatch, so we can't join it
+    Incompatible { server_version: u32 },
+}
+
+struct FoundServer {
+    addr: SocketAddr,
+    listing: Listing,
+    last_seen: Instant,
+}
+
+/// Lists the servers on the local network that answer a broadcast query,
+/// so the player can pick one instead of typing its address.
+pub struct LanScene {
+    // Usually the broadcast address and the port servers listen on
+    query_addr: SocketAddr,
+    // For connecting to the server that's picked, or going back to the
+    // title screen
+    title: TitleSettings,
+    servers: Vec<FoundServer>,
+    selected: usize,
+    last_query: Option<Instant>,
+    go_back: bool,
+}
+
+impl LanScene {
+    pub fn new(query_addr: SocketAddr, title: TitleSettings) -> Self {
+        Self {
+            query_addr,
+            title,
+            servers: Vec::new(),
+            selected: 0,
+            last_query: None,
+            go_back: false,
+        }
+    }
+
+    fn found(&mut self, addr: SocketAddr, listing: Listing, now: Instant) {
+        match self.servers.iter().position(|server| server.addr == addr) {
+            Some(idx) => {
+                self.servers[idx].listing = listing;
+                self.servers[idx].last_seen = now;
+            }
+            None if self.servers.len() < MAX_LISTED => self.servers.push(FoundServer {
+                addr,
+                listing,
+                last_seen: now,
+            }),
+            None => {}
+        }
+    }
+
+    fn forget_silent_servers(&mut self, now: Instant) {
+        self.servers
+            .retain(|server| now.duration_since(server.last_seen) < FORGET_AFTER);
+        self.selected = self.selected.min(self.servers.len().saturating_sub(1));
+    }
+}
+
+impl<T: Transport + ?Sized> Scene<T> for LanScene {
+    fn input(mut self: Box<Self>, _socket: &mut Socket<T>, event: Event) -> Box<dyn Scene<T>> {
+        if let Event::KeyDown {
+            keycode: Some(keycode),
+            ..
+        } = event
+        {
+            match keycode {
+                Keycode::Up => self.selected = self.selected.saturating_sub(1),
+                Keycode::Down if self.selected + 1 < self.servers.len() => self.selected += 1,
+                Keycode::Backspace => self.go_back = true,
+                Keycode::Return => {
+                    if let Some(FoundServer {
+                        addr,
+                        listing: Listing::Compatible(_),
+                        ..
+                    }) = self.servers.get(self.selected)
+                    {
+                        return self.title.connect_to(*addr);
+                    }
+                }
+                _ => {}
+            }
+        }
+        self
+    }
+
+    fn render(&self, renderer: &mut Renderer) {
+        renderer.render_text(Text::new("LAN Games").center_xy(400, 60).height(50).build());
+
+        if self.servers.is_empty() {
+            renderer.render_text(
+                Text::new("Searching...")
+                    .center_xy(400, 300)
+                    .height(40)
+                    .build(),
+            );
+        }
+
+        for (idx, server) in self.servers.iter().enumerate() {
+            let line = match server.listing {
+                Listing::Compatible(ref info) => format!(
+                    "{}  {}/{} players  {}{}",
+                    info.name,
+                    info.players,
+                    info.players_per_game,
+                    if info.lockstep { "lockstep" } else { "server" },
+                    if info.in_game { "  in game" } else { "" },
+                ),
+                Listing::Incompatible { server_version } => format!(
+                    "{}  version {}, you have {}",
+                    server.addr, server_version, PROTOCOL_VERSION
+                ),
+            };
+            let color = match (idx == self.selected, &server.listing) {
+                (_, Listing::Incompatible { .. }) => Color::RGB(128, 128, 128),
+                (true, _) => Color::RGB(234, 77, 72),
+                (false, _) => Color::RGB(255, 255, 255),
+            };
+
+            renderer.render_text(
+                Text::from(line)
+                    .left_top_xy(80, 130 + 45 * idx as i32)
+                    .height(36)
+                    .color(color)
+                    .build(),
+            );
+        }
+
+        renderer.render_text(
+            Text::new("Enter to join, Backspace to go back")
+                .center_xy(400, 560)
+                .height(30)
+                .color_gray()
+                .build(),
+        );
+    }
+
+    fn handle_message(
+        mut self: Box<Self>,
+        _socket: &mut Socket<T>,
+        source_addr: SocketAddr,
+        message: ServerMessage,
+    ) -> Box<dyn Scene<T>> {
+        let now = Instant::now();
+        match message {
+            ServerMessage::ServerInfo(info) => {
+                let listing = if info.version == PROTOCOL_VERSION {
+                    Listing::Compatible(info)
+                } else {
+                    Listing::Incompatible {
+                        server_version: info.version,
+                    }
+                };
+                self.found(source_addr, listing, now);
+            }
+            ServerMessage::VersionMismatch { server_version } => {
+                self.found(source_addr, Listing::Incompatible { server_version }, now);
+            }
+            _ => {}
+        }
+        self
+    }
+
+    fn update(
+        mut self: Box<Self>,
+        socket: &mut Socket<T>,
+        _sounds: &mut Vec<GameSoundEvent>,
+    ) -> Box<dyn Scene<T>> {
+        if self.go_back {
+            return Box::new(TitleScene::new(self.title));
+        }
+
+        let now = Instant::now();
+        let query_due = match self.last_query {
+            Some(last_query) => now.duration_since(last_query) >= QUERY_INTERVAL,
+            None => true,
+        };
+        if query_due {
+            self.last_query = Some(now);
+            if let Err(e) = socket.send(self.query_addr, ClientMessage::Discover) {
+                error!("unable to look for LAN games: {}", e);
+            }
+            self.forget_silent_servers(now);
+        }
+        self
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn server_info(name: &str) -> ServerInfo {
+    ServerInfo {
+        name: String::from(name),
+        version: PROTOCOL_VERSION,
+        players: 0,
+        players_per_game: 2,
+        lockstep: false,
+        in_game: false,
+    }
+}
+
+#[test]
+fn test_lan_scene_lists_each_server_once_until_it_goes_quiet() {
+    let addr = "127.0.0.1:1".parse().unwrap();
+    let title = TitleSettings {
+        server_addr: addr,
+        peer_mode: None,
+        timeout: Duration::from_secs(10),
+        require_encryption: false,
+    };
+    let mut scene = LanScene::new(addr, title);
+    let first = "127.0.0.1:2".parse().unwrap();
+    let second = "127.0.0.1:3".parse().unwrap();
+    let start = Instant::now();
+
+    scene.found(first, Listing::Compatible(server_info("first")), start);
+    scene.found(second, Listing::Compatible(server_info("second")), start);
+    scene.found(
+        first,
+        Listing::Compatible(server_info("renamed")),
+        start + FORGET_AFTER / 2,
+    );
+    assert_eq!(scene.servers.len(), 2);
+    match scene.servers[0].listing {
+        Listing::Compatible(ref info) => assert_eq!(info.name, "renamed"),
+        _ => panic!("expected a compatible server"),
+    }
+
+    scene.selected = 1;
+    scene.forget_silent_servers(start + FORGET_AFTER);
+    assert_eq!(scene.servers.len(), 1);
+    assert_eq!(scene.servers[0].addr, first);
+    assert_eq!(scene.selected, 0);
+}
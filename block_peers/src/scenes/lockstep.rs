@@ -0,0 +1,290 @@
+use sdl2::event::Event;
+use sdl2::pixels::Color;
+
+use std::net::SocketAddr;
+
+use crate::chat::ChatBox;
+use crate::grid::GameSoundEvent;
+use crate::lockstep::{LockstepMessage, LockstepSession, LockstepSettings, SessionEvent};
+use crate::net::{ClientMessage, Keepalive, ServerMessage, Socket};
+use crate::profile::PlayerInfo;
+use crate::render::Renderer;
+use crate::replay;
+use crate::scene::{AppLifecycleEvent, Scene};
+use crate::scenes::game::{grid_input_event, render_grids};
+use crate::scenes::{DisconnectedScene, GameOverScene, TitleSettings};
+use crate::text::Text;
+use crate::transport::Transport;
+
+/// Who the lockstep messages are exchanged with.
+#[derive(Copy, Clone, Debug)]
+pub enum LockstepPeer {
+    /// A server relays our messages to the other players. The token lets us
+    /// reconnect to it if our address changes.
+    Server { addr: SocketAddr, token: u64 },
+    /// The other peer directly, without any server involved.
+    Direct(SocketAddr),
+}
+
+/// Plays a match where this client simulates every grid itself and only the
+/// inputs of each player are exchanged.
+pub struct LockstepScene {
+    session: LockstepSession,
+    players: Vec<PlayerInfo>,
+    peer: LockstepPeer,
+    // When hosting a direct match, the start message for the other peer.
+    // It's resent until the first message arrives from them.
+    pending_start: Option<(u32, LockstepSettings)>,
+    desynced: bool,
+    keepalive: Keepalive,
+    // What to go back to the title screen with once the match is over
+    title: TitleSettings,
+    // Only used when there's a server to pass messages on
+    chat: ChatBox,
+}
+
+impl LockstepScene {
+    pub fn new(
+        player_id: u32,
+        settings: LockstepSettings,
+        players: Vec<PlayerInfo>,
+        peer: LockstepPeer,
+        title: TitleSettings,
+    ) -> Self {
+        Self {
+            session: LockstepSession::new(player_id, settings),
+            players,
+            peer,
+            pending_start: None,
+            desynced: false,
+            keepalive: Keepalive::new(title.timeout),
+            title,
+            chat: ChatBox::new(),
+        }
+    }
+
+    /// Carries on with the chat from the lobby.
+    pub fn with_chat(mut self, chat: ChatBox) -> Self {
+        self.chat = chat;
+        self
+    }
+
+    /// Starts a direct match hosted by this peer, telling the other peer which
+    /// player it is, what settings to use and who is playing.
+    pub fn host(
+        settings: LockstepSettings,
+        players: Vec<PlayerInfo>,
+        peer_addr: SocketAddr,
+        title: TitleSettings,
+    ) -> Self {
+        let mut scene = Self::new(
+            0,
+            settings.clone(),
+            players,
+            LockstepPeer::Direct(peer_addr),
+            title,
+        );
+        scene.pending_start = Some((1, settings));
+        scene
+    }
+
+    fn send<T: Transport + ?Sized>(&self, socket: &mut Socket<T>, message: LockstepMessage) {
+        let sent = match self.peer {
+            LockstepPeer::Server { addr, .. } => {
+                socket.send(addr, ClientMessage::Lockstep(message))
+            }
+            LockstepPeer::Direct(addr) => socket.send(addr, ServerMessage::Lockstep(message)),
+        };
+        if let Err(e) = sent {
+            warn!("error sending lockstep message: {}", e);
+        }
+    }
+
+    // Keeps what's been confirmed of the match, which is everything when
+    // it ended normally
+    fn save_replay(&self) {
+        match replay::save_to_replay_dir(&self.session.replay()) {
+            Ok(Some(path)) => info!("saved replay to {}", path.display()),
+            Ok(None) => {}
+            Err(e) => error!("error saving replay: {}", e),
+        }
+    }
+
+    fn server_addr(&self) -> Option<SocketAddr> {
+        match self.peer {
+            LockstepPeer::Server { addr, .. } => Some(addr),
+            LockstepPeer::Direct(_) => None,
+        }
+    }
+}
+
+impl<T: Transport + ?Sized> Scene<T> for LockstepScene {
+    fn typing(&self) -> bool {
+        self.chat.typing()
+    }
+
+    fn lifecycle(&mut self, socket: &mut Socket<T>, event: AppLifecycleEvent) {
+        match event {
+            AppLifecycleEvent::Shutdown => {
+                self.save_replay();
+                if let Some(server_addr) = self.server_addr() {
+                    trace!("sending disconnect to the server");
+                    if let Err(e) = socket.send(server_addr, ClientMessage::Disconnect) {
+                        warn!("error sending disconnect to the server: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn input(mut self: Box<Self>, _socket: &mut Socket<T>, event: Event) -> Box<dyn Scene<T>> {
+        if self.server_addr().is_some() && self.chat.input(&event) {
+            return self;
+        }
+
+        if let Event::KeyDown {
+            keycode: Some(keycode),
+            ..
+        } = event
+        {
+            if let Some(event) = grid_input_event(keycode) {
+                self.session.add_local_input(event);
+            }
+        }
+        self
+    }
+
+    fn render(&self, renderer: &mut Renderer) {
+        render_grids(
+            renderer,
+            self.session.grids(),
+            &self.players,
+            Some(self.session.local_player_id()),
+        );
+        self.chat.render(renderer);
+
+        if self.desynced {
+            renderer.render_text(
+                Text::new("Desync detected")
+                    .center_xy(400, 20)
+                    .height(30)
+                    .color(Color::RGB(234, 77, 72))
+                    .build(),
+            );
+        }
+    }
+
+    fn update(
+        mut self: Box<Self>,
+        socket: &mut Socket<T>,
+        sounds: &mut Vec<GameSoundEvent>,
+    ) -> Box<dyn Scene<T>> {
+        if let (Some((player_id, settings)), LockstepPeer::Direct(peer_addr)) =
+            (&self.pending_start, self.peer)
+        {
+            let message = ServerMessage::LockstepStart {
+                player_id: *player_id,
+                settings: settings.clone(),
+                players: self.players.clone(),
+            };
+            if let Err(e) = socket.send(peer_addr, message) {
+                warn!("error sending match start to {}: {}", peer_addr, e);
+            }
+        }
+
+        if self.keepalive.timed_out() {
+            error!("stopped hearing from the other peers");
+            self.save_replay();
+            let message = match self.peer {
+                LockstepPeer::Server { .. } => "Lost connection to server",
+                LockstepPeer::Direct(_) => "Lost connection to peer",
+            };
+            return Box::new(DisconnectedScene::new(message));
+        }
+
+        if let LockstepPeer::Server { addr, token } = self.peer {
+            self.chat.update(socket, addr);
+            if self.keepalive.reconnect_due() {
+                debug!("haven't heard from the other players, trying to reconnect");
+                if let Err(e) = socket.send(addr, ClientMessage::Reconnect { token }) {
+                    warn!("error sending reconnect to the server: {}", e);
+                }
+            }
+        }
+
+        if !self.session.advance() {
+            trace!("waiting on input from other peers");
+        }
+
+        for message in self.session.drain_outgoing() {
+            self.send(socket, message);
+        }
+
+        for event in self.session.drain_events() {
+            match event {
+                SessionEvent::Desync { .. } => self.desynced = true,
+            }
+        }
+
+        if let Some(result) = self.session.result() {
+            self.save_replay();
+            let scene = GameOverScene::new(
+                Some(self.session.local_player_id()),
+                result,
+                self.players,
+                self.title,
+            );
+            match self.peer {
+                LockstepPeer::Server { addr, token } => {
+                    let mut scene = scene.on_server(addr, token);
+                    if let Some(tick) = self.session.ended_on() {
+                        scene = scene.ended_on(tick);
+                    }
+                    Box::new(scene.with_chat(self.chat))
+                }
+                LockstepPeer::Direct(_) => Box::new(scene),
+            }
+        } else {
+            self.session.drain_sound_events(sounds);
+            self
+        }
+    }
+
+    fn handle_message(
+        mut self: Box<Self>,
+        socket: &mut Socket<T>,
+        source_addr: SocketAddr,
+        message: ServerMessage,
+    ) -> Box<dyn Scene<T>> {
+        let expected_addr = match self.peer {
+            LockstepPeer::Server { addr, .. } | LockstepPeer::Direct(addr) => addr,
+        };
+        if source_addr != expected_addr {
+            return self;
+        }
+
+        match message {
+            // Only count messages from the other players, a relaying server
+            // staying alive doesn't mean they are
+            ServerMessage::Lockstep(message) => {
+                self.keepalive.received();
+                self.pending_start = None;
+                self.session.receive(message);
+                self
+            }
+            ServerMessage::ConnectionRejected => {
+                error!("server would not let us back into the match");
+                Box::new(DisconnectedScene::new("Could not rejoin the match"))
+            }
+            ServerMessage::Chat { sequence, line } => {
+                self.chat.receive(socket, source_addr, sequence, line);
+                self
+            }
+            ServerMessage::ChatAck { received } => {
+                self.chat.acknowledge(received);
+                self
+            }
+            _ => self,
+        }
+    }
+}
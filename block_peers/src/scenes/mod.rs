@@ -1,9 +1,19 @@
 pub mod connect;
+pub mod disconnected;
 pub mod game;
 pub mod game_over;
+pub mod lan;
+pub mod lockstep;
+pub mod peer_connect;
+pub mod replay;
 pub mod title;
 
 pub use crate::scenes::connect::ConnectScene;
+pub use crate::scenes::disconnected::DisconnectedScene;
 pub use crate::scenes::game::GameScene;
 pub use crate::scenes::game_over::GameOverScene;
-pub use crate::scenes::title::TitleScene;
+pub use crate::scenes::lan::LanScene;
+pub use crate::scenes::lockstep::LockstepScene;
+pub use crate::scenes::peer_connect::PeerConnectScene;
+pub use crate::scenes::replay::ReplayScene;
+pub use crate::scenes::title::{TitleScene, TitleSettings};
@@ -0,0 +1,235 @@
+use sdl2::event::Event;
+
+use std::net::SocketAddr;
+
+use crate::grid::GameSoundEvent;
+use crate::lockstep::{LockstepSettings, DEFAULT_INPUT_DELAY, GRID_HEIGHT, GRID_WIDTH};
+use crate::net::{ClientMessage, ServerMessage, Socket};
+use crate::profile::{self, PlayerInfo};
+use crate::render::Renderer;
+use crate::scene::Scene;
+use crate::scenes::lockstep::LockstepPeer;
+use crate::scenes::{LockstepScene, TitleSettings};
+use crate::simulation::Rules;
+use crate::text::Text;
+use crate::transport::Transport;
+
+// ~10 seconds at 60 fps
+const MAX_CONNECTION_ATTEMPTS: u64 = 600;
+// Only send handshake packets every few ticks, no need to flood the peer
+const SEND_INTERVAL: u64 = 10;
+
+/// How to find the other peer when playing without a server.
+#[derive(Clone, Debug)]
+pub enum PeerMode {
+    /// Wait for another peer to join on our socket.
+    Host,
+    /// Connect directly to a peer that is hosting.
+    Join(SocketAddr),
+    /// Have an introducer pair us up with another peer that registered with
+    /// the same session name.
+    Rendezvous {
+        introducer: SocketAddr,
+        session: String,
+    },
+}
+
+enum PeerState {
+    // Registering with the introducer until it tells us who the other
+    // peer is
+    Registering {
+        introducer: SocketAddr,
+        session: String,
+    },
+    // Waiting for a peer to say hello so we can start the match. When we
+    // were introduced we already know who to expect, and keep sending
+    // hellos ourselves to open up any NAT in front of us.
+    Hosting {
+        expected_peer: Option<SocketAddr>,
+    },
+    // Saying hello to the host until it tells us to start
+    Joining {
+        peer_addr: SocketAddr,
+    },
+    // We've tried reaching the peer for X seconds without an answer
+    TimedOut,
+}
+
+/// Sets up a two player lockstep match directly between two clients. The
+/// hosting peer picks the settings for the match.
+pub struct PeerConnectScene {
+    state: PeerState,
+    tick_counter: u64,
+    // Carried into the match, for going back to the title screen
+    title: TitleSettings,
+}
+
+impl PeerConnectScene {
+    pub fn new(mode: PeerMode, title: TitleSettings) -> Self {
+        let state = match mode {
+            PeerMode::Host => PeerState::Hosting {
+                expected_peer: None,
+            },
+            PeerMode::Join(peer_addr) => PeerState::Joining { peer_addr },
+            PeerMode::Rendezvous {
+                introducer,
+                session,
+            } => PeerState::Registering {
+                introducer,
+                session,
+            },
+        };
+
+        Self {
+            state,
+            tick_counter: 0,
+            title,
+        }
+    }
+
+    fn start_hosting<T: Transport + ?Sized>(
+        &self,
+        peer_addr: SocketAddr,
+        peer: PlayerInfo,
+    ) -> Box<dyn Scene<T>> {
+        debug!("starting direct match with {} ({})", peer_addr, peer.name);
+
+        let settings = LockstepSettings {
+            num_players: 2,
+            seed: rand::random(),
+            input_delay: DEFAULT_INPUT_DELAY,
+            grid_height: GRID_HEIGHT,
+            grid_width: GRID_WIDTH,
+            rules: Rules::Versus,
+        };
+        // The host is always the first player. The name the peer sent is
+        // cleaned up like the server would, since it's drawn as given.
+        let players = vec![
+            PlayerInfo::from_profile(&profile::local_profile(), 0),
+            PlayerInfo::from_profile(
+                &profile::Profile {
+                    name: peer.name,
+                    id: None,
+                },
+                1,
+            ),
+        ];
+        Box::new(LockstepScene::host(
+            settings,
+            players,
+            peer_addr,
+            self.title.clone(),
+        ))
+    }
+}
+
+impl<T: Transport + ?Sized> Scene<T> for PeerConnectScene {
+    fn input(self: Box<Self>, _socket: &mut Socket<T>, _event: Event) -> Box<dyn Scene<T>> {
+        self
+    }
+
+    fn render(&self, renderer: &mut Renderer) {
+        let message = match self.state {
+            PeerState::Registering { .. } => "Looking for a peer...",
+            PeerState::Hosting { .. } => "Waiting for a peer to join...",
+            PeerState::Joining { .. } => "Connecting to peer...",
+            PeerState::TimedOut => "Timed Out",
+        };
+
+        renderer.render_text(Text::new(message).center_xy(400, 300).height(40).build());
+    }
+
+    fn update(
+        mut self: Box<Self>,
+        socket: &mut Socket<T>,
+        _sounds: &mut Vec<GameSoundEvent>,
+    ) -> Box<dyn Scene<T>> {
+        self.tick_counter += 1;
+        if !self.tick_counter.is_multiple_of(SEND_INTERVAL) {
+            return self;
+        }
+
+        match self.state {
+            PeerState::Registering {
+                introducer,
+                ref session,
+            } => {
+                let message = ClientMessage::Rendezvous {
+                    session: session.clone(),
+                };
+                if let Err(e) = socket.send(introducer, message) {
+                    warn!("error registering with the rendezvous server: {}", e);
+                }
+            }
+            PeerState::Hosting {
+                expected_peer: Some(peer_addr),
+            }
+            | PeerState::Joining { peer_addr } => {
+                if self.tick_counter >= MAX_CONNECTION_ATTEMPTS {
+                    self.state = PeerState::TimedOut;
+                } else {
+                    let hello = PlayerInfo::from_profile(&profile::local_profile(), 0);
+                    if let Err(e) = socket.send(peer_addr, ServerMessage::PeerHello(hello)) {
+                        warn!("error saying hello to {}: {}", peer_addr, e);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        self
+    }
+
+    fn handle_message(
+        mut self: Box<Self>,
+        _socket: &mut Socket<T>,
+        source_addr: SocketAddr,
+        message: ServerMessage,
+    ) -> Box<dyn Scene<T>> {
+        match (&self.state, message) {
+            (
+                PeerState::Registering { introducer, .. },
+                ServerMessage::Introduction {
+                    peer_addr,
+                    player_id,
+                },
+            ) if source_addr == *introducer => {
+                debug!("introduced to {} as player {}", peer_addr, player_id);
+
+                self.tick_counter = 0;
+                self.state = if player_id == 0 {
+                    PeerState::Hosting {
+                        expected_peer: Some(peer_addr),
+                    }
+                } else {
+                    PeerState::Joining { peer_addr }
+                };
+                self
+            }
+            (PeerState::Hosting { expected_peer }, ServerMessage::PeerHello(peer)) => {
+                match expected_peer {
+                    Some(peer_addr) if *peer_addr != source_addr => self,
+                    _ => self.start_hosting(source_addr, peer),
+                }
+            }
+            (
+                PeerState::Joining { peer_addr },
+                ServerMessage::LockstepStart {
+                    player_id,
+                    settings,
+                    players,
+                },
+            ) if source_addr == *peer_addr && settings.is_playable_by(player_id) => {
+                debug!("joined direct match with {}", peer_addr);
+                Box::new(LockstepScene::new(
+                    player_id,
+                    settings,
+                    players,
+                    LockstepPeer::Direct(*peer_addr),
+                    self.title,
+                ))
+            }
+            _ => self,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+use std::net::SocketAddr;
+
+use crate::grid::GameSoundEvent;
+use crate::net::{ServerMessage, Socket};
+use crate::render::{Renderer, VIEWPORT_WIDTH};
+use crate::replay::{Replay, ReplayPlayer};
+use crate::scene::Scene;
+use crate::scenes::game::render_grids;
+use crate::text::Text;
+use crate::transport::Transport;
+
+// Playback speeds the arrow keys step through, normal speed included
+const SPEEDS: [f32; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+const NORMAL_SPEED: usize = 2;
+// The client updates this often, and a replay plays at normal speed when
+// it's stepped as many times a second as it was recorded at
+const UPDATES_PER_SECOND: f32 = 60.0;
+// How far Left and Right jump
+const SEEK_SECONDS: u32 = 5;
+
+/// Plays back a recorded match, with the keys to pause, seek and change
+/// how fast it goes.
+pub struct ReplayScene {
+    player: ReplayPlayer,
+    speed: usize,
+    paused: bool,
+    // Ticks owed to the player, which builds up slower than one per update
+    // below normal speed
+    progress: f32,
+}
+
+impl ReplayScene {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            player: ReplayPlayer::new(replay),
+            speed: NORMAL_SPEED,
+            paused: false,
+            progress: 0.0,
+        }
+    }
+
+    fn advance(&mut self) {
+        if self.paused || self.player.finished() {
+            return;
+        }
+
+        let tick_rate = self.player.replay().settings().tick_rate as f32;
+        self.progress += SPEEDS[self.speed] * tick_rate / UPDATES_PER_SECOND;
+        while self.progress >= 1.0 {
+            self.player.step();
+            self.progress -= 1.0;
+        }
+    }
+
+    fn seek_ticks(&self) -> i64 {
+        i64::from(SEEK_SECONDS * self.player.replay().settings().tick_rate)
+    }
+
+    fn seek_by(&mut self, ticks: i64) {
+        let tick = (i64::from(self.player.tick()) + ticks).max(0) as u32;
+        self.player.seek(tick);
+        self.progress = 0.0;
+    }
+}
+
+impl<T: Transport + ?Sized> Scene<T> for ReplayScene {
+    fn input(mut self: Box<Self>, _socket: &mut Socket<T>, event: Event) -> Box<dyn Scene<T>> {
+        if let Event::KeyDown {
+            keycode: Some(keycode),
+            ..
+        } = event
+        {
+            match keycode {
+                Keycode::Space => self.paused = !self.paused,
+                Keycode::Left => self.seek_by(-self.seek_ticks()),
+                Keycode::Right => self.seek_by(self.seek_ticks()),
+                Keycode::Up if self.speed + 1 < SPEEDS.len() => self.speed += 1,
+                Keycode::Down if self.speed > 0 => self.speed -= 1,
+                Keycode::Home => self.seek_by(-i64::from(self.player.tick())),
+                _ => {}
+            }
+        }
+        self
+    }
+
+    fn render(&self, renderer: &mut Renderer) {
+        // Replays don't know who played, so the grids are numbered
+        render_grids(renderer, self.player.grids(), &[], None);
+
+        let status = format!(
+            "{}/{}  {}x{}",
+            self.player.tick(),
+            self.player.length(),
+            SPEEDS[self.speed],
+            if self.paused { "  paused" } else { "" },
+        );
+        renderer.render_text(
+            Text::from(status)
+                .center_xy((VIEWPORT_WIDTH / 2) as i32, 20)
+                .height(30)
+                .build(),
+        );
+    }
+
+    fn handle_message(
+        self: Box<Self>,
+        _socket: &mut Socket<T>,
+        _source_addr: SocketAddr,
+        _message: ServerMessage,
+    ) -> Box<dyn Scene<T>> {
+        self
+    }
+
+    fn update(
+        mut self: Box<Self>,
+        _socket: &mut Socket<T>,
+        sounds: &mut Vec<GameSoundEvent>,
+    ) -> Box<dyn Scene<T>> {
+        self.advance();
+
+        // Sped up, the sounds would pile on top of each other
+        let audible = self.speed == NORMAL_SPEED;
+        for grid in self.player.grids_mut().iter_mut() {
+            if audible {
+                sounds.extend(grid.sound_events.iter().cloned());
+            }
+            grid.sound_events.clear();
+        }
+        self
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn empty_replay(length: u32) -> Replay {
+    use crate::replay::ReplaySettings;
+    use crate::simulation::Rules;
+
+    let mut replay = Replay::new(ReplaySettings {
+        num_players: 1,
+        seed: 3,
+        grid_height: 20,
+        grid_width: 10,
+        tick_rate: 60,
+        rules: Rules::Versus,
+    });
+    for _ in 0..length {
+        replay.push_tick(&[]);
+    }
+    replay
+}
+
+#[test]
+fn test_replay_scene_plays_at_the_chosen_speed() {
+    let mut scene = ReplayScene::new(empty_replay(1000));
+
+    scene.speed = 0;
+    for _ in 0..8 {
+        scene.advance();
+    }
+    assert_eq!(scene.player.tick(), 2);
+
+    scene.speed = SPEEDS.len() - 1;
+    scene.advance();
+    assert_eq!(scene.player.tick(), 10);
+
+    scene.paused = true;
+    scene.advance();
+    assert_eq!(scene.player.tick(), 10);
+
+    scene.seek_by(-scene.seek_ticks());
+    assert_eq!(scene.player.tick(), 0);
+}
@@ -3,27 +3,60 @@ use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::slice::Iter;
 use std::sync::atomic::Ordering;
+use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::ai::DumbAI;
 use crate::brick::CELL_SIZE;
-use crate::grid::Grid;
+use crate::grid::{GameSoundEvent, Grid};
 use crate::image::Image;
 use crate::net::{ServerMessage, Socket};
 use crate::piece::Piece;
 use crate::render::{Opacity, Renderer, VIEWPORT_HEIGHT, VIEWPORT_WIDTH};
-use crate::scene::{GameSoundEvent, Scene};
-use crate::scenes::ConnectScene;
+use crate::scene::Scene;
+use crate::scenes::peer_connect::PeerMode;
+use crate::scenes::{ConnectScene, LanScene, PeerConnectScene};
 use crate::sound::SOUND_IS_ENABLED;
 use crate::text::Text;
+use crate::transport::Transport;
 
 const GAME_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// What the title screen was opened with. It's carried along through the
+/// match so the player can go back to it, or straight into the next one.
+#[derive(Clone)]
+pub struct TitleSettings {
+    pub server_addr: SocketAddr,
+    // Set when playing directly against another peer instead of
+    // connecting to the server
+    pub peer_mode: Option<PeerMode>,
+    // How long to wait without hearing from the server or peer before
+    // giving up on the connection
+    pub timeout: Duration,
+    // Refuse to play on a server that won't encrypt the connection
+    pub require_encryption: bool,
+}
+
+impl TitleSettings {
+    /// Starts looking for a match the way "Start Game" does.
+    pub fn start_game<T: Transport + ?Sized>(&self) -> Box<dyn Scene<T>> {
+        match self.peer_mode {
+            Some(ref mode) => Box::new(PeerConnectScene::new(mode.clone(), self.clone())),
+            None => self.connect_to(self.server_addr),
+        }
+    }
+
+    /// Joins the server at `server_addr` with these settings.
+    pub fn connect_to<T: Transport + ?Sized>(&self, server_addr: SocketAddr) -> Box<dyn Scene<T>> {
+        Box::new(ConnectScene::new(server_addr, self.clone()))
+    }
+}
+
 pub struct TitleScene {
-    server_addr: SocketAddr,
+    settings: TitleSettings,
     ai: DumbAI,
     state: MenuState,
     should_quit: bool,
@@ -32,7 +65,7 @@ pub struct TitleScene {
 }
 
 impl TitleScene {
-    pub fn new(server_addr: SocketAddr) -> Self {
+    pub fn new(settings: TitleSettings) -> Self {
         let width = VIEWPORT_WIDTH / CELL_SIZE;
         let height = VIEWPORT_HEIGHT / CELL_SIZE;
         let mut background_grid = Grid::new(height, width);
@@ -46,7 +79,7 @@ impl TitleScene {
         background_grid.place_piece_at_bottom(Piece::new(1).rotate().move_right_times(8));
 
         Self {
-            server_addr,
+            settings,
             ai: DumbAI::new(background_grid),
             state: MenuState::StartGame,
             should_quit: false,
@@ -56,14 +89,23 @@ impl TitleScene {
     }
 }
 
-impl Scene for TitleScene {
-    fn input(mut self: Box<Self>, _socket: &mut Socket, event: Event) -> Box<dyn Scene> {
+impl<T: Transport + ?Sized> Scene<T> for TitleScene {
+    fn input(mut self: Box<Self>, _socket: &mut Socket<T>, event: Event) -> Box<dyn Scene<T>> {
         match event {
             Event::KeyDown {
                 keycode: Some(Keycode::Return),
                 ..
             } => match self.state {
-                MenuState::StartGame => Box::new(ConnectScene::new(self.server_addr)),
+                MenuState::StartGame => self.settings.start_game(),
+                MenuState::FindLanGames => {
+                    // Servers on the local network listen on the same port
+                    // as the one we'd connect to
+                    let query_addr = SocketAddr::new(
+                        Ipv4Addr::BROADCAST.into(),
+                        self.settings.server_addr.port(),
+                    );
+                    Box::new(LanScene::new(query_addr, self.settings))
+                }
                 MenuState::ToggleSound => {
                     if SOUND_IS_ENABLED.load(Ordering::Relaxed) {
                         SOUND_IS_ENABLED.store(false, Ordering::Relaxed);
@@ -191,18 +233,18 @@ impl Scene for TitleScene {
 
     fn handle_message(
         self: Box<Self>,
-        _socket: &mut Socket,
+        _socket: &mut Socket<T>,
         _source_addr: SocketAddr,
         _message: ServerMessage,
-    ) -> Box<dyn Scene> {
+    ) -> Box<dyn Scene<T>> {
         self
     }
 
     fn update(
         mut self: Box<Self>,
-        _socket: &mut Socket,
+        _socket: &mut Socket<T>,
         sounds: &mut Vec<GameSoundEvent>,
-    ) -> Box<dyn Scene> {
+    ) -> Box<dyn Scene<T>> {
         self.ai.update();
 
         if self.should_stop_sounds {
@@ -224,6 +266,7 @@ impl Scene for TitleScene {
 #[derive(Debug, PartialEq)]
 enum MenuState {
     StartGame,
+    FindLanGames,
     ToggleSound,
     Quit,
 }
@@ -231,7 +274,7 @@ enum MenuState {
 impl MenuState {
     fn iter() -> Iter<'static, MenuState> {
         use MenuState::*;
-        static STATES: [MenuState; 3] = [StartGame, ToggleSound, Quit];
+        static STATES: [MenuState; 4] = [StartGame, FindLanGames, ToggleSound, Quit];
         STATES.iter()
     }
 
@@ -239,6 +282,7 @@ impl MenuState {
         use MenuState::*;
         match self {
             StartGame => "Start Game",
+            FindLanGames => "Find LAN Games",
             ToggleSound => "Toggle Sound",
             Quit => "Quit",
         }
@@ -247,7 +291,8 @@ impl MenuState {
     fn next(&self) -> Self {
         use MenuState::*;
         match self {
-            StartGame => ToggleSound,
+            StartGame => FindLanGames,
+            FindLanGames => ToggleSound,
             ToggleSound => Quit,
             Quit => StartGame,
         }
@@ -257,7 +302,8 @@ impl MenuState {
         use MenuState::*;
         match self {
             StartGame => Quit,
-            ToggleSound => StartGame,
+            FindLanGames => StartGame,
+            ToggleSound => FindLanGames,
             Quit => ToggleSound,
         }
     }
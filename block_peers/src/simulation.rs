@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+
+use crate::grid::{fnv1a_hash, Grid, GridAttackEvent, GridInputEvent};
+use crate::royale;
+use crate::wire::{Reader, Writer};
+
+/// Who attacks go to, and what else decides a match besides topping out.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Rules {
+    /// Every attack hits every opponent.
+    Versus,
+    /// Attacks go to one target at a time, and KOs earn badges that make
+    /// them hit harder. See `royale`.
+    BattleRoyale,
+    /// The team each player is on, in player_id order. Attacks hit
+    /// everyone on the other teams, and a team is out once all of its
+    /// players have topped out.
+    Teams(Vec<u32>),
+}
+
+impl Rules {
+    /// Splits the players into `teams` teams, putting each on the team
+    /// they chose where they did. Everyone else is dealt in turn onto
+    /// whichever team is shortest, so with no choices player `n` is on team
+    /// `n % teams`. Choices that would leave nobody to play against are
+    /// ignored and everyone is dealt out.
+    pub fn teams(teams: u32, choices: &[Option<u32>]) -> Self {
+        let teams = teams.max(1);
+        let choices: Vec<Option<u32>> = choices
+            .iter()
+            .map(|choice| choice.filter(|team| *team < teams))
+            .collect();
+        let chosen = deal_teams(teams, &choices);
+        if choices.len() > 1 && chosen.iter().all(|team| *team == chosen[0]) {
+            return Rules::Teams(deal_teams(teams, &vec![None; choices.len()]));
+        }
+        Rules::Teams(chosen)
+    }
+
+    /// The team a player is on, if the match is played in teams.
+    pub fn team(&self, player_id: u32) -> Option<u32> {
+        match self {
+            Rules::Teams(teams) => teams.get(player_id as usize).copied(),
+            _ => None,
+        }
+    }
+
+    /// Whether attacks from one player should hit the other.
+    pub fn are_opponents(&self, player_id: u32, other_player_id: u32) -> bool {
+        player_id != other_player_id
+            && match self.team(player_id) {
+                Some(team) => self.team(other_player_id) != Some(team),
+                None => true,
+            }
+    }
+}
+
+// -----------
+// Wire Format
+// -----------
+//
+// A byte for the kind of rules. A team match follows it with the number of
+// players and then each player's team.
+impl Rules {
+    pub fn write(&self, writer: &mut Writer) {
+        match self {
+            Rules::Versus => writer.u8(0),
+            Rules::BattleRoyale => writer.u8(1),
+            Rules::Teams(teams) => {
+                writer.u8(2);
+                writer.varint(teams.len() as u64);
+                for team in teams.iter() {
+                    writer.varint((*team).into());
+                }
+            }
+        }
+    }
+
+    pub fn read(reader: &mut Reader) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Rules::Versus),
+            1 => Some(Rules::BattleRoyale),
+            2 => {
+                let num_players = reader.varint_u32()?;
+                if num_players == 0 || num_players > royale::MAX_PLAYERS {
+                    return None;
+                }
+                // There can't be more teams than players
+                let mut teams = Vec::with_capacity(num_players as usize);
+                for _ in 0..num_players {
+                    match reader.varint_u32()? {
+                        team if team < num_players => teams.push(team),
+                        _ => return None,
+                    }
+                }
+                Some(Rules::Teams(teams))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Puts everyone who didn't choose a team on the shortest one, in turn
+fn deal_teams(teams: u32, choices: &[Option<u32>]) -> Vec<u32> {
+    let mut sizes = vec![0; teams as usize];
+    for team in choices.iter().flatten() {
+        sizes[*team as usize] += 1;
+    }
+    choices
+        .iter()
+        .map(|choice| match choice {
+            Some(team) => *team,
+            None => {
+                let shortest = (0..teams)
+                    .min_by_key(|team| sizes[*team as usize])
+                    .unwrap_or(0);
+                sizes[shortest as usize] += 1;
+                shortest
+            }
+        })
+        .collect()
+}
+
+/// Creates the grids for a new match. Each grid gets its own seed derived from
+/// `seed` so the whole match can be recreated from that single number.
+pub fn new_grids(height: u32, width: u32, num_players: u32, seed: u64) -> Vec<Grid> {
+    (0..num_players)
+        .map(|player_id| Grid::with_seed(height, width, seed.wrapping_add(player_id as u64)))
+        .collect()
+}
+
+/// Advances every grid in a match by a single tick.
+///
+/// The inputs are applied first, in the order given, then each grid is updated
+/// and any attacks are delivered to the other grids as the rules say. The
+/// server and lockstep peers both go through here so they simulate a match in
+/// exactly the same way.
+pub fn step(grids: &mut [Grid], inputs: &[(u32, GridInputEvent)], rules: &Rules) {
+    let standing: Vec<bool> = grids.iter().map(|grid| !grid.gameover).collect();
+    for (player_id, event) in inputs.iter() {
+        if let Some(grid) = grids.get_mut(*player_id as usize) {
+            grid.handle_input(*event);
+        }
+    }
+
+    let mut attacks: Vec<(u32, GridAttackEvent)> = Vec::new();
+    for (i, grid) in grids.iter_mut().enumerate() {
+        if let Some(attack) = grid.update() {
+            attacks.push((i as u32, attack));
+        }
+    }
+
+    match rules {
+        Rules::BattleRoyale => royale::resolve(grids, &attacks, &standing),
+        _ => {
+            for (from_player_id, attack) in attacks.iter() {
+                for (i, grid) in grids.iter_mut().enumerate() {
+                    if rules.are_opponents(*from_player_id, i as u32) {
+                        grid.attack(*attack);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Combined hash of the state of all grids in a match.
+pub fn state_hash(grids: &[Grid]) -> u64 {
+    let hashes: Vec<u8> = grids
+        .iter()
+        .flat_map(|grid| grid.state_hash().to_le_bytes().to_vec())
+        .collect();
+    fnv1a_hash(&hashes)
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_players_play_on_the_teams_they_chose() {
+    // Nobody chose, so everyone is dealt out in turn
+    assert_eq!(Rules::teams(2, &[None; 4]), Rules::Teams(vec![0, 1, 0, 1]));
+
+    // Not how they'd have been dealt
+    let rules = Rules::teams(2, &[Some(1), Some(1), Some(0), Some(0)]);
+    assert_eq!(rules, Rules::Teams(vec![1, 1, 0, 0]));
+    assert!(!rules.are_opponents(0, 1));
+    assert!(rules.are_opponents(1, 2));
+    assert!(!rules.are_opponents(2, 3));
+
+    // Everyone else fills in the shortest teams around the choices
+    let rules = Rules::teams(2, &[None, Some(0), None, Some(0)]);
+    assert_eq!(rules, Rules::Teams(vec![1, 0, 1, 0]));
+    let rules = Rules::teams(3, &[Some(2), None, None, Some(7)]);
+    assert_eq!(rules, Rules::Teams(vec![2, 0, 1, 0]));
+
+    // There has to be someone to play against
+    let rules = Rules::teams(2, &[Some(1), Some(1), Some(1)]);
+    assert_eq!(rules, Rules::Teams(vec![0, 1, 0]));
+}
@@ -0,0 +1,229 @@
+//! Getting the grids of a match to clients a datagram at a time.
+//!
+//! There's no fragmentation, so a snapshot of every grid in a big match
+//! wouldn't fit in one packet. Instead it's split into parts that each hold
+//! a run of the grids along with who plays them, and clients keep each grid
+//! as of the newest part it came in. A lost part only leaves those grids a
+//! snapshot behind until the next one.
+
+use serde::{Deserialize, Serialize};
+
+use std::borrow::Cow;
+
+use crate::grid::Grid;
+use crate::profile::{self, PlayerInfo};
+use crate::royale;
+
+/// Most bytes of grids and players put in one part, unless a single grid
+/// takes more than that. Leaves plenty of room in a packet for framing.
+pub const MAX_PART_BYTES: usize = 2048;
+/// Most sound events sent along with a grid. A client that's only sent a
+/// snapshot every so often could otherwise be owed any number of them.
+pub const MAX_SOUNDS: usize = 32;
+// What bincode adds to each grid and player for lengths and the team
+const GRID_OVERHEAD: usize = 8;
+const PLAYER_OVERHEAD: usize = 13;
+
+/// A run of the grids in a match as they were on `tick`.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SnapshotPart<'a> {
+    pub tick: u64,
+    /// Grids in the whole match.
+    pub num_grids: u32,
+    /// Which grid the run starts with.
+    pub first_grid: u32,
+    pub grids: Cow<'a, [Grid]>,
+    /// Who plays each of the grids, in the same order.
+    pub players: Cow<'a, [PlayerInfo]>,
+}
+
+/// Splits every grid on `tick` into as few parts as it takes to keep each
+/// under `MAX_PART_BYTES`.
+pub fn split<'a>(tick: u64, grids: &'a [Grid], players: &'a [PlayerInfo]) -> Vec<SnapshotPart<'a>> {
+    let mut parts = Vec::new();
+    let mut first = 0;
+    let mut bytes = 0;
+    for (idx, grid) in grids.iter().enumerate() {
+        let player_bytes = players
+            .get(idx)
+            .map_or(0, |player| player.name.len() + PLAYER_OVERHEAD);
+        let size = grid.to_wire().len() + GRID_OVERHEAD + player_bytes;
+        if idx > first && bytes + size > MAX_PART_BYTES {
+            parts.push(part(tick, grids, players, first, idx));
+            first = idx;
+            bytes = 0;
+        }
+        bytes += size;
+    }
+    if first < grids.len() {
+        parts.push(part(tick, grids, players, first, grids.len()));
+    }
+    parts
+}
+
+fn part<'a>(
+    tick: u64,
+    grids: &'a [Grid],
+    players: &'a [PlayerInfo],
+    start: usize,
+    end: usize,
+) -> SnapshotPart<'a> {
+    let players = if players.len() >= end {
+        &players[start..end]
+    } else {
+        &[]
+    };
+    SnapshotPart {
+        tick,
+        num_grids: grids.len() as u32,
+        first_grid: start as u32,
+        grids: Cow::Borrowed(&grids[start..end]),
+        players: Cow::Borrowed(players),
+    }
+}
+
+/// Every grid in a match, kept up to date from the parts of snapshots as
+/// they arrive.
+#[derive(Default)]
+pub struct Snapshot {
+    // Newest tick any part came from
+    tick: u64,
+    // Tick each grid was last updated on, None until it first arrives
+    grid_ticks: Vec<Option<u64>>,
+    grids: Vec<Grid>,
+    players: Vec<PlayerInfo>,
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the grids from a part, other than ones we already have from a
+    /// newer part. Parts that don't fit this match are ignored.
+    pub fn receive(&mut self, part: SnapshotPart) {
+        let num_grids = part.num_grids as usize;
+        let end = part.first_grid as usize + part.grids.len();
+        if num_grids == 0
+            || part.num_grids > royale::MAX_PLAYERS
+            || end > num_grids
+            || (!part.players.is_empty() && part.players.len() != part.grids.len())
+        {
+            return;
+        }
+
+        if self.grids.is_empty() {
+            // Grids that haven't arrived yet are held by a stand-in, which
+            // is never shown since the match isn't until they're all here
+            let stand_in = match part.grids.first() {
+                Some(grid) => grid.clone(),
+                None => return,
+            };
+            self.grid_ticks = vec![None; num_grids];
+            self.grids = vec![stand_in; num_grids];
+            self.players = (0..part.num_grids)
+                .map(|player_id| PlayerInfo {
+                    name: profile::fallback_name(player_id),
+                    team: None,
+                })
+                .collect();
+        } else if self.grids.len() != num_grids {
+            return;
+        }
+
+        for (offset, grid) in part.grids.iter().enumerate() {
+            let idx = part.first_grid as usize + offset;
+            if self.grid_ticks[idx].is_some_and(|tick| tick >= part.tick) {
+                continue;
+            }
+            self.grid_ticks[idx] = Some(part.tick);
+            self.grids[idx] = grid.clone();
+            if let Some(player) = part.players.get(offset) {
+                self.players[idx] = player.clone();
+            }
+        }
+        self.tick = self.tick.max(part.tick);
+    }
+
+    /// Whether every grid has arrived at least once, so the match can be
+    /// shown.
+    pub fn is_complete(&self) -> bool {
+        !self.grid_ticks.is_empty() && self.grid_ticks.iter().all(Option::is_some)
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn grids(&self) -> &[Grid] {
+        &self.grids
+    }
+
+    /// Lets the sounds that came with the grids be taken once played.
+    pub fn grids_mut(&mut self) -> &mut [Grid] {
+        &mut self.grids
+    }
+
+    pub fn players(&self) -> &[PlayerInfo] {
+        &self.players
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn test_players(count: u32) -> Vec<PlayerInfo> {
+    (0..count)
+        .map(|player_id| PlayerInfo {
+            name: profile::fallback_name(player_id),
+            team: None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_big_matches_are_split_and_put_back_together() {
+    use crate::grid::worst_case_grid;
+
+    let grids: Vec<Grid> = (0..royale::MAX_PLAYERS)
+        .map(|seed| worst_case_grid(20, 10, u64::from(seed)))
+        .collect();
+    let players = test_players(royale::MAX_PLAYERS);
+    let parts = split(7, &grids, &players);
+    assert!(parts.len() > 1);
+
+    let mut snapshot = Snapshot::new();
+    for part in parts.into_iter().rev() {
+        assert!(!snapshot.is_complete());
+        snapshot.receive(part);
+    }
+    assert!(snapshot.is_complete());
+    assert_eq!(snapshot.tick(), 7);
+    assert_eq!(snapshot.players(), players.as_slice());
+    for (received, sent) in snapshot.grids().iter().zip(grids.iter()) {
+        assert_eq!(received.state_hash(), sent.state_hash());
+    }
+}
+
+#[test]
+fn test_older_parts_dont_replace_newer_grids() {
+    use crate::simulation;
+
+    let old = simulation::new_grids(20, 10, 2, 1);
+    let new = simulation::new_grids(20, 10, 2, 2);
+    let players = test_players(2);
+
+    let mut snapshot = Snapshot::new();
+    snapshot.receive(split(5, &new, &players).remove(0));
+    snapshot.receive(split(4, &old, &players).remove(0));
+    assert_eq!(snapshot.tick(), 5);
+    assert_eq!(snapshot.grids()[0].state_hash(), new[0].state_hash());
+
+    // Parts that can't belong to the match are ignored
+    let mut bogus = split(6, &old, &players).remove(0);
+    bogus.first_grid = 1;
+    snapshot.receive(bogus);
+    assert_eq!(snapshot.grids()[1].state_hash(), new[1].state_hash());
+}
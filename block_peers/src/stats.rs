@@ -0,0 +1,229 @@
+//! Counters kept for each address a socket talks to, so players can see
+//! how well their connection is doing.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How often another ping is sent to an address we're talking to.
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// A ping that hasn't been answered by now is counted as lost.
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+/// Loss is worked out over this many of the most recent pings.
+const PINGS_REMEMBERED: usize = 20;
+/// Rates are counted over windows of this length.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// What we know about the connection to one address at a moment in time.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct NetStats {
+    /// Smoothed round trip time, once a ping has been answered.
+    pub rtt: Option<Duration>,
+    /// Share of recent pings that went unanswered, from 0 to 1.
+    pub loss: f32,
+    pub bytes_sent_per_second: u32,
+    pub bytes_received_per_second: u32,
+    pub packets_sent_per_second: u32,
+    pub packets_received_per_second: u32,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Counts {
+    bytes_sent: u32,
+    bytes_received: u32,
+    packets_sent: u32,
+    packets_received: u32,
+}
+
+struct Ping {
+    id: u32,
+    sent_at: Instant,
+    answered: bool,
+}
+
+/// Collects the traffic to and from one address. Every method takes the
+/// current time so tests don't have to wait for it to pass.
+pub struct ConnectionStats {
+    rtt: Option<Duration>,
+    pings: VecDeque<Ping>,
+    next_ping_id: u32,
+    window_started: Instant,
+    current: Counts,
+    // The last full window, which is what the rates are reported from
+    previous: Counts,
+}
+
+impl ConnectionStats {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            rtt: None,
+            pings: VecDeque::with_capacity(PINGS_REMEMBERED),
+            next_ping_id: 0,
+            window_started: now,
+            current: Counts::default(),
+            previous: Counts::default(),
+        }
+    }
+
+    pub fn sent(&mut self, bytes: usize, now: Instant) {
+        self.roll_window(now);
+        self.current.bytes_sent += bytes as u32;
+        self.current.packets_sent += 1;
+    }
+
+    pub fn received(&mut self, bytes: usize, now: Instant) {
+        self.roll_window(now);
+        self.current.bytes_received += bytes as u32;
+        self.current.packets_received += 1;
+    }
+
+    /// Returns the id of a new ping to send, if it's time for one.
+    pub fn ping_due(&mut self, now: Instant) -> Option<u32> {
+        if let Some(last) = self.pings.back() {
+            if now.duration_since(last.sent_at) < PING_INTERVAL {
+                return None;
+            }
+        }
+
+        if self.pings.len() == PINGS_REMEMBERED {
+            self.pings.pop_front();
+        }
+        let id = self.next_ping_id;
+        self.next_ping_id = self.next_ping_id.wrapping_add(1);
+        self.pings.push_back(Ping {
+            id,
+            sent_at: now,
+            answered: false,
+        });
+        Some(id)
+    }
+
+    /// Records the answer to a ping. Answers to pings we never sent, or
+    /// that were already answered, are ignored.
+    pub fn pong(&mut self, id: u32, now: Instant) {
+        let ping = match self
+            .pings
+            .iter_mut()
+            .find(|ping| ping.id == id && !ping.answered)
+        {
+            Some(ping) => ping,
+            None => return,
+        };
+        ping.answered = true;
+
+        // Smoothed the same way TCP does, so one slow packet doesn't make
+        // the number jump around
+        let sample = now.duration_since(ping.sent_at);
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    pub fn snapshot(&mut self, now: Instant) -> NetStats {
+        self.roll_window(now);
+
+        // Pings still within their timeout may yet be answered, so they
+        // don't count either way
+        let settled = self
+            .pings
+            .iter()
+            .filter(|ping| ping.answered || now.duration_since(ping.sent_at) >= PING_TIMEOUT);
+        let (total, lost) = settled.fold((0, 0), |(total, lost), ping| {
+            (total + 1, lost + !ping.answered as u32)
+        });
+        let loss = if total == 0 {
+            0.0
+        } else {
+            lost as f32 / total as f32
+        };
+
+        NetStats {
+            rtt: self.rtt,
+            loss,
+            bytes_sent_per_second: self.previous.bytes_sent,
+            bytes_received_per_second: self.previous.bytes_received,
+            packets_sent_per_second: self.previous.packets_sent,
+            packets_received_per_second: self.previous.packets_received,
+        }
+    }
+
+    fn roll_window(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_started);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+
+        // A whole window without traffic means nothing was sent in the
+        // last one either
+        self.previous = if elapsed < RATE_WINDOW * 2 {
+            self.current
+        } else {
+            Counts::default()
+        };
+        self.current = Counts::default();
+        self.window_started = now;
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_stats_count_rates_over_the_last_window() {
+    let start = Instant::now();
+    let mut stats = ConnectionStats::new(start);
+
+    for _ in 0..10 {
+        stats.sent(100, start);
+    }
+    stats.received(40, start);
+    assert_eq!(stats.snapshot(start).bytes_sent_per_second, 0);
+
+    let later = start + RATE_WINDOW;
+    let snapshot = stats.snapshot(later);
+    assert_eq!(snapshot.bytes_sent_per_second, 1000);
+    assert_eq!(snapshot.packets_sent_per_second, 10);
+    assert_eq!(snapshot.bytes_received_per_second, 40);
+    assert_eq!(snapshot.packets_received_per_second, 1);
+
+    assert_eq!(stats.snapshot(later + RATE_WINDOW * 3), NetStats::default());
+}
+
+#[test]
+fn test_stats_smooth_round_trip_time() {
+    let start = Instant::now();
+    let mut stats = ConnectionStats::new(start);
+
+    let id = stats.ping_due(start).unwrap();
+    assert_eq!(stats.ping_due(start), None);
+    stats.pong(id, start + Duration::from_millis(80));
+    assert_eq!(stats.snapshot(start).rtt, Some(Duration::from_millis(80)));
+
+    let later = start + PING_INTERVAL;
+    let id = stats.ping_due(later).unwrap();
+    stats.pong(id, later + Duration::from_millis(160));
+    // Answering twice, or a ping we never sent, changes nothing
+    stats.pong(id, later + Duration::from_secs(1));
+    stats.pong(1234, later + Duration::from_secs(1));
+    assert_eq!(stats.snapshot(later).rtt, Some(Duration::from_millis(90)));
+}
+
+#[test]
+fn test_stats_count_unanswered_pings_as_lost() {
+    let start = Instant::now();
+    let mut stats = ConnectionStats::new(start);
+
+    for n in 0..4 {
+        let now = start + PING_INTERVAL * n;
+        let id = stats.ping_due(now).unwrap();
+        if n % 2 == 0 {
+            stats.pong(id, now);
+        }
+    }
+
+    // The last ping might still be answered
+    let now = start + PING_INTERVAL * 3;
+    assert!((stats.snapshot(now).loss - 1.0 / 3.0).abs() < 1e-6);
+    assert!((stats.snapshot(now + PING_TIMEOUT).loss - 0.5).abs() < 1e-6);
+}
@@ -0,0 +1,125 @@
+//! Decides how often to send snapshots to a client, backing off when its
+//! connection can't keep up and creeping back up once it can.
+
+use std::time::{Duration, Instant};
+
+use crate::stats::NetStats;
+
+/// Never send less often than this, or the game becomes unplayable.
+const MIN_SEND_RATE: f32 = 2.0;
+/// How much the rate goes back up each adjustment while things are fine.
+const RATE_STEP: f32 = 2.0;
+/// More loss than this is taken to mean we're sending too much.
+const LOSS_LIMIT: f32 = 0.05;
+/// The stats only change once a second, so reacting faster would just
+/// react to the same numbers twice.
+const ADJUST_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct SendThrottle {
+    tick_rate: f32,
+    max_rate: f32,
+    rate: f32,
+    // Bytes per second we're willing to send to this client
+    bandwidth: u32,
+    last_sent: Option<u64>,
+    last_adjusted: Instant,
+}
+
+impl SendThrottle {
+    /// Starts out sending `max_rate` times a second, out of the
+    /// `tick_rate` ticks the server runs each second.
+    pub fn new(tick_rate: u32, max_rate: u32, bandwidth: u32, now: Instant) -> Self {
+        let max_rate = max_rate.min(tick_rate) as f32;
+        Self {
+            tick_rate: tick_rate as f32,
+            max_rate,
+            rate: max_rate,
+            bandwidth,
+            last_sent: None,
+            last_adjusted: now,
+        }
+    }
+
+    /// Sends per second.
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    /// Halves the rate when the client is losing packets or we're sending
+    /// it more than its share of bandwidth, otherwise raises it a little.
+    pub fn adjust(&mut self, stats: &NetStats, now: Instant) {
+        if now.duration_since(self.last_adjusted) < ADJUST_INTERVAL {
+            return;
+        }
+        self.last_adjusted = now;
+
+        let congested = stats.loss > LOSS_LIMIT || stats.bytes_sent_per_second > self.bandwidth;
+        self.rate = if congested {
+            (self.rate / 2.0).max(MIN_SEND_RATE.min(self.max_rate))
+        } else {
+            (self.rate + RATE_STEP).min(self.max_rate)
+        };
+    }
+
+    /// Whether it's time to send on this tick, in which case the send is
+    /// assumed to happen straight away. Counting ticks rather than time
+    /// keeps the sends evenly spaced however late the ticks run.
+    pub fn ready(&mut self, tick: u64) -> bool {
+        let interval = (self.tick_rate / self.rate).round().max(1.0) as u64;
+        let due = match self.last_sent {
+            Some(last_sent) => tick >= last_sent + interval,
+            None => true,
+        };
+        if due {
+            self.last_sent = Some(tick);
+        }
+        due
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_throttle_spaces_out_sends() {
+    let start = Instant::now();
+    let mut throttle = SendThrottle::new(60, 20, 10_000, start);
+    assert_eq!((0..60).filter(|tick| throttle.ready(*tick)).count(), 20);
+
+    // Never more than once a tick
+    let mut throttle = SendThrottle::new(30, 60, 10_000, start);
+    assert_eq!((0..60).filter(|tick| throttle.ready(*tick)).count(), 60);
+}
+
+#[test]
+fn test_throttle_backs_off_and_recovers() {
+    let start = Instant::now();
+    let mut throttle = SendThrottle::new(60, 20, 10_000, start);
+    let lossy = NetStats {
+        loss: 0.2,
+        ..NetStats::default()
+    };
+    let saturated = NetStats {
+        bytes_sent_per_second: 20_000,
+        ..NetStats::default()
+    };
+
+    throttle.adjust(&lossy, start + ADJUST_INTERVAL);
+    assert_eq!(throttle.rate(), 10.0);
+    // Too soon to change again
+    throttle.adjust(&lossy, start + ADJUST_INTERVAL);
+    assert_eq!(throttle.rate(), 10.0);
+    throttle.adjust(&saturated, start + ADJUST_INTERVAL * 2);
+    assert_eq!(throttle.rate(), 5.0);
+
+    for n in 3..20 {
+        throttle.adjust(&lossy, start + ADJUST_INTERVAL * n);
+    }
+    assert_eq!(throttle.rate(), MIN_SEND_RATE);
+
+    for n in 20..40 {
+        throttle.adjust(&NetStats::default(), start + ADJUST_INTERVAL * n);
+    }
+    assert_eq!(throttle.rate(), 20.0);
+}
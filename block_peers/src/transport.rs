@@ -0,0 +1,879 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+use tungstenite::handshake::client::ClientHandshake;
+use tungstenite::handshake::client::Response;
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::{Message as WebSocketMessage, WebSocket};
+
+use crate::rng::Rng;
+
+/// Extra delay for a datagram the simulator reorders, unless the latency
+/// is longer.
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+/// Longest latency or jitter the simulator will add, far more than any
+/// real connection but still a time it can count to.
+const MAX_SIMULATED_DELAY: Duration = Duration::from_secs(60 * 60);
+/// Largest datagram the simulator will pass along.
+const MAX_SIMULATED_DATAGRAM: usize = 65536;
+
+/// Moves datagrams between addresses. `Socket` does the framing, encryption
+/// and compression on top, so it works the same over any of these.
+///
+/// Datagrams may be lost, but each one that arrives arrives whole. Neither
+/// method blocks.
+pub trait Transport {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    fn send_to(&mut self, bytes: &[u8], addr: SocketAddr) -> io::Result<()>;
+
+    /// Copies the next datagram into `buffer`, returning its length and who
+    /// sent it, or `None` if there's nothing waiting. A datagram longer than
+    /// the buffer is cut short.
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>>;
+}
+
+// ----
+// UDP
+// ----
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+
+    /// Allows sending to broadcast addresses, for finding servers on the
+    /// local network.
+    pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        self.socket.set_broadcast(broadcast)
+    }
+}
+
+impl Transport for UdpTransport {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn send_to(&mut self, bytes: &[u8], addr: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(bytes, addr)?;
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match self.socket.recv_from(buffer) {
+            Ok(received) => Ok(Some(received)),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// ---------
+// In Memory
+// ---------
+
+/// One end of a pair of transports joined by a channel, so sockets can talk
+/// to each other in tests without opening real ports. Nothing is ever lost
+/// or reordered.
+pub struct MemoryTransport {
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    /// Creates two transports that pretend to be bound to the given
+    /// addresses and can only reach each other.
+    pub fn pair(first: SocketAddr, second: SocketAddr) -> (Self, Self) {
+        let (first_sender, second_receiver) = channel();
+        let (second_sender, first_receiver) = channel();
+        (
+            Self {
+                local_addr: first,
+                peer_addr: second,
+                sender: first_sender,
+                receiver: first_receiver,
+            },
+            Self {
+                local_addr: second,
+                peer_addr: first,
+                sender: second_sender,
+                receiver: second_receiver,
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    // Like UDP, sending to an address nobody is listening on isn't an error,
+    // the datagram just never arrives.
+    fn send_to(&mut self, bytes: &[u8], addr: SocketAddr) -> io::Result<()> {
+        if addr == self.peer_addr {
+            // Fails only once the other end is gone, which is the same thing
+            let _ = self.sender.send(bytes.to_vec());
+        }
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match self.receiver.try_recv() {
+            Ok(bytes) => Ok(Some((copy_datagram(&bytes, buffer), self.peer_addr))),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => Ok(None),
+        }
+    }
+}
+
+// ---------
+// WebSocket
+// ---------
+
+/// Sends each datagram as a binary WebSocket message over TCP, for networks
+/// (and browsers) where UDP isn't available. Nothing is lost, but a slow
+/// connection holds up everything behind it.
+///
+/// A bound transport accepts connections from any number of clients, a
+/// connected one only talks to the address it connected to.
+pub struct WebSocketTransport {
+    local_addr: SocketAddr,
+    listener: Option<TcpListener>,
+    handshakes: Vec<(
+        SocketAddr,
+        MidHandshake<ServerHandshake<TcpStream, NoCallback>>,
+    )>,
+    // The handshake with the server we're connecting to, if it isn't done
+    client_handshake: Option<(SocketAddr, MidHandshake<ClientHandshake<TcpStream>>)>,
+    // Sent before the handshake with the server was done
+    queued: Vec<Vec<u8>>,
+    connections: HashMap<SocketAddr, WebSocket<TcpStream>>,
+    // Messages read while looking for one from somewhere else
+    received: VecDeque<(SocketAddr, Vec<u8>)>,
+}
+
+impl WebSocketTransport {
+    /// Listens for clients on the given address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            local_addr: listener.local_addr()?,
+            listener: Some(listener),
+            handshakes: Vec::new(),
+            client_handshake: None,
+            queued: Vec::new(),
+            connections: HashMap::new(),
+            received: VecDeque::new(),
+        })
+    }
+
+    /// Connects to a server listening with `bind`. Anything sent before the
+    /// WebSocket handshake is done goes out once it is.
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+
+        let mut transport = Self {
+            local_addr: stream.local_addr()?,
+            listener: None,
+            handshakes: Vec::new(),
+            client_handshake: None,
+            queued: Vec::new(),
+            connections: HashMap::new(),
+            received: VecDeque::new(),
+        };
+
+        let url = format!("ws://{}/", addr);
+        let result = tungstenite::client(url.as_str(), stream);
+        transport.continue_client_handshake(addr, result)?;
+        Ok(transport)
+    }
+
+    fn continue_client_handshake(
+        &mut self,
+        addr: SocketAddr,
+        result: Result<
+            (WebSocket<TcpStream>, Response),
+            HandshakeError<ClientHandshake<TcpStream>>,
+        >,
+    ) -> io::Result<()> {
+        match result {
+            Ok((websocket, _)) => {
+                self.connections.insert(addr, websocket);
+                for bytes in std::mem::take(&mut self.queued) {
+                    self.send_to(&bytes, addr)?;
+                }
+                Ok(())
+            }
+            Err(HandshakeError::Interrupted(handshake)) => {
+                self.client_handshake = Some((addr, handshake));
+                Ok(())
+            }
+            Err(HandshakeError::Failure(e)) => Err(websocket_error(e)),
+        }
+    }
+
+    fn accept_connections(&mut self) -> io::Result<()> {
+        loop {
+            let accepted = match &self.listener {
+                Some(listener) => listener.accept(),
+                None => return Ok(()),
+            };
+
+            match accepted {
+                Ok((stream, addr)) => {
+                    stream.set_nonblocking(true)?;
+                    stream.set_nodelay(true)?;
+                    self.continue_handshake(addr, tungstenite::accept(stream));
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Handshakes happen a bit at a time as the client's request comes in, so
+    // a client that never finishes one can't hold up anyone else.
+    fn continue_handshakes(&mut self) -> io::Result<()> {
+        for (addr, handshake) in std::mem::take(&mut self.handshakes) {
+            self.continue_handshake(addr, handshake.handshake());
+        }
+
+        if let Some((addr, handshake)) = self.client_handshake.take() {
+            self.continue_client_handshake(addr, handshake.handshake())?;
+        }
+        Ok(())
+    }
+
+    fn continue_handshake(
+        &mut self,
+        addr: SocketAddr,
+        result: Result<
+            WebSocket<TcpStream>,
+            HandshakeError<ServerHandshake<TcpStream, NoCallback>>,
+        >,
+    ) {
+        match result {
+            Ok(websocket) => {
+                self.connections.insert(addr, websocket);
+            }
+            Err(HandshakeError::Interrupted(handshake)) => self.handshakes.push((addr, handshake)),
+            Err(HandshakeError::Failure(e)) => {
+                debug!("websocket handshake with {} failed: {}", addr, e)
+            }
+        }
+    }
+
+    // Reads whatever has arrived on every connection, dropping the ones
+    // that have closed.
+    fn read_connections(&mut self) {
+        let mut closed = Vec::new();
+        for (addr, websocket) in self.connections.iter_mut() {
+            loop {
+                match websocket.read() {
+                    Ok(WebSocketMessage::Binary(bytes)) => self.received.push_back((*addr, bytes)),
+                    // Pings are answered by tungstenite itself
+                    Ok(_) => {}
+                    Err(tungstenite::Error::Io(ref e)) if e.kind() == ErrorKind::WouldBlock => {
+                        break
+                    }
+                    Err(e) => {
+                        debug!("websocket connection with {} closed: {}", addr, e);
+                        closed.push(*addr);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for addr in closed {
+            self.connections.remove(&addr);
+        }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn send_to(&mut self, bytes: &[u8], addr: SocketAddr) -> io::Result<()> {
+        let websocket = match self.connections.get_mut(&addr) {
+            Some(websocket) => websocket,
+            None => {
+                match &self.client_handshake {
+                    Some((server_addr, _)) if *server_addr == addr => {
+                        self.queued.push(bytes.to_vec())
+                    }
+                    // Nobody to send it to, so it's lost like a datagram would be
+                    _ => {}
+                }
+                return Ok(());
+            }
+        };
+
+        match websocket.send(WebSocketMessage::Binary(bytes.to_vec())) {
+            Ok(()) => Ok(()),
+            // Queued, and written out the next time anything is sent or read
+            Err(tungstenite::Error::Io(ref e)) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(e) => {
+                self.connections.remove(&addr);
+                Err(websocket_error(e))
+            }
+        }
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        if self.received.is_empty() {
+            self.accept_connections()?;
+            self.continue_handshakes()?;
+            self.read_connections();
+        }
+
+        match self.received.pop_front() {
+            Some((addr, bytes)) => Ok(Some((copy_datagram(&bytes, buffer), addr))),
+            None => Ok(None),
+        }
+    }
+}
+
+fn websocket_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(ErrorKind::InvalidData, e),
+    }
+}
+
+// Copies as much of the datagram as fits, the same as `UdpSocket` does with
+// one bigger than the buffer.
+fn copy_datagram(bytes: &[u8], buffer: &mut [u8]) -> usize {
+    let length = bytes.len().min(buffer.len());
+    buffer[..length].copy_from_slice(&bytes[..length]);
+    length
+}
+
+// --------------------
+// Simulated Conditions
+// --------------------
+
+/// How bad to make the network when simulating one. Every datagram, in
+/// either direction, is delayed by `latency` plus up to `jitter`, and may be
+/// lost, duplicated or held back long enough to arrive after later ones.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct NetworkConditions {
+    pub latency: Duration,
+    pub jitter: Duration,
+    /// Chance of a datagram being dropped, from 0 to 1.
+    pub loss: f64,
+    /// Chance of a datagram arriving twice.
+    pub duplicate: f64,
+    /// Chance of a datagram being held back so later ones overtake it.
+    pub reorder: f64,
+    /// Seeds the random choices, so a run can be repeated exactly.
+    pub seed: u64,
+}
+
+impl FromStr for NetworkConditions {
+    type Err = String;
+
+    /// Parses a comma separated list such as
+    /// `latency=100,jitter=20,loss=5,duplicate=1,reorder=2,seed=7`. Times
+    /// are in milliseconds and chances in percent. Anything left out is zero.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut conditions = Self::default();
+        for setting in s.split(',').filter(|setting| !setting.is_empty()) {
+            let (name, value) = match setting.split_once('=') {
+                Some(pair) => pair,
+                None => return Err(format!("expected name=value, got {}", setting)),
+            };
+            let number = |value: &str| {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|number| number.is_finite() && *number >= 0.0)
+                    .ok_or_else(|| format!("invalid value for {}: {}", name, value))
+            };
+            let millis = |value: &str| {
+                number(value).and_then(|number| {
+                    Duration::try_from_secs_f64(number / 1000.0)
+                        .ok()
+                        .filter(|delay| *delay <= MAX_SIMULATED_DELAY)
+                        .ok_or_else(|| format!("{} can't be over an hour", name))
+                })
+            };
+            let percent = |value: &str| {
+                number(value).and_then(|number| {
+                    if number <= 100.0 {
+                        Ok(number / 100.0)
+                    } else {
+                        Err(format!("{} can't be over 100 percent", name))
+                    }
+                })
+            };
+
+            match name {
+                "latency" => conditions.latency = millis(value)?,
+                "jitter" => conditions.jitter = millis(value)?,
+                "loss" => conditions.loss = percent(value)?,
+                "duplicate" => conditions.duplicate = percent(value)?,
+                "reorder" => conditions.reorder = percent(value)?,
+                "seed" => {
+                    conditions.seed = value
+                        .parse()
+                        .map_err(|_| format!("invalid value for seed: {}", value))?
+                }
+                _ => return Err(format!("unknown network condition {}", name)),
+            }
+        }
+        Ok(conditions)
+    }
+}
+
+/// Where the simulator gets the time from. Tests use a manual clock so
+/// what arrives when doesn't depend on how fast they run.
+enum Clock {
+    System,
+    Manual(Instant),
+}
+
+struct Delayed {
+    due: Instant,
+    // Keeps datagrams due at the same time in the order they were sent
+    order: u64,
+    addr: SocketAddr,
+    bytes: Vec<u8>,
+}
+
+/// Wraps another transport to make the network worse than it really is, so
+/// problems players see on bad connections can be reproduced on loopback.
+pub struct SimulatedTransport {
+    inner: Box<dyn Transport>,
+    conditions: NetworkConditions,
+    rng: Rng,
+    clock: Clock,
+    next_order: u64,
+    outgoing: Vec<Delayed>,
+    incoming: Vec<Delayed>,
+    buffer: Vec<u8>,
+}
+
+impl SimulatedTransport {
+    pub fn new(inner: Box<dyn Transport>, conditions: NetworkConditions) -> Self {
+        Self {
+            inner,
+            conditions,
+            rng: Rng::new(conditions.seed),
+            clock: Clock::System,
+            next_order: 0,
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+            buffer: vec![0; MAX_SIMULATED_DATAGRAM],
+        }
+    }
+
+    /// Stops following the system clock. Time only passes from then on
+    /// when `advance` is called.
+    pub fn use_manual_clock(&mut self) {
+        self.clock = Clock::Manual(self.now());
+    }
+
+    pub fn advance(&mut self, by: Duration) {
+        match &mut self.clock {
+            Clock::Manual(now) => *now += by,
+            Clock::System => panic!("can only advance a manual clock"),
+        }
+    }
+
+    fn now(&self) -> Instant {
+        match self.clock {
+            Clock::System => Instant::now(),
+            Clock::Manual(now) => now,
+        }
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        // The top 53 bits make an evenly spread f64 in [0, 1)
+        let roll = (self.rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        roll < probability
+    }
+
+    // Decides what happens to a datagram and queues up each copy that
+    // will arrive, along with when.
+    fn schedule(&mut self, addr: SocketAddr, bytes: &[u8]) -> Vec<Delayed> {
+        if self.chance(self.conditions.loss) {
+            return Vec::new();
+        }
+        let copies = if self.chance(self.conditions.duplicate) {
+            2
+        } else {
+            1
+        };
+
+        // A copy due later than the clock can count is as good as lost
+        let now = self.now();
+        (0..copies)
+            .filter_map(|_| {
+                let jitter = self.conditions.jitter.as_micros() as usize;
+                let jitter = Duration::from_micros(self.rng.gen_range(0, jitter + 1) as u64);
+                let mut delay = self.conditions.latency.checked_add(jitter);
+                if self.chance(self.conditions.reorder) {
+                    let held_back = REORDER_DELAY.max(self.conditions.latency);
+                    delay = delay.and_then(|delay| delay.checked_add(held_back));
+                }
+                let due = delay.and_then(|delay| now.checked_add(delay))?;
+
+                self.next_order += 1;
+                Some(Delayed {
+                    due,
+                    order: self.next_order,
+                    addr,
+                    bytes: bytes.to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    fn send_due(&mut self) -> io::Result<()> {
+        let now = self.now();
+        while let Some(delayed) = take_next_due(&mut self.outgoing, now) {
+            self.inner.send_to(&delayed.bytes, delayed.addr)?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport for SimulatedTransport {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn send_to(&mut self, bytes: &[u8], addr: SocketAddr) -> io::Result<()> {
+        let scheduled = self.schedule(addr, bytes);
+        self.outgoing.extend(scheduled);
+        self.send_due()
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        self.send_due()?;
+
+        while let Some((size, addr)) = self.inner.recv_from(&mut self.buffer)? {
+            let bytes = self.buffer[..size].to_vec();
+            let scheduled = self.schedule(addr, &bytes);
+            self.incoming.extend(scheduled);
+        }
+
+        let now = self.now();
+        match take_next_due(&mut self.incoming, now) {
+            Some(delayed) => Ok(Some((copy_datagram(&delayed.bytes, buffer), delayed.addr))),
+            None => Ok(None),
+        }
+    }
+}
+
+fn take_next_due(queue: &mut Vec<Delayed>, now: Instant) -> Option<Delayed> {
+    let (index, _) = queue
+        .iter()
+        .enumerate()
+        .filter(|(_, delayed)| delayed.due <= now)
+        .min_by_key(|(_, delayed)| (delayed.due, delayed.order))?;
+    Some(queue.remove(index))
+}
+
+// --------
+// Tests
+// --------
+
+#[cfg(test)]
+fn receive_within<T: Transport>(transport: &mut T, buffer: &mut [u8]) -> (usize, SocketAddr) {
+    let started = std::time::Instant::now();
+    while started.elapsed() < std::time::Duration::from_secs(2) {
+        if let Some(received) = transport.recv_from(buffer).unwrap() {
+            return received;
+        }
+    }
+    panic!("nothing received");
+}
+
+// Both ends only make progress on the handshake when asked to receive
+#[cfg(test)]
+fn connect_websocket(server: &mut WebSocketTransport) -> WebSocketTransport {
+    let server_addr = server.local_addr().unwrap();
+    let mut client = WebSocketTransport::connect(server_addr).unwrap();
+    let mut buffer = [0; 64];
+
+    let started = std::time::Instant::now();
+    while client.connections.is_empty() {
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        assert!(server.recv_from(&mut buffer).unwrap().is_none());
+        assert!(client.recv_from(&mut buffer).unwrap().is_none());
+    }
+    client
+}
+
+#[test]
+fn test_memory_transports_only_reach_each_other() {
+    let first_addr = "127.0.0.1:1".parse().unwrap();
+    let second_addr = "127.0.0.1:2".parse().unwrap();
+    let (mut first, mut second) = MemoryTransport::pair(first_addr, second_addr);
+    let mut buffer = [0; 8];
+
+    first.send_to(b"hello", second_addr).unwrap();
+    first
+        .send_to(b"lost", "127.0.0.1:3".parse().unwrap())
+        .unwrap();
+    assert_eq!(
+        second.recv_from(&mut buffer).unwrap(),
+        Some((5, first_addr))
+    );
+    assert_eq!(&buffer[..5], b"hello");
+    assert_eq!(second.recv_from(&mut buffer).unwrap(), None);
+
+    // Datagrams too big for the buffer are cut short
+    second.send_to(b"much too long", first_addr).unwrap();
+    assert_eq!(
+        first.recv_from(&mut buffer).unwrap(),
+        Some((8, second_addr))
+    );
+}
+
+#[test]
+fn test_websocket_transport_exchanges_datagrams() {
+    let mut server = WebSocketTransport::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut buffer = [0; 64];
+
+    let mut clients: Vec<WebSocketTransport> =
+        (0..2).map(|_| connect_websocket(&mut server)).collect();
+    for (index, client) in clients.iter_mut().enumerate() {
+        client.send_to(&[index as u8; 3], server_addr).unwrap();
+    }
+
+    for _ in 0..clients.len() {
+        let (size, addr) = receive_within(&mut server, &mut buffer);
+        assert_eq!(size, 3);
+        let index = buffer[0] as usize;
+        assert_eq!(addr, clients[index].local_addr().unwrap());
+
+        server.send_to(b"reply", addr).unwrap();
+        let (size, from) = receive_within(&mut clients[index], &mut buffer);
+        assert_eq!(&buffer[..size], b"reply");
+        assert_eq!(from, server_addr);
+    }
+}
+
+#[test]
+fn test_websocket_transport_forgets_closed_connections() {
+    let mut server = WebSocketTransport::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut buffer = [0; 64];
+
+    let mut client = connect_websocket(&mut server);
+    let client_addr = client.local_addr().unwrap();
+    client.send_to(b"hi", server_addr).unwrap();
+    receive_within(&mut server, &mut buffer);
+    assert!(server.connections.contains_key(&client_addr));
+
+    drop(client);
+    let started = std::time::Instant::now();
+    while server.connections.contains_key(&client_addr) {
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        server.recv_from(&mut buffer).unwrap();
+    }
+}
+
+#[test]
+fn test_websocket_transport_sends_what_was_queued_during_the_handshake() {
+    let mut server = WebSocketTransport::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = WebSocketTransport::connect(server_addr).unwrap();
+    let mut buffer = [0; 64];
+
+    client.send_to(b"early", server_addr).unwrap();
+    let started = std::time::Instant::now();
+    let received = loop {
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        client.recv_from(&mut buffer).unwrap();
+        if let Some((size, _)) = server.recv_from(&mut buffer).unwrap() {
+            break buffer[..size].to_vec();
+        }
+    };
+    assert_eq!(received, b"early");
+}
+
+#[cfg(test)]
+fn simulated_pair(conditions: NetworkConditions) -> (SimulatedTransport, MemoryTransport) {
+    let first_addr = "127.0.0.1:1".parse().unwrap();
+    let second_addr = "127.0.0.1:2".parse().unwrap();
+    let (first, second) = MemoryTransport::pair(first_addr, second_addr);
+    let mut simulated = SimulatedTransport::new(Box::new(first), conditions);
+    simulated.use_manual_clock();
+    (simulated, second)
+}
+
+// Sends numbered datagrams through the simulator, letting `ticks`
+// milliseconds pass after each, and returns the numbers in the order they
+// arrived.
+#[cfg(test)]
+fn simulate_sends(conditions: NetworkConditions, count: u32, ticks: u64) -> Vec<u32> {
+    let (mut simulated, mut other) = simulated_pair(conditions);
+    let other_addr = other.local_addr().unwrap();
+    let mut buffer = [0; 4];
+    let mut received = Vec::new();
+
+    for number in 0..count + 1000 {
+        if number < count {
+            simulated
+                .send_to(&number.to_le_bytes(), other_addr)
+                .unwrap();
+        }
+        simulated.advance(Duration::from_millis(ticks));
+        simulated.recv_from(&mut buffer).unwrap();
+        while other.recv_from(&mut buffer).unwrap().is_some() {
+            received.push(u32::from_le_bytes(buffer));
+        }
+    }
+    received
+}
+
+#[test]
+fn test_simulator_passes_everything_through_on_a_perfect_network() {
+    let received = simulate_sends(NetworkConditions::default(), 100, 0);
+    assert_eq!(received, (0..100).collect::<Vec<u32>>());
+}
+
+#[test]
+fn test_simulator_delays_datagrams_both_ways() {
+    let conditions = NetworkConditions {
+        latency: Duration::from_millis(100),
+        ..NetworkConditions::default()
+    };
+    let (mut simulated, mut other) = simulated_pair(conditions);
+    let simulated_addr = simulated.local_addr().unwrap();
+    let other_addr = other.local_addr().unwrap();
+    let mut buffer = [0; 8];
+
+    simulated.send_to(b"out", other_addr).unwrap();
+    other.send_to(b"in", simulated_addr).unwrap();
+    // Incoming datagrams are only delayed from when they're first polled for
+    assert_eq!(simulated.recv_from(&mut buffer).unwrap(), None);
+    simulated.advance(Duration::from_millis(99));
+    assert_eq!(simulated.recv_from(&mut buffer).unwrap(), None);
+    assert_eq!(other.recv_from(&mut buffer).unwrap(), None);
+
+    simulated.advance(Duration::from_millis(1));
+    assert_eq!(
+        simulated.recv_from(&mut buffer).unwrap(),
+        Some((2, other_addr))
+    );
+    assert_eq!(
+        other.recv_from(&mut buffer).unwrap(),
+        Some((3, simulated_addr))
+    );
+}
+
+#[test]
+fn test_simulator_loses_and_duplicates_datagrams() {
+    let lossy = NetworkConditions {
+        loss: 0.25,
+        ..NetworkConditions::default()
+    };
+    let received = simulate_sends(lossy, 1000, 1);
+    assert!(received.len() > 700 && received.len() < 800);
+
+    let duplicating = NetworkConditions {
+        duplicate: 0.1,
+        ..NetworkConditions::default()
+    };
+    let received = simulate_sends(duplicating, 1000, 1);
+    assert!(received.len() > 1050 && received.len() < 1150);
+}
+
+#[test]
+fn test_simulator_reorders_datagrams() {
+    let conditions = NetworkConditions {
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(10),
+        reorder: 0.1,
+        ..NetworkConditions::default()
+    };
+    let received = simulate_sends(conditions, 200, 5);
+
+    let mut sorted = received.clone();
+    sorted.sort();
+    assert_eq!(sorted, (0..200).collect::<Vec<u32>>());
+    assert_ne!(received, sorted);
+}
+
+#[test]
+fn test_simulator_repeats_itself_given_the_same_seed() {
+    let conditions = NetworkConditions {
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(20),
+        loss: 0.1,
+        duplicate: 0.1,
+        reorder: 0.1,
+        seed: 37,
+    };
+    let first = simulate_sends(conditions, 300, 3);
+    assert_eq!(simulate_sends(conditions, 300, 3), first);
+
+    let other_seed = NetworkConditions {
+        seed: 38,
+        ..conditions
+    };
+    assert_ne!(simulate_sends(other_seed, 300, 3), first);
+}
+
+#[test]
+fn test_simulator_drops_datagrams_due_too_far_ahead() {
+    let conditions = NetworkConditions {
+        latency: Duration::MAX,
+        reorder: 1.0,
+        ..NetworkConditions::default()
+    };
+    assert!(simulate_sends(conditions, 10, 1).is_empty());
+}
+
+#[test]
+fn test_network_conditions_parse() {
+    let conditions: NetworkConditions =
+        "latency=100,jitter=20.5,loss=5,duplicate=1,reorder=2,seed=7"
+            .parse()
+            .unwrap();
+    assert_eq!(conditions.latency, Duration::from_millis(100));
+    assert_eq!(conditions.jitter, Duration::from_micros(20500));
+    assert!((conditions.loss - 0.05).abs() < 1e-9);
+    assert!((conditions.duplicate - 0.01).abs() < 1e-9);
+    assert!((conditions.reorder - 0.02).abs() < 1e-9);
+    assert_eq!(conditions.seed, 7);
+
+    assert_eq!("".parse(), Ok(NetworkConditions::default()));
+    assert!("loss=101".parse::<NetworkConditions>().is_err());
+    assert!("latency=-1".parse::<NetworkConditions>().is_err());
+    assert!("latency=inf".parse::<NetworkConditions>().is_err());
+    assert!("jitter=inf".parse::<NetworkConditions>().is_err());
+    assert!("jitter=NaN".parse::<NetworkConditions>().is_err());
+    assert!("loss=NaN".parse::<NetworkConditions>().is_err());
+    assert!("latency=1e300".parse::<NetworkConditions>().is_err());
+    assert!("latency=1e22".parse::<NetworkConditions>().is_err());
+    assert!("jitter=3600001".parse::<NetworkConditions>().is_err());
+    assert!("latency=3600000,jitter=3600000"
+        .parse::<NetworkConditions>()
+        .is_ok());
+    assert!("speed=10".parse::<NetworkConditions>().is_err());
+    assert!("latency".parse::<NetworkConditions>().is_err());
+}
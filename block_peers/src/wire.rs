@@ -0,0 +1,210 @@
+//! Helpers for hand-written compact encodings, used where bincode's output
+//! is too large to send every tick.
+//!
+//! Integers are written as LEB128 varints, so small values take a single
+//! byte, and signed ones are zigzag encoded first so small negative values
+//! stay small too.
+
+#[derive(Default)]
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    pub fn signed_varint(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    /// Packs four bit values two to a byte, first value in the high half.
+    pub fn nibbles<I: IntoIterator<Item = u8>>(&mut self, values: I) {
+        let mut pending = None;
+        for value in values {
+            debug_assert!(value < 16, "nibble out of range");
+            match pending.take() {
+                None => pending = Some(value << 4),
+                Some(high) => self.bytes.push(high | value),
+            }
+        }
+        if let Some(high) = pending {
+            self.bytes.push(high);
+        }
+    }
+}
+
+/// Reads what `Writer` wrote. Every method returns `None` once the bytes
+/// run out or don't make sense, so malformed input can't cause a panic.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        let (first, rest) = self.bytes.split_first()?;
+        self.bytes = rest;
+        Some(*first)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        if self.bytes.len() < 8 {
+            return None;
+        }
+        let (value, rest) = self.bytes.split_at(8);
+        self.bytes = rest;
+
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(value);
+        Some(u64::from_le_bytes(buffer))
+    }
+
+    pub fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            // Only the lowest bit of the tenth byte still fits
+            if shift == 63 && byte > 1 {
+                return None;
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    /// A varint that must fit in a `u32`.
+    pub fn varint_u32(&mut self) -> Option<u32> {
+        let value = self.varint()?;
+        if value > u64::from(u32::MAX) {
+            return None;
+        }
+        Some(value as u32)
+    }
+
+    pub fn signed_varint(&mut self) -> Option<i64> {
+        let value = self.varint()?;
+        Some((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// Reads `count` four bit values written by `Writer::nibbles`.
+    pub fn nibbles(&mut self, count: usize) -> Option<Vec<u8>> {
+        let length = count.div_ceil(2);
+        if self.bytes.len() < length {
+            return None;
+        }
+        let (packed, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+
+        let values = packed
+            .iter()
+            .flat_map(|byte| vec![byte >> 4, byte & 0x0f])
+            .take(count)
+            .collect();
+        Some(values)
+    }
+}
+
+// --------
+// Tests
+// --------
+
+#[test]
+fn test_varints_roundtrip() {
+    let values = [0, 1, 127, 128, 300, u64::from(u32::MAX), u64::MAX];
+
+    let mut writer = Writer::new();
+    for value in values.iter() {
+        writer.varint(*value);
+    }
+    let bytes = writer.into_bytes();
+
+    let mut reader = Reader::new(&bytes);
+    for value in values.iter() {
+        assert_eq!(reader.varint(), Some(*value));
+    }
+    assert!(reader.is_empty());
+}
+
+#[test]
+fn test_small_varints_take_one_byte() {
+    let mut writer = Writer::new();
+    writer.varint(127);
+    writer.signed_varint(-64);
+    writer.signed_varint(63);
+    assert_eq!(writer.into_bytes().len(), 3);
+}
+
+#[test]
+fn test_signed_varints_roundtrip() {
+    let values = [0, -1, 1, -64, 64, i64::from(i32::MIN), i64::MAX, i64::MIN];
+
+    let mut writer = Writer::new();
+    for value in values.iter() {
+        writer.signed_varint(*value);
+    }
+    let bytes = writer.into_bytes();
+
+    let mut reader = Reader::new(&bytes);
+    for value in values.iter() {
+        assert_eq!(reader.signed_varint(), Some(*value));
+    }
+}
+
+#[test]
+fn test_nibbles_roundtrip() {
+    let values = vec![1, 15, 0, 7, 9];
+
+    let mut writer = Writer::new();
+    writer.nibbles(values.clone());
+    writer.u8(42);
+    let bytes = writer.into_bytes();
+    assert_eq!(bytes.len(), 4);
+
+    let mut reader = Reader::new(&bytes);
+    assert_eq!(reader.nibbles(values.len()), Some(values));
+    assert_eq!(reader.u8(), Some(42));
+}
+
+#[test]
+fn test_reader_refuses_short_or_overlong_input() {
+    assert_eq!(Reader::new(&[0x80, 0x80]).varint(), None);
+    assert_eq!(Reader::new(&[0xff; 11]).varint(), None);
+    assert_eq!(Reader::new(&[1, 2, 3]).u64(), None);
+    assert_eq!(Reader::new(&[0x12]).nibbles(3), None);
+    assert_eq!(
+        Reader::new(&[0x80, 0x80, 0x80, 0x80, 0x10]).varint_u32(),
+        None
+    );
+}